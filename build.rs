@@ -1,12 +1,23 @@
-extern crate cheddar;
+extern crate cbindgen;
 
 fn main() {
-    // Generate mp4parse.h.
-    cheddar::Cheddar::new().expect("could not read manifest")
-        .module("capi").expect("invalid module path")
-        .insert_code("// THIS FILE IS AUTOGENERATED BY mp4parse-rust/build.rs - DO NOT EDIT\n\n")
-        .insert_code("// This Source Code Form is subject to the terms of the Mozilla Public\n")
-        .insert_code("// License, v. 2.0. If a copy of the MPL was not distributed with this\n")
-        .insert_code("// file, You can obtain one at https://mozilla.org/MPL/2.0/.")
-        .run_build("include/mp4parse.h");
+    // Generate mp4parse.h from the C API alone, so the crate's other
+    // public constants stay out of it.
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("could not find manifest directory");
+    let capi = std::path::Path::new(&crate_dir).join("src").join("capi.rs");
+    println!("cargo:rerun-if-changed={}", capi.display());
+    let mut config: cbindgen::Config = Default::default();
+    config.language = cbindgen::Language::C;
+    config.header = Some(String::from(
+        "// THIS FILE IS AUTOGENERATED BY mp4parse-rust/build.rs - DO NOT EDIT\n\n\
+         // This Source Code Form is subject to the terms of the Mozilla Public\n\
+         // License, v. 2.0. If a copy of the MPL was not distributed with this\n\
+         // file, You can obtain one at https://mozilla.org/MPL/2.0/."));
+    config.include_guard = Some(String::from("MP4PARSE_H"));
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(capi)
+        .generate()
+        .expect("could not generate header")
+        .write_to_file(std::path::Path::new(&crate_dir).join("include").join("mp4parse.h"));
 }
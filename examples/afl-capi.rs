@@ -22,7 +22,7 @@ fn doit() {
     let mut input = Vec::new();
     std::io::stdin().read_to_end(&mut input).unwrap();
     let mut cursor = std::io::Cursor::new(input);
-    let io = mp4parse_io { read: vec_read, userdata: &mut cursor as *mut _ as *mut std::os::raw::c_void };
+    let io = mp4parse_io { read: Some(vec_read), userdata: &mut cursor as *mut _ as *mut std::os::raw::c_void };
     unsafe {
         let context = mp4parse_new(&io);
         let rv = mp4parse_read(context);
@@ -40,6 +40,9 @@ fn doit() {
                     track_id: 0,
                     duration: 0,
                     media_time: 0,
+                    language: [0; 4],
+                    name: std::ptr::null(),
+                    name_length: 0,
                 };
                 let rv = mp4parse_get_track_info(context, track, &mut info);
                 if rv == mp4parse_error::MP4PARSE_OK {
@@ -52,6 +55,11 @@ fn doit() {
                                 display_height: 0,
                                 image_width: 0,
                                 image_height: 0,
+                                profile: 0,
+                                level: 0,
+                                bit_depth: 0,
+                                chroma_format: 0,
+                                extra_data: Default::default(),
                             };
                             let rv = mp4parse_get_track_video_info(context, track, &mut video);
                             if rv == mp4parse_error::MP4PARSE_OK {
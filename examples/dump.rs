@@ -4,7 +4,53 @@ use std::env;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
 
-fn dump_file(filename: &String, verbose: bool) {
+/// Print the timestamp and byte range of each video sync sample.
+fn list_keyframes(context: &mp4parse::MediaContext) {
+    for (index, track) in context.tracks.iter().enumerate() {
+        match track.track_type {
+            mp4parse::TrackType::Video => {}
+            _ => continue,
+        }
+        let timescale = match track.timescale {
+            Some(timescale) if timescale.0 > 0 => timescale.0,
+            _ => continue,
+        };
+        let samples = match track.sample_table() {
+            Ok(samples) => samples,
+            Err(e) => {
+                println!("ERROR: {:?} in track {}", e, index);
+                continue;
+            }
+        };
+        for (number, sample) in samples.iter().enumerate().filter(|&(_, s)| s.sync) {
+            println!("track {} sample {} time {:.3}s bytes {}-{}",
+                     index, number,
+                     sample.composition_time as f64 / timescale as f64,
+                     sample.offset,
+                     (sample.offset + sample.size as u64).saturating_sub(1));
+        }
+    }
+}
+
+/// Print a hexdump of each box matching `path`.
+fn dump_boxes(reader: &mut File, path: &str) {
+    let nodes = match mp4parse::select_boxes(reader, path) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            println!("ERROR: {:?} selecting '{}'", e, path);
+            return;
+        }
+    };
+    for node in nodes {
+        println!("{:?} @ {} size {}", node.name, node.position, node.size);
+        match node.hexdump(reader, 4096) {
+            Ok(dump) => print!("{}", dump),
+            Err(e) => println!("ERROR: {:?}", e),
+        }
+    }
+}
+
+fn dump_file(filename: &String, verbose: bool, keyframes: bool, hexdump: Option<&String>) {
     let mut reader = match File::open(filename) {
         Ok(reader) => reader,
         _ => {
@@ -32,6 +78,12 @@ fn dump_file(filename: &String, verbose: bool) {
     if verbose {
         println!("-- result of parsing '{}' --\n{:?}", filename, context);
     }
+    if keyframes {
+        list_keyframes(&context);
+    }
+    if let Some(path) = hexdump {
+        dump_boxes(&mut reader, path);
+    }
 }
 
 fn main() {
@@ -39,16 +91,22 @@ fn main() {
     if args.len() < 2 {
         return;
     }
-    let (skip, verbose) = if args[1] == "-v" {
-        (2, true)
-    } else {
-        (1, false)
-    };
-    for filename in args.iter().skip(skip) {
+    let verbose = args.iter().any(|arg| arg == "-v");
+    // List keyframe byte ranges, e.g. for thumbnailing over HTTP.
+    let keyframes = args.iter().any(|arg| arg == "-k");
+    // Hexdump the boxes at a path, e.g. -x moov/trak[1]/mdia/minf/stbl/stsd.
+    let hexdump = args.iter().position(|arg| arg == "-x").and_then(|i| args.get(i + 1));
+    let filenames = args.iter().enumerate().skip(1)
+        .filter(|&(i, arg)| {
+            *arg != "-v" && *arg != "-k" && *arg != "-x" &&
+            !(i > 0 && args[i - 1] == "-x")
+        })
+        .map(|(_, arg)| arg);
+    for filename in filenames {
         if verbose {
             println!("-- dump of '{}' --", filename);
         }
-        dump_file(&filename, verbose);
+        dump_file(&filename, verbose, keyframes, hexdump);
         if verbose {
             println!("-- end of '{}' --", filename);
         }
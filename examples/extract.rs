@@ -0,0 +1,85 @@
+extern crate mp4parse;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process;
+
+fn fail(message: String) -> ! {
+    println!("ERROR: {}", message);
+    process::exit(1);
+}
+
+fn extract(input: &mut File, track: &mp4parse::Track, output: &mut File) -> mp4parse::Result<()> {
+    let samples = try!(track.sample_table());
+    match track.data {
+        Some(mp4parse::SampleEntry::Video(ref v)) => match v.codec_specific {
+            mp4parse::VideoCodecSpecific::AVCConfig(ref avcc) => {
+                let config = try!(mp4parse::avcc_to_annexb(avcc));
+                for sample in &samples {
+                    let data = try!(mp4parse::read_sample(input, sample));
+                    if sample.sync {
+                        try!(output.write_all(&config.parameter_sets));
+                    }
+                    try!(output.write_all(&try!(mp4parse::sample_to_annexb(&data, config.nal_length_size))));
+                }
+                Ok(())
+            }
+            _ => Err(mp4parse::Error::Unsupported("no elementary stream format for codec")),
+        },
+        Some(mp4parse::SampleEntry::Audio(ref a)) => match a.codec_specific {
+            mp4parse::AudioCodecSpecific::ES_Descriptor(ref esds) => {
+                let config = try!(mp4parse::esds_audio_specific_config(esds));
+                for sample in &samples {
+                    let data = try!(mp4parse::read_sample(input, sample));
+                    try!(output.write_all(&try!(mp4parse::adts_header(&config, data.len()))));
+                    try!(output.write_all(&data));
+                }
+                Ok(())
+            }
+            mp4parse::AudioCodecSpecific::OpusSpecificBox(ref opus) => {
+                let timescale = match track.timescale {
+                    Some(timescale) if timescale.0 > 0 => timescale.0,
+                    _ => return Err(mp4parse::Error::InvalidData("missing track timescale")),
+                };
+                let mut writer = try!(mp4parse::OggOpusWriter::new(output, 1, opus));
+                for sample in &samples {
+                    let data = try!(mp4parse::read_sample(input, sample));
+                    try!(writer.write_packet(&data, sample.duration as u64 * 48000 / timescale));
+                }
+                try!(writer.finish());
+                Ok(())
+            }
+            mp4parse::AudioCodecSpecific::FLACSpecificBox(ref flac) => {
+                // FLAC frames are self-delimiting, so follow the header as is.
+                try!(output.write_all(&try!(mp4parse::flac_stream_header(flac))));
+                for sample in &samples {
+                    try!(output.write_all(&try!(mp4parse::read_sample(input, sample))));
+                }
+                Ok(())
+            }
+        },
+        _ => Err(mp4parse::Error::Unsupported("no elementary stream format for codec")),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        println!("usage: {} <input.mp4> <track index> <output>", args[0]);
+        process::exit(2);
+    }
+    let mut input = File::open(&args[1]).unwrap_or_else(|_| fail(format!("invalid path '{}'", args[1])));
+    let mut context = mp4parse::MediaContext::new();
+    if let Err(e) = mp4parse::read_mp4(&mut input, &mut context) {
+        fail(format!("{:?} in '{}'", e, args[1]));
+    }
+    let track = match args[2].parse::<usize>().ok().and_then(|i| context.tracks.get(i)) {
+        Some(track) => track,
+        None => fail(format!("no track '{}'", args[2])),
+    };
+    let mut output = File::create(&args[3]).unwrap_or_else(|_| fail(format!("can't create '{}'", args[3])));
+    if let Err(e) = extract(&mut input, track, &mut output) {
+        fail(format!("{:?} extracting track {}", e, args[2]));
+    }
+}
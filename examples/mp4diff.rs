@@ -0,0 +1,38 @@
+extern crate mp4parse;
+
+use std::env;
+use std::fs::File;
+use std::process;
+
+fn parse_file(filename: &String) -> mp4parse::MediaContext {
+    let mut reader = match File::open(filename) {
+        Ok(reader) => reader,
+        _ => {
+            println!("ERROR: invalid path '{}'", filename);
+            process::exit(2);
+        }
+    };
+    let mut context = mp4parse::MediaContext::new();
+    if let Err(e) = mp4parse::read_mp4(&mut reader, &mut context) {
+        println!("ERROR: {:?} in '{}'", e, filename);
+        process::exit(2);
+    }
+    context
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("usage: {} <left.mp4> <right.mp4>", args[0]);
+        process::exit(2);
+    }
+    let left = parse_file(&args[1]);
+    let right = parse_file(&args[2]);
+    let differences = mp4parse::compare(&left, &right);
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    if !differences.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,374 @@
+/// Replay a C API capture log through the C API.
+///
+/// Logs are written by parsers built with the "capture" feature when
+/// MP4PARSE_CAPTURE_DIR is set. A parser is created with the recorded
+/// options, its read callback returns the logged reads in order, and
+/// each recorded call is made on it with the recorded arguments, so a
+/// parse or crash seen in an embedding application can be reproduced
+/// and debugged standalone. The result of each call is printed.
+
+extern crate mp4parse;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::raw::c_void;
+use std::process;
+
+use mp4parse::*;
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+/// A read callback result: data, or None for an error.
+type ReadResult = Option<Vec<u8>>;
+
+/// Queue the callback results which reproduce a recorded read.
+///
+/// The log records what each read of the parser's `mp4parse_io`
+/// returned, which may have taken several callbacks. Data is handed
+/// back in one callback, and a short read is ended with an empty one,
+/// since the original read stopped at the end of input or on a
+/// would-block.
+fn queue_read(fields: &[&str], reads: &mut VecDeque<ReadResult>) -> Option<()> {
+    let requested: usize = match fields.get(0).and_then(|f| f.parse().ok()) {
+        Some(requested) => requested,
+        None => return None,
+    };
+    let result: isize = match fields.get(1).and_then(|f| f.parse().ok()) {
+        Some(result) => result,
+        None => return None,
+    };
+    if result < 0 {
+        reads.push_back(None);
+        return Some(());
+    }
+    let mut data = match fields.get(2) {
+        Some(hex) => match parse_hex(hex) {
+            Some(data) => data,
+            None => return None,
+        },
+        None => Vec::new(),
+    };
+    data.truncate(result as usize);
+    let short = data.len() < requested;
+    if !data.is_empty() {
+        reads.push_back(Some(data));
+    }
+    if short {
+        reads.push_back(Some(Vec::new()));
+    }
+    Some(())
+}
+
+extern fn replay_read(buf: *mut u8, size: usize, userdata: *mut c_void) -> isize {
+    let reads = unsafe { &mut *(userdata as *mut VecDeque<ReadResult>) };
+    match reads.pop_front() {
+        Some(Some(mut data)) => {
+            if data.len() > size {
+                let rest = data.split_off(size);
+                reads.push_front(Some(rest));
+            }
+            let buf = unsafe { std::slice::from_raw_parts_mut(buf, data.len()) };
+            buf.copy_from_slice(&data);
+            data.len() as isize
+        }
+        Some(None) => -1,
+        // The capture ran out, so the original input had too.
+        None => 0,
+    }
+}
+
+/// Allocation hooks for parsers captured with an allocator, keeping the
+/// block size in a header as free() doesn't get one.
+extern fn replay_malloc(size: usize, _: *mut c_void) -> *mut c_void {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    unsafe {
+        let raw = System.alloc(Layout::from_size_align(size + 16, 16).unwrap());
+        if raw.is_null() {
+            return std::ptr::null_mut();
+        }
+        *(raw as *mut usize) = size;
+        raw.offset(16) as *mut c_void
+    }
+}
+
+extern fn replay_free(ptr: *mut c_void, _: *mut c_void) {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    unsafe {
+        let raw = (ptr as *mut u8).offset(-16);
+        System.dealloc(raw, Layout::from_size_align(*(raw as *mut usize) + 16, 16).unwrap());
+    }
+}
+
+/// Create a parser as the recorded `new` and `options` events did.
+unsafe fn new_parser(io: &mp4parse_io, options: Option<&[String]>, allocator: &mp4parse_allocator,
+                     track_ids: &mut Vec<u32>) -> *mut mp4parse_parser {
+    let options = match options {
+        Some(options) => options,
+        None => return mp4parse_new(io),
+    };
+    let value = |i: usize| options.get(i).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    let filtered = match options.get(7) {
+        Some(ids) if ids != "-" => {
+            *track_ids = ids.split(',').filter_map(|id| id.parse().ok()).collect();
+            true
+        }
+        _ => false,
+    };
+    let size = std::cmp::min(value(1) as usize, std::mem::size_of::<mp4parse_parser_options>());
+    let opts = mp4parse_parser_options {
+        size: size as u32,
+        lenient: value(2) != 0,
+        mode: match value(3) {
+            1 => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_METADATA,
+            2 => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_FULL,
+            _ => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+        },
+        moov_size_limit: value(4),
+        skip_hint_tracks: value(5) != 0,
+        allocator: if value(8) != 0 { allocator } else { std::ptr::null() },
+        read_buffer_size: value(6) as u32,
+        track_ids: if filtered { track_ids.as_ptr() } else { std::ptr::null() },
+        track_id_count: track_ids.len() as u32,
+    };
+    mp4parse_new_with_options(io, &opts)
+}
+
+/// Format `length` bytes at `data` as hex, touching each as the
+/// application would.
+unsafe fn hex(data: *const u8, length: usize) -> String {
+    if data.is_null() {
+        return String::from("null");
+    }
+    std::slice::from_raw_parts(data, length).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Make a recorded call on `parser`, returning its result.
+unsafe fn call(parser: *mut mp4parse_parser, name: &str, args: &[u32]) -> String {
+    let arg = |i: usize| args.get(i).cloned().unwrap_or(0);
+    match name {
+        "read" => format!("{:?}", mp4parse_read(parser)),
+        "get_track_count" => {
+            let mut count = 0;
+            let rv = mp4parse_get_track_count(parser, &mut count);
+            format!("{:?} count {}", rv, count)
+        }
+        "get_file_info" => {
+            let mut info: mp4parse_file_info = std::mem::zeroed();
+            let rv = mp4parse_get_file_info(parser, &mut info);
+            format!("{:?} brand {:08x} timescale {} duration {} ({} ms) fragmented {} encrypted {}",
+                    rv, info.major_brand, info.timescale, info.duration, info.duration_ms,
+                    info.fragmented, info.encrypted)
+        }
+        "get_track_info" => {
+            let mut info: mp4parse_track_info = std::mem::zeroed();
+            let rv = mp4parse_get_track_info(parser, arg(0), &mut info);
+            format!("{:?} {:?} {:?} id {} duration {} media_time {} language {:?} name {}",
+                    rv, info.track_type, info.codec, info.track_id, info.duration, info.media_time,
+                    info.language, hex(info.name, info.name_length as usize))
+        }
+        "get_track_audio_info" => {
+            let mut info: mp4parse_track_audio_info = Default::default();
+            let rv = mp4parse_get_track_audio_info(parser, arg(0), &mut info);
+            format!("{:?} channels {} bit_depth {} sample_rate {} profile {}/{} bitrate {}/{} extra_data {}",
+                    rv, info.channels, info.bit_depth, info.sample_rate, info.profile,
+                    info.extended_profile, info.max_bitrate, info.avg_bitrate,
+                    hex(info.extra_data.data, info.extra_data.length as usize))
+        }
+        "get_track_video_info" => {
+            let mut info: mp4parse_track_video_info = std::mem::zeroed();
+            let rv = mp4parse_get_track_video_info(parser, arg(0), &mut info);
+            format!("{:?} display {}x{} image {}x{} profile {} level {} bit_depth {} chroma {} extra_data {}",
+                    rv, info.display_width, info.display_height, info.image_width, info.image_height,
+                    info.profile, info.level, info.bit_depth, info.chroma_format,
+                    hex(info.extra_data.data, info.extra_data.length as usize))
+        }
+        "get_track_opus_trim_info" => {
+            let mut info: mp4parse_track_opus_trim_info = Default::default();
+            let rv = mp4parse_get_track_opus_trim_info(parser, arg(0), &mut info);
+            format!("{:?} pre_skip {} padding {}", rv, info.pre_skip, info.padding)
+        }
+        "get_memory_usage" => {
+            let mut usage = 0;
+            let rv = mp4parse_get_memory_usage(parser, &mut usage);
+            format!("{:?} {} bytes", rv, usage)
+        }
+        "get_io_counters" => {
+            let mut counters = Default::default();
+            let rv = mp4parse_get_io_counters(parser, &mut counters);
+            format!("{:?} {:?}", rv, counters)
+        }
+        "get_track_encryption_info" => {
+            let mut info: mp4parse_track_encryption_info = std::mem::zeroed();
+            let rv = mp4parse_get_track_encryption_info(parser, arg(0), &mut info);
+            format!("{:?} scheme {:08x} encrypted {} iv_size {} kid {} pattern {}/{} constant_iv {}",
+                    rv, info.scheme_type, info.is_encrypted, info.iv_size,
+                    hex(info.kid.as_ptr(), info.kid.len()), info.crypt_byte_block, info.skip_byte_block,
+                    hex(info.constant_iv, info.constant_iv_size as usize))
+        }
+        "get_pssh_count" => {
+            let mut count = 0;
+            let rv = mp4parse_get_pssh_count(parser, &mut count);
+            format!("{:?} count {}", rv, count)
+        }
+        "get_pssh" => {
+            let mut system_id = [0; 16];
+            let mut data = std::ptr::null();
+            let mut length = 0;
+            let rv = mp4parse_get_pssh(parser, arg(0), system_id.as_mut_ptr(), &mut data, &mut length);
+            format!("{:?} system {} data {}", rv, hex(system_id.as_ptr(), 16), hex(data, length as usize))
+        }
+        "get_required_moov_size" => {
+            let mut size = 0;
+            let rv = mp4parse_get_required_moov_size(parser, &mut size);
+            format!("{:?} {} bytes", rv, size)
+        }
+        "get_unsupported_feature" => {
+            let mut feature: mp4parse_unsupported_feature = std::mem::zeroed();
+            let rv = mp4parse_get_unsupported_feature(parser, &mut feature);
+            let description = if feature.description.is_null() {
+                String::new()
+            } else {
+                let bytes = std::slice::from_raw_parts(feature.description, feature.description_length as usize);
+                String::from_utf8_lossy(bytes).into_owned()
+            };
+            format!("{:?} box {:08x} '{}'", rv, feature.box_type, description)
+        }
+        "get_track_sample_info" => {
+            let (mut count, mut max_size, mut total_size) = (0, 0, 0);
+            let rv = mp4parse_get_track_sample_info(parser, arg(0), &mut count, &mut max_size, &mut total_size);
+            format!("{:?} count {} max {} total {}", rv, count, max_size, total_size)
+        }
+        "get_sample_count" => {
+            let mut count = 0;
+            let rv = mp4parse_get_sample_count(parser, arg(0), &mut count);
+            format!("{:?} count {}", rv, count)
+        }
+        "get_sample_info" => {
+            let mut info: mp4parse_sample_info = std::mem::zeroed();
+            let rv = mp4parse_get_sample_info(parser, arg(0), arg(1), &mut info);
+            let subsamples = if info.subsamples.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(info.subsamples, info.subsample_count as usize)
+                    .iter()
+                    .map(|s| (s.clear_bytes, s.encrypted_bytes))
+                    .collect()
+            };
+            format!("{:?} offset {} size {} decode {} composition {} duration {} sync {} encrypted {} iv {} kid {} subsamples {:?}",
+                    rv, info.offset, info.size, info.decode_time, info.composition_time, info.duration,
+                    info.sync, info.encrypted, hex(info.iv, info.iv_size as usize),
+                    hex(info.kid.as_ptr(), info.kid.len()), subsamples)
+        }
+        "get_fragment_count" => {
+            let mut count = 0;
+            let rv = mp4parse_get_fragment_count(parser, arg(0), &mut count);
+            format!("{:?} count {}", rv, count)
+        }
+        "get_fragment_range" => {
+            let mut range: mp4parse_fragment_range = std::mem::zeroed();
+            let rv = mp4parse_get_fragment_range(parser, arg(0), arg(1), &mut range);
+            format!("{:?} start {} duration {} bytes {}+{}",
+                    rv, range.start_time, range.duration, range.offset, range.length)
+        }
+        "get_indice_table" => {
+            let mut indices = std::ptr::null();
+            let mut count = 0;
+            let rv = mp4parse_get_indice_table(parser, arg(0), &mut indices, &mut count);
+            let table = if indices.is_null() {
+                &[][..]
+            } else {
+                std::slice::from_raw_parts(indices, count as usize)
+            };
+            let bytes = table.iter().map(|i| i.end_offset - i.start_offset).sum::<u64>();
+            format!("{:?} count {} total {} bytes last {:?}", rv, count, bytes, table.last())
+        }
+        _ => String::from("not replayed"),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        println!("usage: {} <capture.log>", args[0]);
+        process::exit(2);
+    }
+    let file = match File::open(&args[1]) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("ERROR: {} opening '{}'", e, args[1]);
+            process::exit(2);
+        }
+    };
+
+    // Reads are made in order, so the callback replays them as a queue.
+    let mut reads = VecDeque::new();
+    let mut events = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.expect("unreadable capture log");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.first() {
+            Some(&"io") => {
+                if queue_read(&fields[1..], &mut reads).is_none() {
+                    println!("ERROR: malformed read on line {}", number + 1);
+                    process::exit(2);
+                }
+            }
+            Some(_) => events.push(fields.iter().map(|f| f.to_string()).collect::<Vec<_>>()),
+            None => {}
+        }
+    }
+
+    let io = mp4parse_io {
+        read: Some(replay_read),
+        userdata: &mut reads as *mut _ as *mut c_void,
+    };
+    let allocator = mp4parse_allocator {
+        malloc: Some(replay_malloc),
+        free: Some(replay_free),
+        userdata: std::ptr::null_mut(),
+    };
+    let mut track_ids = Vec::new();
+    let mut parser: *mut mp4parse_parser = std::ptr::null_mut();
+    let mut events = events.into_iter().peekable();
+    while let Some(event) = events.next() {
+        match event[0].as_str() {
+            "new" => {
+                let options = match events.peek() {
+                    Some(options) if options[0] == "options" => Some(options.clone()),
+                    _ => None,
+                };
+                parser = unsafe { new_parser(&io, options.as_ref().map(|o| &o[..]), &allocator, &mut track_ids) };
+                println!("new -> {}", if parser.is_null() { "null" } else { "parser" });
+            }
+            "options" => {}
+            "call" if parser.is_null() => println!("call {} without a parser, skipped", event[1]),
+            "call" if event[1] == "free" => {
+                unsafe { mp4parse_free(parser) };
+                parser = std::ptr::null_mut();
+                println!("free");
+            }
+            "call" => {
+                let args: Vec<u32> = event[2..].iter().filter_map(|a| a.parse().ok()).collect();
+                println!("{} {:?} -> {}", event[1], args, unsafe { call(parser, &event[1], &args) });
+            }
+            other => println!("unknown event '{}', skipped", other),
+        }
+    }
+    if !parser.is_null() {
+        unsafe { mp4parse_free(parser) };
+    }
+    // The same calls on the same data make the same reads, so any left
+    // over show the replay diverged from the capture.
+    if !reads.is_empty() {
+        println!("{} captured reads were not replayed", reads.len());
+    }
+}
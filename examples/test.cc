@@ -13,7 +13,9 @@
 #include <string>
 #include <vector>
 
+extern "C" {
 #include "mp4parse.h"
+}
 
 intptr_t abort_read(uint8_t *buffer, uintptr_t size, void *userdata)
 {
@@ -207,6 +209,8 @@ const char * errorstring(mp4parse_error error)
     case MP4PARSE_ERROR_UNSUPPORTED: return "Feature unsupported";
     case MP4PARSE_ERROR_EOF: return "Unexpected end-of-file";
     case MP4PARSE_ERROR_IO: return "I/O error";
+    case MP4PARSE_ERROR_MOOV_TOO_LARGE: return "Movie header too large";
+    case MP4PARSE_ERROR_POISONED: return "Parser poisoned by an earlier error";
   }
   return "Unknown error";
 }
@@ -0,0 +1,608 @@
+// THIS FILE IS AUTOGENERATED BY mp4parse-rust/build.rs - DO NOT EDIT
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#ifndef MP4PARSE_H
+#define MP4PARSE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Version of the C API's struct layouts and function signatures.
+ *
+ * This is bumped whenever an existing struct or function changes
+ * incompatibly, so callers can compare it against the value their
+ * header was generated with before making any other call.
+ */
+#define MP4PARSE_ABI_VERSION 5
+
+/**
+ * Read callback result meaning no data is available yet.
+ */
+#define MP4PARSE_IO_WOULD_BLOCK -2
+
+typedef enum mp4parse_codec {
+  MP4PARSE_CODEC_UNKNOWN,
+  MP4PARSE_CODEC_AAC,
+  MP4PARSE_CODEC_OPUS,
+  MP4PARSE_CODEC_AVC,
+  MP4PARSE_CODEC_VP9,
+  MP4PARSE_CODEC_HEVC,
+  MP4PARSE_CODEC_AV1,
+  MP4PARSE_CODEC_FLAC,
+} mp4parse_codec;
+
+typedef enum mp4parse_error {
+  MP4PARSE_OK = 0,
+  MP4PARSE_ERROR_BADARG = 1,
+  MP4PARSE_ERROR_INVALID = 2,
+  MP4PARSE_ERROR_UNSUPPORTED = 3,
+  MP4PARSE_ERROR_EOF = 4,
+  MP4PARSE_ERROR_IO = 5,
+  MP4PARSE_ERROR_MOOV_TOO_LARGE = 6,
+  /**
+   * An earlier `mp4parse_read()` failed in a way which prevents
+   * reading further. Getters still return what was parsed before
+   * the failure.
+   */
+  MP4PARSE_ERROR_POISONED = 7,
+} mp4parse_error;
+
+typedef enum mp4parse_parse_mode {
+  MP4PARSE_PARSE_MODE_TABLES = 0,
+  MP4PARSE_PARSE_MODE_METADATA = 1,
+  MP4PARSE_PARSE_MODE_FULL = 2,
+} mp4parse_parse_mode;
+
+typedef enum mp4parse_track_type {
+  MP4PARSE_TRACK_TYPE_VIDEO = 0,
+  MP4PARSE_TRACK_TYPE_AUDIO = 1,
+} mp4parse_track_type;
+
+/**
+ * Parser state, opaque to C, which only handles it by pointer.
+ */
+typedef struct mp4parse_parser mp4parse_parser;
+
+/**
+ * Callback to read into `buffer`.
+ *
+ * Returns the number of bytes read, which must not exceed `size`, 0 at
+ * the end of the input, -1 on error, or `MP4PARSE_IO_WOULD_BLOCK` if
+ * no data is available yet. Short reads are fine: the parser calls
+ * again for the remainder. Would-block results are retried a limited
+ * number of times, yielding the thread in between, before the read
+ * fails, so nonblocking sources should buffer enough data before
+ * calling `mp4parse_read()`.
+ */
+typedef struct mp4parse_io {
+  intptr_t (*read)(uint8_t *buffer, uintptr_t size, void *userdata);
+  void *userdata;
+} mp4parse_io;
+
+/**
+ * malloc and free compatible functions for a parser to allocate with.
+ *
+ * `malloc` must return memory aligned as the platform's malloc does.
+ * Blocks are freed with `free` by the time the parser is freed, so
+ * `userdata` must outlive every parser using it.
+ */
+typedef struct mp4parse_allocator {
+  void *(*malloc)(uintptr_t size, void *userdata);
+  void (*free)(void *ptr, void *userdata);
+  void *userdata;
+} mp4parse_allocator;
+
+/**
+ * Options for `mp4parse_new_with_options()`.
+ *
+ * Callers must set `size` to `sizeof(mp4parse_parser_options)`. New
+ * fields are only ever appended, and are only read if `size` shows
+ * the caller's header includes them, so older callers keep working.
+ */
+typedef struct mp4parse_parser_options {
+  uint32_t size;
+  /**
+   * Skip malformed leaf boxes instead of failing the parse.
+   */
+  bool lenient;
+  /**
+   * How much of the file to read. Defaults to
+   * `MP4PARSE_PARSE_MODE_TABLES` for callers which predate it.
+   */
+  enum mp4parse_parse_mode mode;
+  /**
+   * Largest moov to parse, in bytes, or 0 for no limit.
+   */
+  uint64_t moov_size_limit;
+  /**
+   * Leave hint tracks out of the track count and indices.
+   */
+  bool skip_hint_tracks;
+  /**
+   * Hooks to allocate the parser's buffers with, or null for the
+   * default allocator. The buffers are the read-ahead buffer and the
+   * tables and headers returned by the getters; the parser's other
+   * memory comes from the default allocator. Only available when the library is built with
+   * the "alloc_hooks" feature; otherwise a non-null value makes
+   * `mp4parse_new_with_options()` fail.
+   */
+  const struct mp4parse_allocator *allocator;
+  /**
+   * Bytes to read ahead from the io callback, so scanning box
+   * headers doesn't cost a callback per field. 0 selects the
+   * default of 4096; 1 passes reads through unbuffered.
+   */
+  uint32_t read_buffer_size;
+  /**
+   * IDs of the only tracks whose sample tables and sample
+   * descriptions should be parsed, or null to parse every track.
+   * Other tracks are still counted, but only their header
+   * information is available.
+   */
+  const uint32_t *track_ids;
+  uint32_t track_id_count;
+} mp4parse_parser_options;
+
+/**
+ * Properties of the whole file.
+ */
+typedef struct mp4parse_file_info {
+  /**
+   * Major brand from the 'ftyp', or 0 if there is none.
+   */
+  uint32_t major_brand;
+  /**
+   * Movie timescale from the 'mvhd'.
+   */
+  uint32_t timescale;
+  /**
+   * Movie duration in `timescale` units and in milliseconds, or 0
+   * if unknown.
+   */
+  uint64_t duration;
+  uint64_t duration_ms;
+  /**
+   * True if the file has movie fragments.
+   */
+  bool fragmented;
+  /**
+   * True if the file has protected tracks or 'pssh' boxes.
+   */
+  bool encrypted;
+} mp4parse_file_info;
+
+typedef struct mp4parse_track_info {
+  enum mp4parse_track_type track_type;
+  enum mp4parse_codec codec;
+  uint32_t track_id;
+  uint64_t duration;
+  int64_t media_time;
+  /**
+   * NUL-terminated ISO 639-2/T language code, or all zeros if unknown.
+   */
+  uint8_t language[4];
+  /**
+   * UTF-8 track name, or null if the track has none. It is not
+   * NUL-terminated, and is owned by the parser and valid until it is
+   * freed.
+   */
+  const uint8_t *name;
+  uint32_t name_length;
+} mp4parse_track_info;
+
+typedef struct mp4parse_codec_specific_config {
+  uint32_t length;
+  const uint8_t *data;
+} mp4parse_codec_specific_config;
+
+typedef struct mp4parse_track_audio_info {
+  uint16_t channels;
+  uint16_t bit_depth;
+  uint32_t sample_rate;
+  /**
+   * MPEG-4 audio object type of the core codec, 2 for AAC LC, or 0
+   * if the track has no AudioSpecificConfig.
+   */
+  uint16_t profile;
+  /**
+   * The explicitly signalled SBR (5) or parametric stereo (29)
+   * object type, or else the same as `profile`.
+   */
+  uint16_t extended_profile;
+  /**
+   * Bitrates from the 'esds' in bits per second, or 0 if unknown.
+   */
+  uint32_t max_bitrate;
+  uint32_t avg_bitrate;
+  /**
+   * The AudioSpecificConfig from the 'esds', or for Opus an
+   * OpusHead packet rebuilt from the 'dOps' as Ogg and WebM carry
+   * it. Owned by the parser and valid until it is freed.
+   */
+  struct mp4parse_codec_specific_config extra_data;
+  struct mp4parse_codec_specific_config codec_specific_config;
+} mp4parse_track_audio_info;
+
+typedef struct mp4parse_track_video_info {
+  uint32_t display_width;
+  uint32_t display_height;
+  uint16_t image_width;
+  uint16_t image_height;
+  /**
+   * Profile and level from the track's 'avcC', 'hvcC', 'vpcC' or
+   * 'av1C', using the codec's own numbering, or 0 if the record
+   * can't be parsed.
+   */
+  uint8_t profile;
+  uint8_t level;
+  /**
+   * Luma bit depth, or 0 if unknown.
+   */
+  uint8_t bit_depth;
+  /**
+   * 0 for monochrome, 1 for 4:2:0, 2 for 4:2:2 or 3 for 4:4:4, as
+   * in H.264 and H.265. Only meaningful if `bit_depth` is set.
+   */
+  uint8_t chroma_format;
+  /**
+   * Contents of the 'avcC', 'hvcC' or 'av1C' of the track, the
+   * configuration a decoder needs, or empty for other codecs.
+   * Owned by the parser and valid until it is freed.
+   */
+  struct mp4parse_codec_specific_config extra_data;
+} mp4parse_track_video_info;
+
+typedef struct mp4parse_track_opus_trim_info {
+  uint16_t pre_skip;
+  uint64_t padding;
+} mp4parse_track_opus_trim_info;
+
+/**
+ * Input read by a parser so far.
+ */
+typedef struct mp4parse_io_counters {
+  /**
+   * Bytes the parser asked for from its input.
+   */
+  uint64_t requested;
+  /**
+   * Bytes the input returned.
+   */
+  uint64_t consumed;
+} mp4parse_io_counters;
+
+/**
+ * Encryption parameters of a protected track.
+ *
+ * The `constant_iv` pointer is owned by the parser and valid until it
+ * is freed.
+ */
+typedef struct mp4parse_track_encryption_info {
+  /**
+   * Four character code of the scheme, e.g. 'cenc' or 'cbcs'.
+   */
+  uint32_t scheme_type;
+  bool is_encrypted;
+  uint8_t iv_size;
+  uint8_t kid[16];
+  /**
+   * Encryption pattern; both 0 if every block is encrypted.
+   */
+  uint8_t crypt_byte_block;
+  uint8_t skip_byte_block;
+  uint32_t constant_iv_size;
+  const uint8_t *constant_iv;
+} mp4parse_track_encryption_info;
+
+/**
+ * The construct which made `mp4parse_read()` return
+ * `MP4PARSE_ERROR_UNSUPPORTED`, or which made a track's sample entry
+ * unusable.
+ *
+ * `box_type` is the four character code of the box it was found in,
+ * or 0 if there is nothing to report. The description is not
+ * NUL-terminated and is valid for the lifetime of the library.
+ */
+typedef struct mp4parse_unsupported_feature {
+  uint32_t box_type;
+  uint32_t description_length;
+  const uint8_t *description;
+} mp4parse_unsupported_feature;
+
+typedef struct mp4parse_subsample {
+  uint16_t clear_bytes;
+  uint32_t encrypted_bytes;
+} mp4parse_subsample;
+
+/**
+ * A sample's location, timing and decryption parameters.
+ *
+ * Times are in the track's timescale. The `iv` and `subsamples`
+ * pointers are owned by the parser and valid until it is freed.
+ */
+typedef struct mp4parse_sample_info {
+  uint64_t offset;
+  uint32_t size;
+  uint64_t decode_time;
+  int64_t composition_time;
+  uint32_t duration;
+  bool sync;
+  /**
+   * True if the sample is encrypted, with the parameters below.
+   */
+  bool encrypted;
+  uint32_t iv_size;
+  const uint8_t *iv;
+  uint32_t subsample_count;
+  const struct mp4parse_subsample *subsamples;
+  /**
+   * Key ID of an encrypted sample, which may vary with key rotation.
+   */
+  uint8_t kid[16];
+} mp4parse_sample_info;
+
+/**
+ * Time span and byte range of one fragment of a track.
+ *
+ * Times are in microseconds; the range covers the moof and its mdat.
+ */
+typedef struct mp4parse_fragment_range {
+  uint64_t start_time;
+  uint64_t duration;
+  uint64_t offset;
+  uint64_t length;
+} mp4parse_fragment_range;
+
+/**
+ * Byte range and timing of one sample of a track.
+ *
+ * Times are in microseconds. The offsets and composition times give
+ * half-open ranges, ending where the sample's data and duration end.
+ */
+typedef struct mp4parse_indice {
+  uint64_t start_offset;
+  uint64_t end_offset;
+  int64_t start_composition;
+  int64_t end_composition;
+  int64_t start_decode;
+  bool sync;
+} mp4parse_indice;
+
+/**
+ * Allocate an `mp4parse_parser*` to read from the supplied `mp4parse_io`.
+ */
+struct mp4parse_parser *mp4parse_new(const struct mp4parse_io *io);
+
+/**
+ * Allocate an `mp4parse_parser*` to read from `size` bytes at `buffer`.
+ *
+ * The data is parsed in place rather than through a read callback,
+ * so the buffer must remain valid and unchanged until the parser is
+ * freed.
+ */
+struct mp4parse_parser *mp4parse_new_from_buffer(const uint8_t *buffer, uintptr_t size);
+
+/**
+ * Return the `MP4PARSE_ABI_VERSION` this library was built with.
+ */
+uint32_t mp4parse_version(void);
+
+/**
+ * Allocate an `mp4parse_parser*` configured by `options`.
+ *
+ * Returns null if the arguments are invalid, including an `options`
+ * whose `size` is smaller than the first version of the struct or
+ * whose `mode` is unknown.
+ */
+struct mp4parse_parser *mp4parse_new_with_options(const struct mp4parse_io *io,
+                                                  const struct mp4parse_parser_options *options);
+
+/**
+ * Free an `mp4parse_parser*` allocated by `mp4parse_new()`.
+ */
+void mp4parse_free(struct mp4parse_parser *parser);
+
+/**
+ * Run the `mp4parse_parser*` allocated by `mp4parse_new()` until EOF or error.
+ */
+enum mp4parse_error mp4parse_read(struct mp4parse_parser *parser);
+
+/**
+ * Return the number of tracks parsed by previous `mp4parse_read()` call.
+ */
+enum mp4parse_error mp4parse_get_track_count(const struct mp4parse_parser *parser, uint32_t *count);
+
+/**
+ * Fill the supplied `mp4parse_file_info` with properties of the file
+ * parsed by `mp4parse_read()`.
+ */
+enum mp4parse_error mp4parse_get_file_info(const struct mp4parse_parser *parser,
+                                           struct mp4parse_file_info *info);
+
+/**
+ * Fill the supplied `mp4parse_track_info` with metadata for `track`.
+ */
+enum mp4parse_error mp4parse_get_track_info(struct mp4parse_parser *parser,
+                                            uint32_t track_index,
+                                            struct mp4parse_track_info *info);
+
+/**
+ * Fill the supplied `mp4parse_track_audio_info` with metadata for `track`.
+ */
+enum mp4parse_error mp4parse_get_track_audio_info(struct mp4parse_parser *parser,
+                                                  uint32_t track_index,
+                                                  struct mp4parse_track_audio_info *info);
+
+/**
+ * Fill the supplied `mp4parse_track_video_info` with metadata for `track`.
+ */
+enum mp4parse_error mp4parse_get_track_video_info(struct mp4parse_parser *parser,
+                                                  uint32_t track_index,
+                                                  struct mp4parse_track_video_info *info);
+
+/**
+ * Fill the supplied `mp4parse_track_opus_trim_info` for an Opus `track`.
+ *
+ * Both values count samples at 48 kHz. `pre_skip` comes from the
+ * dOps box and `padding` is the media following the track's edit,
+ * or zero if there is no edit list to derive it from.
+ */
+enum mp4parse_error mp4parse_get_track_opus_trim_info(struct mp4parse_parser *parser,
+                                                      uint32_t track_index,
+                                                      struct mp4parse_track_opus_trim_info *info);
+
+/**
+ * Return the number of heap bytes held by the parser's parsed data.
+ *
+ * Caches the getters fill on first use, such as Opus headers and
+ * indice tables, aren't counted, so the figure depends only on the
+ * input and not on which getters have been called.
+ */
+enum mp4parse_error mp4parse_get_memory_usage(const struct mp4parse_parser *parser,
+                                              uint64_t *usage);
+
+/**
+ * Fill the supplied `mp4parse_io_counters` with the totals over all
+ * `mp4parse_read()` calls on the parser.
+ *
+ * Comparing `consumed` with the file size shows how much of the
+ * input parsing needed, for example whether media data was read.
+ */
+enum mp4parse_error mp4parse_get_io_counters(const struct mp4parse_parser *parser,
+                                             struct mp4parse_io_counters *counters);
+
+/**
+ * Fill the supplied `mp4parse_track_encryption_info` for `track`.
+ *
+ * Returns `MP4PARSE_ERROR_INVALID` if the track isn't protected or
+ * lacks a 'tenc'.
+ */
+enum mp4parse_error mp4parse_get_track_encryption_info(const struct mp4parse_parser *parser,
+                                                       uint32_t track_index,
+                                                       struct mp4parse_track_encryption_info *info);
+
+/**
+ * Return the protection scheme, per-sample IV size and default key ID
+ * of `track`, for choosing a key system before reading samples.
+ *
+ * `kid` receives 16 bytes. This is a subset of
+ * `mp4parse_get_track_encryption_info()`, with the same errors.
+ */
+enum mp4parse_error mp4parse_get_track_crypto_info(const struct mp4parse_parser *parser,
+                                                   uint32_t track_index,
+                                                   uint32_t *scheme,
+                                                   uint8_t *iv_size,
+                                                   uint8_t *kid);
+
+/**
+ * Return the number of 'pssh' boxes found by `mp4parse_read()`.
+ */
+enum mp4parse_error mp4parse_get_pssh_count(const struct mp4parse_parser *parser, uint32_t *count);
+
+/**
+ * Return the protection system and initialization data of the 'pssh'
+ * box at `index`.
+ *
+ * The 16-byte system ID is copied to `system_id`, so callers can pick
+ * a system they support. `data` is set to the whole box, header
+ * included, which is the form decryption modules expect; it is owned
+ * by the parser and valid until it is freed.
+ */
+enum mp4parse_error mp4parse_get_pssh(const struct mp4parse_parser *parser,
+                                      uint32_t index,
+                                      uint8_t *system_id,
+                                      const uint8_t **data,
+                                      uint32_t *length);
+
+/**
+ * Return the moov size needed to parse the file after `mp4parse_read()`
+ * failed with `MP4PARSE_ERROR_MOOV_TOO_LARGE`, or 0 otherwise.
+ *
+ * This is available even though the failure poisons the parser.
+ */
+enum mp4parse_error mp4parse_get_required_moov_size(const struct mp4parse_parser *parser,
+                                                    uint64_t *size);
+
+/**
+ * Fill the supplied `mp4parse_unsupported_feature` with the most
+ * recent unsupported construct found while parsing.
+ */
+enum mp4parse_error mp4parse_get_unsupported_feature(const struct mp4parse_parser *parser,
+                                                     struct mp4parse_unsupported_feature *feature);
+
+/**
+ * Return the sample count, largest sample size and total sample size
+ * of `track`, in bytes.
+ *
+ * These come from the sample table directly, so unlike the per-sample
+ * calls they don't need `MP4PARSE_PARSE_MODE_FULL`. Tracks whose
+ * samples are all in movie fragments report zeros, as do all tracks
+ * parsed with `MP4PARSE_PARSE_MODE_METADATA`.
+ */
+enum mp4parse_error mp4parse_get_track_sample_info(const struct mp4parse_parser *parser,
+                                                   uint32_t track_index,
+                                                   uint32_t *sample_count,
+                                                   uint32_t *max_size,
+                                                   uint64_t *total_size);
+
+/**
+ * Return the number of samples in `track`.
+ *
+ * The sample index is only built by `MP4PARSE_PARSE_MODE_FULL`; other
+ * modes return `MP4PARSE_ERROR_BADARG`.
+ */
+enum mp4parse_error mp4parse_get_sample_count(const struct mp4parse_parser *parser,
+                                              uint32_t track_index,
+                                              uint32_t *count);
+
+/**
+ * Fill the supplied `mp4parse_sample_info` for sample `sample_index`
+ * of `track`, in decode order.
+ */
+enum mp4parse_error mp4parse_get_sample_info(const struct mp4parse_parser *parser,
+                                             uint32_t track_index,
+                                             uint32_t sample_index,
+                                             struct mp4parse_sample_info *info);
+
+/**
+ * Return the number of fragments in the index of `track`.
+ *
+ * The index is built from the movie fragments parsed so far, or the
+ * segment index if no fragment carries the track. Returns
+ * `MP4PARSE_ERROR_INVALID` if the track has no timescale.
+ */
+enum mp4parse_error mp4parse_get_fragment_count(struct mp4parse_parser *parser,
+                                                uint32_t track_index,
+                                                uint32_t *count);
+
+/**
+ * Fill the supplied `mp4parse_fragment_range` for fragment
+ * `fragment_index` of `track`.
+ */
+enum mp4parse_error mp4parse_get_fragment_range(struct mp4parse_parser *parser,
+                                                uint32_t track_index,
+                                                uint32_t fragment_index,
+                                                struct mp4parse_fragment_range *range);
+
+/**
+ * Return the sample index of the track with ID `track_id`.
+ *
+ * `indices` is set to an array of `count` entries, one per sample in
+ * decode order, covering the track's sample tables and the movie
+ * fragments parsed so far. The array is owned by the parser and valid
+ * until the next call to `mp4parse_read()` or the parser is freed.
+ * Returns `MP4PARSE_ERROR_INVALID` if the track has no timescale, its
+ * sample tables are inconsistent or another track has the same ID.
+ */
+enum mp4parse_error mp4parse_get_indice_table(struct mp4parse_parser *parser,
+                                              uint32_t track_id,
+                                              const struct mp4parse_indice **indices,
+                                              uint32_t *count);
+
+#endif /* MP4PARSE_H */
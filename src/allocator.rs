@@ -0,0 +1,119 @@
+//! Allocation of a parser's buffers with embedder-supplied hooks.
+//!
+//! A `Buffer` is allocated with the `mp4parse_allocator` of the parser
+//! it belongs to, if the parser was created with one and the library
+//! built with the "alloc_hooks" feature, and otherwise with the system
+//! allocator. It records how it was allocated, so it's freed the same
+//! way. Allocations other than these buffers use the global allocator.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::os::raw::c_void;
+
+use mp4parse_allocator;
+
+/// A fixed-size array of `T` allocated with a parser's hooks.
+pub struct Buffer<T: Clone> {
+    ptr: *mut T,
+    /// Items initialized so far.
+    len: usize,
+    capacity: usize,
+    /// Hooks the array was allocated with, or None for the system
+    /// allocator.
+    hooks: Option<mp4parse_allocator>,
+}
+
+impl<T: Clone> Buffer<T> {
+    /// Allocate room for `capacity` items, aborting like `Vec` does if
+    /// that fails.
+    fn allocate(capacity: usize, hooks: Option<mp4parse_allocator>) -> Buffer<T> {
+        let layout = match Layout::array::<T>(capacity) {
+            Ok(layout) => layout,
+            Err(_) => panic!("buffer size overflows"),
+        };
+        if layout.size() == 0 {
+            return Buffer { ptr: std::ptr::NonNull::dangling().as_ptr(), len: 0, capacity: capacity, hooks: None };
+        }
+        let (ptr, hooks) = unsafe {
+            match hooks {
+                Some(mp4parse_allocator { malloc: Some(malloc), free: Some(_), userdata }) => {
+                    (malloc(layout.size(), userdata) as *mut u8, hooks)
+                }
+                _ => (System.alloc(layout), None),
+            }
+        };
+        // The hooks promise malloc's alignment, which suffices for the
+        // types buffered.
+        if ptr.is_null() || ptr as usize % layout.align() != 0 {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Buffer { ptr: ptr as *mut T, len: 0, capacity: capacity, hooks: hooks }
+    }
+
+    /// Append `item`, which there must be room for.
+    fn push(&mut self, item: T) {
+        assert!(self.len < self.capacity);
+        unsafe { std::ptr::write(self.ptr.add(self.len), item) };
+        self.len += 1;
+    }
+
+    /// Copy `items` into a new buffer.
+    pub fn from_slice(items: &[T], hooks: Option<mp4parse_allocator>) -> Buffer<T> {
+        let mut buffer = Buffer::allocate(items.len(), hooks);
+        for item in items {
+            buffer.push(item.clone());
+        }
+        buffer
+    }
+
+    /// Make a buffer of `len` copies of `value`.
+    pub fn filled(value: T, len: usize, hooks: Option<mp4parse_allocator>) -> Buffer<T> {
+        let mut buffer = Buffer::allocate(len, hooks);
+        for _ in 0..len {
+            buffer.push(value.clone());
+        }
+        buffer
+    }
+}
+
+impl<T: Clone> Default for Buffer<T> {
+    fn default() -> Buffer<T> {
+        Buffer::allocate(0, None)
+    }
+}
+
+impl<T: Clone> std::ops::Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T: Clone> std::ops::DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T: Clone> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        unsafe { std::ptr::drop_in_place(&mut self[..] as *mut [T]) };
+        let layout = match Layout::array::<T>(self.capacity) {
+            Ok(layout) if layout.size() > 0 => layout,
+            _ => return,
+        };
+        unsafe {
+            match self.hooks {
+                Some(mp4parse_allocator { free: Some(free), userdata, .. }) => {
+                    free(self.ptr as *mut c_void, userdata)
+                }
+                _ => System.dealloc(self.ptr as *mut u8, layout),
+            }
+        }
+    }
+}
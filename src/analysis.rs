@@ -0,0 +1,249 @@
+//! Whole-movie analyses built on the parsed track data.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Range;
+
+use MediaContext;
+use SampleInfo;
+
+/// How closely the media data of different tracks is interleaved.
+///
+/// Progressive playback reads the file front to back, so data which
+/// is presented together should be stored close together. A large
+/// `max_distance` means a player must buffer that many bytes of one
+/// track before it reaches concurrent data of another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterleaveReport {
+    /// Largest byte distance between samples of two tracks which are
+    /// due at the same decode time.
+    pub max_distance: u64,
+    /// Decode time in microseconds where `max_distance` occurs.
+    pub max_distance_time_us: u64,
+    /// Indices of the tracks involved, or None if fewer than two
+    /// tracks could be compared.
+    pub tracks: Option<(usize, usize)>,
+}
+
+/// A break in the movie fragment sequence numbers.
+///
+/// Fragments are expected to carry 'mfhd' sequence numbers which
+/// increase by one. A `found` value above `expected` means fragments
+/// are missing; a lower value means fragments repeat or are out of
+/// order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceGap {
+    /// Index into `MediaContext::fragments` where the break occurs.
+    pub fragment: usize,
+    pub expected: u32,
+    pub found: u32,
+}
+
+/// A mismatch between a fragment's 'tfdt' and the end of the track's
+/// previous fragment.
+///
+/// A `found` value above `expected` is a gap in the timeline; a lower
+/// value means the fragment overlaps media already presented. Times
+/// are in the track's timescale.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeTimeDiscontinuity {
+    pub track_id: u32,
+    /// Index into `MediaContext::fragments` where the mismatch occurs.
+    pub fragment: usize,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl DecodeTimeDiscontinuity {
+    /// True if media is missing rather than overlapping.
+    pub fn is_gap(&self) -> bool {
+        self.found > self.expected
+    }
+}
+
+/// A sample whose data doesn't lie within the file's media data.
+///
+/// Reading such a range would return box headers or other metadata
+/// as if it were media.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleOutOfBounds {
+    /// Index of the track in `MediaContext::tracks`.
+    pub track: usize,
+    /// Index of the sample in the track's sample table.
+    pub sample: usize,
+    /// Byte range the sample tables give for the sample.
+    pub range: Range<u64>,
+    /// The part of `range` inside the media data it starts in, or None
+    /// if it starts outside any.
+    pub clipped: Option<Range<u64>>,
+}
+
+/// Per-track samples with decode times converted to microseconds.
+struct TimedSamples {
+    track_index: usize,
+    samples: Vec<(u64, SampleInfo)>,
+}
+
+pub fn to_microseconds(time: u64, timescale: u64) -> u64 {
+    (time as u128 * 1000000 / timescale as u128) as u64
+}
+
+impl MediaContext {
+    /// Check that fragment sequence numbers increase by one.
+    ///
+    /// Fragments without an 'mfhd' are ignored.
+    pub fn fragment_sequence_gaps(&self) -> Vec<SequenceGap> {
+        let mut gaps = Vec::new();
+        let mut previous: Option<u32> = None;
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            let found = match fragment.sequence_number {
+                Some(found) => found,
+                None => continue,
+            };
+            if let Some(previous) = previous {
+                let expected = previous.wrapping_add(1);
+                if found != expected {
+                    gaps.push(SequenceGap {
+                        fragment: index,
+                        expected: expected,
+                        found: found,
+                    });
+                }
+            }
+            previous = Some(found);
+        }
+        gaps
+    }
+
+    /// Compare each fragment's 'tfdt' against the decode time implied
+    /// by the track's preceding fragments.
+    ///
+    /// Track fragments without a 'tfdt' are assumed to continue where
+    /// the previous fragment ended.
+    pub fn decode_time_discontinuities(&self) -> Vec<DecodeTimeDiscontinuity> {
+        let mut result = Vec::new();
+        // Expected next decode time, per track_id.
+        let mut next: Vec<(u32, u64)> = Vec::new();
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            for traf in &fragment.tracks {
+                let position = next.iter().position(|&(id, _)| id == traf.track_id);
+                let start = match (position, traf.base_decode_time) {
+                    (Some(i), Some(found)) => {
+                        let expected = next[i].1;
+                        if found != expected {
+                            result.push(DecodeTimeDiscontinuity {
+                                track_id: traf.track_id,
+                                fragment: index,
+                                expected: expected,
+                                found: found,
+                            });
+                        }
+                        found
+                    }
+                    (Some(i), None) => next[i].1,
+                    (None, time) => time.unwrap_or(0),
+                };
+                let end = start.saturating_add(traf.duration);
+                match position {
+                    Some(i) => next[i].1 = end,
+                    None => next.push((traf.track_id, end)),
+                }
+            }
+        }
+        result
+    }
+
+    /// Measure how the tracks' media data is interleaved.
+    ///
+    /// Tracks without a timescale or usable sample tables are ignored.
+    pub fn interleave_analysis(&self) -> InterleaveReport {
+        let mut timed = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            let timescale = match track.timescale {
+                Some(timescale) if timescale.0 > 0 => timescale.0,
+                _ => continue,
+            };
+            let samples = match track.sample_table() {
+                Ok(samples) => samples,
+                Err(_) => continue,
+            };
+            if samples.is_empty() {
+                continue;
+            }
+            timed.push(TimedSamples {
+                track_index: index,
+                samples: samples.into_iter()
+                    .map(|s| (to_microseconds(s.decode_time, timescale), s))
+                    .collect(),
+            });
+        }
+
+        let mut report = InterleaveReport {
+            max_distance: 0,
+            max_distance_time_us: 0,
+            tracks: None,
+        };
+        for (i, a) in timed.iter().enumerate() {
+            for b in timed.iter().skip(i + 1) {
+                // Walk both tracks in decode order, pairing each sample
+                // of `a` with the sample of `b` current at that time.
+                let mut current = 0;
+                for &(time, ref sample) in &a.samples {
+                    while current + 1 < b.samples.len() && b.samples[current + 1].0 <= time {
+                        current += 1;
+                    }
+                    let other = &b.samples[current].1;
+                    let distance = if sample.offset > other.offset {
+                        sample.offset - other.offset
+                    } else {
+                        other.offset - sample.offset
+                    };
+                    if report.tracks.is_none() || distance > report.max_distance {
+                        report.max_distance = distance;
+                        report.max_distance_time_us = time;
+                        report.tracks = Some((a.track_index, b.track_index));
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Check that each sample of the tracks' sample tables lies inside
+    /// a top-level 'mdat'.
+    ///
+    /// A sample running from one 'mdat' into the next is out of bounds,
+    /// since its data would include the second box's header. Tracks
+    /// whose sample tables fail to build are skipped, as are files
+    /// where no 'mdat' was seen, such as initialization segments.
+    pub fn check_sample_bounds(&self) -> Vec<SampleOutOfBounds> {
+        let mut result = Vec::new();
+        if self.media_data.is_empty() {
+            return result;
+        }
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            let samples = match track.sample_table() {
+                Ok(samples) => samples,
+                Err(_) => continue,
+            };
+            for (index, sample) in samples.iter().enumerate() {
+                let range = sample.offset..sample.offset + sample.size as u64;
+                if self.media_data.iter().any(|e| e.start <= range.start && range.end <= e.end) {
+                    continue;
+                }
+                let clipped = self.media_data.iter()
+                    .find(|e| e.start <= range.start && range.start < e.end)
+                    .map(|e| range.start..e.end);
+                result.push(SampleOutOfBounds {
+                    track: track_index,
+                    sample: index,
+                    range: range,
+                    clipped: clipped,
+                });
+            }
+        }
+        result
+    }
+}
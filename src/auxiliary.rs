@@ -0,0 +1,141 @@
+//! Auxiliary images and video, such as alpha planes and depth maps.
+//!
+//! An auxiliary image item has an 'auxl' reference to the image it
+//! belongs to and an 'auxC' property giving its kind as a URN. Video
+//! tracks do the same with an 'auxl' track reference, an 'auxv'
+//! handler and an 'auxi' box in the sample entry.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::Read;
+
+use BMFFBox;
+use ItemMetadata;
+use ItemProperty;
+use MediaContext;
+use Result;
+use Track;
+use read_fullbox_extra;
+use read_null_terminated_string;
+use skip;
+
+/// Reference type marking an item or track as auxiliary to the
+/// referenced ones.
+pub const REFERENCE_AUXILIARY: u32 = 0x6175786c; // "auxl"
+
+/// Reference type from an image to its alpha plane when the image's
+/// colour values are premultiplied by the alpha.
+pub const REFERENCE_PREMULTIPLIED: u32 = 0x7072656d; // "prem"
+
+/// Kinds of auxiliary image, by their URNs in 'auxC' and 'auxi' boxes.
+const ALPHA_URNS: [&'static str; 2] = [
+    "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
+    "urn:mpeg:hevc:2015:auxid:1",
+];
+const DEPTH_URNS: [&'static str; 2] = [
+    "urn:mpeg:mpegB:cicp:systems:auxiliary:depth",
+    "urn:mpeg:hevc:2015:auxid:2",
+];
+
+/// The kind of an auxiliary image or video track.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuxiliaryType {
+    /// Transparency to combine with the master image.
+    Alpha,
+    /// Distance from the viewer of each pixel of the master image.
+    Depth,
+    /// Any other URN, as given in the file.
+    Other(String),
+}
+
+impl AuxiliaryType {
+    pub fn from_urn(urn: &str) -> AuxiliaryType {
+        if ALPHA_URNS.contains(&urn) {
+            AuxiliaryType::Alpha
+        } else if DEPTH_URNS.contains(&urn) {
+            AuxiliaryType::Depth
+        } else {
+            AuxiliaryType::Other(urn.to_string())
+        }
+    }
+}
+
+/// Parse an 'auxC' item property.
+pub fn read_auxc<T: Read>(src: &mut BMFFBox<T>) -> Result<AuxiliaryType> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    let bytes_left = src.bytes_left();
+    let urn = try!(read_null_terminated_string(src, bytes_left));
+    // The subtype is codec specific, such as an HEVC SEI message.
+    let bytes_left = src.bytes_left();
+    try!(skip(src, bytes_left));
+    Ok(AuxiliaryType::from_urn(&urn))
+}
+
+/// Parse an 'auxi' box from a sample entry.
+pub fn read_auxi<T: Read>(src: &mut BMFFBox<T>) -> Result<AuxiliaryType> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    let bytes_left = src.bytes_left();
+    let urn = try!(read_null_terminated_string(src, bytes_left));
+    Ok(AuxiliaryType::from_urn(&urn))
+}
+
+impl ItemMetadata {
+    /// The kind of auxiliary image `item_id` is, from its 'auxC'.
+    pub fn auxiliary_type(&self, item_id: u32) -> Option<&AuxiliaryType> {
+        self.item_properties(item_id).into_iter()
+            .filter_map(|p| match *p {
+                ItemProperty::AuxiliaryType(ref aux) => Some(aux),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Auxiliary images of `item_id` with their kinds, in file order.
+    ///
+    /// Auxiliary items without an 'auxC' are left out, since there's
+    /// no telling how to use them.
+    pub fn auxiliary_items(&self, item_id: u32) -> Vec<(u32, &AuxiliaryType)> {
+        self.referencing_items(REFERENCE_AUXILIARY, item_id)
+            .into_iter()
+            .filter_map(|id| self.auxiliary_type(id).map(|aux| (id, aux)))
+            .collect()
+    }
+
+    /// The alpha plane of `item_id`, if it has one.
+    pub fn alpha_item(&self, item_id: u32) -> Option<u32> {
+        self.auxiliary_items(item_id).into_iter()
+            .find(|&(_, aux)| *aux == AuxiliaryType::Alpha)
+            .map(|(id, _)| id)
+    }
+
+    /// True if `item_id` has an alpha plane and its colour values have
+    /// been multiplied by it, so must be divided by it to recover them.
+    pub fn is_alpha_premultiplied(&self, item_id: u32) -> bool {
+        match self.alpha_item(item_id) {
+            Some(alpha) => self.references.iter().any(|r| {
+                r.reference_type == REFERENCE_PREMULTIPLIED && r.from_item_id == item_id &&
+                    r.to_item_ids.contains(&alpha)
+            }),
+            None => false,
+        }
+    }
+
+    /// The depth map of `item_id`, if it has one.
+    pub fn depth_item(&self, item_id: u32) -> Option<u32> {
+        self.auxiliary_items(item_id).into_iter()
+            .find(|&(_, aux)| *aux == AuxiliaryType::Depth)
+            .map(|(id, _)| id)
+    }
+}
+
+impl MediaContext {
+    /// Auxiliary video tracks applying to the track with ID `track_id`,
+    /// in file order.
+    pub fn auxiliary_tracks(&self, track_id: u32) -> Vec<&Track> {
+        self.tracks.iter()
+            .filter(|t| t.auxiliary_for.contains(&track_id))
+            .collect()
+    }
+}
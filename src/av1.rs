@@ -0,0 +1,337 @@
+//! AV1 sequence header parsing.
+//!
+//! An 'av1C' box repeats the profile, level and bit depth of the
+//! stream in its fixed fields, but the sequence header OBU in its
+//! configOBUs is what the decoder uses, so codec details are taken
+//! from there.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::Cursor;
+
+use BitReader;
+use Error;
+use Result;
+use SampleEntry;
+use Track;
+use VideoCodecSpecific;
+use read_leb128;
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+
+/// Length of the fixed fields at the start of an 'av1C' box.
+const AV1C_HEADER_SIZE: usize = 4;
+
+/// Fixed fields at the start of an 'av1C' AV1CodecConfigurationRecord.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Av1DecoderConfig {
+    pub seq_profile: u8,
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: u8,
+    /// 8, 10 or 12, from the high_bitdepth and twelve_bit flags.
+    pub bit_depth: u8,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+}
+
+/// Fields of an AV1 sequence header OBU.
+///
+/// Level and tier are those of the first operating point, which is
+/// what a player selects by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Av1SequenceHeader {
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    pub seq_level_idx: u8,
+    pub seq_tier: u8,
+    pub bit_depth: u8,
+    pub mono_chrome: bool,
+    pub subsampling_x: bool,
+    pub subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub color_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+    pub max_frame_width: u32,
+    pub max_frame_height: u32,
+    pub film_grain_params_present: bool,
+}
+
+impl Av1SequenceHeader {
+    /// The RFC 6381 codecs parameter for the stream, in the short
+    /// form giving profile, level, tier and bit depth.
+    pub fn codecs_string(&self) -> String {
+        format!("av01.{}.{:02}{}.{:02}",
+                self.seq_profile,
+                self.seq_level_idx,
+                if self.seq_tier == 0 { 'M' } else { 'H' },
+                self.bit_depth)
+    }
+}
+
+/// Read a variable length code, uvlc() in the AV1 specification.
+fn read_uvlc(bits: &mut BitReader) -> Result<u32> {
+    let mut leading_zeros = 0;
+    while !try!(bits.read_bool()) {
+        leading_zeros += 1;
+        if leading_zeros >= 32 {
+            return Ok(u32::max_value());
+        }
+    }
+    let value = try!(bits.read_bits(leading_zeros)) + (1 << leading_zeros) - 1;
+    Ok(value as u32)
+}
+
+/// Parse the payload of a sequence header OBU.
+pub fn parse_av1_sequence_header(payload: &[u8]) -> Result<Av1SequenceHeader> {
+    let mut bits = BitReader::new(payload);
+    let seq_profile = try!(bits.read_bits(3)) as u8;
+    if seq_profile > 2 {
+        return Err(Error::Unsupported("unknown AV1 seq_profile"));
+    }
+    let still_picture = try!(bits.read_bool());
+    let reduced_still_picture_header = try!(bits.read_bool());
+
+    let seq_level_idx;
+    let mut seq_tier = 0;
+    if reduced_still_picture_header {
+        seq_level_idx = try!(bits.read_bits(5)) as u8;
+    } else {
+        let mut decoder_model_info_present = false;
+        let mut buffer_delay_length = 0;
+        if try!(bits.read_bool()) {
+            // timing_info()
+            let _num_units_in_display_tick = try!(bits.read_bits(32));
+            let _time_scale = try!(bits.read_bits(32));
+            if try!(bits.read_bool()) {
+                let _num_ticks_per_picture_minus_1 = try!(read_uvlc(&mut bits));
+            }
+            decoder_model_info_present = try!(bits.read_bool());
+            if decoder_model_info_present {
+                buffer_delay_length = try!(bits.read_bits(5)) as usize + 1;
+                let _num_units_in_decoding_tick = try!(bits.read_bits(32));
+                let _buffer_removal_time_length_minus_1 = try!(bits.read_bits(5));
+                let _frame_presentation_time_length_minus_1 = try!(bits.read_bits(5));
+            }
+        }
+        let initial_display_delay_present = try!(bits.read_bool());
+        let operating_points = try!(bits.read_bits(5)) + 1;
+        let mut first_level = None;
+        for _ in 0..operating_points {
+            let _operating_point_idc = try!(bits.read_bits(12));
+            let level = try!(bits.read_bits(5)) as u8;
+            let tier = if level > 7 { try!(bits.read_bits(1)) as u8 } else { 0 };
+            if first_level.is_none() {
+                first_level = Some(level);
+                seq_tier = tier;
+            }
+            if decoder_model_info_present && try!(bits.read_bool()) {
+                // operating_parameters_info()
+                try!(bits.skip_bits(2 * buffer_delay_length + 1));
+            }
+            if initial_display_delay_present && try!(bits.read_bool()) {
+                try!(bits.skip_bits(4));
+            }
+        }
+        seq_level_idx = first_level.unwrap_or(0);
+    }
+
+    let width_bits = try!(bits.read_bits(4)) as usize + 1;
+    let height_bits = try!(bits.read_bits(4)) as usize + 1;
+    let max_frame_width = try!(bits.read_bits(width_bits)) as u32 + 1;
+    let max_frame_height = try!(bits.read_bits(height_bits)) as u32 + 1;
+    if !reduced_still_picture_header && try!(bits.read_bool()) {
+        // delta_frame_id_length_minus_2 and additional_frame_id_length_minus_1
+        try!(bits.skip_bits(7));
+    }
+    // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+    try!(bits.skip_bits(3));
+    if !reduced_still_picture_header {
+        // enable_interintra_compound, enable_masked_compound,
+        // enable_warped_motion, enable_dual_filter
+        try!(bits.skip_bits(4));
+        let enable_order_hint = try!(bits.read_bool());
+        if enable_order_hint {
+            // enable_jnt_comp, enable_ref_frame_mvs
+            try!(bits.skip_bits(2));
+        }
+        let seq_force_screen_content_tools = if try!(bits.read_bool()) {
+            2
+        } else {
+            try!(bits.read_bits(1))
+        };
+        if seq_force_screen_content_tools > 0 && !try!(bits.read_bool()) {
+            // seq_force_integer_mv
+            try!(bits.skip_bits(1));
+        }
+        if enable_order_hint {
+            // order_hint_bits_minus_1
+            try!(bits.skip_bits(3));
+        }
+    }
+    // enable_superres, enable_cdef, enable_restoration
+    try!(bits.skip_bits(3));
+
+    // color_config()
+    let high_bitdepth = try!(bits.read_bool());
+    let bit_depth = match (seq_profile, high_bitdepth) {
+        (2, true) => if try!(bits.read_bool()) { 12 } else { 10 },
+        (_, true) => 10,
+        (_, false) => 8,
+    };
+    let mono_chrome = if seq_profile == 1 { false } else { try!(bits.read_bool()) };
+    let (mut color_primaries, mut transfer_characteristics, mut matrix_coefficients) = (2, 2, 2);
+    if try!(bits.read_bool()) {
+        color_primaries = try!(bits.read_bits(8)) as u8;
+        transfer_characteristics = try!(bits.read_bits(8)) as u8;
+        matrix_coefficients = try!(bits.read_bits(8)) as u8;
+    }
+    let full_range;
+    let mut subsampling_x = true;
+    let mut subsampling_y = true;
+    let mut chroma_sample_position = 0;
+    if mono_chrome {
+        full_range = try!(bits.read_bool());
+    } else if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0 {
+        // sRGB
+        full_range = true;
+        subsampling_x = false;
+        subsampling_y = false;
+    } else {
+        full_range = try!(bits.read_bool());
+        match seq_profile {
+            0 => (),
+            1 => {
+                subsampling_x = false;
+                subsampling_y = false;
+            }
+            _ => if bit_depth == 12 {
+                subsampling_x = try!(bits.read_bool());
+                subsampling_y = subsampling_x && try!(bits.read_bool());
+            } else {
+                subsampling_y = false;
+            },
+        }
+        if subsampling_x && subsampling_y {
+            chroma_sample_position = try!(bits.read_bits(2)) as u8;
+        }
+    }
+    if !mono_chrome {
+        let _separate_uv_delta_q = try!(bits.read_bool());
+    }
+    let film_grain_params_present = try!(bits.read_bool());
+
+    Ok(Av1SequenceHeader {
+        seq_profile: seq_profile,
+        still_picture: still_picture,
+        reduced_still_picture_header: reduced_still_picture_header,
+        seq_level_idx: seq_level_idx,
+        seq_tier: seq_tier,
+        bit_depth: bit_depth,
+        mono_chrome: mono_chrome,
+        subsampling_x: subsampling_x,
+        subsampling_y: subsampling_y,
+        chroma_sample_position: chroma_sample_position,
+        color_primaries: color_primaries,
+        transfer_characteristics: transfer_characteristics,
+        matrix_coefficients: matrix_coefficients,
+        full_range: full_range,
+        max_frame_width: max_frame_width,
+        max_frame_height: max_frame_height,
+        film_grain_params_present: film_grain_params_present,
+    })
+}
+
+/// Find and parse the sequence header in a run of OBUs.
+///
+/// Returns None if there is no sequence header OBU.
+pub fn find_av1_sequence_header(obus: &[u8]) -> Result<Option<Av1SequenceHeader>> {
+    let mut data = obus;
+    while !data.is_empty() {
+        let header = data[0];
+        if header & 0x80 != 0 {
+            return Err(Error::InvalidData("AV1 OBU forbidden bit set"));
+        }
+        let obu_type = header >> 3 & 0xf;
+        let header_size = if header & 0x04 != 0 { 2 } else { 1 };
+        if data.len() < header_size {
+            return Err(Error::InvalidData("truncated AV1 OBU header"));
+        }
+        let (payload_start, payload_size) = if header & 0x02 != 0 {
+            let mut size = Cursor::new(&data[header_size..]);
+            let payload_size = try!(read_leb128(&mut size));
+            (header_size + size.position() as usize, payload_size)
+        } else {
+            // Without a size field the OBU runs to the end of the data.
+            (header_size, (data.len() - header_size) as u64)
+        };
+        if payload_size > (data.len() - payload_start) as u64 {
+            return Err(Error::InvalidData("truncated AV1 OBU"));
+        }
+        let payload_end = payload_start + payload_size as usize;
+        if obu_type == OBU_SEQUENCE_HEADER {
+            return parse_av1_sequence_header(&data[payload_start..payload_end]).map(Some);
+        }
+        data = &data[payload_end..];
+    }
+    Ok(None)
+}
+
+/// Parse the fixed fields of the contents of an 'av1C' box.
+pub fn parse_av1c(av1c: &[u8]) -> Result<Av1DecoderConfig> {
+    if av1c.len() < AV1C_HEADER_SIZE {
+        return Err(Error::InvalidData("truncated av1C"));
+    }
+    // marker and version
+    if av1c[0] != 0x81 {
+        return Err(Error::Unsupported("unknown av1C version"));
+    }
+    let high_bitdepth = av1c[2] & 0x40 != 0;
+    let twelve_bit = av1c[2] & 0x20 != 0;
+    Ok(Av1DecoderConfig {
+        seq_profile: av1c[1] >> 5,
+        seq_level_idx_0: av1c[1] & 0x1f,
+        seq_tier_0: av1c[2] >> 7,
+        bit_depth: match (high_bitdepth, twelve_bit) {
+            (true, true) => 12,
+            (true, false) => 10,
+            _ => 8,
+        },
+        monochrome: av1c[2] & 0x10 != 0,
+        chroma_subsampling_x: av1c[2] & 0x08 != 0,
+        chroma_subsampling_y: av1c[2] & 0x04 != 0,
+        chroma_sample_position: av1c[2] & 0x03,
+    })
+}
+
+/// Parse the sequence header from the contents of an 'av1C' box.
+pub fn av1c_sequence_header(av1c: &[u8]) -> Result<Option<Av1SequenceHeader>> {
+    if av1c.len() < AV1C_HEADER_SIZE {
+        return Err(Error::InvalidData("truncated av1C"));
+    }
+    // marker and version
+    if av1c[0] != 0x81 {
+        return Err(Error::Unsupported("unknown av1C version"));
+    }
+    find_av1_sequence_header(&av1c[AV1C_HEADER_SIZE..])
+}
+
+impl Track {
+    /// Parse the profile, level and bit depth from the track's 'av1C',
+    /// if it has one.
+    pub fn av1_decoder_config(&self) -> Result<Option<Av1DecoderConfig>> {
+        match self.data {
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::AV1Config(ref av1c) => parse_av1c(av1c).map(Some),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! H.264 sequence parameter set parsing.
+//!
+//! The SPS carried in an 'avcC' box describes the coded stream
+//! itself, so it is the authority when the sample entry or track
+//! header disagree with it.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use BitReader;
+use Error;
+use MediaContext;
+use Result;
+use SampleEntry;
+use Track;
+use VideoCodecSpecific;
+
+const NAL_UNIT_TYPE_SPS: u8 = 7;
+
+/// Fixed fields at the start of an 'avcC' AVCDecoderConfigurationRecord.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AvcDecoderConfig {
+    pub profile_indication: u8,
+    /// Constraint flags, as in the SPS.
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    /// Size in bytes of the length prefix on each NAL unit in samples.
+    pub nal_length_size: u8,
+}
+
+/// Profiles whose SPS carries chroma format and bit depth fields.
+const HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// Fields of an H.264 sequence parameter set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcSequenceParameterSet {
+    pub profile_idc: u8,
+    /// constraint_set0_flag through constraint_set5_flag and the two
+    /// reserved bits, as coded.
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    /// 0 for monochrome, 1 for 4:2:0, 2 for 4:2:2 and 3 for 4:4:4.
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    /// Size of the decoded pictures, a whole number of macroblocks.
+    pub coded_width: u32,
+    pub coded_height: u32,
+    /// Size after applying the frame cropping rectangle.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Copy out the RBSP from a NAL unit payload, dropping emulation
+/// prevention bytes.
+fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+pub fn skip_scaling_list(bits: &mut BitReader, size: usize) -> Result<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta = try!(bits.read_se());
+            if delta < -128 || delta > 127 {
+                return Err(Error::InvalidData("SPS delta_scale out of range"));
+            }
+            next_scale = (last_scale + delta + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Parse an SPS NAL unit, including its one byte header.
+pub fn parse_avc_sps(nal: &[u8]) -> Result<AvcSequenceParameterSet> {
+    match nal.first() {
+        Some(header) if header & 0x1f == NAL_UNIT_TYPE_SPS => (),
+        Some(_) => return Err(Error::InvalidData("NAL unit is not an SPS")),
+        None => return Err(Error::InvalidData("empty SPS")),
+    }
+    let rbsp = unescape_rbsp(&nal[1..]);
+    let mut bits = BitReader::new(&rbsp);
+
+    let profile_idc = try!(bits.read_bits(8)) as u8;
+    let constraint_flags = try!(bits.read_bits(8)) as u8;
+    let level_idc = try!(bits.read_bits(8)) as u8;
+    let _seq_parameter_set_id = try!(bits.read_ue());
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    let mut bit_depth_luma = 8;
+    let mut bit_depth_chroma = 8;
+    if HIGH_PROFILES.contains(&profile_idc) {
+        chroma_format_idc = try!(bits.read_ue());
+        if chroma_format_idc > 3 {
+            return Err(Error::InvalidData("invalid SPS chroma_format_idc"));
+        }
+        if chroma_format_idc == 3 {
+            separate_colour_plane = try!(bits.read_bool());
+        }
+        let luma_minus8 = try!(bits.read_ue());
+        let chroma_minus8 = try!(bits.read_ue());
+        if luma_minus8 > 6 || chroma_minus8 > 6 {
+            return Err(Error::InvalidData("invalid SPS bit depth"));
+        }
+        bit_depth_luma = 8 + luma_minus8 as u8;
+        bit_depth_chroma = 8 + chroma_minus8 as u8;
+        let _qpprime_y_zero_transform_bypass = try!(bits.read_bool());
+        if try!(bits.read_bool()) {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if try!(bits.read_bool()) {
+                    try!(skip_scaling_list(&mut bits, if i < 6 { 16 } else { 64 }));
+                }
+            }
+        }
+    }
+
+    let _log2_max_frame_num_minus4 = try!(bits.read_ue());
+    match try!(bits.read_ue()) {
+        0 => {
+            let _log2_max_pic_order_cnt_lsb_minus4 = try!(bits.read_ue());
+        }
+        1 => {
+            let _delta_pic_order_always_zero = try!(bits.read_bool());
+            let _offset_for_non_ref_pic = try!(bits.read_se());
+            let _offset_for_top_to_bottom_field = try!(bits.read_se());
+            let cycle = try!(bits.read_ue());
+            if cycle > 255 {
+                return Err(Error::InvalidData("invalid SPS pic order count cycle"));
+            }
+            for _ in 0..cycle {
+                try!(bits.read_se());
+            }
+        }
+        2 => (),
+        _ => return Err(Error::InvalidData("invalid SPS pic_order_cnt_type")),
+    }
+    let _max_num_ref_frames = try!(bits.read_ue());
+    let _gaps_in_frame_num_value_allowed = try!(bits.read_bool());
+    let width_in_mbs = try!(bits.read_ue()) as u64 + 1;
+    let height_in_map_units = try!(bits.read_ue()) as u64 + 1;
+    let frame_mbs_only = try!(bits.read_bool());
+    if !frame_mbs_only {
+        let _mb_adaptive_frame_field = try!(bits.read_bool());
+    }
+    let _direct_8x8_inference = try!(bits.read_bool());
+    let mut crop = [0u64; 4];
+    if try!(bits.read_bool()) {
+        for offset in crop.iter_mut() {
+            *offset = try!(bits.read_ue()) as u64;
+        }
+    }
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let coded_width = width_in_mbs * 16;
+    let coded_height = field_factor * height_in_map_units * 16;
+    let (crop_unit_x, crop_unit_y) = match (separate_colour_plane, chroma_format_idc) {
+        (true, _) | (_, 0) => (1, field_factor),
+        (_, 1) => (2, 2 * field_factor),
+        (_, 2) => (2, field_factor),
+        _ => (1, field_factor),
+    };
+    let crop_x = (crop[0] + crop[1]) * crop_unit_x;
+    let crop_y = (crop[2] + crop[3]) * crop_unit_y;
+    if coded_width > u32::max_value() as u64 || coded_height > u32::max_value() as u64 {
+        return Err(Error::InvalidData("SPS picture size too large"));
+    }
+    if crop_x >= coded_width || crop_y >= coded_height {
+        return Err(Error::InvalidData("SPS cropping exceeds picture size"));
+    }
+
+    Ok(AvcSequenceParameterSet {
+        profile_idc: profile_idc,
+        constraint_flags: constraint_flags,
+        level_idc: level_idc,
+        chroma_format_idc: chroma_format_idc as u8,
+        bit_depth_luma: bit_depth_luma,
+        bit_depth_chroma: bit_depth_chroma,
+        coded_width: coded_width as u32,
+        coded_height: coded_height as u32,
+        width: (coded_width - crop_x) as u32,
+        height: (coded_height - crop_y) as u32,
+    })
+}
+
+/// Parse the fixed fields of the contents of an 'avcC' box.
+pub fn parse_avcc(avcc: &[u8]) -> Result<AvcDecoderConfig> {
+    if avcc.len() < 5 {
+        return Err(Error::InvalidData("truncated avcC"));
+    }
+    if avcc[0] != 1 {
+        return Err(Error::Unsupported("unknown avcC configuration version"));
+    }
+    Ok(AvcDecoderConfig {
+        profile_indication: avcc[1],
+        profile_compatibility: avcc[2],
+        level_indication: avcc[3],
+        nal_length_size: (avcc[4] & 0x3) + 1,
+    })
+}
+
+/// Parse the first SPS in the contents of an 'avcC' box.
+///
+/// Returns None if the record holds no SPS.
+pub fn avcc_sequence_parameter_set(avcc: &[u8]) -> Result<Option<AvcSequenceParameterSet>> {
+    if avcc.len() < 6 {
+        return Err(Error::InvalidData("truncated avcC"));
+    }
+    if avcc[5] & 0x1f == 0 {
+        return Ok(None);
+    }
+    if avcc.len() < 8 {
+        return Err(Error::InvalidData("truncated avcC"));
+    }
+    let len = (avcc[6] as usize) << 8 | avcc[7] as usize;
+    match avcc.get(8..8 + len) {
+        Some(nal) => parse_avc_sps(nal).map(Some),
+        None => Err(Error::InvalidData("truncated avcC")),
+    }
+}
+
+/// A track whose sample entry or track header gives a picture size
+/// different from its SPS.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DimensionMismatch {
+    /// The sample entry's width and height differ from the cropped
+    /// SPS picture size.
+    SampleEntry { track: usize, sps: (u32, u32), found: (u32, u32) },
+    /// The track header's presentation size differs from the cropped
+    /// SPS picture size. This is legitimate for anamorphic video, but
+    /// usually an encoder error.
+    TrackHeader { track: usize, sps: (u32, u32), found: (u32, u32) },
+}
+
+impl Track {
+    /// Parse the profile, level and NAL length size from the track's
+    /// 'avcC', if it has one.
+    pub fn avc_decoder_config(&self) -> Result<Option<AvcDecoderConfig>> {
+        match self.data {
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::AVCConfig(ref avcc) => parse_avcc(avcc).map(Some),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse the SPS from the track's 'avcC', if it has one.
+    pub fn avc_sequence_parameter_set(&self) -> Result<Option<AvcSequenceParameterSet>> {
+        match self.data {
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::AVCConfig(ref avcc) => avcc_sequence_parameter_set(avcc),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+impl MediaContext {
+    /// Compare the dimensions of each AVC track with its SPS.
+    ///
+    /// Tracks whose SPS is missing or can't be parsed are skipped.
+    pub fn check_dimensions(&self) -> Vec<DimensionMismatch> {
+        let mut mismatches = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            let sps = match track.avc_sequence_parameter_set() {
+                Ok(Some(sps)) => (sps.width, sps.height),
+                _ => continue,
+            };
+            if let Some(SampleEntry::Video(ref video)) = track.data {
+                let found = (video.width as u32, video.height as u32);
+                if found != sps {
+                    mismatches.push(DimensionMismatch::SampleEntry { track: index, sps: sps, found: found });
+                }
+            }
+            if let Some(ref tkhd) = track.tkhd {
+                let found = (tkhd.width.integer_part() as u32, tkhd.height.integer_part() as u32);
+                if found != sps {
+                    mismatches.push(DimensionMismatch::TrackHeader { track: index, sps: sps, found: found });
+                }
+            }
+        }
+        mismatches
+    }
+}
@@ -0,0 +1,136 @@
+//! Variable length integers and bit-level reads for codec parsers.
+//!
+//! Codec configuration records and frame headers pack fields more
+//! tightly than the byte-aligned box structure, so they are read with
+//! these helpers rather than the `be_*` functions.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use byteorder::ReadBytesExt;
+
+use Error;
+use Result;
+
+/// Read a UTF-8 style coded number, as used for FLAC frame and sample
+/// numbers.
+///
+/// The count of leading one bits in the first byte gives the number
+/// of bytes, as in UTF-8, extended to seven bytes so values up to 36
+/// bits can be coded.
+pub fn read_utf8_coded<T: ReadBytesExt>(src: &mut T) -> Result<u64> {
+    let first = try!(src.read_u8());
+    let length = (!first).leading_zeros();
+    let mut value = match length {
+        0 => return Ok(first as u64),
+        1 | 8 => return Err(Error::InvalidData("invalid coded number lead byte")),
+        // The lead byte of the longest form holds no value bits.
+        7 => 0,
+        _ => (first & (0x7f >> length)) as u64,
+    };
+    for _ in 1..length {
+        let byte = try!(src.read_u8());
+        if byte & 0xc0 != 0x80 {
+            return Err(Error::InvalidData("invalid coded number continuation byte"));
+        }
+        value = value << 6 | (byte & 0x3f) as u64;
+    }
+    Ok(value)
+}
+
+/// Read an unsigned LEB128 number, as used for AV1 OBU sizes.
+///
+/// Each byte holds seven bits, least significant first, with the top
+/// bit set on all but the last. At most eight bytes are read.
+pub fn read_leb128<T: ReadBytesExt>(src: &mut T) -> Result<u64> {
+    let mut value = 0;
+    for i in 0..8 {
+        let byte = try!(src.read_u8());
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidData("leb128 number too long"))
+}
+
+/// Reads fields of arbitrary bit width, most significant bit first,
+/// from a byte slice.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits from the start of `data`.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    /// Bits left to read.
+    pub fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    /// Read a `count` bit unsigned value, up to 64 bits.
+    pub fn read_bits(&mut self, count: usize) -> Result<u64> {
+        if count > 64 {
+            return Err(Error::InvalidData("bit field wider than 64 bits"));
+        }
+        if count > self.remaining() {
+            return Err(Error::UnexpectedEOF);
+        }
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data[self.position / 8];
+            let bit = byte >> (7 - self.position % 8) & 1;
+            value = value << 1 | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(try!(self.read_bits(1)) == 1)
+    }
+
+    /// Read a `count` bit value without advancing.
+    pub fn peek_bits(&self, count: usize) -> Result<u64> {
+        BitReader { data: self.data, position: self.position }.read_bits(count)
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Result<()> {
+        if count > self.remaining() {
+            return Err(Error::UnexpectedEOF);
+        }
+        self.position += count;
+        Ok(())
+    }
+
+    /// Read an unsigned Exp-Golomb code, ue(v) in the H.264 and H.265
+    /// specifications.
+    pub fn read_ue(&mut self) -> Result<u32> {
+        let mut leading_zeros = 0;
+        while !try!(self.read_bool()) {
+            leading_zeros += 1;
+            // Valid codes fit in 32 bits.
+            if leading_zeros > 31 {
+                return Err(Error::InvalidData("Exp-Golomb code too long"));
+            }
+        }
+        let suffix = try!(self.read_bits(leading_zeros));
+        Ok(((1u64 << leading_zeros) - 1 + suffix) as u32)
+    }
+
+    /// Read a signed Exp-Golomb code, se(v).
+    pub fn read_se(&mut self) -> Result<i32> {
+        let code = try!(self.read_ue()) as i64;
+        // Codes alternate between positive and negative values.
+        let value = if code % 2 == 1 { (code + 1) / 2 } else { -(code / 2) };
+        Ok(value as i32)
+    }
+}
@@ -19,12 +19,23 @@ macro_rules! box_database {
                 }
             }
         }
+
+        impl From<BoxType> for u32 {
+            fn from(b: BoxType) -> u32 {
+                use self::BoxType::*;
+                match b {
+                    $($boxenum => $boxtype),*,
+                    UnknownBox(t) => t,
+                }
+            }
+        }
     }
 }
 
 box_database!(
     FileTypeBox                0x66747970, // "ftyp"
     MovieBox                   0x6d6f6f76, // "moov"
+    CompressedMovieBox         0x636d6f76, // "cmov"
     MovieHeaderBox             0x6d766864, // "mvhd"
     TrackBox                   0x7472616b, // "trak"
     TrackHeaderBox             0x746b6864, // "tkhd"
@@ -39,6 +50,7 @@ box_database!(
     TimeToSampleBox            0x73747473, // "stts"
     SampleToChunkBox           0x73747363, // "stsc"
     SampleSizeBox              0x7374737a, // "stsz"
+    CompactSampleSizeBox       0x73747a32, // "stz2"
     ChunkOffsetBox             0x7374636f, // "stco"
     ChunkLargeOffsetBox        0x636f3634, // "co64"
     SyncSampleBox              0x73747373, // "stss"
@@ -54,4 +66,69 @@ box_database!(
     OpusSpecificBox            0x644f7073, // "dOps"
     ProtectedVisualSampleEntry 0x656e6376, // "encv" - Need to check official name in spec.
     ProtectedAudioSampleEntry  0x656e6361, // "enca" - Need to check official name in spec.
+    RtpHintSampleEntry         0x72747020, // "rtp "
+    SrtpHintSampleEntry        0x73727470, // "srtp"
+    MovieExtendsBox            0x6d766578, // "mvex"
+    MovieFragmentBox           0x6d6f6f66, // "moof"
+    MovieFragmentHeaderBox     0x6d666864, // "mfhd"
+    ProtectionSystemSpecificHeaderBox 0x70737368, // "pssh"
+    TrackReferenceBox          0x74726566, // "tref"
+    ChapterTrackReferenceBox   0x63686170, // "chap" - QuickTime chapter track reference.
+    UserdataBox                0x75647461, // "udta"
+    ChapterListBox             0x6368706c, // "chpl" - Nero chapter list.
+    TitleBox                   0x7469746c, // "titl" - 3GPP track title.
+    MasteringDisplayColourVolumeBox 0x6d646376, // "mdcv"
+    ContentLightLevelBox       0x636c6c69, // "clli"
+    SMPTE2086MasteringDisplayMetadataBox 0x536d446d, // "SmDm" - vp9 binding.
+    ContentLightLevelInfoBox   0x436f4c4c, // "CoLL" - vp9 binding.
+    MetadataBox                0x6d657461, // "meta"
+    MetadataItemListBox        0x696c7374, // "ilst" - iTunes metadata.
+    FreeformMetadataItem       0x2d2d2d2d, // "----" - iTunes metadata.
+    MetadataMeanBox            0x6d65616e, // "mean" - iTunes metadata.
+    MetadataNameBox            0x6e616d65, // "name" - iTunes metadata.
+    MetadataDataBox            0x64617461, // "data" - iTunes metadata.
+    SampleGroupDescriptionBox  0x73677064, // "sgpd"
+    SampleToGroupBox           0x73626770, // "sbgp"
+    CompositionOffsetBox       0x63747473, // "ctts"
+    SegmentIndexBox            0x73696478, // "sidx"
+    TrackExtendsBox            0x74726578, // "trex"
+    TrackFragmentBox           0x74726166, // "traf"
+    TrackFragmentHeaderBox     0x74666864, // "tfhd"
+    TrackFragmentBaseMediaDecodeTimeBox 0x74666474, // "tfdt"
+    TrackRunBox                0x7472756e, // "trun"
+    MediaDataBox               0x6d646174, // "mdat"
+    SegmentTypeBox             0x73747970, // "styp"
+    MovieFragmentRandomAccessBox 0x6d667261, // "mfra"
+    TrackFragmentRandomAccessBox 0x74667261, // "tfra"
+    MovieFragmentRandomAccessOffsetBox 0x6d66726f, // "mfro"
+    ProtectionSchemeInfoBox    0x73696e66, // "sinf"
+    OriginalFormatBox          0x66726d61, // "frma"
+    SchemeTypeBox              0x7363686d, // "schm"
+    SchemeInformationBox       0x73636869, // "schi"
+    TrackEncryptionBox         0x74656e63, // "tenc"
+    SampleEncryptionBox        0x73656e63, // "senc"
+    SubSampleInformationBox    0x73756273, // "subs"
+    PrimaryItemBox             0x7069746d, // "pitm"
+    ItemInfoBox                0x69696e66, // "iinf"
+    ItemInfoEntry              0x696e6665, // "infe"
+    ItemLocationBox            0x696c6f63, // "iloc"
+    ItemReferenceBox           0x69726566, // "iref"
+    ItemDataBox                0x69646174, // "idat"
+    UuidBox                    0x75756964, // "uuid"
+    ItemPropertiesBox          0x69707270, // "iprp"
+    ItemPropertyContainerBox   0x6970636f, // "ipco"
+    ItemPropertyAssociationBox 0x69706d61, // "ipma"
+    AuxiliaryTypeProperty      0x61757843, // "auxC"
+    AuxiliaryTypeInfoBox       0x61757869, // "auxi"
+    CleanApertureBox           0x636c6170, // "clap"
+    ImageRotationProperty      0x69726f74, // "irot"
+    ImageMirrorProperty        0x696d6972, // "imir"
+    PixelInformationProperty   0x70697869, // "pixi"
+    HEVCSampleEntry            0x68766331, // "hvc1"
+    HEV1SampleEntry            0x68657631, // "hev1"
+    HEVCConfigurationBox       0x68766343, // "hvcC"
+    AV1SampleEntry             0x61763031, // "av01"
+    AV1CodecConfigurationBox   0x61763143, // "av1C"
+    FLACSampleEntry            0x664c6143, // "fLaC"
+    FLACSpecificBox            0x64664c61, // "dfLa"
 );
@@ -0,0 +1,792 @@
+//! Persisted parse results, so a file needn't be walked again to
+//! rebuild its `MediaContext`.
+//!
+//! The cache holds what parsing produced: the movie and track headers,
+//! sample descriptions, sample tables, the sample index built in
+//! `ParseMode::Full`, fragments, segment indexes and item metadata.
+//! Nothing is re-parsed on loading; each value is read back field by
+//! field. The skipped boxes and unsupported features recorded as
+//! diagnostics aren't stored.
+//!
+//! Callers are responsible for noticing when a source file changes and
+//! its cache is stale.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::{Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use AudioCodecSpecific;
+use AudioSampleEntry;
+use AuxiliaryType;
+use BoxType;
+use ChannelMappingTable;
+use ChunkOffsetBox;
+use CleanAperture;
+use CompositionOffset;
+use CompositionOffsetBox;
+use Error;
+use FLACSpecificBox;
+use FLAC_METADATA_STREAMINFO;
+use FixedPoint16_16;
+use FixedPoint8_8;
+use FlacMetadataBlock;
+use FullSample;
+use ItemExtent;
+use ItemInfoEntry;
+use ItemLocation;
+use ItemMetadata;
+use ItemProperty;
+use ItemPropertyAssociation;
+use ItemReference;
+use MediaContext;
+use MediaFeatures;
+use MediaScaledTime;
+use MediaTimeScale;
+use MirrorAxis;
+use MovieFragment;
+use OpusSpecificBox;
+use PropertyAssociation;
+use ProtectionSchemeInfoBox;
+use ProtectionSystemSpecificHeaderBox;
+use Result;
+use Sample;
+use SampleCrypto;
+use SampleEncryptionEntry;
+use SampleEntry;
+use SampleInfo;
+use SampleSizeBox;
+use SampleToChunk;
+use SampleToChunkBox;
+use SampleToGroupBox;
+use SegmentIndexBox;
+use SegmentReference;
+use SignedFixedPoint16_16;
+use SubSampleEntry;
+use SubSampleInformationBox;
+use Subsample;
+use SyncSampleBox;
+use TimeToSampleBox;
+use Track;
+use TrackEncryptionBox;
+use TrackExtendsBox;
+use TrackFragment;
+use TrackHeaderBox;
+use TrackScaledTime;
+use TrackTimeScale;
+use TrackType;
+use UnknownBox;
+use VPxConfigBox;
+use VideoCodecSpecific;
+use VideoSampleEntry;
+use be_u32;
+use parse_flac_stream_info;
+
+const CACHE_MAGIC: u32 = 0x6d703463; // "mp4c"
+const CACHE_VERSION: u32 = 3;
+
+/// A value which can be stored in a parse cache.
+trait Cached: Sized {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()>;
+    fn read_from<R: Read>(src: &mut R) -> Result<Self>;
+}
+
+/// Store a struct as its fields, in the order listed. Every field must
+/// be listed for the struct to be rebuilt.
+macro_rules! cached_struct {
+    ($name:ident { $($field:ident),*, }) => {
+        impl Cached for $name {
+            fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+                $(try!(self.$field.write_to(dst));)*
+                Ok(())
+            }
+
+            fn read_from<R: Read>(src: &mut R) -> Result<$name> {
+                Ok($name { $($field: try!(Cached::read_from(src))),* })
+            }
+        }
+    }
+}
+
+/// Store a tuple struct as its fields, named here only for the
+/// expansion.
+macro_rules! cached_tuple {
+    ($name:ident ( $($field:ident),* )) => {
+        impl Cached for $name {
+            fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+                let $name($(ref $field),*) = *self;
+                $(try!($field.write_to(dst));)*
+                Ok(())
+            }
+
+            fn read_from<R: Read>(src: &mut R) -> Result<$name> {
+                Ok($name($({ let $field = try!(Cached::read_from(src)); $field }),*))
+            }
+        }
+    }
+}
+
+macro_rules! cached_int {
+    ($t:ty, $write:ident, $read:ident) => {
+        impl Cached for $t {
+            fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+                try!(dst.$write::<BigEndian>(*self));
+                Ok(())
+            }
+
+            fn read_from<R: Read>(src: &mut R) -> Result<$t> {
+                Ok(try!(src.$read::<BigEndian>()))
+            }
+        }
+    }
+}
+
+cached_int!(u16, write_u16, read_u16);
+cached_int!(u32, write_u32, read_u32);
+cached_int!(u64, write_u64, read_u64);
+cached_int!(i16, write_i16, read_i16);
+cached_int!(i32, write_i32, read_i32);
+cached_int!(i64, write_i64, read_i64);
+
+impl Cached for u8 {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        try!(dst.write_u8(*self));
+        Ok(())
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<u8> {
+        Ok(try!(src.read_u8()))
+    }
+}
+
+impl Cached for bool {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        (*self as u8).write_to(dst)
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<bool> {
+        match try!(u8::read_from(src)) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidData("malformed parse cache entry")),
+        }
+    }
+}
+
+impl Cached for usize {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        (*self as u64).write_to(dst)
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<usize> {
+        let value = try!(u64::read_from(src));
+        if value > std::usize::MAX as u64 {
+            return Err(Error::InvalidData("parse cache value exceeds usize"));
+        }
+        Ok(value as usize)
+    }
+}
+
+/// Write the length of a sequence, which must fit the u32 it's stored in.
+fn write_count<W: Write>(count: usize, dst: &mut W) -> Result<()> {
+    if count > std::u32::MAX as usize {
+        return Err(Error::Unsupported("too many entries for a parse cache"));
+    }
+    (count as u32).write_to(dst)
+}
+
+impl<T: Cached> Cached for Vec<T> {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        try!(write_count(self.len(), dst));
+        for item in self {
+            try!(item.write_to(dst));
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<Vec<T>> {
+        // The count isn't trusted for preallocation; a short cache
+        // fails at its end instead.
+        let count = try!(be_u32(src));
+        let mut items = Vec::new();
+        for _ in 0..count {
+            items.push(try!(T::read_from(src)));
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Cached> Cached for Option<T> {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        match *self {
+            Some(ref value) => {
+                try!(true.write_to(dst));
+                value.write_to(dst)
+            }
+            None => false.write_to(dst),
+        }
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<Option<T>> {
+        match try!(bool::read_from(src)) {
+            true => Ok(Some(try!(T::read_from(src)))),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<A: Cached, B: Cached> Cached for (A, B) {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        try!(self.0.write_to(dst));
+        self.1.write_to(dst)
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<(A, B)> {
+        let a = try!(A::read_from(src));
+        Ok((a, try!(B::read_from(src))))
+    }
+}
+
+impl Cached for String {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        try!(write_count(self.len(), dst));
+        try!(dst.write_all(self.as_bytes()));
+        Ok(())
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<String> {
+        let count = try!(be_u32(src)) as u64;
+        let mut bytes = Vec::new();
+        if try!(src.by_ref().take(count).read_to_end(&mut bytes)) as u64 != count {
+            return Err(Error::UnexpectedEOF);
+        }
+        Ok(try!(String::from_utf8(bytes)))
+    }
+}
+
+impl Cached for std::ops::Range<u64> {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        try!(self.start.write_to(dst));
+        self.end.write_to(dst)
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<std::ops::Range<u64>> {
+        let start = try!(u64::read_from(src));
+        Ok(start..try!(u64::read_from(src)))
+    }
+}
+
+impl Cached for BoxType {
+    fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+        u32::from(*self).write_to(dst)
+    }
+
+    fn read_from<R: Read>(src: &mut R) -> Result<BoxType> {
+        Ok(BoxType::from(try!(u32::read_from(src))))
+    }
+}
+
+/// Store an enum as a one byte tag followed by the variant's fields.
+macro_rules! cached_enum {
+    ($name:ident { $($tag:tt => $variant:ident $(($field:ident))*),*, }) => {
+        impl Cached for $name {
+            fn write_to<W: Write>(&self, dst: &mut W) -> Result<()> {
+                match *self {
+                    $($name::$variant $((ref $field))* => {
+                        try!((($tag) as u8).write_to(dst));
+                        $(try!($field.write_to(dst));)*
+                    })*
+                }
+                Ok(())
+            }
+
+            fn read_from<R: Read>(src: &mut R) -> Result<$name> {
+                match try!(u8::read_from(src)) {
+                    $($tag => Ok($name::$variant $(({ let $field = try!(Cached::read_from(src)); $field }))*),)*
+                    _ => Err(Error::InvalidData("malformed parse cache entry")),
+                }
+            }
+        }
+    }
+}
+
+cached_tuple!(FixedPoint16_16(value));
+cached_tuple!(SignedFixedPoint16_16(value));
+cached_tuple!(FixedPoint8_8(value));
+cached_tuple!(MediaFeatures(bits));
+cached_tuple!(MediaTimeScale(scale));
+cached_tuple!(MediaScaledTime(time));
+cached_tuple!(TrackTimeScale(scale, track));
+cached_tuple!(TrackScaledTime(time, track));
+
+cached_enum!(TrackType {
+    0 => Audio,
+    1 => Video,
+    2 => Hint,
+    3 => Unknown,
+});
+
+cached_enum!(SampleEntry {
+    0 => Audio(entry),
+    1 => Video(entry),
+    2 => Unknown,
+});
+
+cached_enum!(AudioCodecSpecific {
+    0 => ES_Descriptor(esds),
+    1 => OpusSpecificBox(dops),
+    2 => FLACSpecificBox(dfla),
+});
+
+cached_enum!(VideoCodecSpecific {
+    0 => AVCConfig(avcc),
+    1 => HEVCConfig(hvcc),
+    2 => VPxConfig(vpcc),
+    3 => AV1Config(av1c),
+});
+
+cached_enum!(AuxiliaryType {
+    0 => Alpha,
+    1 => Depth,
+    2 => Other(urn),
+});
+
+cached_enum!(MirrorAxis {
+    0 => Vertical,
+    1 => Horizontal,
+});
+
+cached_enum!(ItemProperty {
+    0 => AuxiliaryType(auxiliary_type),
+    1 => CleanAperture(clap),
+    2 => Rotation(angle),
+    3 => Mirror(axis),
+    4 => PixelInformation(bits_per_channel),
+    5 => Unsupported(box_type),
+});
+
+cached_struct!(AudioSampleEntry {
+    data_reference_index,
+    channelcount,
+    samplesize,
+    samplerate,
+    codec_specific,
+});
+
+cached_struct!(VideoSampleEntry {
+    name,
+    data_reference_index,
+    width,
+    height,
+    codec_specific,
+});
+
+cached_struct!(VPxConfigBox {
+    version,
+    profile,
+    level,
+    bit_depth,
+    color_space,
+    chroma_subsampling,
+    transfer_function,
+    video_full_range,
+    colour_primaries,
+    matrix_coefficients,
+    codec_init,
+});
+
+cached_struct!(ChannelMappingTable {
+    stream_count,
+    coupled_count,
+    channel_mapping,
+});
+
+cached_struct!(OpusSpecificBox {
+    version,
+    output_channel_count,
+    pre_skip,
+    input_sample_rate,
+    output_gain,
+    channel_mapping_family,
+    channel_mapping_table,
+});
+
+cached_struct!(FLACSpecificBox {
+    version,
+    blocks,
+});
+
+cached_struct!(FlacMetadataBlock {
+    block_type,
+    last,
+    data,
+});
+
+cached_struct!(TrackHeaderBox {
+    track_id,
+    disabled,
+    enabled,
+    in_movie,
+    in_preview,
+    duration,
+    layer,
+    alternate_group,
+    volume,
+    width,
+    height,
+});
+
+cached_struct!(TimeToSampleBox {
+    samples,
+});
+
+cached_struct!(Sample {
+    sample_count,
+    sample_delta,
+});
+
+cached_struct!(CompositionOffsetBox {
+    samples,
+});
+
+cached_struct!(CompositionOffset {
+    sample_count,
+    sample_offset,
+});
+
+cached_struct!(SampleToChunkBox {
+    samples,
+});
+
+cached_struct!(SampleToChunk {
+    first_chunk,
+    samples_per_chunk,
+    sample_description_index,
+});
+
+cached_struct!(SampleSizeBox {
+    sample_size,
+    sample_count,
+    sample_sizes,
+});
+
+cached_struct!(ChunkOffsetBox {
+    offsets,
+});
+
+cached_struct!(SyncSampleBox {
+    samples,
+});
+
+cached_struct!(SampleEncryptionEntry {
+    iv,
+    subsamples,
+});
+
+cached_struct!(Subsample {
+    clear_bytes,
+    encrypted_bytes,
+});
+
+cached_struct!(SubSampleInformationBox {
+    flags,
+    samples,
+});
+
+cached_struct!(SubSampleEntry {
+    size,
+    priority,
+    discardable,
+    codec_specific_parameters,
+});
+
+cached_struct!(TrackEncryptionBox {
+    crypt_byte_block,
+    skip_byte_block,
+    is_encrypted,
+    iv_size,
+    kid,
+    constant_iv,
+});
+
+cached_struct!(SampleToGroupBox {
+    grouping_type,
+    entries,
+});
+
+cached_struct!(ProtectionSchemeInfoBox {
+    original_format,
+    scheme_type,
+    tenc,
+});
+
+cached_struct!(ProtectionSystemSpecificHeaderBox {
+    system_id,
+    kid,
+    data,
+    box_content,
+});
+
+cached_struct!(SampleInfo {
+    offset,
+    size,
+    decode_time,
+    composition_time,
+    duration,
+    sync,
+});
+
+cached_struct!(SampleCrypto {
+    kid,
+    iv,
+    subsamples,
+});
+
+cached_struct!(FullSample {
+    info,
+    crypto,
+});
+
+cached_struct!(Track {
+    id,
+    track_type,
+    empty_duration,
+    media_time,
+    edit_duration,
+    timescale,
+    duration,
+    track_id,
+    mime_type,
+    language,
+    name,
+    data,
+    tkhd,
+    edit_count,
+    roll_distance,
+    stts,
+    ctts,
+    stsc,
+    stsz,
+    stco,
+    stss,
+    senc,
+    subs,
+    seig_entries,
+    seig_groups,
+    protection_info,
+    samples,
+    enabled,
+    in_movie,
+    in_preview,
+    auxiliary_type,
+    auxiliary_for,
+});
+
+cached_struct!(SegmentIndexBox {
+    reference_id,
+    timescale,
+    earliest_presentation_time,
+    first_offset,
+    references,
+    position,
+    size,
+});
+
+cached_struct!(SegmentReference {
+    references_index,
+    referenced_size,
+    subsegment_duration,
+    starts_with_sap,
+    sap_type,
+    sap_delta_time,
+});
+
+cached_struct!(MovieFragment {
+    position,
+    size,
+    sequence_number,
+    segment,
+    tracks,
+});
+
+cached_struct!(TrackFragment {
+    track_id,
+    base_decode_time,
+    sample_count,
+    duration,
+    samples,
+    senc,
+});
+
+cached_struct!(TrackExtendsBox {
+    track_id,
+    default_sample_duration,
+    default_sample_size,
+    default_sample_flags,
+});
+
+cached_struct!(UnknownBox {
+    name,
+    size,
+    payload,
+});
+
+cached_struct!(ItemMetadata {
+    handler_type,
+    primary_item_id,
+    items,
+    locations,
+    references,
+    item_data,
+    properties,
+    property_associations,
+});
+
+cached_struct!(ItemInfoEntry {
+    item_id,
+    item_type,
+    item_name,
+    content_type,
+    hidden,
+});
+
+cached_struct!(ItemLocation {
+    item_id,
+    construction_method,
+    data_reference_index,
+    base_offset,
+    extents,
+});
+
+cached_struct!(ItemExtent {
+    offset,
+    length,
+});
+
+cached_struct!(ItemReference {
+    reference_type,
+    from_item_id,
+    to_item_ids,
+});
+
+cached_struct!(ItemPropertyAssociation {
+    item_id,
+    associations,
+});
+
+cached_struct!(PropertyAssociation {
+    property_index,
+    essential,
+});
+
+cached_struct!(CleanAperture {
+    width_n,
+    width_d,
+    height_n,
+    height_d,
+    horizontal_offset_n,
+    horizontal_offset_d,
+    vertical_offset_n,
+    vertical_offset_d,
+});
+
+/// Apply `$op` to each parse result held by a MediaContext, in cache
+/// order. The parse options and diagnostics aren't included.
+macro_rules! context_results {
+    ($op:ident) => {
+        $op!(timescale, duration, rate, volume, next_track_id, tracks,
+             duplicate_track_ids, unknown_boxes, features, itunes_smpb,
+             segment_indexes, fragments, psshs, track_extends, brands,
+             segment_brands, top_level_boxes, media_data, item_metadata,
+             exif)
+    }
+}
+
+/// Write a parse cache holding the results of parsing into `context`.
+///
+/// The context should be the product of `read_mp4` or
+/// `read_mp4_seekable`; its options decide what was parsed, and so
+/// what the cache holds.
+pub fn write_parse_cache<W: Write>(context: &MediaContext, dst: &mut W) -> Result<()> {
+    try!(dst.write_u32::<BigEndian>(CACHE_MAGIC));
+    try!(dst.write_u32::<BigEndian>(CACHE_VERSION));
+    macro_rules! write_fields {
+        ($($field:ident),*) => { $(try!(context.$field.write_to(dst));)* }
+    }
+    context_results!(write_fields);
+    Ok(())
+}
+
+/// Check the invariants the parser guarantees and the C API relies on,
+/// so a corrupt or hand-made cache can't produce a context that parsing
+/// never would.
+fn validate(context: &MediaContext) -> Result<()> {
+    if context.timescale.map_or(false, |t| t.0 == 0) {
+        return Err(Error::InvalidData("zero movie timescale in parse cache"));
+    }
+    for track in &context.tracks {
+        if track.timescale.map_or(false, |t| t.0 == 0) {
+            return Err(Error::InvalidData("zero track timescale in parse cache"));
+        }
+        let audio = match track.data {
+            Some(SampleEntry::Audio(ref audio)) => audio,
+            _ => continue,
+        };
+        match audio.codec_specific {
+            AudioCodecSpecific::FLACSpecificBox(ref dfla) => match dfla.blocks.first() {
+                Some(block) if block.block_type == FLAC_METADATA_STREAMINFO => {
+                    try!(parse_flac_stream_info(&block.data));
+                }
+                _ => return Err(Error::InvalidData("dfLa without STREAMINFO in parse cache")),
+            },
+            AudioCodecSpecific::OpusSpecificBox(ref dops) => {
+                let valid = match dops.channel_mapping_table {
+                    None => dops.channel_mapping_family == 0 &&
+                        dops.output_channel_count > 0 && dops.output_channel_count <= 2,
+                    Some(ref table) => {
+                        let decoded_channels = table.stream_count as u32 + table.coupled_count as u32;
+                        dops.channel_mapping_family != 0 &&
+                            table.stream_count > 0 && table.coupled_count <= table.stream_count &&
+                            table.channel_mapping.len() == dops.output_channel_count as usize &&
+                            table.channel_mapping.iter().all(|&c| c == 255 || (c as u32) < decoded_channels)
+                    }
+                };
+                if !valid {
+                    return Err(Error::InvalidData("invalid dOps in parse cache"));
+                }
+            }
+            AudioCodecSpecific::ES_Descriptor(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Restore the parse results in a parse cache to `context`.
+///
+/// The result matches the context the cache was written from, apart
+/// from the diagnostics in `skipped_boxes` and `unsupported_features`.
+/// The options set on `context` are left as they are. A cache whose
+/// contents break the parser's invariants is rejected with
+/// `Error::InvalidData`, and `context` is only changed on success.
+pub fn read_parse_cache<T: Read>(f: &mut T, context: &mut MediaContext) -> Result<()> {
+    if try!(be_u32(f)) != CACHE_MAGIC {
+        return Err(Error::InvalidData("not a parse cache"));
+    }
+    if try!(be_u32(f)) != CACHE_VERSION {
+        return Err(Error::Unsupported("unknown parse cache version"));
+    }
+    let mut loaded = MediaContext::new();
+    macro_rules! read_fields {
+        ($($field:ident),*) => { $(loaded.$field = try!(Cached::read_from(f));)* }
+    }
+    context_results!(read_fields);
+    try!(validate(&loaded));
+    macro_rules! move_fields {
+        ($($field:ident),*) => { $(context.$field = loaded.$field;)* }
+    }
+    context_results!(move_fields);
+    Ok(())
+}
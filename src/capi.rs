@@ -2,6 +2,17 @@
 //!
 //! Parses ISO Base Media Format aka video/mp4 streams.
 //!
+//! # Threading
+//!
+//! A parser may be used from any thread, but calls which modify it,
+//! `mp4parse_read()` and `mp4parse_get_track_audio_info()`, must not
+//! overlap with any other call on the same parser. Overlapping calls
+//! to those functions are detected and fail with
+//! `MP4PARSE_ERROR_BADARG`. The remaining `mp4parse_get_*` functions
+//! only read the parsed data and may be called concurrently once
+//! `mp4parse_read()` has returned. Rust callers wanting to share
+//! parsed data between threads should use `MediaContext::freeze()`.
+//!
 //! # Examples
 //!
 //! ```rust
@@ -18,7 +29,7 @@
 //! }
 //!
 //! let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
-//! let io = mp4parse::mp4parse_io { read: buf_read,
+//! let io = mp4parse::mp4parse_io { read: Some(buf_read),
 //!                                  userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
 //! unsafe {
 //!     let parser = mp4parse::mp4parse_new(&io);
@@ -38,6 +49,7 @@ use std::collections::HashMap;
 
 // Symbols we need from our rust api.
 use MediaContext;
+use MediaFeatures;
 use TrackType;
 use read_mp4;
 use Error;
@@ -49,12 +61,19 @@ use MediaScaledTime;
 use TrackTimeScale;
 use TrackScaledTime;
 use serialize_opus_header;
-
-// rusty-cheddar's C enum generation doesn't namespace enum members by
-// prefixing them, so we're forced to do it in our member names until
-// https://github.com/Sean1708/rusty-cheddar/pull/35 is fixed.  Importing
-// the members into the module namespace avoids doubling up on the
-// namespacing on the Rust side.
+use ParseStrictness;
+use ParseMode;
+use FragmentRange;
+use CountingReader;
+use IoCounters;
+use capture::{Capture, CaptureReader};
+use allocator::Buffer;
+
+// The enum members carry their full C names, since cbindgen emits member
+// names as they are and the C API's names don't all follow from the enum
+// name (MP4PARSE_OK isn't MP4PARSE_ERROR_OK). Importing the members into
+// the module namespace avoids doubling up on the namespacing on the Rust
+// side.
 use mp4parse_error::*;
 use mp4parse_track_type::*;
 
@@ -67,6 +86,11 @@ pub enum mp4parse_error {
     MP4PARSE_ERROR_UNSUPPORTED = 3,
     MP4PARSE_ERROR_EOF = 4,
     MP4PARSE_ERROR_IO = 5,
+    MP4PARSE_ERROR_MOOV_TOO_LARGE = 6,
+    /// An earlier `mp4parse_read()` failed in a way which prevents
+    /// reading further. Getters still return what was parsed before
+    /// the failure.
+    MP4PARSE_ERROR_POISONED = 7,
 }
 
 #[repr(C)]
@@ -76,6 +100,14 @@ pub enum mp4parse_track_type {
     MP4PARSE_TRACK_TYPE_AUDIO = 1,
 }
 
+#[repr(C)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum mp4parse_parse_mode {
+    MP4PARSE_PARSE_MODE_TABLES = 0,
+    MP4PARSE_PARSE_MODE_METADATA = 1,
+    MP4PARSE_PARSE_MODE_FULL = 2,
+}
+
 #[repr(C)]
 #[derive(PartialEq, Debug)]
 pub enum mp4parse_codec {
@@ -84,6 +116,9 @@ pub enum mp4parse_codec {
     MP4PARSE_CODEC_OPUS,
     MP4PARSE_CODEC_AVC,
     MP4PARSE_CODEC_VP9,
+    MP4PARSE_CODEC_HEVC,
+    MP4PARSE_CODEC_AV1,
+    MP4PARSE_CODEC_FLAC,
 }
 
 #[repr(C)]
@@ -94,6 +129,13 @@ pub struct mp4parse_track_info {
     pub duration: u64,
     pub media_time: i64, // wants to be u64? understand how elst adjustment works
     // TODO(kinetik): include crypto guff
+    /// NUL-terminated ISO 639-2/T language code, or all zeros if unknown.
+    pub language: [u8; 4],
+    /// UTF-8 track name, or null if the track has none. It is not
+    /// NUL-terminated, and is owned by the parser and valid until it is
+    /// freed.
+    pub name: *const u8,
+    pub name_length: u32,
 }
 
 #[repr(C)]
@@ -117,9 +159,19 @@ pub struct mp4parse_track_audio_info {
     pub channels: u16,
     pub bit_depth: u16,
     pub sample_rate: u32,
-    // TODO(kinetik):
-    // int32_t profile;
-    // int32_t extended_profile; // check types
+    /// MPEG-4 audio object type of the core codec, 2 for AAC LC, or 0
+    /// if the track has no AudioSpecificConfig.
+    pub profile: u16,
+    /// The explicitly signalled SBR (5) or parametric stereo (29)
+    /// object type, or else the same as `profile`.
+    pub extended_profile: u16,
+    /// Bitrates from the 'esds' in bits per second, or 0 if unknown.
+    pub max_bitrate: u32,
+    pub avg_bitrate: u32,
+    /// The AudioSpecificConfig from the 'esds', or for Opus an
+    /// OpusHead packet rebuilt from the 'dOps' as Ogg and WebM carry
+    /// it. Owned by the parser and valid until it is freed.
+    pub extra_data: mp4parse_codec_specific_config,
     codec_specific_config: mp4parse_codec_specific_config,
 }
 
@@ -129,68 +181,467 @@ pub struct mp4parse_track_video_info {
     pub display_height: u32,
     pub image_width: u16,
     pub image_height: u16,
-    // TODO(kinetik):
-    // extra_data
-    // codec_specific_config
+    /// Profile and level from the track's 'avcC', 'hvcC', 'vpcC' or
+    /// 'av1C', using the codec's own numbering, or 0 if the record
+    /// can't be parsed.
+    pub profile: u8,
+    pub level: u8,
+    /// Luma bit depth, or 0 if unknown.
+    pub bit_depth: u8,
+    /// 0 for monochrome, 1 for 4:2:0, 2 for 4:2:2 or 3 for 4:4:4, as
+    /// in H.264 and H.265. Only meaningful if `bit_depth` is set.
+    pub chroma_format: u8,
+    /// Contents of the 'avcC', 'hvcC' or 'av1C' of the track, the
+    /// configuration a decoder needs, or empty for other codecs.
+    /// Owned by the parser and valid until it is freed.
+    pub extra_data: mp4parse_codec_specific_config,
+}
+
+/// Version of the C API's struct layouts and function signatures.
+///
+/// This is bumped whenever an existing struct or function changes
+/// incompatibly, so callers can compare it against the value their
+/// header was generated with before making any other call.
+pub const MP4PARSE_ABI_VERSION: u32 = 5;
+
+/// Options for `mp4parse_new_with_options()`.
+///
+/// Callers must set `size` to `sizeof(mp4parse_parser_options)`. New
+/// fields are only ever appended, and are only read if `size` shows
+/// the caller's header includes them, so older callers keep working.
+#[repr(C)]
+pub struct mp4parse_parser_options {
+    pub size: u32,
+    /// Skip malformed leaf boxes instead of failing the parse.
+    pub lenient: bool,
+    /// How much of the file to read. Defaults to
+    /// `MP4PARSE_PARSE_MODE_TABLES` for callers which predate it.
+    pub mode: mp4parse_parse_mode,
+    /// Largest moov to parse, in bytes, or 0 for no limit.
+    pub moov_size_limit: u64,
+    /// Leave hint tracks out of the track count and indices.
+    pub skip_hint_tracks: bool,
+    /// Hooks to allocate the parser's buffers with, or null for the
+    /// default allocator. The buffers are the read-ahead buffer and the
+    /// tables and headers returned by the getters; the parser's other
+    /// memory comes from the default allocator. Only available when the library is built with
+    /// the "alloc_hooks" feature; otherwise a non-null value makes
+    /// `mp4parse_new_with_options()` fail.
+    pub allocator: *const mp4parse_allocator,
+    /// Bytes to read ahead from the io callback, so scanning box
+    /// headers doesn't cost a callback per field. 0 selects the
+    /// default of 4096; 1 passes reads through unbuffered.
+    pub read_buffer_size: u32,
+    /// IDs of the only tracks whose sample tables and sample
+    /// descriptions should be parsed, or null to parse every track.
+    /// Other tracks are still counted, but only their header
+    /// information is available.
+    pub track_ids: *const u32,
+    pub track_id_count: u32,
+}
+
+/// malloc and free compatible functions for a parser to allocate with.
+///
+/// `malloc` must return memory aligned as the platform's malloc does.
+/// Blocks are freed with `free` by the time the parser is freed, so
+/// `userdata` must outlive every parser using it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct mp4parse_allocator {
+    pub malloc: Option<extern fn(size: usize, userdata: *mut std::os::raw::c_void) -> *mut std::os::raw::c_void>,
+    pub free: Option<extern fn(ptr: *mut std::os::raw::c_void, userdata: *mut std::os::raw::c_void)>,
+    pub userdata: *mut std::os::raw::c_void,
+}
+
+/// Offset of `field` in `mp4parse_parser_options` on this target.
+macro_rules! options_offset {
+    ($field:ident) => {{
+        let options: mp4parse_parser_options = std::mem::zeroed();
+        &options.$field as *const _ as usize - &options as *const _ as usize
+    }}
+}
+
+/// Size of `mp4parse_parser_options` up to the end of `field`, so a
+/// field is only read if the caller's `size` covers it.
+macro_rules! options_end {
+    ($field:ident) => {{
+        let options: mp4parse_parser_options = std::mem::zeroed();
+        options_offset!($field) + std::mem::size_of_val(&options.$field)
+    }}
+}
+
+/// Read `field` of the options at `options` as a `$ty`, without
+/// assuming the caller stored a valid Rust value of the field's type.
+macro_rules! options_raw {
+    ($options:expr, $field:ident, $ty:ty) => {
+        *((($options) as *const u8).offset(options_offset!($field) as isize) as *const $ty)
+    }
+}
+
+/// Size of the first version of `mp4parse_parser_options`, holding
+/// `size` and `lenient`.
+unsafe fn parser_options_v1_size() -> usize { options_end!(lenient) }
+/// Size of the second version, adding `mode`.
+unsafe fn parser_options_v2_size() -> usize { options_end!(mode) }
+/// Size of the third version, adding `moov_size_limit`.
+unsafe fn parser_options_v3_size() -> usize { options_end!(moov_size_limit) }
+/// Size of the fourth version, adding `skip_hint_tracks`.
+unsafe fn parser_options_v4_size() -> usize { options_end!(skip_hint_tracks) }
+/// Size of the fifth version, adding `allocator`.
+unsafe fn parser_options_v5_size() -> usize { options_end!(allocator) }
+/// Size of the sixth version, adding `read_buffer_size`.
+unsafe fn parser_options_v6_size() -> usize { options_end!(read_buffer_size) }
+/// Size of the seventh version, adding `track_ids` and `track_id_count`.
+unsafe fn parser_options_v7_size() -> usize { options_end!(track_id_count) }
+
+/// Read-ahead used unless the parser options set one.
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+
+#[repr(C)]
+pub struct mp4parse_subsample {
+    pub clear_bytes: u16,
+    pub encrypted_bytes: u32,
+}
+
+/// Encryption parameters of a protected track.
+///
+/// The `constant_iv` pointer is owned by the parser and valid until it
+/// is freed.
+#[repr(C)]
+pub struct mp4parse_track_encryption_info {
+    /// Four character code of the scheme, e.g. 'cenc' or 'cbcs'.
+    pub scheme_type: u32,
+    pub is_encrypted: bool,
+    pub iv_size: u8,
+    pub kid: [u8; 16],
+    /// Encryption pattern; both 0 if every block is encrypted.
+    pub crypt_byte_block: u8,
+    pub skip_byte_block: u8,
+    pub constant_iv_size: u32,
+    pub constant_iv: *const u8,
+}
+
+/// The construct which made `mp4parse_read()` return
+/// `MP4PARSE_ERROR_UNSUPPORTED`, or which made a track's sample entry
+/// unusable.
+///
+/// `box_type` is the four character code of the box it was found in,
+/// or 0 if there is nothing to report. The description is not
+/// NUL-terminated and is valid for the lifetime of the library.
+#[repr(C)]
+pub struct mp4parse_unsupported_feature {
+    pub box_type: u32,
+    pub description_length: u32,
+    pub description: *const u8,
+}
+
+/// A sample's location, timing and decryption parameters.
+///
+/// Times are in the track's timescale. The `iv` and `subsamples`
+/// pointers are owned by the parser and valid until it is freed.
+#[repr(C)]
+pub struct mp4parse_sample_info {
+    pub offset: u64,
+    pub size: u32,
+    pub decode_time: u64,
+    pub composition_time: i64,
+    pub duration: u32,
+    pub sync: bool,
+    /// True if the sample is encrypted, with the parameters below.
+    pub encrypted: bool,
+    pub iv_size: u32,
+    pub iv: *const u8,
+    pub subsample_count: u32,
+    pub subsamples: *const mp4parse_subsample,
+    /// Key ID of an encrypted sample, which may vary with key rotation.
+    pub kid: [u8; 16],
+}
+
+/// Time span and byte range of one fragment of a track.
+///
+/// Times are in microseconds; the range covers the moof and its mdat.
+#[repr(C)]
+pub struct mp4parse_fragment_range {
+    pub start_time: u64,
+    pub duration: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Byte range and timing of one sample of a track.
+///
+/// Times are in microseconds. The offsets and composition times give
+/// half-open ranges, ending where the sample's data and duration end.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct mp4parse_indice {
+    pub start_offset: u64,
+    pub end_offset: u64,
+    pub start_composition: i64,
+    pub end_composition: i64,
+    pub start_decode: i64,
+    pub sync: bool,
+}
+
+/// Input read by a parser so far.
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct mp4parse_io_counters {
+    /// Bytes the parser asked for from its input.
+    pub requested: u64,
+    /// Bytes the input returned.
+    pub consumed: u64,
+}
+
+/// Properties of the whole file.
+#[repr(C)]
+pub struct mp4parse_file_info {
+    /// Major brand from the 'ftyp', or 0 if there is none.
+    pub major_brand: u32,
+    /// Movie timescale from the 'mvhd'.
+    pub timescale: u32,
+    /// Movie duration in `timescale` units and in milliseconds, or 0
+    /// if unknown.
+    pub duration: u64,
+    pub duration_ms: u64,
+    /// True if the file has movie fragments.
+    pub fragmented: bool,
+    /// True if the file has protected tracks or 'pssh' boxes.
+    pub encrypted: bool,
+}
+
+#[derive(Default)]
+#[repr(C)]
+pub struct mp4parse_track_opus_trim_info {
+    pub pre_skip: u16,
+    pub padding: u64,
 }
 
-// Even though mp4parse_parser is opaque to C, rusty-cheddar won't let us
-// use more than one member, so we introduce *another* wrapper.
-struct Wrap {
+/// Parser state, opaque to C, which only handles it by pointer.
+#[allow(non_camel_case_types)]
+pub struct mp4parse_parser {
     context: MediaContext,
     io: mp4parse_io,
     poisoned: bool,
-    opus_header: HashMap<u32, Vec<u8>>,
+    opus_header: HashMap<u32, Buffer<u8>>,
+    /// Set while a call which modifies the parser is running.
+    busy: std::sync::atomic::AtomicBool,
+    /// Declared size of a moov rejected by the size limit.
+    moov_size: u64,
+    /// Fragment indexes built on first use, by track index. Cleared by
+    /// each read, since further fragments may extend them.
+    fragment_index: HashMap<u32, Buffer<FragmentRange>>,
+    /// Sample indices built on first use, by track ID. Cleared by each
+    /// read, like `fragment_index`.
+    indice_table: HashMap<u32, Buffer<mp4parse_indice>>,
+    /// Log of calls and reads, if capture is enabled.
+    capture: Option<std::sync::Mutex<Capture>>,
+    /// Hooks to allocate the buffers above with, set from the parser
+    /// options.
+    allocator: Option<mp4parse_allocator>,
+    /// In-memory input, read in place of `io` when set. The caller
+    /// keeps the buffer alive until the parser is freed.
+    buffer: Option<std::io::Cursor<&'static [u8]>>,
+    /// Totals over all reads of the input.
+    io_counters: IoCounters,
+    /// Data read ahead from `io`. Kept between reads, since it has
+    /// already been taken from the callback.
+    read_buffer: ReadBuffer,
 }
 
-#[repr(C)]
-#[allow(non_camel_case_types)]
-pub struct mp4parse_parser(Wrap);
+/// Input read ahead from the io callback but not yet parsed.
+struct ReadBuffer {
+    data: Buffer<u8>,
+    pos: usize,
+    end: usize,
+    /// Bytes to read ahead; reads at least this large bypass the buffer.
+    size: usize,
+    /// Hooks to allocate `data` with, as for the parser's other buffers.
+    allocator: Option<mp4parse_allocator>,
+}
+
+/// Reads `inner` through a `ReadBuffer`.
+struct BufferedReader<'a, T: 'a + Read> {
+    inner: &'a mut T,
+    buffer: &'a mut ReadBuffer,
+}
+
+impl<'a, T: Read> Read for BufferedReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let buffer = &mut *self.buffer;
+        if buffer.pos == buffer.end {
+            if buf.len() >= buffer.size {
+                return self.inner.read(buf);
+            }
+            // Allocated on first use, so in-memory parsers don't pay for it.
+            if buffer.data.len() != buffer.size {
+                buffer.data = Buffer::filled(0, buffer.size, buffer.allocator);
+            }
+            buffer.end = try!(self.inner.read(&mut buffer.data));
+            buffer.pos = 0;
+        }
+        let n = std::cmp::min(buf.len(), buffer.end - buffer.pos);
+        buf[..n].copy_from_slice(&buffer.data[buffer.pos..buffer.pos + n]);
+        buffer.pos += n;
+        Ok(n)
+    }
+}
 
 impl mp4parse_parser {
     fn context(&self) -> &MediaContext {
-        &self.0.context
+        &self.context
     }
 
     fn context_mut(&mut self) -> &mut MediaContext {
-        &mut self.0.context
-    }
-
-    fn io_mut(&mut self) -> &mut mp4parse_io {
-        &mut self.0.io
+        &mut self.context
     }
 
     fn poisoned(&self) -> bool {
-        self.0.poisoned
+        self.poisoned
     }
 
     fn set_poisoned(&mut self, poisoned: bool) {
-        self.0.poisoned = poisoned;
+        self.poisoned = poisoned;
+    }
+
+    fn opus_header_mut(&mut self) -> &mut HashMap<u32, Buffer<u8>> {
+        &mut self.opus_header
+    }
+
+    /// Return the fragment index of a track, building it if needed.
+    fn fragment_index(&mut self, track_index: u32) -> Option<&[FragmentRange]> {
+        if !self.fragment_index.contains_key(&track_index) {
+            let index = match self.context().tracks.get(track_index as usize).and_then(|t| t.track_id) {
+                Some(track_id) => self.context().fragment_index(track_id),
+                None => None,
+            };
+            match index {
+                Some(index) => {
+                    let index = Buffer::from_slice(&index, self.allocator);
+                    self.fragment_index.insert(track_index, index);
+                }
+                None => return None,
+            }
+        }
+        self.fragment_index.get(&track_index).map(|index| &index[..])
+    }
+
+    /// Return the sample index of a track, building it if needed.
+    fn indice_table(&mut self, track_id: u32) -> Option<&[mp4parse_indice]> {
+        if !self.indice_table.contains_key(&track_id) {
+            let table = match self.context().track_by_id(track_id) {
+                Some(track) => build_indice_table(self.context(), track),
+                None => None,
+            };
+            match table {
+                Some(table) => {
+                    let table = Buffer::from_slice(&table, self.allocator);
+                    self.indice_table.insert(track_id, table);
+                }
+                None => return None,
+            }
+        }
+        self.indice_table.get(&track_id).map(|table| &table[..])
+    }
+
+    /// Add to the capture log, if capturing.
+    fn capture<F: FnOnce(&mut Capture)>(&self, f: F) {
+        if let Some(ref capture) = self.capture {
+            if let Ok(mut capture) = capture.lock() {
+                f(&mut capture);
+            }
+        }
+    }
+
+    /// Mark the parser busy, returning false if it already was.
+    fn acquire(&self) -> bool {
+        !self.busy.swap(true, std::sync::atomic::Ordering::Acquire)
     }
 
-    fn opus_header_mut(&mut self) -> &mut HashMap<u32, Vec<u8>> {
-        &mut self.0.opus_header
+    fn release(&self) {
+        self.busy.store(false, std::sync::atomic::Ordering::Release);
     }
 }
 
+/// Callback to read into `buffer`.
+///
+/// Returns the number of bytes read, which must not exceed `size`, 0 at
+/// the end of the input, -1 on error, or `MP4PARSE_IO_WOULD_BLOCK` if
+/// no data is available yet. Short reads are fine: the parser calls
+/// again for the remainder. Would-block results are retried a limited
+/// number of times, yielding the thread in between, before the read
+/// fails, so nonblocking sources should buffer enough data before
+/// calling `mp4parse_read()`.
 #[repr(C)]
 #[derive(Clone)]
 pub struct mp4parse_io {
-    pub read: extern fn(buffer: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize,
+    pub read: Option<extern fn(buffer: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize>,
     pub userdata: *mut std::os::raw::c_void,
 }
 
+/// Read callback result meaning no data is available yet.
+pub const MP4PARSE_IO_WOULD_BLOCK: isize = -2;
+
+/// Consecutive would-block results tolerated before a read fails.
+const MAX_WOULD_BLOCK_RETRIES: u32 = 1000;
+
+impl mp4parse_io {
+    /// Check the members passed from C are usable.
+    fn is_valid(&self) -> bool {
+        self.read.is_some() && !self.userdata.is_null()
+    }
+}
+
 impl Read for mp4parse_io {
+    /// Fill `buf`, calling the callback as often as needed, unless the
+    /// input ends first.
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if buf.len() > isize::max_value() as usize {
             return Err(std::io::Error::new(std::io::ErrorKind::Other, "buf length overflow in mp4parse_io Read impl"));
         }
-        let rv = (self.read)(buf.as_mut_ptr(), buf.len(), self.userdata);
-        if rv >= 0 {
-            Ok(rv as usize)
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "I/O error in mp4parse_io Read impl"))
+        let read = match self.read {
+            Some(read) => read,
+            None => return Err(std::io::Error::new(std::io::ErrorKind::Other, "no read callback in mp4parse_io")),
+        };
+        let mut filled = 0;
+        let mut retries = 0;
+        while filled < buf.len() {
+            let rest = &mut buf[filled..];
+            let rv = read(rest.as_mut_ptr(), rest.len(), self.userdata);
+            if rv == 0 {
+                break;
+            }
+            if rv == MP4PARSE_IO_WOULD_BLOCK {
+                // Hand over what we have; the caller may not need more.
+                if filled > 0 {
+                    break;
+                }
+                retries += 1;
+                if retries > MAX_WOULD_BLOCK_RETRIES {
+                    return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "no data from mp4parse_io Read impl"));
+                }
+                std::thread::yield_now();
+                continue;
+            }
+            if rv < 0 {
+                // Return the data read so far; the error recurs on the
+                // next call if it persists.
+                if filled > 0 {
+                    break;
+                }
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "I/O error in mp4parse_io Read impl"));
+            }
+            if rv as usize > rest.len() {
+                // The callback claims to have written past the end of the
+                // buffer. Trap this in debug builds, since memory may already
+                // be corrupt, and otherwise treat it as a read failure.
+                debug_assert!(false, "mp4parse_io read returned {} bytes for a {} byte buffer", rv, rest.len());
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "overlong read in mp4parse_io Read impl"));
+            }
+            filled += rv as usize;
+            retries = 0;
         }
+        Ok(filled)
     }
 }
 
@@ -199,30 +650,149 @@ impl Read for mp4parse_io {
 /// Allocate an `mp4parse_parser*` to read from the supplied `mp4parse_io`.
 #[no_mangle]
 pub unsafe extern fn mp4parse_new(io: *const mp4parse_io) -> *mut mp4parse_parser {
-    if io.is_null() || (*io).userdata.is_null() {
+    if io.is_null() || !(*io).is_valid() {
         return std::ptr::null_mut();
     }
-    // is_null() isn't available on a fn type because it can't be null (in
-    // Rust) by definition.  But since this value is coming from the C API,
-    // it *could* be null.  Ideally, we'd wrap it in an Option to represent
-    // reality, but this causes rusty-cheddar to emit the wrong type
-    // (https://github.com/Sean1708/rusty-cheddar/issues/30).
-    if ((*io).read as *mut std::os::raw::c_void).is_null() {
+    allocate_parser((*io).clone(), None)
+}
+
+/// Allocate an `mp4parse_parser*` to read from `size` bytes at `buffer`.
+///
+/// The data is parsed in place rather than through a read callback,
+/// so the buffer must remain valid and unchanged until the parser is
+/// freed.
+#[no_mangle]
+pub unsafe extern fn mp4parse_new_from_buffer(buffer: *const u8, size: usize) -> *mut mp4parse_parser {
+    if buffer.is_null() || size > isize::max_value() as usize {
         return std::ptr::null_mut();
     }
-    let parser = Box::new(mp4parse_parser(Wrap {
+    let data = std::slice::from_raw_parts(buffer, size);
+    let io = mp4parse_io {
+        read: None,
+        userdata: std::ptr::null_mut(),
+    };
+    allocate_parser(io, Some(std::io::Cursor::new(data)))
+}
+
+fn allocate_parser(io: mp4parse_io, buffer: Option<std::io::Cursor<&'static [u8]>>) -> *mut mp4parse_parser {
+    let parser = Box::new(mp4parse_parser {
         context: MediaContext::new(),
-        io: (*io).clone(),
+        io: io,
         poisoned: false,
         opus_header: HashMap::new(),
-    }));
+        busy: std::sync::atomic::AtomicBool::new(false),
+        moov_size: 0,
+        fragment_index: HashMap::new(),
+        indice_table: HashMap::new(),
+        capture: Capture::from_env().map(std::sync::Mutex::new),
+        allocator: None,
+        buffer: buffer,
+        io_counters: IoCounters::default(),
+        read_buffer: ReadBuffer {
+            data: Buffer::default(),
+            pos: 0,
+            end: 0,
+            size: DEFAULT_READ_BUFFER_SIZE,
+            allocator: None,
+        },
+    });
+    parser.capture(|c| c.record("new"));
     Box::into_raw(parser)
 }
 
+/// Return the `MP4PARSE_ABI_VERSION` this library was built with.
+#[no_mangle]
+pub extern fn mp4parse_version() -> u32 {
+    MP4PARSE_ABI_VERSION
+}
+
+/// Allocate an `mp4parse_parser*` configured by `options`.
+///
+/// Returns null if the arguments are invalid, including an `options`
+/// whose `size` is smaller than the first version of the struct or
+/// whose `mode` is unknown.
+#[no_mangle]
+pub unsafe extern fn mp4parse_new_with_options(io: *const mp4parse_io, options: *const mp4parse_parser_options) -> *mut mp4parse_parser {
+    if options.is_null() || ((*options).size as usize) < parser_options_v1_size() {
+        return std::ptr::null_mut();
+    }
+    let allocator = match (*options).size as usize >= parser_options_v5_size() {
+        true if !(*options).allocator.is_null() => {
+            let allocator = *(*options).allocator;
+            if !cfg!(feature = "alloc_hooks") || allocator.malloc.is_none() || allocator.free.is_none() {
+                return std::ptr::null_mut();
+            }
+            Some(allocator)
+        }
+        _ => None,
+    };
+    let parser = mp4parse_new(io);
+    if parser.is_null() {
+        return parser;
+    }
+    (*parser).allocator = allocator;
+    (*parser).read_buffer.allocator = allocator;
+    if options_raw!(options, lenient, u8) != 0 {
+        (*parser).context_mut().strictness = ParseStrictness::Lenient;
+    }
+    if (*options).size as usize >= parser_options_v2_size() {
+        // C callers may pass values outside the enum.
+        let mode = options_raw!(options, mode, u32);
+        (*parser).context_mut().mode = if mode == mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES as u32 {
+            ParseMode::Tables
+        } else if mode == mp4parse_parse_mode::MP4PARSE_PARSE_MODE_METADATA as u32 {
+            ParseMode::Metadata
+        } else if mode == mp4parse_parse_mode::MP4PARSE_PARSE_MODE_FULL as u32 {
+            ParseMode::Full
+        } else {
+            mp4parse_free(parser);
+            return std::ptr::null_mut();
+        };
+    }
+    if (*options).size as usize >= parser_options_v3_size() && (*options).moov_size_limit > 0 {
+        (*parser).context_mut().moov_size_limit = Some((*options).moov_size_limit);
+    }
+    if (*options).size as usize >= parser_options_v4_size() {
+        (*parser).context_mut().skip_hint_tracks = options_raw!(options, skip_hint_tracks, u8) != 0;
+    }
+    if (*options).size as usize >= parser_options_v6_size() && (*options).read_buffer_size > 0 {
+        (*parser).read_buffer.size = (*options).read_buffer_size as usize;
+    }
+    if (*options).size as usize >= parser_options_v7_size() && !(*options).track_ids.is_null() {
+        let ids = std::slice::from_raw_parts((*options).track_ids, (*options).track_id_count as usize);
+        (*parser).context_mut().track_filter = Some(ids.to_vec());
+    }
+    // Record the options as applied, since fields beyond `size` are
+    // not to be read.
+    (*parser).capture(|c| {
+        let context = &(*parser).context;
+        let mode = match context.mode {
+            ParseMode::Tables => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+            ParseMode::Metadata => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_METADATA,
+            ParseMode::Full => mp4parse_parse_mode::MP4PARSE_PARSE_MODE_FULL,
+        };
+        let track_ids = match context.track_filter {
+            Some(ref ids) => ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(","),
+            None => String::from("-"),
+        };
+        c.record(&format!("options {} {} {} {} {} {} {} {}",
+                          (*options).size,
+                          (options_raw!(options, lenient, u8) != 0) as u8,
+                          mode as u32,
+                          context.moov_size_limit.unwrap_or(0),
+                          context.skip_hint_tracks as u8,
+                          (*parser).read_buffer.size,
+                          track_ids,
+                          (*parser).allocator.is_some() as u8));
+    });
+    parser
+}
+
 /// Free an `mp4parse_parser*` allocated by `mp4parse_new()`.
 #[no_mangle]
 pub unsafe extern fn mp4parse_free(parser: *mut mp4parse_parser) {
     assert!(!parser.is_null());
+    (*parser).capture(|c| c.call("free", &[]));
     let _ = Box::from_raw(parser);
 }
 
@@ -230,21 +800,73 @@ pub unsafe extern fn mp4parse_free(parser: *mut mp4parse_parser) {
 #[no_mangle]
 pub unsafe extern fn mp4parse_read(parser: *mut mp4parse_parser) -> mp4parse_error {
     // Validate arguments from C.
-    if parser.is_null() || (*parser).poisoned() {
+    if parser.is_null() {
         return MP4PARSE_ERROR_BADARG;
     }
+    if (*parser).poisoned() {
+        return MP4PARSE_ERROR_POISONED;
+    }
+    (*parser).capture(|c| c.call("read", &[]));
+
+    if !(*parser).acquire() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    let rv = read_locked(parser);
+    (*parser).release();
+    rv
+}
+
+/// Parse from `src`, logging reads if capturing.
+fn read_buffered<T: Read>(io: &mut T, buffer: &mut ReadBuffer, counters: &mut IoCounters, context: &mut MediaContext) -> ::Result<()> {
+    let mut src = CountingReader::new(BufferedReader { inner: io, buffer: buffer });
+    let r = read_mp4(&mut src, context);
+    *counters = src.counters();
+    r
+}
 
-    let mut context = (*parser).context_mut();
-    let mut io = (*parser).io_mut();
+unsafe fn read_locked(parser: *mut mp4parse_parser) -> mp4parse_error {
+    // Further fragments may extend the indexes.
+    (*parser).fragment_index.clear();
+    (*parser).indice_table.clear();
+    read_context(parser)
+}
 
-    let r = read_mp4(io, context);
+unsafe fn read_context(parser: *mut mp4parse_parser) -> mp4parse_error {
+    let state = &mut *parser;
+    let (r, counters) = match state.buffer {
+        Some(ref mut buffer) => {
+            let mut src = CountingReader::new(buffer);
+            (read_mp4(&mut src, &mut state.context), src.counters())
+        }
+        None => {
+            // Capture records the callback's reads, beneath the buffer.
+            let mut counters = IoCounters::default();
+            let r = match state.capture {
+                Some(ref capture) => {
+                    let mut io = CaptureReader { inner: &mut state.io, capture: capture };
+                    read_buffered(&mut io, &mut state.read_buffer, &mut counters, &mut state.context)
+                }
+                None => read_buffered(&mut state.io, &mut state.read_buffer, &mut counters, &mut state.context),
+            };
+            (r, counters)
+        }
+    };
+    state.io_counters.add(counters);
     match r {
         Ok(_) => MP4PARSE_OK,
-        Err(Error::NoMoov) | Err(Error::InvalidData(_)) => {
+        Err(Error::NoMoov) | Err(Error::InvalidData(_)) |
+        Err(Error::NoPrimaryItem) | Err(Error::IncompatibleBrand) => {
             // Block further calls. We've probable lost sync.
             (*parser).set_poisoned(true);
             MP4PARSE_ERROR_INVALID
         }
+        Err(Error::MoovTooLarge(size)) => {
+            // The moov header has been consumed, so the caller must
+            // retry with a new parser and a larger limit.
+            (*parser).moov_size = size;
+            (*parser).set_poisoned(true);
+            MP4PARSE_ERROR_MOOV_TOO_LARGE
+        }
         Err(Error::Unsupported(_)) => MP4PARSE_ERROR_UNSUPPORTED,
         Err(Error::UnexpectedEOF) => MP4PARSE_ERROR_EOF,
         Err(Error::Io(_)) => {
@@ -262,9 +884,10 @@ pub unsafe extern fn mp4parse_read(parser: *mut mp4parse_parser) -> mp4parse_err
 #[no_mangle]
 pub unsafe extern fn mp4parse_get_track_count(parser: *const mp4parse_parser, count: *mut u32) -> mp4parse_error {
     // Validate arguments from C.
-    if parser.is_null() || count.is_null() || (*parser).poisoned() {
+    if parser.is_null() || count.is_null() {
         return MP4PARSE_ERROR_BADARG;
     }
+    (*parser).capture(|c| c.call("get_track_count", &[]));
     let context = (*parser).context();
 
     // Make sure the track count fits in a u32.
@@ -275,23 +898,68 @@ pub unsafe extern fn mp4parse_get_track_count(parser: *const mp4parse_parser, co
     MP4PARSE_OK
 }
 
-fn media_time_to_ms(time: MediaScaledTime, scale: MediaTimeScale) -> u64 {
-    assert!(scale.0 != 0);
-    time.0 * 1000000 / scale.0
+/// Convert to microseconds, or None if the result overflows, which
+/// large durations in untrusted input can make it.
+fn media_time_to_ms(time: MediaScaledTime, scale: MediaTimeScale) -> Option<u64> {
+    if scale.0 == 0 {
+        return None;
+    }
+    time.0.checked_mul(1000000).map(|t| t / scale.0)
 }
 
-fn track_time_to_ms(time: TrackScaledTime, scale: TrackTimeScale) -> u64 {
+fn track_time_to_ms(time: TrackScaledTime, scale: TrackTimeScale) -> Option<u64> {
     assert!(time.1 == scale.1);
-    assert!(scale.0 != 0);
-    time.0 * 1000000 / scale.0
+    if scale.0 == 0 {
+        return None;
+    }
+    time.0.checked_mul(1000000).map(|t| t / scale.0)
+}
+
+/// Fill the supplied `mp4parse_file_info` with properties of the file
+/// parsed by `mp4parse_read()`.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_file_info(parser: *const mp4parse_parser, info: *mut mp4parse_file_info) -> mp4parse_error {
+    if parser.is_null() || info.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_file_info", &[]));
+
+    let context = (*parser).context();
+    let timescale = match context.timescale {
+        Some(timescale) if timescale.0 > 0 && timescale.0 <= u32::max_value() as u64 => timescale,
+        _ => return MP4PARSE_ERROR_INVALID,
+    };
+    // Split the conversion so the remainder can't overflow, and reject
+    // durations whose whole seconds don't fit in milliseconds.
+    let duration_ms = match context.duration {
+        Some(duration) => {
+            let ms = (duration.0 / timescale.0).checked_mul(1000)
+                .and_then(|ms| ms.checked_add(duration.0 % timescale.0 * 1000 / timescale.0));
+            match ms {
+                Some(ms) => ms,
+                None => return MP4PARSE_ERROR_INVALID,
+            }
+        }
+        None => 0,
+    };
+    let info: &mut mp4parse_file_info = &mut *info;
+    info.major_brand = context.major_brand().unwrap_or(0);
+    info.timescale = timescale.0 as u32;
+    info.duration = context.duration.map_or(0, |duration| duration.0);
+    info.duration_ms = duration_ms;
+    info.fragmented = context.features().contains(MediaFeatures::FRAGMENTED);
+    info.encrypted = context.features().contains(MediaFeatures::ENCRYPTED);
+
+    MP4PARSE_OK
 }
 
 /// Fill the supplied `mp4parse_track_info` with metadata for `track`.
 #[no_mangle]
 pub unsafe extern fn mp4parse_get_track_info(parser: *mut mp4parse_parser, track_index: u32, info: *mut mp4parse_track_info) -> mp4parse_error {
-    if parser.is_null() || info.is_null() || (*parser).poisoned() {
+    if parser.is_null() || info.is_null() {
         return MP4PARSE_ERROR_BADARG;
     }
+    (*parser).capture(|c| c.call("get_track_info", &[track_index as u64]));
 
     let context = (*parser).context_mut();
     let track_index: usize = track_index as usize;
@@ -304,7 +972,7 @@ pub unsafe extern fn mp4parse_get_track_info(parser: *mut mp4parse_parser, track
     info.track_type = match context.tracks[track_index].track_type {
         TrackType::Video => MP4PARSE_TRACK_TYPE_VIDEO,
         TrackType::Audio => MP4PARSE_TRACK_TYPE_AUDIO,
-        TrackType::Unknown => return MP4PARSE_ERROR_UNSUPPORTED,
+        TrackType::Hint | TrackType::Unknown => return MP4PARSE_ERROR_UNSUPPORTED,
     };
 
     info.codec = match context.tracks[track_index].data {
@@ -313,12 +981,18 @@ pub unsafe extern fn mp4parse_get_track_info(parser: *mut mp4parse_parser, track
                 mp4parse_codec::MP4PARSE_CODEC_OPUS,
             AudioCodecSpecific::ES_Descriptor(_) =>
                 mp4parse_codec::MP4PARSE_CODEC_AAC,
+            AudioCodecSpecific::FLACSpecificBox(_) =>
+                mp4parse_codec::MP4PARSE_CODEC_FLAC,
         },
         Some(SampleEntry::Video(ref video)) => match video.codec_specific {
             VideoCodecSpecific::VPxConfig(_) =>
                 mp4parse_codec::MP4PARSE_CODEC_VP9,
             VideoCodecSpecific::AVCConfig(_) =>
                 mp4parse_codec::MP4PARSE_CODEC_AVC,
+            VideoCodecSpecific::HEVCConfig(_) =>
+                mp4parse_codec::MP4PARSE_CODEC_HEVC,
+            VideoCodecSpecific::AV1Config(_) =>
+                mp4parse_codec::MP4PARSE_CODEC_AV1,
         },
         _ => mp4parse_codec::MP4PARSE_CODEC_UNKNOWN,
     };
@@ -326,17 +1000,30 @@ pub unsafe extern fn mp4parse_get_track_info(parser: *mut mp4parse_parser, track
     let track = &context.tracks[track_index];
 
     if let (Some(track_timescale),
-            Some(context_timescale),
-            Some(track_duration)) = (track.timescale,
-                                     context.timescale,
-                                     track.duration) {
-        info.media_time = track.media_time.map_or(0, |media_time| {
-            track_time_to_ms(media_time, track_timescale) as i64
-        }) - track.empty_duration.map_or(0, |empty_duration| {
-            media_time_to_ms(empty_duration, context_timescale) as i64
-        });
-
-        info.duration = track_time_to_ms(track_duration, track_timescale);
+            Some(context_timescale)) = (track.timescale,
+                                        context.timescale) {
+        let media_time = match track.media_time.map_or(Some(0), |media_time| {
+            track_time_to_ms(media_time, track_timescale)
+        }) {
+            Some(time) if time <= i64::max_value() as u64 => time as i64,
+            _ => return MP4PARSE_ERROR_INVALID,
+        };
+        let empty_duration = match track.empty_duration.map_or(Some(0), |empty_duration| {
+            media_time_to_ms(empty_duration, context_timescale)
+        }) {
+            Some(time) if time <= i64::max_value() as u64 => time as i64,
+            _ => return MP4PARSE_ERROR_INVALID,
+        };
+        info.media_time = media_time - empty_duration;
+
+        // Tracks without samples, such as those in init segments,
+        // may leave the duration unknown.
+        info.duration = match track.duration.map_or(Some(0), |track_duration| {
+            track_time_to_ms(track_duration, track_timescale)
+        }) {
+            Some(duration) => duration,
+            None => return MP4PARSE_ERROR_INVALID,
+        };
     } else {
         return MP4PARSE_ERROR_INVALID
     }
@@ -346,16 +1033,44 @@ pub unsafe extern fn mp4parse_get_track_info(parser: *mut mp4parse_parser, track
         None => return MP4PARSE_ERROR_INVALID,
     };
 
+    info.language = [0; 4];
+    if let Some(ref language) = track.language {
+        if language.len() == 3 {
+            info.language[..3].copy_from_slice(language.as_bytes());
+        }
+    }
+    match track.name {
+        Some(ref name) if name.len() <= u32::max_value() as usize => {
+            info.name = name.as_ptr();
+            info.name_length = name.len() as u32;
+        }
+        _ => {
+            info.name = std::ptr::null();
+            info.name_length = 0;
+        }
+    }
+
     MP4PARSE_OK
 }
 
 /// Fill the supplied `mp4parse_track_audio_info` with metadata for `track`.
 #[no_mangle]
 pub unsafe extern fn mp4parse_get_track_audio_info(parser: *mut mp4parse_parser, track_index: u32, info: *mut mp4parse_track_audio_info) -> mp4parse_error {
-    if parser.is_null() || info.is_null() || (*parser).poisoned() {
+    if parser.is_null() || info.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_track_audio_info", &[track_index as u64]));
+
+    // This caches the Opus header in the parser.
+    if !(*parser).acquire() {
         return MP4PARSE_ERROR_BADARG;
     }
+    let rv = get_track_audio_info_locked(parser, track_index, info);
+    (*parser).release();
+    rv
+}
 
+unsafe fn get_track_audio_info_locked(parser: *mut mp4parse_parser, track_index: u32, info: *mut mp4parse_track_audio_info) -> mp4parse_error {
     let context = (*parser).context_mut();
 
     if track_index as usize >= context.tracks.len() {
@@ -381,8 +1096,13 @@ pub unsafe extern fn mp4parse_get_track_audio_info(parser: *mut mp4parse_parser,
 
     (*info).channels = audio.channelcount;
     (*info).bit_depth = audio.samplesize;
-    (*info).sample_rate = audio.samplerate >> 16; // 16.16 fixed point
+    (*info).sample_rate = audio.samplerate.integer_part() as u32;
 
+    (*info).profile = 0;
+    (*info).extended_profile = 0;
+    (*info).max_bitrate = 0;
+    (*info).avg_bitrate = 0;
+    (*info).extra_data = Default::default();
     match audio.codec_specific {
         AudioCodecSpecific::ES_Descriptor(ref v) => {
             if v.len() > std::u32::MAX as usize {
@@ -390,6 +1110,18 @@ pub unsafe extern fn mp4parse_get_track_audio_info(parser: *mut mp4parse_parser,
             }
             (*info).codec_specific_config.length = v.len() as u32;
             (*info).codec_specific_config.data = v.as_ptr();
+            // Leave the parsed fields unset if the descriptor is malformed.
+            if let Ok(Some(descriptor)) = audio.es_descriptor() {
+                (*info).max_bitrate = descriptor.max_bitrate;
+                (*info).avg_bitrate = descriptor.avg_bitrate;
+                (*info).extra_data.length = descriptor.decoder_specific_info.len() as u32;
+                (*info).extra_data.data = descriptor.decoder_specific_info.as_ptr();
+            }
+            if let Ok(Some(config)) = audio.audio_specific_config() {
+                (*info).profile = config.audio_object_type as u16;
+                (*info).extended_profile = config.extension_object_type
+                    .unwrap_or(config.audio_object_type) as u16;
+            }
         }
         AudioCodecSpecific::OpusSpecificBox(ref opus) => {
             let mut v = Vec::new();
@@ -398,18 +1130,29 @@ pub unsafe extern fn mp4parse_get_track_audio_info(parser: *mut mp4parse_parser,
                     return MP4PARSE_ERROR_INVALID;
                 }
                 Ok(_) => {
+                    let allocator = (*parser).allocator;
                     let header = (*parser).opus_header_mut();
-                    header.insert(track_index, v);
+                    header.insert(track_index, Buffer::from_slice(&v, allocator));
                     match header.get(&track_index) {
                         None => {}
                         Some(v) => {
                             (*info).codec_specific_config.length = v.len() as u32;
                             (*info).codec_specific_config.data = v.as_ptr();
+                            (*info).extra_data.length = v.len() as u32;
+                            (*info).extra_data.data = v.as_ptr();
                         }
                     }
                 }
             }
         }
+        AudioCodecSpecific::FLACSpecificBox(ref flac) => {
+            // The STREAMINFO block, as decoders take it.
+            let streaminfo = &flac.blocks[0].data;
+            (*info).codec_specific_config.length = streaminfo.len() as u32;
+            (*info).codec_specific_config.data = streaminfo.as_ptr();
+            (*info).extra_data.length = streaminfo.len() as u32;
+            (*info).extra_data.data = streaminfo.as_ptr();
+        }
     }
 
     MP4PARSE_OK
@@ -418,9 +1161,10 @@ pub unsafe extern fn mp4parse_get_track_audio_info(parser: *mut mp4parse_parser,
 /// Fill the supplied `mp4parse_track_video_info` with metadata for `track`.
 #[no_mangle]
 pub unsafe extern fn mp4parse_get_track_video_info(parser: *mut mp4parse_parser, track_index: u32, info: *mut mp4parse_track_video_info) -> mp4parse_error {
-    if parser.is_null() || info.is_null() || (*parser).poisoned() {
+    if parser.is_null() || info.is_null() {
         return MP4PARSE_ERROR_BADARG;
     }
+    (*parser).capture(|c| c.call("get_track_video_info", &[track_index as u64]));
 
     let context = (*parser).context_mut();
 
@@ -446,73 +1190,838 @@ pub unsafe extern fn mp4parse_get_track_video_info(parser: *mut mp4parse_parser,
     };
 
     if let Some(ref tkhd) = track.tkhd {
-        (*info).display_width = tkhd.width >> 16; // 16.16 fixed point
-        (*info).display_height = tkhd.height >> 16; // 16.16 fixed point
+        (*info).display_width = tkhd.width.integer_part() as u32;
+        (*info).display_height = tkhd.height.integer_part() as u32;
     } else {
         return MP4PARSE_ERROR_INVALID;
     }
     (*info).image_width = video.width;
     (*info).image_height = video.height;
+    (*info).profile = 0;
+    (*info).level = 0;
+    (*info).bit_depth = 0;
+    (*info).chroma_format = 0;
+    (*info).extra_data = Default::default();
+    let extra_data = match video.codec_specific {
+        VideoCodecSpecific::AVCConfig(ref avcc) => {
+            if let Ok(config) = ::parse_avcc(avcc) {
+                (*info).profile = config.profile_indication;
+                (*info).level = config.level_indication;
+            }
+            if let Ok(Some(sps)) = ::avcc_sequence_parameter_set(avcc) {
+                (*info).bit_depth = sps.bit_depth_luma;
+                (*info).chroma_format = sps.chroma_format_idc;
+            }
+            &avcc[..]
+        }
+        VideoCodecSpecific::HEVCConfig(ref hvcc) => {
+            if let Ok(config) = ::parse_hvcc(hvcc) {
+                (*info).profile = config.general_profile_idc;
+                (*info).level = config.general_level_idc;
+                (*info).bit_depth = config.bit_depth_luma;
+                (*info).chroma_format = config.chroma_format_idc;
+            }
+            &hvcc[..]
+        }
+        VideoCodecSpecific::VPxConfig(ref vpcc) => {
+            (*info).profile = vpcc.profile;
+            (*info).level = vpcc.level;
+            (*info).bit_depth = vpcc.bit_depth;
+            (*info).chroma_format = match vpcc.chroma_subsampling {
+                0 | 1 => 1,
+                2 => 2,
+                _ => 3,
+            };
+            &[][..]
+        }
+        VideoCodecSpecific::AV1Config(ref av1c) => {
+            if let Ok(config) = ::parse_av1c(av1c) {
+                (*info).profile = config.seq_profile;
+                (*info).level = config.seq_level_idx_0;
+                (*info).bit_depth = config.bit_depth;
+                (*info).chroma_format = match (config.monochrome, config.chroma_subsampling_x, config.chroma_subsampling_y) {
+                    (true, _, _) => 0,
+                    (false, true, true) => 1,
+                    (false, true, false) => 2,
+                    (false, false, _) => 3,
+                };
+            }
+            &av1c[..]
+        }
+    };
+    (*info).extra_data.length = extra_data.len() as u32;
+    (*info).extra_data.data = if extra_data.is_empty() { std::ptr::null() } else { extra_data.as_ptr() };
 
     MP4PARSE_OK
 }
 
-#[cfg(test)]
-extern fn panic_read(_: *mut u8, _: usize, _: *mut std::os::raw::c_void) -> isize {
-    panic!("panic_read shouldn't be called in these tests");
-}
-
-#[cfg(test)]
-extern fn error_read(_: *mut u8, _: usize, _: *mut std::os::raw::c_void) -> isize {
-    -1
-}
+/// Fill the supplied `mp4parse_track_opus_trim_info` for an Opus `track`.
+///
+/// Both values count samples at 48 kHz. `pre_skip` comes from the
+/// dOps box and `padding` is the media following the track's edit,
+/// or zero if there is no edit list to derive it from.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_track_opus_trim_info(parser: *mut mp4parse_parser, track_index: u32, info: *mut mp4parse_track_opus_trim_info) -> mp4parse_error {
+    if parser.is_null() || info.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_track_opus_trim_info", &[track_index as u64]));
 
-#[cfg(test)]
-extern fn valid_read(buf: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize {
-    let mut input: &mut std::fs::File = unsafe { &mut *(userdata as *mut _) };
+    let context = (*parser).context_mut();
 
-    let mut buf = unsafe { std::slice::from_raw_parts_mut(buf, size) };
-    match input.read(&mut buf) {
-        Ok(n) => n as isize,
-        Err(_) => -1,
+    if track_index as usize >= context.tracks.len() {
+        return MP4PARSE_ERROR_BADARG;
     }
-}
 
-#[test]
-fn new_parser() {
-    let mut dummy_value: u32 = 42;
-    let io = mp4parse_io {
-        read: panic_read,
-        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    let track = &context.tracks[track_index as usize];
+
+    let opus = match track.data {
+        Some(SampleEntry::Audio(ref audio)) => match audio.codec_specific {
+            AudioCodecSpecific::OpusSpecificBox(ref opus) => opus,
+            _ => return MP4PARSE_ERROR_INVALID,
+        },
+        _ => return MP4PARSE_ERROR_INVALID,
     };
-    unsafe {
-        let parser = mp4parse_new(&io);
-        assert!(!parser.is_null());
-        mp4parse_free(parser);
-    }
+
+    let padding = match (context.timescale, track.timescale) {
+        (Some(context_timescale), Some(track_timescale)) => {
+            match track.end_padding(context_timescale) {
+                Some(padding) => match padding.0.checked_mul(48000) {
+                    Some(scaled) => scaled / track_timescale.0,
+                    None => return MP4PARSE_ERROR_INVALID,
+                },
+                None => 0,
+            }
+        }
+        _ => return MP4PARSE_ERROR_INVALID,
+    };
+
+    (*info).pre_skip = opus.pre_skip;
+    (*info).padding = padding;
+
+    MP4PARSE_OK
 }
 
-#[test]
-#[should_panic(expected = "assertion failed")]
-fn free_null_parser() {
-    unsafe {
-        mp4parse_free(std::ptr::null_mut());
+/// Return the number of heap bytes held by the parser's parsed data.
+///
+/// Caches the getters fill on first use, such as Opus headers and
+/// indice tables, aren't counted, so the figure depends only on the
+/// input and not on which getters have been called.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_memory_usage(parser: *const mp4parse_parser, usage: *mut u64) -> mp4parse_error {
+    if parser.is_null() || usage.is_null() {
+        return MP4PARSE_ERROR_BADARG;
     }
+    (*parser).capture(|c| c.call("get_memory_usage", &[]));
+
+    *usage = (*parser).context().memory_usage() as u64;
+
+    MP4PARSE_OK
 }
 
-#[test]
-fn get_track_count_null_parser() {
-    unsafe {
-        let mut count: u32 = 0;
-        let rv = mp4parse_get_track_count(std::ptr::null(), std::ptr::null_mut());
-        assert!(rv == MP4PARSE_ERROR_BADARG);
-        let rv = mp4parse_get_track_count(std::ptr::null(), &mut count);
-        assert!(rv == MP4PARSE_ERROR_BADARG);
+/// Fill the supplied `mp4parse_io_counters` with the totals over all
+/// `mp4parse_read()` calls on the parser.
+///
+/// Comparing `consumed` with the file size shows how much of the
+/// input parsing needed, for example whether media data was read.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_io_counters(parser: *const mp4parse_parser, counters: *mut mp4parse_io_counters) -> mp4parse_error {
+    if parser.is_null() || counters.is_null() {
+        return MP4PARSE_ERROR_BADARG;
     }
+    (*parser).capture(|c| c.call("get_io_counters", &[]));
+
+    let totals = (*parser).io_counters;
+    (*counters).requested = totals.requested;
+    (*counters).consumed = totals.consumed;
+
+    MP4PARSE_OK
 }
 
-#[test]
-fn arg_validation() {
+/// Fill the supplied `mp4parse_track_encryption_info` for `track`.
+///
+/// Returns `MP4PARSE_ERROR_INVALID` if the track isn't protected or
+/// lacks a 'tenc'.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_track_encryption_info(parser: *const mp4parse_parser, track_index: u32, info: *mut mp4parse_track_encryption_info) -> mp4parse_error {
+    if parser.is_null() || info.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_track_encryption_info", &[track_index as u64]));
+
+    let sinf = match (*parser).context().tracks.get(track_index as usize) {
+        Some(track) => match track.protection_info {
+            Some(ref sinf) => sinf,
+            None => return MP4PARSE_ERROR_INVALID,
+        },
+        None => return MP4PARSE_ERROR_BADARG,
+    };
+    let tenc = match sinf.tenc {
+        Some(ref tenc) if tenc.kid.len() == 16 => tenc,
+        _ => return MP4PARSE_ERROR_INVALID,
+    };
+
+    (*info).scheme_type = sinf.scheme_type.unwrap_or(0);
+    (*info).is_encrypted = tenc.is_encrypted;
+    (*info).iv_size = tenc.iv_size;
+    (*info).kid.copy_from_slice(&tenc.kid);
+    (*info).crypt_byte_block = tenc.crypt_byte_block;
+    (*info).skip_byte_block = tenc.skip_byte_block;
+    match tenc.constant_iv {
+        Some(ref iv) => {
+            (*info).constant_iv_size = iv.len() as u32;
+            (*info).constant_iv = iv.as_ptr();
+        }
+        None => {
+            (*info).constant_iv_size = 0;
+            (*info).constant_iv = std::ptr::null();
+        }
+    }
+
+    MP4PARSE_OK
+}
+
+/// Return the protection scheme, per-sample IV size and default key ID
+/// of `track`, for choosing a key system before reading samples.
+///
+/// `kid` receives 16 bytes. This is a subset of
+/// `mp4parse_get_track_encryption_info()`, with the same errors.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_track_crypto_info(parser: *const mp4parse_parser, track_index: u32, scheme: *mut u32, iv_size: *mut u8, kid: *mut u8) -> mp4parse_error {
+    if parser.is_null() || scheme.is_null() || iv_size.is_null() || kid.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    let mut info: mp4parse_track_encryption_info = std::mem::zeroed();
+    let rv = mp4parse_get_track_encryption_info(parser, track_index, &mut info);
+    if rv != MP4PARSE_OK {
+        return rv;
+    }
+    *scheme = info.scheme_type;
+    *iv_size = info.iv_size;
+    std::slice::from_raw_parts_mut(kid, 16).copy_from_slice(&info.kid);
+    MP4PARSE_OK
+}
+
+/// Return the number of 'pssh' boxes found by `mp4parse_read()`.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_pssh_count(parser: *const mp4parse_parser, count: *mut u32) -> mp4parse_error {
+    if parser.is_null() || count.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_pssh_count", &[]));
+
+    let psshs = &(*parser).context().psshs;
+    if psshs.len() > u32::max_value() as usize {
+        return MP4PARSE_ERROR_INVALID;
+    }
+    *count = psshs.len() as u32;
+    MP4PARSE_OK
+}
+
+/// Return the protection system and initialization data of the 'pssh'
+/// box at `index`.
+///
+/// The 16-byte system ID is copied to `system_id`, so callers can pick
+/// a system they support. `data` is set to the whole box, header
+/// included, which is the form decryption modules expect; it is owned
+/// by the parser and valid until it is freed.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_pssh(parser: *const mp4parse_parser, index: u32, system_id: *mut u8, data: *mut *const u8, length: *mut u32) -> mp4parse_error {
+    if parser.is_null() || system_id.is_null() || data.is_null() || length.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_pssh", &[index as u64]));
+
+    let pssh = match (*parser).context().psshs.get(index as usize) {
+        Some(pssh) => pssh,
+        None => return MP4PARSE_ERROR_BADARG,
+    };
+    if pssh.system_id.len() != 16 || pssh.box_content.len() > u32::max_value() as usize {
+        return MP4PARSE_ERROR_INVALID;
+    }
+    std::slice::from_raw_parts_mut(system_id, 16).copy_from_slice(&pssh.system_id);
+    *data = pssh.box_content.as_ptr();
+    *length = pssh.box_content.len() as u32;
+    MP4PARSE_OK
+}
+
+/// Return the moov size needed to parse the file after `mp4parse_read()`
+/// failed with `MP4PARSE_ERROR_MOOV_TOO_LARGE`, or 0 otherwise.
+///
+/// This is available even though the failure poisons the parser.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_required_moov_size(parser: *const mp4parse_parser, size: *mut u64) -> mp4parse_error {
+    if parser.is_null() || size.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_required_moov_size", &[]));
+
+    *size = (*parser).moov_size;
+
+    MP4PARSE_OK
+}
+
+/// Fill the supplied `mp4parse_unsupported_feature` with the most
+/// recent unsupported construct found while parsing.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_unsupported_feature(parser: *const mp4parse_parser, feature: *mut mp4parse_unsupported_feature) -> mp4parse_error {
+    if parser.is_null() || feature.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_unsupported_feature", &[]));
+
+    match (*parser).context().unsupported_features.last() {
+        Some(unsupported) => {
+            (*feature).box_type = u32::from(unsupported.box_type);
+            (*feature).description_length = unsupported.feature.len() as u32;
+            (*feature).description = unsupported.feature.as_ptr();
+        }
+        None => {
+            (*feature).box_type = 0;
+            (*feature).description_length = 0;
+            (*feature).description = std::ptr::null();
+        }
+    }
+
+    MP4PARSE_OK
+}
+
+/// Return the sample count, largest sample size and total sample size
+/// of `track`, in bytes.
+///
+/// These come from the sample table directly, so unlike the per-sample
+/// calls they don't need `MP4PARSE_PARSE_MODE_FULL`. Tracks whose
+/// samples are all in movie fragments report zeros, as do all tracks
+/// parsed with `MP4PARSE_PARSE_MODE_METADATA`.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_track_sample_info(parser: *const mp4parse_parser, track_index: u32, sample_count: *mut u32, max_size: *mut u32, total_size: *mut u64) -> mp4parse_error {
+    if parser.is_null() || sample_count.is_null() || max_size.is_null() || total_size.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_track_sample_info", &[track_index as u64]));
+
+    let summary = match (*parser).context().tracks.get(track_index as usize) {
+        Some(track) => track.sample_size_summary(),
+        None => return MP4PARSE_ERROR_BADARG,
+    };
+    *sample_count = summary.sample_count;
+    *max_size = summary.max_size;
+    *total_size = summary.total_size;
+
+    MP4PARSE_OK
+}
+
+/// Return the number of samples in `track`.
+///
+/// The sample index is only built by `MP4PARSE_PARSE_MODE_FULL`; other
+/// modes return `MP4PARSE_ERROR_BADARG`.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_sample_count(parser: *const mp4parse_parser, track_index: u32, count: *mut u32) -> mp4parse_error {
+    if parser.is_null() || count.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_sample_count", &[track_index as u64]));
+
+    let samples = match (*parser).context().tracks.get(track_index as usize) {
+        Some(track) => match track.samples {
+            Some(ref samples) => samples,
+            None => return MP4PARSE_ERROR_BADARG,
+        },
+        None => return MP4PARSE_ERROR_BADARG,
+    };
+    if samples.len() > u32::max_value() as usize {
+        return MP4PARSE_ERROR_INVALID;
+    }
+    *count = samples.len() as u32;
+
+    MP4PARSE_OK
+}
+
+/// Fill the supplied `mp4parse_sample_info` for sample `sample_index`
+/// of `track`, in decode order.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_sample_info(parser: *const mp4parse_parser, track_index: u32, sample_index: u32, info: *mut mp4parse_sample_info) -> mp4parse_error {
+    if parser.is_null() || info.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_sample_info", &[track_index as u64, sample_index as u64]));
+
+    let sample = match (*parser).context().tracks.get(track_index as usize) {
+        Some(track) => match track.samples.as_ref().and_then(|samples| samples.get(sample_index as usize)) {
+            Some(sample) => sample,
+            None => return MP4PARSE_ERROR_BADARG,
+        },
+        None => return MP4PARSE_ERROR_BADARG,
+    };
+
+    (*info).offset = sample.info.offset;
+    (*info).size = sample.info.size;
+    (*info).decode_time = sample.info.decode_time;
+    (*info).composition_time = sample.info.composition_time;
+    (*info).duration = sample.info.duration;
+    (*info).sync = sample.info.sync;
+    match sample.crypto {
+        Some(ref crypto) => {
+            (*info).encrypted = true;
+            (*info).iv_size = crypto.iv.len() as u32;
+            (*info).iv = crypto.iv.as_ptr();
+            (*info).subsample_count = crypto.subsamples.len() as u32;
+            // Subsample is repr(C) with the same layout.
+            (*info).subsamples = crypto.subsamples.as_ptr() as *const mp4parse_subsample;
+            if crypto.kid.len() != 16 {
+                return MP4PARSE_ERROR_INVALID;
+            }
+            (*info).kid.copy_from_slice(&crypto.kid);
+        }
+        None => {
+            (*info).encrypted = false;
+            (*info).iv_size = 0;
+            (*info).iv = std::ptr::null();
+            (*info).subsample_count = 0;
+            (*info).subsamples = std::ptr::null();
+            (*info).kid = [0; 16];
+        }
+    }
+
+    MP4PARSE_OK
+}
+
+/// Return the number of fragments in the index of `track`.
+///
+/// The index is built from the movie fragments parsed so far, or the
+/// segment index if no fragment carries the track. Returns
+/// `MP4PARSE_ERROR_INVALID` if the track has no timescale.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_fragment_count(parser: *mut mp4parse_parser, track_index: u32, count: *mut u32) -> mp4parse_error {
+    if parser.is_null() || count.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_fragment_count", &[track_index as u64]));
+    if track_index as usize >= (*parser).context().tracks.len() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+
+    // This caches the fragment index in the parser.
+    if !(*parser).acquire() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    let rv = get_fragment_count_locked(parser, track_index, count);
+    (*parser).release();
+    rv
+}
+
+unsafe fn get_fragment_count_locked(parser: *mut mp4parse_parser, track_index: u32, count: *mut u32) -> mp4parse_error {
+    let index = match (*parser).fragment_index(track_index) {
+        Some(index) => index,
+        None => return MP4PARSE_ERROR_INVALID,
+    };
+    if index.len() > u32::max_value() as usize {
+        return MP4PARSE_ERROR_INVALID;
+    }
+    *count = index.len() as u32;
+
+    MP4PARSE_OK
+}
+
+/// Fill the supplied `mp4parse_fragment_range` for fragment
+/// `fragment_index` of `track`.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_fragment_range(parser: *mut mp4parse_parser, track_index: u32, fragment_index: u32, range: *mut mp4parse_fragment_range) -> mp4parse_error {
+    if parser.is_null() || range.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_fragment_range", &[track_index as u64, fragment_index as u64]));
+    if track_index as usize >= (*parser).context().tracks.len() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+
+    // This caches the fragment index in the parser.
+    if !(*parser).acquire() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    let rv = get_fragment_range_locked(parser, track_index, fragment_index, range);
+    (*parser).release();
+    rv
+}
+
+unsafe fn get_fragment_range_locked(parser: *mut mp4parse_parser, track_index: u32, fragment_index: u32, range: *mut mp4parse_fragment_range) -> mp4parse_error {
+    let fragment = match (*parser).fragment_index(track_index) {
+        Some(index) => match index.get(fragment_index as usize) {
+            Some(fragment) => fragment,
+            None => return MP4PARSE_ERROR_BADARG,
+        },
+        None => return MP4PARSE_ERROR_INVALID,
+    };
+
+    (*range).start_time = fragment.start_us;
+    (*range).duration = fragment.duration_us;
+    (*range).offset = fragment.byte_range.start;
+    (*range).length = fragment.byte_range.end - fragment.byte_range.start;
+
+    MP4PARSE_OK
+}
+
+/// Build the sample index of `track`, from its sample tables followed
+/// by any movie fragments. Returns None if the track has no timescale,
+/// its sample tables are inconsistent or a time overflows.
+fn build_indice_table(context: &MediaContext, track: &::Track) -> Option<Vec<mp4parse_indice>> {
+    let timescale = match track.timescale {
+        Some(TrackTimeScale(0, _)) | None => return None,
+        Some(TrackTimeScale(timescale, _)) => timescale as i64,
+    };
+    let mut samples = match track.sample_table() {
+        Ok(samples) => samples,
+        Err(_) => return None,
+    };
+    if let Some(track_id) = track.track_id {
+        samples.extend(context.fragment_samples(track_id));
+    }
+    let to_us = |t: i64| t.checked_mul(1000000).map(|t| t / timescale);
+
+    let mut indices = Vec::with_capacity(samples.len());
+    for sample in &samples {
+        let composition_end = match sample.composition_time.checked_add(sample.duration as i64) {
+            Some(end) => end,
+            None => return None,
+        };
+        if sample.decode_time > i64::max_value() as u64 {
+            return None;
+        }
+        indices.push(mp4parse_indice {
+            start_offset: sample.offset,
+            end_offset: match sample.offset.checked_add(sample.size as u64) {
+                Some(end) => end,
+                None => return None,
+            },
+            start_composition: match to_us(sample.composition_time) {
+                Some(t) => t,
+                None => return None,
+            },
+            end_composition: match to_us(composition_end) {
+                Some(t) => t,
+                None => return None,
+            },
+            start_decode: match to_us(sample.decode_time as i64) {
+                Some(t) => t,
+                None => return None,
+            },
+            sync: sample.sync,
+        });
+    }
+    Some(indices)
+}
+
+/// Return the sample index of the track with ID `track_id`.
+///
+/// `indices` is set to an array of `count` entries, one per sample in
+/// decode order, covering the track's sample tables and the movie
+/// fragments parsed so far. The array is owned by the parser and valid
+/// until the next call to `mp4parse_read()` or the parser is freed.
+/// Returns `MP4PARSE_ERROR_INVALID` if the track has no timescale, its
+/// sample tables are inconsistent or another track has the same ID.
+#[no_mangle]
+pub unsafe extern fn mp4parse_get_indice_table(parser: *mut mp4parse_parser, track_id: u32, indices: *mut *const mp4parse_indice, count: *mut u32) -> mp4parse_error {
+    if parser.is_null() || indices.is_null() || count.is_null() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    (*parser).capture(|c| c.call("get_indice_table", &[track_id as u64]));
+    if !(*parser).context().tracks.iter().any(|t| t.track_id == Some(track_id)) {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    if (*parser).context().duplicate_track_ids.contains(&track_id) {
+        return MP4PARSE_ERROR_INVALID;
+    }
+
+    // This caches the sample index in the parser.
+    if !(*parser).acquire() {
+        return MP4PARSE_ERROR_BADARG;
+    }
+    let rv = get_indice_table_locked(parser, track_id, indices, count);
+    (*parser).release();
+    rv
+}
+
+unsafe fn get_indice_table_locked(parser: *mut mp4parse_parser, track_id: u32, indices: *mut *const mp4parse_indice, count: *mut u32) -> mp4parse_error {
+    let table = match (*parser).indice_table(track_id) {
+        Some(table) => table,
+        None => return MP4PARSE_ERROR_INVALID,
+    };
+    if table.len() > u32::max_value() as usize {
+        return MP4PARSE_ERROR_INVALID;
+    }
+    *indices = table.as_ptr();
+    *count = table.len() as u32;
+
+    MP4PARSE_OK
+}
+
+#[cfg(test)]
+extern fn panic_read(_: *mut u8, _: usize, _: *mut std::os::raw::c_void) -> isize {
+    panic!("panic_read shouldn't be called in these tests");
+}
+
+#[cfg(test)]
+extern fn error_read(_: *mut u8, _: usize, _: *mut std::os::raw::c_void) -> isize {
+    -1
+}
+
+#[cfg(test)]
+extern fn valid_read(buf: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize {
+    let mut input: &mut std::fs::File = unsafe { &mut *(userdata as *mut _) };
+
+    let mut buf = unsafe { std::slice::from_raw_parts_mut(buf, size) };
+    match input.read(&mut buf) {
+        Ok(n) => n as isize,
+        Err(_) => -1,
+    }
+}
+
+#[test]
+fn new_parser() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        mp4parse_free(parser);
+    }
+}
+
+/// Allocation hooks counting outstanding blocks in an AtomicIsize.
+#[cfg(test)]
+extern fn counting_malloc(size: usize, userdata: *mut std::os::raw::c_void) -> *mut std::os::raw::c_void {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    let count = unsafe { &*(userdata as *const std::sync::atomic::AtomicIsize) };
+    count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    unsafe {
+        let raw = System.alloc(Layout::from_size_align(size + 16, 16).unwrap());
+        *(raw as *mut usize) = size;
+        raw.offset(16) as *mut std::os::raw::c_void
+    }
+}
+
+#[cfg(test)]
+extern fn counting_free(ptr: *mut std::os::raw::c_void, userdata: *mut std::os::raw::c_void) {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    let count = unsafe { &*(userdata as *const std::sync::atomic::AtomicIsize) };
+    count.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    unsafe {
+        let raw = (ptr as *mut u8).offset(-16);
+        System.dealloc(raw, Layout::from_size_align(*(raw as *mut usize) + 16, 16).unwrap());
+    }
+}
+
+#[test]
+fn allocator_hooks() {
+    let count = std::sync::atomic::AtomicIsize::new(0);
+    let allocator = mp4parse_allocator {
+        malloc: Some(counting_malloc),
+        free: Some(counting_free),
+        userdata: &count as *const _ as *mut std::os::raw::c_void,
+    };
+    let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+    let io = mp4parse_io { read: Some(valid_read),
+                           userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+    let options = mp4parse_parser_options {
+        size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+        lenient: false,
+        mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+        moov_size_limit: 0,
+        skip_hint_tracks: false,
+        allocator: &allocator,
+        read_buffer_size: 0,
+        track_ids: std::ptr::null(),
+        track_id_count: 0,
+    };
+    unsafe {
+        let parser = mp4parse_new_with_options(&io, &options);
+        if !cfg!(feature = "alloc_hooks") {
+            assert!(parser.is_null());
+            return;
+        }
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        assert!(count.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        mp4parse_free(parser);
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+}
+
+#[test]
+fn new_parser_with_options() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    let mut options = mp4parse_parser_options {
+        size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+        lenient: true,
+        mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_METADATA,
+        moov_size_limit: 0,
+        skip_hint_tracks: true,
+        allocator: std::ptr::null(),
+        read_buffer_size: 0,
+        track_ids: std::ptr::null(),
+        track_id_count: 0,
+    };
+    assert_eq!(mp4parse_version(), MP4PARSE_ABI_VERSION);
+    unsafe {
+        let parser = mp4parse_new_with_options(&io, &options);
+        assert!(!parser.is_null());
+        assert_eq!((*parser).context().strictness, ParseStrictness::Lenient);
+        assert_eq!((*parser).context().mode, ParseMode::Metadata);
+        assert!((*parser).context().skip_hint_tracks);
+        mp4parse_free(parser);
+
+        // Fields beyond the caller's size are ignored.
+        options.size = parser_options_v1_size() as u32;
+        let parser = mp4parse_new_with_options(&io, &options);
+        assert!(!parser.is_null());
+        assert_eq!((*parser).context().mode, ParseMode::Tables);
+        assert!(!(*parser).context().skip_hint_tracks);
+        mp4parse_free(parser);
+
+        assert!(mp4parse_new_with_options(&io, std::ptr::null()).is_null());
+        // A struct from a mismatched header is rejected.
+        options.size = 4;
+        assert!(mp4parse_new_with_options(&io, &options).is_null());
+
+        // As is a mode outside the enum.
+        options.size = std::mem::size_of::<mp4parse_parser_options>() as u32;
+        *(&mut options.mode as *mut _ as *mut u32) = 7;
+        assert!(mp4parse_new_with_options(&io, &options).is_null());
+    }
+}
+
+#[test]
+fn parser_options_sizes() {
+    unsafe {
+        // Each version ends with the fields it added, wherever the
+        // target places them.
+        let sizes = [parser_options_v1_size(), parser_options_v2_size(), parser_options_v3_size(),
+                     parser_options_v4_size(), parser_options_v5_size(), parser_options_v6_size(),
+                     parser_options_v7_size()];
+        assert!(sizes.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sizes[1], options_offset!(mode) + 4);
+        assert!(sizes[6] <= std::mem::size_of::<mp4parse_parser_options>());
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(sizes, [5, 12, 24, 25, 40, 44, 60]);
+        }
+    }
+}
+
+#[test]
+fn busy_parser() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        // Simulate a call in progress on another thread.
+        assert!((*parser).acquire());
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_read(parser));
+        let mut info = mp4parse_track_audio_info::default();
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_audio_info(parser, 0, &mut info));
+        (*parser).release();
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+#[should_panic(expected = "assertion failed")]
+fn free_null_parser() {
+    unsafe {
+        mp4parse_free(std::ptr::null_mut());
+    }
+}
+
+#[test]
+fn get_track_count_null_parser() {
+    unsafe {
+        let mut count: u32 = 0;
+        let rv = mp4parse_get_track_count(std::ptr::null(), std::ptr::null_mut());
+        assert!(rv == MP4PARSE_ERROR_BADARG);
+        let rv = mp4parse_get_track_count(std::ptr::null(), &mut count);
+        assert!(rv == MP4PARSE_ERROR_BADARG);
+    }
+}
+
+/// Read one byte at a time from a Cursor, reporting would-block
+/// between bytes.
+#[cfg(test)]
+extern fn trickle_read(buf: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize {
+    let input: &mut (std::io::Cursor<Vec<u8>>, bool) = unsafe { &mut *(userdata as *mut _) };
+    input.1 = !input.1;
+    if input.1 {
+        return MP4PARSE_IO_WOULD_BLOCK;
+    }
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, std::cmp::min(size, 1)) };
+    match input.0.read(buf) {
+        Ok(n) => n as isize,
+        Err(_) => -1,
+    }
+}
+
+#[test]
+fn short_and_would_block_reads() {
+    let mut data = Vec::new();
+    std::fs::File::open("examples/minimal.mp4").unwrap().read_to_end(&mut data).unwrap();
+    let mut input = (std::io::Cursor::new(data), false);
+    let io = mp4parse_io {
+        read: Some(trickle_read),
+        userdata: &mut input as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_count(parser, &mut count));
+        assert_eq!(count, 2);
+        mp4parse_free(parser);
+    }
+
+    extern fn blocked_read(_: *mut u8, _: usize, _: *mut std::os::raw::c_void) -> isize {
+        MP4PARSE_IO_WOULD_BLOCK
+    }
+    let mut dummy_value: u32 = 42;
+    let mut io = mp4parse_io {
+        read: Some(blocked_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    let mut buf = [0; 4];
+    let error = io.read(&mut buf).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn overlong_read() {
+    extern fn overlong_read(_: *mut u8, size: usize, _: *mut std::os::raw::c_void) -> isize {
+        size as isize + 1
+    }
+    let mut dummy_value: u32 = 42;
+    let mut io = mp4parse_io {
+        read: Some(overlong_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    let mut buf = [0; 4];
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| io.read(&mut buf)));
+    // Trapped in debug builds, and a read error otherwise.
+    match result {
+        Ok(read) => assert!(read.is_err()),
+        Err(_) => assert!(cfg!(debug_assertions)),
+    }
+}
+
+#[test]
+fn arg_validation() {
     unsafe {
         // Passing a null mp4parse_io is an error.
         let parser = mp4parse_new(std::ptr::null());
@@ -521,19 +2030,19 @@ fn arg_validation() {
         let null_mut: *mut std::os::raw::c_void = std::ptr::null_mut();
 
         // Passing an mp4parse_io with null members is an error.
-        let io = mp4parse_io { read: std::mem::transmute(null_mut),
+        let io = mp4parse_io { read: None,
                                userdata: null_mut };
         let parser = mp4parse_new(&io);
         assert!(parser.is_null());
 
-        let io = mp4parse_io { read: panic_read,
+        let io = mp4parse_io { read: Some(panic_read),
                                userdata: null_mut };
         let parser = mp4parse_new(&io);
         assert!(parser.is_null());
 
         let mut dummy_value = 42;
         let io = mp4parse_io {
-            read: std::mem::transmute(null_mut),
+            read: None,
             userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
         };
         let parser = mp4parse_new(&io);
@@ -548,6 +2057,9 @@ fn arg_validation() {
             track_id: 0,
             duration: 0,
             media_time: 0,
+            language: [0; 4],
+            name: std::ptr::null(),
+            name_length: 0,
         };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_info(std::ptr::null_mut(), 0, &mut dummy_info));
 
@@ -556,11 +2068,19 @@ fn arg_validation() {
             display_height: 0,
             image_width: 0,
             image_height: 0,
+            profile: 0,
+            level: 0,
+            bit_depth: 0,
+            chroma_format: 0,
+            extra_data: Default::default(),
         };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_video_info(std::ptr::null_mut(), 0, &mut dummy_video));
 
         let mut dummy_audio = Default::default();
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_audio_info(std::ptr::null_mut(), 0, &mut dummy_audio));
+
+        let mut dummy_trim = Default::default();
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_opus_trim_info(std::ptr::null_mut(), 0, &mut dummy_trim));
     }
 }
 
@@ -569,7 +2089,7 @@ fn arg_validation_with_parser() {
     unsafe {
         let mut dummy_value = 42;
         let io = mp4parse_io {
-            read: error_read,
+            read: Some(error_read),
             userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
         };
         let parser = mp4parse_new(&io);
@@ -578,8 +2098,8 @@ fn arg_validation_with_parser() {
         // Our mp4parse_io read should simply fail with an error.
         assert_eq!(MP4PARSE_ERROR_IO, mp4parse_read(parser));
 
-        // The parser is now poisoned and unusable.
-        assert_eq!(MP4PARSE_ERROR_BADARG,  mp4parse_read(parser));
+        // The parser is now poisoned and can't read further.
+        assert_eq!(MP4PARSE_ERROR_POISONED, mp4parse_read(parser));
 
         // Null info pointers are an error.
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_info(parser, 0, std::ptr::null_mut()));
@@ -592,6 +2112,9 @@ fn arg_validation_with_parser() {
             track_id: 0,
             duration: 0,
             media_time: 0,
+            language: [0; 4],
+            name: std::ptr::null(),
+            name_length: 0,
         };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_info(parser, 0, &mut dummy_info));
 
@@ -600,6 +2123,11 @@ fn arg_validation_with_parser() {
             display_height: 0,
             image_width: 0,
             image_height: 0,
+            profile: 0,
+            level: 0,
+            bit_depth: 0,
+            chroma_format: 0,
+            extra_data: Default::default(),
         };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_video_info(parser, 0, &mut dummy_video));
 
@@ -615,7 +2143,7 @@ fn get_track_count_poisoned_parser() {
     unsafe {
         let mut dummy_value = 42;
         let io = mp4parse_io {
-            read: error_read,
+            read: Some(error_read),
             userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
         };
         let parser = mp4parse_new(&io);
@@ -624,9 +2152,635 @@ fn get_track_count_poisoned_parser() {
         // Our mp4parse_io read should simply fail with an error.
         assert_eq!(MP4PARSE_ERROR_IO, mp4parse_read(parser));
 
-        let mut count: u32 = 0;
+        // Getters still work, though nothing was parsed.
+        let mut count: u32 = 1;
         let rv = mp4parse_get_track_count(parser, &mut count);
-        assert!(rv == MP4PARSE_ERROR_BADARG);
+        assert!(rv == MP4PARSE_OK);
+        assert_eq!(count, 0);
+        mp4parse_free(parser);
+    }
+}
+
+/// Read from a Cursor, failing instead of returning EOF.
+#[cfg(test)]
+extern fn failing_eof_read(buf: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize {
+    let input: &mut std::io::Cursor<Vec<u8>> = unsafe { &mut *(userdata as *mut _) };
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, size) };
+    match input.read(buf) {
+        Ok(n) if n > 0 || size == 0 => n as isize,
+        _ => -1,
+    }
+}
+
+#[test]
+fn poisoned_parser_keeps_tracks() {
+    let mut data = Vec::new();
+    std::fs::File::open("examples/minimal.mp4").unwrap().read_to_end(&mut data).unwrap();
+    // Stop after the ftyp and moov, so the next read fails.
+    data.truncate(0x519);
+    let mut input = std::io::Cursor::new(data);
+    let io = mp4parse_io {
+        read: Some(failing_eof_read),
+        userdata: &mut input as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_ERROR_IO, mp4parse_read(parser));
+        assert_eq!(MP4PARSE_ERROR_POISONED, mp4parse_read(parser));
+
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_count(parser, &mut count));
+        assert_eq!(count, 2);
+        let mut info: mp4parse_track_info = std::mem::zeroed();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_info(parser, 1, &mut info));
+        assert_eq!(info.track_type, MP4PARSE_TRACK_TYPE_AUDIO);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn full_mode_sample_info() {
+    unsafe {
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut count: u32 = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        // Without the full mode there's no sample index.
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_sample_count(parser, 0, &mut count));
+        mp4parse_free(parser);
+
+        let options = mp4parse_parser_options {
+            size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+            lenient: false,
+            mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_FULL,
+            moov_size_limit: 0,
+            skip_hint_tracks: false,
+            allocator: std::ptr::null(),
+            read_buffer_size: 0,
+            track_ids: std::ptr::null(),
+            track_id_count: 0,
+        };
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new_with_options(&io, &options);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_sample_count(parser, 0, &mut count));
+        let table = (*parser).context().tracks[0].sample_table().unwrap();
+        assert_eq!(count as usize, table.len());
+
+        let mut info: mp4parse_sample_info = std::mem::zeroed();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_sample_info(parser, 0, 0, &mut info));
+        assert_eq!(info.offset, table[0].offset);
+        assert_eq!(info.size, table[0].size);
+        assert!(info.sync);
+        assert!(!info.encrypted);
+        assert!(info.iv.is_null());
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_sample_info(parser, 0, count, &mut info));
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn track_sample_info() {
+    unsafe {
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+
+        let (mut count, mut max_size, mut total_size) = (0, 0, 0);
+        for track in 0..2 {
+            assert_eq!(MP4PARSE_OK, mp4parse_get_track_sample_info(parser, track, &mut count, &mut max_size, &mut total_size));
+            let table = (*parser).context().tracks[track as usize].sample_table().unwrap();
+            assert_eq!(count as usize, table.len());
+            assert_eq!(max_size, table.iter().map(|s| s.size).max().unwrap());
+            assert_eq!(total_size, table.iter().map(|s| s.size as u64).sum::<u64>());
+        }
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_sample_info(parser, 2, &mut count, &mut max_size, &mut total_size));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_sample_info(parser, 0, &mut count, std::ptr::null_mut(), &mut total_size));
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn indice_table() {
+    unsafe {
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        // Not built until asked for.
+        assert!((*parser).indice_table.is_empty());
+
+        let mut indices: *const mp4parse_indice = std::ptr::null();
+        let mut count: u32 = 0;
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_indice_table(parser, 3, &mut indices, &mut count));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_indice_table(parser, 1, std::ptr::null_mut(), &mut count));
+
+        // Built without the full parse mode.
+        let (track_id, table, timescale) = {
+            let track = &(*parser).context().tracks[0];
+            (track.track_id.unwrap(), track.sample_table().unwrap(), track.timescale.unwrap().0 as i64)
+        };
+        assert_eq!(MP4PARSE_OK, mp4parse_get_indice_table(parser, track_id, &mut indices, &mut count));
+        assert_eq!(count as usize, table.len());
+        let indices = std::slice::from_raw_parts(indices, count as usize);
+        for (indice, sample) in indices.iter().zip(&table) {
+            assert_eq!(indice.start_offset, sample.offset);
+            assert_eq!(indice.end_offset, sample.offset + sample.size as u64);
+            assert_eq!(indice.start_decode, sample.decode_time as i64 * 1000000 / timescale);
+            assert_eq!(indice.start_composition, sample.composition_time * 1000000 / timescale);
+            assert_eq!(indice.end_composition,
+                       (sample.composition_time + sample.duration as i64) * 1000000 / timescale);
+            assert_eq!(indice.sync, sample.sync);
+        }
+        assert!(indices[0].sync);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn file_info() {
+    unsafe {
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut info: mp4parse_file_info = std::mem::zeroed();
+        // Nothing has been parsed yet.
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_file_info(parser, &mut info));
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_file_info(parser, std::ptr::null_mut()));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_file_info(parser, &mut info));
+        assert_eq!(info.major_brand, 0x69736f6d); // 'isom'
+        assert_eq!(info.timescale, 1000);
+        assert_eq!(info.duration, 62);
+        assert_eq!(info.duration_ms, 62);
+        assert!(!info.fragmented);
+        assert!(!info.encrypted);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn file_info_long_duration() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut info: mp4parse_file_info = std::mem::zeroed();
+        // A version 1 mvhd duration that can't be expressed in milliseconds.
+        (*parser).context_mut().timescale = Some(::MediaTimeScale(1));
+        (*parser).context_mut().duration = Some(::MediaScaledTime(1 << 63));
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_file_info(parser, &mut info));
+        // The largest duration that still fits.
+        (*parser).context_mut().duration = Some(::MediaScaledTime(std::u64::MAX / 1000));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_file_info(parser, &mut info));
+        assert_eq!(info.duration_ms, std::u64::MAX / 1000 * 1000);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn opus_extra_data() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut track = ::Track::new(0);
+        track.track_type = TrackType::Audio;
+        track.data = Some(SampleEntry::Audio(::AudioSampleEntry {
+            data_reference_index: 1,
+            channelcount: 1,
+            samplesize: 16,
+            samplerate: ::FixedPoint16_16(48000 << 16),
+            codec_specific: AudioCodecSpecific::OpusSpecificBox(::OpusSpecificBox {
+                version: 0,
+                output_channel_count: 1,
+                pre_skip: 342,
+                input_sample_rate: 24000,
+                output_gain: 0,
+                channel_mapping_family: 0,
+                channel_mapping_table: None,
+            }),
+        }));
+        (*parser).context_mut().tracks.push(track);
+
+        let mut audio = Default::default();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_audio_info(parser, 0, &mut audio));
+        assert_eq!(audio.channels, 1);
+        assert_eq!(audio.sample_rate, 48000);
+        assert_eq!(audio.extra_data.length, 19);
+        let head = std::slice::from_raw_parts(audio.extra_data.data, audio.extra_data.length as usize);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(&head[8..12], &[1, 1, 0x56, 0x01]);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn unsupported_feature() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut feature: mp4parse_unsupported_feature = std::mem::zeroed();
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_unsupported_feature(parser, std::ptr::null_mut()));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_unsupported_feature(parser, &mut feature));
+        assert_eq!(feature.box_type, 0);
+        assert!(feature.description.is_null());
+
+        (*parser).context_mut().unsupported_features.push(::UnsupportedFeature {
+            feature: "compressed moov",
+            box_type: ::BoxType::CompressedMovieBox,
+        });
+        assert_eq!(MP4PARSE_OK, mp4parse_get_unsupported_feature(parser, &mut feature));
+        assert_eq!(feature.box_type, 0x636d6f76);
+        let description = std::slice::from_raw_parts(feature.description, feature.description_length as usize);
+        assert_eq!(description, b"compressed moov");
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn empty_track() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        {
+            let context = (*parser).context_mut();
+            context.timescale = Some(MediaTimeScale(1000));
+            let mut track = ::Track::new(0);
+            track.track_type = TrackType::Video;
+            track.track_id = Some(1);
+            track.timescale = Some(TrackTimeScale(90000, 0));
+            track.samples = Some(track.full_sample_table().unwrap());
+            context.tracks.push(track);
+        }
+        let mut info: mp4parse_track_info = std::mem::zeroed();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_info(parser, 0, &mut info));
+        assert_eq!(info.duration, 0);
+        let mut count = 1;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_sample_count(parser, 0, &mut count));
+        assert_eq!(count, 0);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn overflowing_track_times() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        {
+            let context = (*parser).context_mut();
+            context.timescale = Some(MediaTimeScale(1000));
+            let mut track = ::Track::new(0);
+            track.track_type = TrackType::Video;
+            track.track_id = Some(1);
+            track.timescale = Some(TrackTimeScale(90000, 0));
+            track.duration = Some(TrackScaledTime(u64::max_value() / 2, 0));
+            context.tracks.push(track);
+        }
+        // Too long to express in microseconds, rather than a panic.
+        let mut info: mp4parse_track_info = std::mem::zeroed();
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_track_info(parser, 0, &mut info));
+        (*parser).context_mut().tracks[0].duration = Some(TrackScaledTime(90000, 0));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_info(parser, 0, &mut info));
+        assert_eq!(info.duration, 1000000);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn fragment_range() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        {
+            let context = (*parser).context_mut();
+            let mut track = ::Track::new(0);
+            track.track_id = Some(1);
+            track.timescale = Some(TrackTimeScale(1000, 0));
+            context.tracks.push(track);
+            context.tracks.push(::Track::new(1));
+            context.fragments.push(::MovieFragment {
+                position: 1000,
+                size: 500,
+                sequence_number: Some(1),
+                segment: None,
+                tracks: vec![::TrackFragment {
+                    track_id: 1,
+                    base_decode_time: Some(2000),
+                    sample_count: 10,
+                    duration: 1000,
+                    samples: Vec::new(),
+                    senc: Vec::new(),
+                }],
+            });
+        }
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_fragment_count(parser, 0, &mut count));
+        assert_eq!(count, 1);
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_fragment_count(parser, 1, &mut count));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_fragment_count(parser, 2, &mut count));
+
+        let mut range: mp4parse_fragment_range = std::mem::zeroed();
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_fragment_range(parser, 0, 1, &mut range));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_fragment_range(parser, 0, 0, &mut range));
+        assert_eq!(range.start_time, 2000000);
+        assert_eq!(range.duration, 1000000);
+        assert_eq!(range.offset, 1000);
+        assert_eq!(range.length, 500);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn track_encryption_info() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut track = ::Track::new(0);
+        track.protection_info = Some(::ProtectionSchemeInfoBox {
+            original_format: None,
+            scheme_type: Some(0x63626373),
+            tenc: Some(::TrackEncryptionBox {
+                crypt_byte_block: 1,
+                skip_byte_block: 9,
+                is_encrypted: true,
+                iv_size: 0,
+                kid: vec![7; 16],
+                constant_iv: Some(vec![1; 16]),
+            }),
+        });
+        (*parser).context_mut().tracks.push(::Track::new(0));
+        (*parser).context_mut().tracks.push(track);
+
+        let mut info: mp4parse_track_encryption_info = std::mem::zeroed();
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_track_encryption_info(parser, 0, &mut info));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_encryption_info(parser, 2, &mut info));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_encryption_info(parser, 1, &mut info));
+        assert_eq!(info.scheme_type, 0x63626373);
+        assert_eq!((info.crypt_byte_block, info.skip_byte_block), (1, 9));
+        assert_eq!(info.kid, [7; 16]);
+        assert_eq!(info.constant_iv_size, 16);
+        assert_eq!(*info.constant_iv, 1);
+
+        let (mut scheme, mut iv_size, mut kid) = (0, 1, [0; 16]);
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_track_crypto_info(parser, 0, &mut scheme, &mut iv_size, kid.as_mut_ptr()));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_crypto_info(parser, 1, &mut scheme, &mut iv_size, std::ptr::null_mut()));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_crypto_info(parser, 1, &mut scheme, &mut iv_size, kid.as_mut_ptr()));
+        assert_eq!((scheme, iv_size, kid), (0x63626373, 0, [7; 16]));
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn pssh() {
+    let mut dummy_value: u32 = 42;
+    let io = mp4parse_io {
+        read: Some(panic_read),
+        userdata: &mut dummy_value as *mut _ as *mut std::os::raw::c_void,
+    };
+    unsafe {
+        let parser = mp4parse_new(&io);
+        assert!(!parser.is_null());
+        let mut count = 1;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_pssh_count(parser, &mut count));
+        assert_eq!(count, 0);
+        for id in 1..3 {
+            (*parser).context_mut().psshs.push(::ProtectionSystemSpecificHeaderBox {
+                system_id: vec![id; 16],
+                kid: Vec::new(),
+                data: Vec::new(),
+                box_content: vec![id; 32],
+            });
+        }
+        assert_eq!(MP4PARSE_OK, mp4parse_get_pssh_count(parser, &mut count));
+        assert_eq!(count, 2);
+
+        let mut system_id = [0; 16];
+        let mut data = std::ptr::null();
+        let mut length = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_pssh(parser, 1, system_id.as_mut_ptr(), &mut data, &mut length));
+        assert_eq!(system_id, [2; 16]);
+        assert_eq!(length, 32);
+        assert_eq!(*data, 2);
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_pssh(parser, 2, system_id.as_mut_ptr(), &mut data, &mut length));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_pssh(parser, 0, std::ptr::null_mut(), &mut data, &mut length));
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn new_from_buffer() {
+    use std::io::Read;
+    let mut data = Vec::new();
+    std::fs::File::open("examples/minimal.mp4").unwrap().read_to_end(&mut data).unwrap();
+    unsafe {
+        assert!(mp4parse_new_from_buffer(std::ptr::null(), 0).is_null());
+
+        let parser = mp4parse_new_from_buffer(data.as_ptr(), data.len());
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_count(parser, &mut count));
+        assert_eq!(2, count);
+        mp4parse_free(parser);
+
+        // Truncated input is reported as it would be from a callback.
+        let parser = mp4parse_new_from_buffer(data.as_ptr(), 100);
+        assert_eq!(MP4PARSE_ERROR_EOF, mp4parse_read(parser));
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn io_counters() {
+    use std::io::Read;
+    let mut data = Vec::new();
+    std::fs::File::open("examples/minimal.mp4").unwrap().read_to_end(&mut data).unwrap();
+    unsafe {
+        let parser = mp4parse_new_from_buffer(data.as_ptr(), data.len());
+        let mut counters = mp4parse_io_counters::default();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_io_counters(parser, &mut counters));
+        assert_eq!(counters.consumed, 0);
+
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_io_counters(parser, &mut counters));
+        assert_eq!(counters.consumed, data.len() as u64);
+        assert!(counters.requested >= counters.consumed);
+
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_io_counters(parser, std::ptr::null_mut()));
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_io_counters(std::ptr::null(), &mut counters));
+        mp4parse_free(parser);
+    }
+}
+
+/// Read from a File, counting the callbacks made.
+#[cfg(test)]
+extern fn counted_read(buf: *mut u8, size: usize, userdata: *mut std::os::raw::c_void) -> isize {
+    let input: &mut (std::fs::File, usize) = unsafe { &mut *(userdata as *mut _) };
+    input.1 += 1;
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, size) };
+    match input.0.read(buf) {
+        Ok(n) => n as isize,
+        Err(_) => -1,
+    }
+}
+
+#[test]
+fn read_buffer_size() {
+    let callbacks = |read_buffer_size| {
+        let mut input = (std::fs::File::open("examples/minimal.mp4").unwrap(), 0usize);
+        let io = mp4parse_io {
+            read: Some(counted_read),
+            userdata: &mut input as *mut _ as *mut std::os::raw::c_void,
+        };
+        let options = mp4parse_parser_options {
+            size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+            lenient: false,
+            mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+            moov_size_limit: 0,
+            skip_hint_tracks: false,
+            allocator: std::ptr::null(),
+            read_buffer_size: read_buffer_size,
+            track_ids: std::ptr::null(),
+            track_id_count: 0,
+        };
+        unsafe {
+            let parser = mp4parse_new_with_options(&io, &options);
+            assert!(!parser.is_null());
+            assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+            let mut count = 0;
+            assert_eq!(MP4PARSE_OK, mp4parse_get_track_count(parser, &mut count));
+            assert_eq!(count, 2);
+            mp4parse_free(parser);
+        }
+        input.1
+    };
+    let unbuffered = callbacks(1);
+    let buffered = callbacks(0);
+    // minimal.mp4 fits in the default buffer, so it takes one read
+    // plus those finding the end of the file.
+    assert!(buffered <= 3);
+    assert!(unbuffered > 100);
+}
+
+#[test]
+fn track_ids_option() {
+    let track_ids = [2u32];
+    let options = mp4parse_parser_options {
+        size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+        lenient: false,
+        mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+        moov_size_limit: 0,
+        skip_hint_tracks: false,
+        allocator: std::ptr::null(),
+        read_buffer_size: 0,
+        track_ids: track_ids.as_ptr(),
+        track_id_count: 1,
+    };
+    let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+    let io = mp4parse_io { read: Some(valid_read),
+                           userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+    unsafe {
+        let parser = mp4parse_new_with_options(&io, &options);
+        assert!(!parser.is_null());
+        assert_eq!(MP4PARSE_OK, mp4parse_read(parser));
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_count(parser, &mut count));
+        assert_eq!(count, 2);
+        // Only the audio track's sample description was parsed.
+        let mut video = mp4parse_track_video_info {
+            display_width: 0,
+            display_height: 0,
+            image_width: 0,
+            image_height: 0,
+            profile: 0,
+            level: 0,
+            bit_depth: 0,
+            chroma_format: 0,
+            extra_data: Default::default(),
+        };
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_track_video_info(parser, 0, &mut video));
+        let mut audio = Default::default();
+        assert_eq!(MP4PARSE_OK, mp4parse_get_track_audio_info(parser, 1, &mut audio));
+        assert_eq!(audio.sample_rate, 48000);
+        mp4parse_free(parser);
+    }
+}
+
+#[test]
+fn moov_size_limit() {
+    unsafe {
+        let options = mp4parse_parser_options {
+            size: std::mem::size_of::<mp4parse_parser_options>() as u32,
+            lenient: false,
+            mode: mp4parse_parse_mode::MP4PARSE_PARSE_MODE_TABLES,
+            moov_size_limit: 100,
+            skip_hint_tracks: false,
+            allocator: std::ptr::null(),
+            read_buffer_size: 0,
+            track_ids: std::ptr::null(),
+            track_id_count: 0,
+        };
+        let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
+        let io = mp4parse_io { read: Some(valid_read),
+                               userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
+        let parser = mp4parse_new_with_options(&io, &options);
+        assert!(!parser.is_null());
+        let mut size = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_required_moov_size(parser, &mut size));
+        assert_eq!(size, 0);
+        assert_eq!(MP4PARSE_ERROR_MOOV_TOO_LARGE, mp4parse_read(parser));
+        assert_eq!(MP4PARSE_OK, mp4parse_get_required_moov_size(parser, &mut size));
+        assert!(size > 100);
+        mp4parse_free(parser);
     }
 }
 
@@ -634,7 +2788,7 @@ fn get_track_count_poisoned_parser() {
 fn arg_validation_with_data() {
     unsafe {
         let mut file = std::fs::File::open("examples/minimal.mp4").unwrap();
-        let io = mp4parse_io { read: valid_read,
+        let io = mp4parse_io { read: Some(valid_read),
                                userdata: &mut file as *mut _ as *mut std::os::raw::c_void };
         let parser = mp4parse_new(&io);
         assert!(!parser.is_null());
@@ -651,6 +2805,9 @@ fn arg_validation_with_data() {
             track_id: 0,
             duration: 0,
             media_time: 0,
+            language: [0; 4],
+            name: std::ptr::null(),
+            name_length: 0,
         };
         assert_eq!(MP4PARSE_OK, mp4parse_get_track_info(parser, 0, &mut info));
         assert_eq!(info.track_type, MP4PARSE_TRACK_TYPE_VIDEO);
@@ -658,6 +2815,8 @@ fn arg_validation_with_data() {
         assert_eq!(info.track_id, 1);
         assert_eq!(info.duration, 40000);
         assert_eq!(info.media_time, 0);
+        assert_eq!(info.language, *b"und\0");
+        assert_eq!(std::slice::from_raw_parts(info.name, info.name_length as usize), b"VideoHandler");
 
         assert_eq!(MP4PARSE_OK, mp4parse_get_track_info(parser, 1, &mut info));
         assert_eq!(info.track_type, MP4PARSE_TRACK_TYPE_AUDIO);
@@ -671,18 +2830,55 @@ fn arg_validation_with_data() {
             display_height: 0,
             image_width: 0,
             image_height: 0,
+            profile: 0,
+            level: 0,
+            bit_depth: 0,
+            chroma_format: 0,
+            extra_data: Default::default(),
         };
         assert_eq!(MP4PARSE_OK, mp4parse_get_track_video_info(parser, 0, &mut video));
         assert_eq!(video.display_width, 320);
         assert_eq!(video.display_height, 240);
         assert_eq!(video.image_width, 320);
         assert_eq!(video.image_height, 240);
+        // High profile, level 1.3.
+        assert_eq!(video.profile, 100);
+        assert_eq!(video.level, 13);
+        assert_eq!(video.bit_depth, 8);
+        assert_eq!(video.chroma_format, 1);
+        assert_eq!(video.extra_data.length, 41);
+        let avcc = std::slice::from_raw_parts(video.extra_data.data, video.extra_data.length as usize);
+        assert_eq!(&avcc[..4], &[1, 100, 0, 13]);
 
         let mut audio = Default::default();
         assert_eq!(MP4PARSE_OK, mp4parse_get_track_audio_info(parser, 1, &mut audio));
         assert_eq!(audio.channels, 2);
         assert_eq!(audio.bit_depth, 16);
         assert_eq!(audio.sample_rate, 48000);
+        assert_eq!(audio.profile, 2);
+        assert_eq!(audio.extended_profile, 2);
+        assert_eq!(audio.max_bitrate, 67695);
+        assert_eq!(audio.avg_bitrate, 67695);
+        let config = std::slice::from_raw_parts(audio.extra_data.data, audio.extra_data.length as usize);
+        assert_eq!(config, &[0x11, 0x88, 0x56, 0xe5, 0x00]);
+
+        // The audio track is AAC, so there's no Opus trim info.
+        let mut trim = Default::default();
+        assert_eq!(MP4PARSE_ERROR_INVALID, mp4parse_get_track_opus_trim_info(parser, 1, &mut trim));
+
+        let mut usage = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_memory_usage(parser, &mut usage));
+        assert!(usage > 0);
+        assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_memory_usage(parser, std::ptr::null_mut()));
+
+        // Filling the getter caches doesn't change the figure.
+        let mut indices = std::ptr::null();
+        let mut count = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_indice_table(parser, 1, &mut indices, &mut count));
+        assert!(count > 0);
+        let mut after = 0;
+        assert_eq!(MP4PARSE_OK, mp4parse_get_memory_usage(parser, &mut after));
+        assert_eq!(after, usage);
 
         // Test with an invalid track number.
         let mut info = mp4parse_track_info {
@@ -691,6 +2887,9 @@ fn arg_validation_with_data() {
             track_id: 0,
             duration: 0,
             media_time: 0,
+            language: [0; 4],
+            name: std::ptr::null(),
+            name_length: 0,
         };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_info(parser, 3, &mut info));
         assert_eq!(info.track_type, MP4PARSE_TRACK_TYPE_VIDEO);
@@ -702,7 +2901,12 @@ fn arg_validation_with_data() {
         let mut video = mp4parse_track_video_info { display_width: 0,
                                                     display_height: 0,
                                                     image_width: 0,
-                                                    image_height: 0 };
+                                                    image_height: 0,
+                                                    profile: 0,
+                                                    level: 0,
+                                                    bit_depth: 0,
+                                                    chroma_format: 0,
+                                                    extra_data: Default::default() };
         assert_eq!(MP4PARSE_ERROR_BADARG, mp4parse_get_track_video_info(parser, 3, &mut video));
         assert_eq!(video.display_width, 0);
         assert_eq!(video.display_height, 0);
@@ -0,0 +1,106 @@
+//! Capture of C API call sequences for later replay.
+//!
+//! When built with the "capture" feature and `MP4PARSE_CAPTURE_DIR` is
+//! set, each parser writes a log of the calls made on it and the data
+//! its read callback returned to a new file in that directory. The
+//! `replay` example makes the same calls on a new parser whose read
+//! callback returns the logged data, reproducing the embedding
+//! application's use of the C API without the application.
+//!
+//! The log is text, one event per line:
+//!
+//! ```text
+//! new
+//! options <size> <lenient> <mode> <moov_size_limit> <skip_hint_tracks> <read_buffer_size> <track_ids> <allocator>
+//! call <function> <argument>...
+//! io <requested> <result> <hex data>
+//! ```
+//!
+//! Function names omit the `mp4parse_` prefix and arguments are the
+//! integer inputs to the call. `track_ids` is a comma separated list,
+//! or `-` if every track is parsed. `allocator` is 1 if allocation
+//! hooks were passed; the hooks themselves can't be recorded. `result`
+//! is the read callback's return value, followed by the data returned
+//! when it's positive.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+pub struct Capture {
+    file: File,
+}
+
+impl Capture {
+    /// Start a capture if enabled in the environment.
+    #[cfg(feature = "capture")]
+    pub fn from_env() -> Option<Capture> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = match ::std::env::var_os("MP4PARSE_CAPTURE_DIR") {
+            Some(dir) => ::std::path::PathBuf::from(dir),
+            None => return None,
+        };
+        let name = format!("mp4parse-{}-{}.log",
+                           ::std::process::id(),
+                           COUNTER.fetch_add(1, Ordering::Relaxed));
+        File::create(dir.join(name)).ok().map(|file| Capture { file: file })
+    }
+
+    #[cfg(not(feature = "capture"))]
+    pub fn from_env() -> Option<Capture> {
+        None
+    }
+
+    /// Record a line of the log. Capture is a debugging aid, so write
+    /// errors are ignored.
+    pub fn record(&mut self, line: &str) {
+        let _ = writeln!(self.file, "{}", line);
+    }
+
+    /// Record a call to the C API function `name`.
+    pub fn call(&mut self, name: &str, args: &[u64]) {
+        let mut line = format!("call {}", name);
+        for arg in args {
+            line.push_str(&format!(" {}", arg));
+        }
+        self.record(&line);
+    }
+
+    /// Record a read of `requested` bytes and its result.
+    pub fn read(&mut self, requested: usize, result: &::std::io::Result<usize>, buf: &[u8]) {
+        let mut line = match *result {
+            Ok(n) => format!("io {} {}", requested, n),
+            Err(_) => format!("io {} -1", requested),
+        };
+        if let Ok(n) = *result {
+            if n > 0 {
+                line.push(' ');
+            }
+            for byte in &buf[..n] {
+                line.push_str(&format!("{:02x}", byte));
+            }
+        }
+        self.record(&line);
+    }
+}
+
+/// A reader which records each read into a capture.
+pub struct CaptureReader<'a, T: 'a + Read> {
+    pub inner: &'a mut T,
+    pub capture: &'a ::std::sync::Mutex<Capture>,
+}
+
+impl<'a, T: Read> Read for CaptureReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let result = self.inner.read(buf);
+        if let Ok(mut capture) = self.capture.lock() {
+            capture.read(buf.len(), &result, buf);
+        }
+        result
+    }
+}
@@ -0,0 +1,147 @@
+//! Structural checks for the Common Media Application Format.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Range;
+
+use MediaContext;
+
+/// The CMAF brand, 'cmfc'.
+const CMAF_BRAND: u32 = 0x636d6663;
+
+/// The CMAF segment brand, 'cmfs'.
+const CMAF_SEGMENT_BRAND: u32 = 0x636d6673;
+
+/// The CMAF chunk brand, 'cmfl'.
+const CMAF_CHUNK_BRAND: u32 = 0x636d666c;
+
+/// Brands identifying a CMAF segment, fragment or chunk:
+/// 'cmfs', 'cmff', 'cmfl' and 'cmfc'.
+const CMAF_SEGMENT_BRANDS: [u32; 4] = [CMAF_SEGMENT_BRAND, 0x636d6666, CMAF_CHUNK_BRAND, CMAF_BRAND];
+
+/// A way in which a file fails to meet CMAF's structural constraints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmafViolation {
+    /// The ftyp does not list the 'cmfc' brand.
+    MissingCmafBrand,
+    /// A CMAF track file must contain exactly one track.
+    TrackCount(usize),
+    /// The moov has no mvex, so the track is not fragmented.
+    MissingMovieExtends,
+    /// The track's sample table in the moov is not empty.
+    SamplesInMovieBox { track: usize },
+    /// The edit list has an empty edit.
+    EmptyEdit { track: usize },
+    /// The edit list has more than one edit.
+    MultipleEdits { track: usize },
+    /// A styp does not list a CMAF segment, fragment or chunk brand.
+    SegmentBrand { segment: usize },
+    /// A movie fragment does not hold exactly one track fragment.
+    FragmentTrackCount { fragment: usize, count: usize },
+    /// A track fragment lacks a 'tfdt' base media decode time.
+    MissingDecodeTime { fragment: usize },
+}
+
+/// A CMAF segment and the chunks it is delivered in.
+///
+/// Low-latency streams split segments into several moof/mdat pairs
+/// so decoding can begin before the whole segment has arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CmafSegment {
+    /// Index into `MediaContext::fragments` of the first chunk.
+    pub first_fragment: usize,
+    /// Byte range of each chunk's moof and mdat, in file order.
+    pub chunks: Vec<Range<u64>>,
+    /// True if the segment has several chunks or its styp carries the
+    /// 'cmfl' brand.
+    pub chunked: bool,
+}
+
+impl MediaContext {
+    /// Check the parsed file against CMAF's structural constraints.
+    ///
+    /// Returns every violation found, in file order. An empty list
+    /// means the structure conforms; codec-specific constraints are
+    /// not checked.
+    pub fn check_cmaf(&self) -> Vec<CmafViolation> {
+        let mut violations = Vec::new();
+        if !self.brands.contains(&CMAF_BRAND) {
+            violations.push(CmafViolation::MissingCmafBrand);
+        }
+        if self.tracks.len() != 1 {
+            violations.push(CmafViolation::TrackCount(self.tracks.len()));
+        }
+        if self.track_extends.is_empty() {
+            violations.push(CmafViolation::MissingMovieExtends);
+        }
+        for (index, track) in self.tracks.iter().enumerate() {
+            if track.stsz.as_ref().map_or(false, |stsz| stsz.sample_count > 0) {
+                violations.push(CmafViolation::SamplesInMovieBox { track: index });
+            }
+            // Only a single edit offsetting the presentation is allowed.
+            if track.empty_duration.map_or(false, |d| d.0 > 0) {
+                violations.push(CmafViolation::EmptyEdit { track: index });
+            }
+            if track.edit_count > 1 {
+                violations.push(CmafViolation::MultipleEdits { track: index });
+            }
+        }
+        for (index, brands) in self.segment_brands.iter().enumerate() {
+            if !brands.iter().any(|b| CMAF_SEGMENT_BRANDS.contains(b)) {
+                violations.push(CmafViolation::SegmentBrand { segment: index });
+            }
+        }
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            if fragment.tracks.len() != 1 {
+                violations.push(CmafViolation::FragmentTrackCount {
+                    fragment: index,
+                    count: fragment.tracks.len(),
+                });
+            }
+            if fragment.tracks.iter().any(|traf| traf.base_decode_time.is_none()) {
+                violations.push(CmafViolation::MissingDecodeTime { fragment: index });
+            }
+        }
+        violations
+    }
+
+    /// Group the movie fragments into segments and their chunks.
+    ///
+    /// A styp starts a new segment unless it only identifies a chunk,
+    /// listing 'cmfl' without 'cmfs'. Fragments which don't follow a
+    /// styp are each taken as a segment of their own. Grouping stops at
+    /// a fragment whose byte range overflows.
+    pub fn cmaf_segments(&self) -> Vec<CmafSegment> {
+        let mut segments: Vec<CmafSegment> = Vec::new();
+        let mut previous = None;
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            let range = match fragment.position.checked_add(fragment.size) {
+                Some(end) => fragment.position..end,
+                None => break,
+            };
+            let brands = fragment.segment
+                .and_then(|segment| self.segment_brands.get(segment))
+                .map_or(&[][..], |brands| &brands[..]);
+            let declares_chunk = brands.contains(&CMAF_CHUNK_BRAND);
+            let new_styp = fragment.segment.is_some() && fragment.segment != previous;
+            let chunk_styp = new_styp && declares_chunk && !brands.contains(&CMAF_SEGMENT_BRAND);
+            previous = fragment.segment;
+            let starts_segment = fragment.segment.is_none() || (new_styp && !chunk_styp);
+            if !starts_segment {
+                if let Some(segment) = segments.last_mut() {
+                    segment.chunks.push(range);
+                    segment.chunked = true;
+                    continue;
+                }
+            }
+            segments.push(CmafSegment {
+                first_fragment: index,
+                chunks: vec![range],
+                chunked: declares_chunk,
+            });
+        }
+        segments
+    }
+}
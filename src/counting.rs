@@ -0,0 +1,66 @@
+//! Measurement of the input read while parsing.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Result, Seek, SeekFrom};
+
+/// Running totals of reads made on a `CountingReader`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoCounters {
+    /// Bytes asked for, the sum of the buffer sizes passed to `read`.
+    pub requested: u64,
+    /// Bytes actually returned by the underlying reader.
+    pub consumed: u64,
+}
+
+impl IoCounters {
+    /// Add the totals from `other`.
+    pub fn add(&mut self, other: IoCounters) {
+        self.requested = self.requested.saturating_add(other.requested);
+        self.consumed = self.consumed.saturating_add(other.consumed);
+    }
+}
+
+/// Wraps a reader, counting the bytes requested from and returned by
+/// it. Seeks are passed through uncounted, so parsing with
+/// `read_mp4_seekable` shows how much media data was skipped rather
+/// than read.
+pub struct CountingReader<T> {
+    inner: T,
+    counters: IoCounters,
+}
+
+impl<T> CountingReader<T> {
+    pub fn new(inner: T) -> CountingReader<T> {
+        CountingReader {
+            inner: inner,
+            counters: IoCounters::default(),
+        }
+    }
+
+    /// Totals since the reader was created.
+    pub fn counters(&self) -> IoCounters {
+        self.counters
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for CountingReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.counters.requested = self.counters.requested.saturating_add(buf.len() as u64);
+        let n = try!(self.inner.read(buf));
+        self.counters.consumed = self.counters.consumed.saturating_add(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T: Seek> Seek for CountingReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
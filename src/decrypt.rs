@@ -0,0 +1,425 @@
+//! Decryption of Common Encryption protected files with known keys.
+//!
+//! This is meant for test pipelines which need clear media from
+//! 'cenc', 'cens', 'cbc1' or 'cbcs' protected files whose keys they
+//! hold. Samples are decrypted in place and the protection boxes are
+//! neutralized without changing any box sizes, so the file layout and
+//! every sample offset are preserved.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::{Read, Seek, SeekFrom, Write};
+use aes::Aes128;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use byteorder::{BigEndian, ByteOrder};
+
+use BoxType;
+use Error;
+use FullSample;
+use MediaContext;
+use ParseMode;
+use Result;
+use SampleCrypto;
+use TopLevelBoxIter;
+use Track;
+use TrackEncryptionBox;
+use read_mp4_seekable;
+
+const CENC: u32 = 0x63656e63;
+const CENS: u32 = 0x63656e73;
+const CBC1: u32 = 0x63626331;
+const CBCS: u32 = 0x63626373;
+
+// Arbitrary limits on the moov, moof and sample sizes we're prepared
+// to hold in memory.
+const BOX_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
+const SAMPLE_SIZE_LIMIT: u32 = 256 * 1024 * 1024;
+
+const FREE: u32 = 0x66726565;
+const SINF: u32 = 0x73696e66;
+const FRMA: u32 = 0x66726d61;
+const STSD: u32 = 0x73747364;
+const ENCV: u32 = 0x656e6376;
+const ENCA: u32 = 0x656e6361;
+const SGPD: u32 = 0x73677064;
+const SBGP: u32 = 0x73626770;
+const SEIG: u32 = 0x73656967;
+
+/// Boxes holding the sample tables and sample descriptions, which are
+/// searched for protection boxes.
+const CONTAINERS: [u32; 7] = [
+    0x6d6f6f76, // "moov"
+    0x7472616b, // "trak"
+    0x6d646961, // "mdia"
+    0x6d696e66, // "minf"
+    0x7374626c, // "stbl"
+    0x6d6f6f66, // "moof"
+    0x74726166, // "traf"
+];
+
+/// Boxes which only carry protection information: 'pssh', 'senc',
+/// 'saiz' and 'saio'.
+const PROTECTION_BOXES: [u32; 4] = [0x70737368, 0x73656e63, 0x7361697a, 0x7361696f];
+
+/// A content key and the key ID it's used under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentKey {
+    pub kid: [u8; 16],
+    pub key: [u8; 16],
+}
+
+/// AES-128 counter mode, with the counter in the low 64 bits of the
+/// IV as Common Encryption specifies.
+struct Ctr {
+    cipher: Aes128,
+    counter: [u8; 16],
+    keystream: [u8; 16],
+    used: usize,
+}
+
+impl Ctr {
+    fn new(cipher: Aes128, iv: [u8; 16]) -> Ctr {
+        Ctr {
+            cipher: cipher,
+            counter: iv,
+            keystream: [0; 16],
+            used: 16,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 16 {
+                let mut block = GenericArray::clone_from_slice(&self.counter);
+                self.cipher.encrypt_block(&mut block);
+                self.keystream.copy_from_slice(&block);
+                let low = BigEndian::read_u64(&self.counter[8..]).wrapping_add(1);
+                BigEndian::write_u64(&mut self.counter[8..], low);
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// AES-128 CBC, chaining from block to block.
+struct Cbc {
+    cipher: Aes128,
+    chain: [u8; 16],
+    encrypt: bool,
+}
+
+impl Cbc {
+    /// Encrypt or decrypt one 16-byte block.
+    fn apply(&mut self, block: &mut [u8]) {
+        if self.encrypt {
+            for (byte, c) in block.iter_mut().zip(self.chain.iter()) {
+                *byte ^= *c;
+            }
+            let mut cipher_block = GenericArray::clone_from_slice(block);
+            self.cipher.encrypt_block(&mut cipher_block);
+            block.copy_from_slice(&cipher_block);
+            self.chain.copy_from_slice(block);
+            return;
+        }
+        let mut next = [0; 16];
+        next.copy_from_slice(block);
+        let mut plain = GenericArray::clone_from_slice(block);
+        self.cipher.decrypt_block(&mut plain);
+        for (byte, (p, c)) in block.iter_mut().zip(plain.iter().zip(self.chain.iter())) {
+            *byte = p ^ c;
+        }
+        self.chain = next;
+    }
+}
+
+/// The protected ranges of a sample of `size` bytes.
+fn protected_ranges(crypto: &SampleCrypto, size: usize) -> Result<Vec<std::ops::Range<usize>>> {
+    if crypto.subsamples.is_empty() {
+        return Ok(vec![0..size]);
+    }
+    let mut ranges = Vec::with_capacity(crypto.subsamples.len());
+    let mut offset = 0usize;
+    for subsample in &crypto.subsamples {
+        let start = offset + subsample.clear_bytes as usize;
+        let end = match start.checked_add(subsample.encrypted_bytes as usize) {
+            Some(end) if end <= size => end,
+            _ => return Err(Error::InvalidData("subsamples exceed sample size")),
+        };
+        ranges.push(start..end);
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+/// Decrypt one sample in place.
+///
+/// `scheme` is the four character code from the 'schm' and the pattern
+/// is from the 'tenc'; a pattern of 0:0 means every block of the
+/// protected ranges is encrypted. With a pattern or a CBC scheme only
+/// whole 16-byte blocks are encrypted, leaving any partial block at
+/// the end of a range clear.
+pub fn decrypt_sample(data: &mut [u8], crypto: &SampleCrypto, scheme: u32,
+                      crypt_byte_block: u8, skip_byte_block: u8, key: &[u8; 16]) -> Result<()> {
+    apply_cipher(data, crypto, scheme, crypt_byte_block, skip_byte_block, key, false)
+}
+
+/// Encrypt one sample in place, the reverse of `decrypt_sample`.
+#[cfg(feature = "encrypt")]
+pub fn encrypt_sample(data: &mut [u8], crypto: &SampleCrypto, scheme: u32,
+                      crypt_byte_block: u8, skip_byte_block: u8, key: &[u8; 16]) -> Result<()> {
+    apply_cipher(data, crypto, scheme, crypt_byte_block, skip_byte_block, key, true)
+}
+
+fn apply_cipher(data: &mut [u8], crypto: &SampleCrypto, scheme: u32, crypt_byte_block: u8,
+                skip_byte_block: u8, key: &[u8; 16], encrypt: bool) -> Result<()> {
+    let mut iv = [0; 16];
+    match crypto.iv.len() {
+        8 | 16 => iv[..crypto.iv.len()].copy_from_slice(&crypto.iv),
+        0 => return Err(Error::Unsupported("sample IV not available")),
+        _ => return Err(Error::InvalidData("invalid sample IV size")),
+    }
+    let ranges = try!(protected_ranges(crypto, data.len()));
+    let cipher = Aes128::new(GenericArray::from_slice(&key[..]));
+    // Encrypted and skipped blocks per pattern repetition.
+    let pattern = if crypt_byte_block > 0 && skip_byte_block > 0 {
+        Some((crypt_byte_block as usize, skip_byte_block as usize))
+    } else {
+        None
+    };
+
+    match scheme {
+        CENC | CENS => {
+            // The counter runs on across the protected ranges.
+            let mut ctr = Ctr::new(cipher, iv);
+            for range in ranges {
+                let data = &mut data[range];
+                match pattern {
+                    None if scheme == CENC => ctr.apply(data),
+                    _ => {
+                        let (crypt, skip) = pattern.unwrap_or((1, 0));
+                        for (i, block) in data.chunks_mut(16).enumerate() {
+                            if block.len() == 16 && i % (crypt + skip) < crypt {
+                                ctr.apply(block);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        CBC1 | CBCS => {
+            // 'cbc1' chains across the protected ranges, while 'cbcs'
+            // restarts from the IV in each.
+            let mut cbc = Cbc { cipher: cipher, chain: iv, encrypt: encrypt };
+            for range in ranges {
+                if scheme == CBCS {
+                    cbc.chain = iv;
+                }
+                let (crypt, skip) = pattern.unwrap_or((1, 0));
+                for (i, block) in data[range].chunks_mut(16).enumerate() {
+                    if block.len() == 16 && i % (crypt + skip) < crypt {
+                        cbc.apply(block);
+                    }
+                }
+            }
+        }
+        _ => return Err(Error::Unsupported("unknown protection scheme")),
+    }
+    Ok(())
+}
+
+/// A change to make while copying the input.
+enum Edit<'a> {
+    /// Replace the four character code at this position.
+    Rename(u32),
+    /// Decrypt a sample of a track, with the encryption parameters of
+    /// its 'seig' group or the 'tenc'.
+    Sample(&'a Track, &'a FullSample, &'a SampleCrypto, &'a TrackEncryptionBox),
+}
+
+/// Iterate over the boxes in `data`, yielding the offset of each box
+/// in `data`, its type, and its payload's offset and length.
+fn for_each_box<F>(data: &[u8], mut f: F) -> Result<()>
+    where F: FnMut(usize, u32, usize, usize) -> Result<()>
+{
+    let mut offset = 0;
+    while data.len() - offset >= 8 {
+        let size32 = BigEndian::read_u32(&data[offset..]);
+        let name = BigEndian::read_u32(&data[offset + 4..]);
+        let (size, header_len): (u64, usize) = match size32 {
+            0 => ((data.len() - offset) as u64, 8),
+            1 if data.len() - offset >= 16 => (BigEndian::read_u64(&data[offset + 8..]), 16),
+            1 => return Err(Error::InvalidData("truncated box header")),
+            _ => (size32 as u64, 8),
+        };
+        if size < header_len as u64 || size > (data.len() - offset) as u64 {
+            return Err(Error::InvalidData("malformed box size"));
+        }
+        try!(f(offset, name, offset + header_len, size as usize - header_len));
+        offset += size as usize;
+    }
+    Ok(())
+}
+
+/// Find the renames which remove the protection from the sample
+/// descriptions and boxes in `data`, which is found at `base` in the
+/// input.
+fn find_renames(data: &[u8], base: u64, edits: &mut Vec<(u64, Edit)>) -> Result<()> {
+    for_each_box(data, |offset, name, start, len| {
+        let payload = &data[start..start + len];
+        let type_position = base + offset as u64 + 4;
+        if CONTAINERS.contains(&name) {
+            try!(find_renames(payload, base + start as u64, edits));
+        } else if name == STSD && len >= 8 {
+            let entries_base = base + start as u64 + 8;
+            try!(for_each_box(&payload[8..], |offset, name, start, len| {
+                let entry = &payload[8 + start..8 + start + len];
+                let fields = match name {
+                    ENCV => 78,
+                    ENCA if entry.len() >= 10 => match BigEndian::read_u16(&entry[8..]) {
+                        0 => 28,
+                        1 => 44,
+                        2 => 64,
+                        _ => return Err(Error::Unsupported("unknown audio sample entry version")),
+                    },
+                    _ => return Ok(()),
+                };
+                if entry.len() < fields {
+                    return Err(Error::InvalidData("truncated protected sample entry"));
+                }
+                let children_base = entries_base + start as u64 + fields as u64;
+                let mut original = None;
+                try!(for_each_box(&entry[fields..], |offset, name, start, len| {
+                    if name == SINF {
+                        let sinf = &entry[fields + start..fields + start + len];
+                        try!(for_each_box(sinf, |_, name, start, len| {
+                            if name == FRMA && len >= 4 {
+                                original = Some(BigEndian::read_u32(&sinf[start..]));
+                            }
+                            Ok(())
+                        }));
+                        edits.push((children_base + offset as u64 + 4, Edit::Rename(FREE)));
+                    }
+                    Ok(())
+                }));
+                match original {
+                    Some(original) => {
+                        edits.push((entries_base + offset as u64 + 4, Edit::Rename(original)));
+                        Ok(())
+                    }
+                    None => Err(Error::InvalidData("protected sample entry without frma")),
+                }
+            }));
+        } else if PROTECTION_BOXES.contains(&name) {
+            edits.push((type_position, Edit::Rename(FREE)));
+        } else if (name == SGPD || name == SBGP) && len >= 8 &&
+                  BigEndian::read_u32(&payload[4..]) == SEIG {
+            // The key rotation sample group.
+            edits.push((type_position, Edit::Rename(FREE)));
+        }
+        Ok(())
+    })
+}
+
+fn copy_range<R: Read, W: Write>(src: &mut R, dst: &mut W, len: u64) -> Result<()> {
+    let copied = try!(std::io::copy(&mut Read::by_ref(src).take(len), dst));
+    if copied != len {
+        return Err(Error::UnexpectedEOF);
+    }
+    Ok(())
+}
+
+/// Write a clear copy of a protected file using the given keys.
+///
+/// Every encrypted sample, in the sample tables and in movie
+/// fragments, is decrypted. Protected sample entries are renamed to
+/// their original format from 'frma', and their 'sinf' along with the
+/// 'pssh', 'senc', 'saiz', 'saio' and 'seig' sample group boxes are
+/// turned into 'free' boxes of the same size.
+///
+/// Each sample's key ID and pattern come from its 'seig' sample group
+/// in the 'stbl', if any, otherwise from the 'tenc'. Sample groups in
+/// movie fragments aren't applied, and fragments whose IVs are only
+/// located by 'saio' aren't supported. A sample whose key ID has no
+/// key fails with `Error::Unsupported`.
+pub fn decrypt_to_clear<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W, keys: &[ContentKey]) -> Result<()> {
+    let mut context = MediaContext::new();
+    context.mode = ParseMode::Full;
+    try!(read_mp4_seekable(src, &mut context));
+
+    let mut edits = Vec::new();
+    let mut boxes = Vec::new();
+    for node in TopLevelBoxIter::new(src) {
+        let node = try!(node);
+        if node.name == BoxType::MovieBox || node.name == BoxType::MovieFragmentBox {
+            boxes.push(node);
+        }
+    }
+    for node in &boxes {
+        if node.size > BOX_SIZE_LIMIT {
+            return Err(Error::InvalidData("box exceeds BOX_SIZE_LIMIT"));
+        }
+        let mut data = Vec::with_capacity(node.size as usize);
+        try!(src.seek(SeekFrom::Start(node.position)));
+        try!(copy_range(src, &mut data, node.size));
+        try!(find_renames(&data, node.position, &mut edits));
+    }
+
+    for track in &context.tracks {
+        for (index, sample) in track.samples.iter().flat_map(|samples| samples.iter()).enumerate() {
+            if let Some(ref crypto) = sample.crypto {
+                let defaults = match try!(track.encryption_defaults(index)) {
+                    Some(defaults) => defaults,
+                    None => return Err(Error::InvalidData("protected track without tenc")),
+                };
+                edits.push((sample.info.offset, Edit::Sample(track, sample, crypto, defaults)));
+            }
+        }
+    }
+    edits.sort_by_key(|edit| edit.0);
+
+    try!(src.seek(SeekFrom::Start(0)));
+    let mut position = 0;
+    for (offset, edit) in edits {
+        if offset < position {
+            return Err(Error::InvalidData("overlapping protected data"));
+        }
+        try!(copy_range(src, dst, offset - position));
+        match edit {
+            Edit::Rename(name) => {
+                try!(src.seek(SeekFrom::Current(4)));
+                let mut fourcc = [0; 4];
+                BigEndian::write_u32(&mut fourcc, name);
+                try!(dst.write_all(&fourcc));
+                position = offset + 4;
+            }
+            Edit::Sample(track, sample, crypto, defaults) => {
+                let sinf = track.protection_info.as_ref();
+                let scheme = match sinf.and_then(|sinf| sinf.scheme_type) {
+                    Some(scheme) => scheme,
+                    None => return Err(Error::InvalidData("protected track without schm")),
+                };
+                let key = match keys.iter().find(|k| k.kid[..] == crypto.kid[..]) {
+                    Some(key) => key,
+                    None => return Err(Error::Unsupported("no key for a sample's key ID")),
+                };
+                if sample.info.size > SAMPLE_SIZE_LIMIT {
+                    return Err(Error::InvalidData("sample exceeds SAMPLE_SIZE_LIMIT"));
+                }
+                let mut data = vec![0; sample.info.size as usize];
+                try!(src.read_exact(&mut data));
+                try!(decrypt_sample(&mut data, crypto, scheme,
+                                    defaults.crypt_byte_block, defaults.skip_byte_block, &key.key));
+                try!(dst.write_all(&data));
+                position = offset + sample.info.size as u64;
+            }
+        }
+    }
+    try!(std::io::copy(src, dst));
+    Ok(())
+}
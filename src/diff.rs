@@ -0,0 +1,123 @@
+//! Structural comparison of two parsed files.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use MediaContext;
+use Track;
+
+/// A property which differs between two files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Index of the track concerned, or None for movie-level properties.
+    pub track: Option<usize>,
+    pub field: &'static str,
+    /// Debug representations of the values in each file.
+    pub left: String,
+    pub right: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.track {
+            Some(track) => try!(write!(f, "track {} ", track)),
+            None => try!(write!(f, "movie ")),
+        }
+        write!(f, "{}: {} != {}", self.field, self.left, self.right)
+    }
+}
+
+struct Differences {
+    track: Option<usize>,
+    list: Vec<Difference>,
+}
+
+impl Differences {
+    fn check<T: fmt::Debug + PartialEq>(&mut self, field: &'static str, left: T, right: T) {
+        if left != right {
+            self.list.push(Difference {
+                track: self.track,
+                field: field,
+                left: format!("{:?}", left),
+                right: format!("{:?}", right),
+            });
+        }
+    }
+}
+
+/// Summary of a track's samples, if its sample tables are usable.
+struct SampleSummary {
+    count: usize,
+    duration: u64,
+    sizes: Vec<u32>,
+    sync: Vec<usize>,
+}
+
+fn summarize_samples(track: &Track) -> Option<SampleSummary> {
+    let table = match track.sample_table() {
+        Ok(table) => table,
+        Err(_) => return None,
+    };
+    Some(SampleSummary {
+        count: table.len(),
+        duration: table.iter().map(|s| s.duration as u64).sum(),
+        sizes: table.iter().map(|s| s.size).collect(),
+        sync: table.iter().enumerate().filter(|&(_, s)| s.sync).map(|(i, _)| i).collect(),
+    })
+}
+
+fn compare_tracks(diffs: &mut Differences, left: &Track, right: &Track) {
+    diffs.check("track type", &left.track_type, &right.track_type);
+    diffs.check("codec", &left.mime_type, &right.mime_type);
+    diffs.check("track id", left.track_id, right.track_id);
+    diffs.check("timescale", left.timescale.map(|t| t.0), right.timescale.map(|t| t.0));
+    diffs.check("duration", left.duration.map(|t| t.0), right.duration.map(|t| t.0));
+    diffs.check("empty duration", left.empty_duration, right.empty_duration);
+    diffs.check("media time", left.media_time.map(|t| t.0), right.media_time.map(|t| t.0));
+    diffs.check("edit duration", left.edit_duration, right.edit_duration);
+    diffs.check("enabled", left.enabled, right.enabled);
+    diffs.check("in movie", left.in_movie, right.in_movie);
+    diffs.check("in preview", left.in_preview, right.in_preview);
+    if let (Some(l), Some(r)) = (left.tkhd.as_ref(), right.tkhd.as_ref()) {
+        diffs.check("width", l.width, r.width);
+        diffs.check("height", l.height, r.height);
+        diffs.check("volume", l.volume, r.volume);
+    }
+    match (summarize_samples(left), summarize_samples(right)) {
+        (Some(l), Some(r)) => {
+            diffs.check("sample count", l.count, r.count);
+            diffs.check("sample duration", l.duration, r.duration);
+            // Report only the first differing sample size.
+            if let Some(i) = l.sizes.iter().zip(r.sizes.iter()).position(|(a, b)| a != b) {
+                diffs.check("sample size", (i, l.sizes[i]), (i, r.sizes[i]));
+            }
+            diffs.check("sync samples", l.sync.len(), r.sync.len());
+        }
+        (l, r) => diffs.check("sample table", l.is_some(), r.is_some()),
+    }
+}
+
+/// Compare two parsed files, listing the properties which differ.
+///
+/// Movie-level properties are compared first, then tracks pairwise
+/// by index. Media data itself is not read, so changes which preserve
+/// every sample's size and timing are not detected.
+pub fn compare(left: &MediaContext, right: &MediaContext) -> Vec<Difference> {
+    let mut diffs = Differences { track: None, list: Vec::new() };
+    diffs.check("timescale", left.timescale, right.timescale);
+    diffs.check("duration", left.duration, right.duration);
+    diffs.check("rate", left.rate, right.rate);
+    diffs.check("volume", left.volume, right.volume);
+    diffs.check("features", left.features, right.features);
+    diffs.check("brands", &left.brands, &right.brands);
+    diffs.check("fragment count", left.fragments.len(), right.fragments.len());
+    diffs.check("track count", left.tracks.len(), right.tracks.len());
+    for (index, (l, r)) in left.tracks.iter().zip(right.tracks.iter()).enumerate() {
+        diffs.track = Some(index);
+        compare_tracks(&mut diffs, l, r);
+    }
+    diffs.list
+}
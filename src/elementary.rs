@@ -0,0 +1,406 @@
+//! Conversion of track samples to elementary stream formats.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use AudioCodecSpecific;
+use AudioSampleEntry;
+use BitReader;
+use Error;
+use FLACSpecificBox;
+use OpusSpecificBox;
+use Result;
+use SampleInfo;
+use serialize_opus_header;
+use BUF_SIZE_LIMIT;
+
+const ANNEXB_START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Build the header of a native FLAC stream from a track's 'dfLa': the
+/// stream marker and the metadata blocks, the last flagged as such.
+/// The track's samples are FLAC frames which follow it unchanged.
+pub fn flac_stream_header(dfla: &FLACSpecificBox) -> Result<Vec<u8>> {
+    let mut header = b"fLaC".to_vec();
+    for (i, block) in dfla.blocks.iter().enumerate() {
+        if block.data.len() >= 1 << 24 {
+            return Err(Error::InvalidData("FLAC metadata block too large"));
+        }
+        let last = if i + 1 == dfla.blocks.len() { 0x80 } else { 0 };
+        header.push(last | block.block_type);
+        header.push((block.data.len() >> 16) as u8);
+        header.push((block.data.len() >> 8) as u8);
+        header.push(block.data.len() as u8);
+        header.extend_from_slice(&block.data);
+    }
+    Ok(header)
+}
+
+/// Read the data for `sample` from the file it was parsed from.
+pub fn read_sample<T: Read + Seek>(src: &mut T, sample: &SampleInfo) -> Result<Vec<u8>> {
+    if sample.size as u64 > BUF_SIZE_LIMIT {
+        return Err(Error::InvalidData("sample exceeds BUF_SIZE_LIMIT"));
+    }
+    try!(src.seek(SeekFrom::Start(sample.offset)));
+    let mut buf = vec![0; sample.size as usize];
+    try!(src.read_exact(&mut buf));
+    Ok(buf)
+}
+
+/// Decoder setup from an 'avcC' box for Annex B output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnexBConfig {
+    /// Size in bytes of the length prefix on each sample NAL unit.
+    pub nal_length_size: usize,
+    /// SPS and PPS NAL units, each preceded by a start code.
+    pub parameter_sets: Vec<u8>,
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(Error::InvalidData("truncated codec data"));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+fn take_u8(data: &mut &[u8]) -> Result<u8> {
+    Ok(try!(take(data, 1))[0])
+}
+
+fn take_u16(data: &mut &[u8]) -> Result<u16> {
+    let bytes = try!(take(data, 2));
+    Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+/// Parse the contents of an 'avcC' box for Annex B conversion.
+pub fn avcc_to_annexb(avcc: &[u8]) -> Result<AnnexBConfig> {
+    let mut data = avcc;
+    // Skip configurationVersion and profile/level indication.
+    try!(take(&mut data, 4));
+    let nal_length_size = (try!(take_u8(&mut data)) & 0x3) as usize + 1;
+    let mut parameter_sets = Vec::new();
+    let sps_count = try!(take_u8(&mut data)) & 0x1f;
+    for _ in 0..sps_count {
+        let len = try!(take_u16(&mut data)) as usize;
+        parameter_sets.extend_from_slice(&ANNEXB_START_CODE);
+        parameter_sets.extend_from_slice(try!(take(&mut data, len)));
+    }
+    let pps_count = try!(take_u8(&mut data));
+    for _ in 0..pps_count {
+        let len = try!(take_u16(&mut data)) as usize;
+        parameter_sets.extend_from_slice(&ANNEXB_START_CODE);
+        parameter_sets.extend_from_slice(try!(take(&mut data, len)));
+    }
+    Ok(AnnexBConfig {
+        nal_length_size: nal_length_size,
+        parameter_sets: parameter_sets,
+    })
+}
+
+/// Replace the length prefixes of a sample's NAL units with start codes.
+pub fn sample_to_annexb(sample: &[u8], nal_length_size: usize) -> Result<Vec<u8>> {
+    if nal_length_size < 1 || nal_length_size > 4 {
+        return Err(Error::InvalidData("invalid NAL length size"));
+    }
+    let mut data = sample;
+    let mut out = Vec::with_capacity(sample.len() + 16);
+    while !data.is_empty() {
+        let len = try!(take(&mut data, nal_length_size))
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        out.extend_from_slice(&ANNEXB_START_CODE);
+        out.extend_from_slice(try!(take(&mut data, len)));
+    }
+    Ok(out)
+}
+
+/// Read an MPEG-4 descriptor header, returning the tag and size.
+fn take_descriptor(data: &mut &[u8]) -> Result<(u8, usize)> {
+    let tag = try!(take_u8(data));
+    let mut size = 0usize;
+    // The size is coded in up to four bytes, 7 bits at a time.
+    for _ in 0..4 {
+        let b = try!(take_u8(data));
+        size = size << 7 | (b & 0x7f) as usize;
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok((tag, size))
+}
+
+/// Decoder configuration from an 'esds' ES_Descriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ESDescriptor<'a> {
+    /// 0x40 for MPEG-4 audio such as AAC, 0x69 or 0x6b for MP3.
+    pub object_type_indication: u8,
+    /// Bitrates in bits per second, or 0 if unknown.
+    pub max_bitrate: u32,
+    pub avg_bitrate: u32,
+    /// The DecoderSpecificInfo, which for MPEG-4 audio is the
+    /// AudioSpecificConfig. Empty if there is none.
+    pub decoder_specific_info: &'a [u8],
+}
+
+/// Fields of an MPEG-4 AudioSpecificConfig.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSpecificConfig {
+    /// Object type of the core codec, 2 for AAC LC.
+    pub audio_object_type: u8,
+    /// 5 for SBR or 29 for parametric stereo, if explicitly signalled.
+    pub extension_object_type: Option<u8>,
+    /// Sample rate of the core codec.
+    pub sample_rate: u32,
+    /// Output sample rate with the extension, if signalled.
+    pub extension_sample_rate: Option<u32>,
+    /// Channel layout index; 0 means it's given elsewhere.
+    pub channel_configuration: u8,
+}
+
+/// Rates selected by samplingFrequencyIndex.
+const AAC_SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000,
+                                     24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+/// Parse the contents of an 'esds' box.
+pub fn parse_esds(esds: &[u8]) -> Result<ESDescriptor> {
+    let mut data = esds;
+    let (tag, _) = try!(take_descriptor(&mut data));
+    if tag != 0x03 {
+        return Err(Error::InvalidData("expected ES_Descriptor"));
+    }
+    // Skip ES_ID.
+    try!(take(&mut data, 2));
+    let flags = try!(take_u8(&mut data));
+    if flags & 0x80 != 0 {
+        // Skip dependsOn_ES_ID.
+        try!(take(&mut data, 2));
+    }
+    if flags & 0x40 != 0 {
+        let url_len = try!(take_u8(&mut data)) as usize;
+        try!(take(&mut data, url_len));
+    }
+    if flags & 0x20 != 0 {
+        // Skip OCR_ES_Id.
+        try!(take(&mut data, 2));
+    }
+    let (tag, _) = try!(take_descriptor(&mut data));
+    if tag != 0x04 {
+        return Err(Error::InvalidData("expected DecoderConfigDescriptor"));
+    }
+    let object_type_indication = try!(take_u8(&mut data));
+    // Skip stream type and buffer size.
+    try!(take(&mut data, 4));
+    let max_bitrate = (try!(take_u16(&mut data)) as u32) << 16 | try!(take_u16(&mut data)) as u32;
+    let avg_bitrate = (try!(take_u16(&mut data)) as u32) << 16 | try!(take_u16(&mut data)) as u32;
+    // The DecoderSpecificInfo is optional.
+    let decoder_specific_info = match data.first() {
+        Some(&0x05) => {
+            let (_, size) = try!(take_descriptor(&mut data));
+            try!(take(&mut data, size))
+        }
+        _ => &[],
+    };
+    Ok(ESDescriptor {
+        object_type_indication: object_type_indication,
+        max_bitrate: max_bitrate,
+        avg_bitrate: avg_bitrate,
+        decoder_specific_info: decoder_specific_info,
+    })
+}
+
+/// Find the AudioSpecificConfig in an 'esds' ES_Descriptor.
+pub fn esds_audio_specific_config(esds: &[u8]) -> Result<Vec<u8>> {
+    let descriptor = try!(parse_esds(esds));
+    if descriptor.decoder_specific_info.is_empty() {
+        return Err(Error::InvalidData("expected DecoderSpecificInfo"));
+    }
+    Ok(descriptor.decoder_specific_info.to_vec())
+}
+
+fn read_audio_object_type(bits: &mut BitReader) -> Result<u8> {
+    match try!(bits.read_bits(5)) as u8 {
+        31 => Ok(32 + try!(bits.read_bits(6)) as u8),
+        object_type => Ok(object_type),
+    }
+}
+
+fn read_sampling_frequency(bits: &mut BitReader) -> Result<u32> {
+    match try!(bits.read_bits(4)) as usize {
+        0xf => Ok(try!(bits.read_bits(24)) as u32),
+        index => match AAC_SAMPLE_RATES.get(index) {
+            Some(rate) => Ok(*rate),
+            None => Err(Error::InvalidData("reserved samplingFrequencyIndex")),
+        },
+    }
+}
+
+/// Parse the start of an AudioSpecificConfig, up to the core codec's
+/// object type.
+pub fn parse_audio_specific_config(config: &[u8]) -> Result<AudioSpecificConfig> {
+    let mut bits = BitReader::new(config);
+    let mut audio_object_type = try!(read_audio_object_type(&mut bits));
+    let sample_rate = try!(read_sampling_frequency(&mut bits));
+    let channel_configuration = try!(bits.read_bits(4)) as u8;
+    let (extension_object_type, extension_sample_rate) = match audio_object_type {
+        // Explicit hierarchical signalling: the extension comes first.
+        5 | 29 => {
+            let extension = audio_object_type;
+            let extension_sample_rate = try!(read_sampling_frequency(&mut bits));
+            audio_object_type = try!(read_audio_object_type(&mut bits));
+            (Some(extension), Some(extension_sample_rate))
+        }
+        _ => (None, None),
+    };
+    Ok(AudioSpecificConfig {
+        audio_object_type: audio_object_type,
+        extension_object_type: extension_object_type,
+        sample_rate: sample_rate,
+        extension_sample_rate: extension_sample_rate,
+        channel_configuration: channel_configuration,
+    })
+}
+
+impl AudioSampleEntry {
+    /// Parse the entry's 'esds', if it has one.
+    pub fn es_descriptor(&self) -> Result<Option<ESDescriptor>> {
+        match self.codec_specific {
+            AudioCodecSpecific::ES_Descriptor(ref esds) => parse_esds(esds).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse the AudioSpecificConfig from the entry's 'esds', if it
+    /// has one and carries MPEG-4 audio.
+    pub fn audio_specific_config(&self) -> Result<Option<AudioSpecificConfig>> {
+        match try!(self.es_descriptor()) {
+            Some(ref descriptor) if descriptor.object_type_indication == 0x40 &&
+                                    !descriptor.decoder_specific_info.is_empty() => {
+                parse_audio_specific_config(descriptor.decoder_specific_info).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Build an ADTS header for an AAC frame of `frame_size` bytes.
+pub fn adts_header(audio_specific_config: &[u8], frame_size: usize) -> Result<[u8; 7]> {
+    if audio_specific_config.len() < 2 {
+        return Err(Error::InvalidData("truncated AudioSpecificConfig"));
+    }
+    let object_type = audio_specific_config[0] >> 3;
+    let frequency_index = (audio_specific_config[0] & 0x7) << 1 | audio_specific_config[1] >> 7;
+    let channel_config = (audio_specific_config[1] >> 3) & 0xf;
+    // ADTS only carries object types 1 to 4 and indexed sample rates.
+    if object_type < 1 || object_type > 4 || frequency_index > 12 {
+        return Err(Error::Unsupported("audio config not representable in ADTS"));
+    }
+    let length = frame_size + 7;
+    if length > 0x1fff {
+        return Err(Error::InvalidData("frame too large for ADTS"));
+    }
+    Ok([0xff,
+        0xf1, // MPEG-4, layer 0, no CRC.
+        (object_type - 1) << 6 | frequency_index << 2 | channel_config >> 2,
+        (channel_config & 0x3) << 6 | (length >> 11) as u8,
+        (length >> 3) as u8,
+        ((length & 0x7) << 5) as u8 | 0x1f,
+        0xfc])
+}
+
+pub fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                crc << 1 ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Write Opus packets to an Ogg stream, one packet per page.
+pub struct OggOpusWriter<W: Write> {
+    dst: W,
+    serial: u32,
+    sequence: u32,
+    granule: u64,
+    /// The last packet written, held back to mark the end of stream.
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Start an Ogg Opus stream, writing the OpusHead and OpusTags pages.
+    pub fn new(dst: W, serial: u32, opus: &OpusSpecificBox) -> Result<OggOpusWriter<W>> {
+        let mut writer = OggOpusWriter {
+            dst: dst,
+            serial: serial,
+            sequence: 0,
+            granule: 0,
+            pending: None,
+        };
+        let mut head = Vec::new();
+        try!(serialize_opus_header(opus, &mut head));
+        try!(writer.write_page(&head, 0x02, 0));
+        let vendor = b"mp4parse";
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        try!(tags.write_u32::<LittleEndian>(vendor.len() as u32));
+        tags.extend_from_slice(vendor);
+        try!(tags.write_u32::<LittleEndian>(0));
+        try!(writer.write_page(&tags, 0, 0));
+        Ok(writer)
+    }
+
+    /// Add a packet decoding to `duration` samples at 48 kHz.
+    pub fn write_packet(&mut self, packet: &[u8], duration: u64) -> Result<()> {
+        if let Some((previous, granule)) = self.pending.take() {
+            try!(self.write_page(&previous, 0, granule));
+        }
+        self.granule += duration;
+        self.pending = Some((packet.to_vec(), self.granule));
+        Ok(())
+    }
+
+    /// Write the final page and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some((last, granule)) = self.pending.take() {
+            try!(self.write_page(&last, 0x04, granule));
+        }
+        Ok(self.dst)
+    }
+
+    fn write_page(&mut self, packet: &[u8], header_type: u8, granule: u64) -> Result<()> {
+        let segments = packet.len() / 255 + 1;
+        if segments > 255 {
+            return Err(Error::Unsupported("packet too large for one ogg page"));
+        }
+        let mut page = Vec::with_capacity(27 + segments + packet.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(header_type);
+        try!(page.write_u64::<LittleEndian>(granule));
+        try!(page.write_u32::<LittleEndian>(self.serial));
+        try!(page.write_u32::<LittleEndian>(self.sequence));
+        try!(page.write_u32::<LittleEndian>(0)); // crc, filled in below
+        page.push(segments as u8);
+        for _ in 0..segments - 1 {
+            page.push(255);
+        }
+        page.push((packet.len() % 255) as u8);
+        page.extend_from_slice(packet);
+        let crc = ogg_crc(&page);
+        try!((&mut page[22..26]).write_u32::<LittleEndian>(crc));
+        try!(self.dst.write_all(&page));
+        self.sequence += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,481 @@
+//! Common Encryption packaging of clear files with known keys.
+//!
+//! This is the counterpart of `decrypt_to_clear`, for making protected
+//! test content: the samples of the chosen tracks are encrypted in
+//! place under the 'cenc' or 'cbcs' scheme, and the moov gains the
+//! boxes describing their protection.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::{Cursor, Read, Seek, Write};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use decrypt::{encrypt_sample, ContentKey};
+use faststart::{copy_box, header_size, node_size, parse_children, write_header, write_node, Node,
+                MOOV_SIZE_LIMIT};
+use remux::{children_mut, copy_range, fit_tables, fullbox_leaf, leaf_mut, map_offset, name, track_id,
+            OutputBox};
+use elementary::read_sample;
+use parse_avcc;
+use parse_hvcc;
+use read_mp4;
+use BoxType;
+use Error;
+use MediaContext;
+use ProtectionSystemSpecificHeaderBox;
+use Result;
+use SampleCrypto;
+use SampleEntry;
+use SampleInfo;
+use Subsample;
+use TopLevelBoxIter;
+use VideoCodecSpecific;
+
+const CENC: u32 = 0x63656e63;
+const CBCS: u32 = 0x63626373;
+
+const MDAT: u32 = 0x6d646174;
+const MDIA: u32 = 0x6d646961;
+const MINF: u32 = 0x6d696e66;
+const STBL: u32 = 0x7374626c;
+const STSD: u32 = 0x73747364;
+const ENCV: u32 = 0x656e6376;
+const ENCA: u32 = 0x656e6361;
+const SINF: u32 = 0x73696e66;
+const FRMA: u32 = 0x66726d61;
+const SCHM: u32 = 0x7363686d;
+const SCHI: u32 = 0x73636869;
+const TENC: u32 = 0x74656e63;
+const SENC: u32 = 0x73656e63;
+const SAIZ: u32 = 0x7361697a;
+const SAIO: u32 = 0x7361696f;
+const PSSH: u32 = 0x70737368;
+
+/// A track chosen for encryption.
+struct ProtectedTrack<'a> {
+    id: u32,
+    key: &'a ContentKey,
+    /// NAL unit length size, and whether the codec is HEVC, for video
+    /// encrypted by subsample.
+    nal_units: Option<(usize, bool)>,
+    /// Size of the per-sample IVs, or 0 for a constant IV.
+    iv_size: usize,
+    /// Encrypted and skipped blocks of the pattern, or 0:0 for none.
+    pattern: (u8, u8),
+    samples: Vec<SampleInfo>,
+    crypto: Vec<SampleCrypto>,
+}
+
+impl<'a> ProtectedTrack<'a> {
+    /// Whether the samples have auxiliary information for an 'senc'.
+    fn has_senc(&self) -> bool {
+        self.iv_size > 0 || self.nal_units.is_some()
+    }
+}
+
+/// Split a sample of length-prefixed NAL units into subsamples.
+///
+/// The length prefixes, NAL unit headers and NAL units other than coded
+/// slices are left clear. The protected part of each slice is a whole
+/// number of 16-byte blocks, with the remainder left clear after its
+/// header.
+fn nal_subsamples(mut data: &[u8], length_size: usize, hevc: bool) -> Result<Vec<Subsample>> {
+    let nal_header_size = if hevc { 2 } else { 1 };
+    let mut subsamples = Vec::new();
+    let mut clear = 0usize;
+    while !data.is_empty() {
+        if data.len() < length_size {
+            return Err(Error::InvalidData("truncated NAL unit length"));
+        }
+        let len = data[..length_size].iter().fold(0usize, |len, b| len << 8 | *b as usize);
+        if len > data.len() - length_size {
+            return Err(Error::InvalidData("NAL unit exceeds sample size"));
+        }
+        let is_slice = match data.get(length_size) {
+            Some(b) if hevc => (b >> 1) & 0x3f < 32,
+            Some(b) => b & 0x1f >= 1 && b & 0x1f <= 5,
+            None => false,
+        };
+        let encrypted = if is_slice && len > nal_header_size {
+            (len - nal_header_size) / 16 * 16
+        } else {
+            0
+        };
+        clear += length_size + len - encrypted;
+        if encrypted > 0 {
+            while clear > std::u16::MAX as usize {
+                subsamples.push(Subsample { clear_bytes: std::u16::MAX, encrypted_bytes: 0 });
+                clear -= std::u16::MAX as usize;
+            }
+            subsamples.push(Subsample { clear_bytes: clear as u16, encrypted_bytes: encrypted as u32 });
+            clear = 0;
+        }
+        data = &data[length_size + len..];
+    }
+    while clear > 0 {
+        let clear_bytes = std::cmp::min(clear, std::u16::MAX as usize);
+        subsamples.push(Subsample { clear_bytes: clear_bytes as u16, encrypted_bytes: 0 });
+        clear -= clear_bytes;
+    }
+    Ok(subsamples)
+}
+
+fn push_box(dst: &mut Vec<u8>, name: u32, payload: &[u8]) -> Result<()> {
+    try!(write_header(dst, name, payload.len() as u64));
+    dst.extend_from_slice(payload);
+    Ok(())
+}
+
+/// The 'sinf' for a sample entry of type `original`.
+fn protection_scheme_info(original: u32, scheme: u32, track: &ProtectedTrack, iv: &[u8; 16]) -> Result<Vec<u8>> {
+    let mut tenc = Vec::new();
+    if track.iv_size == 0 {
+        // Version 1, with the pattern and a constant IV.
+        tenc.extend_from_slice(&[1, 0, 0, 0, 0, track.pattern.0 << 4 | track.pattern.1, 1, 0]);
+        tenc.extend_from_slice(&track.key.kid);
+        tenc.push(16);
+        tenc.extend_from_slice(iv);
+    } else {
+        tenc.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, track.iv_size as u8]);
+        tenc.extend_from_slice(&track.key.kid);
+    }
+    let mut schi = Vec::new();
+    try!(push_box(&mut schi, TENC, &tenc));
+
+    let mut frma = Vec::new();
+    try!(frma.write_u32::<BigEndian>(original));
+    let mut schm = vec![0, 0, 0, 0];
+    try!(schm.write_u32::<BigEndian>(scheme));
+    try!(schm.write_u32::<BigEndian>(0x10000));
+
+    let mut sinf = Vec::new();
+    try!(push_box(&mut sinf, FRMA, &frma));
+    try!(push_box(&mut sinf, SCHM, &schm));
+    try!(push_box(&mut sinf, SCHI, &schi));
+    let mut data = Vec::new();
+    try!(push_box(&mut data, SINF, &sinf));
+    Ok(data)
+}
+
+/// Rename each sample entry of an 'stsd' payload to its protected type
+/// and append its 'sinf'.
+fn protect_sample_entries(stsd: &[u8], scheme: u32, track: &ProtectedTrack, iv: &[u8; 16]) -> Result<Vec<u8>> {
+    if stsd.len() < 8 {
+        return Err(Error::InvalidData("truncated stsd"));
+    }
+    let protected_type = if track.nal_units.is_some() { ENCV } else { ENCA };
+    let mut protected = stsd[..8].to_vec();
+    let mut data = &stsd[8..];
+    while !data.is_empty() {
+        let size = if data.len() >= 8 { BigEndian::read_u32(data) as usize } else { 0 };
+        if size < 8 || size > data.len() {
+            return Err(Error::InvalidData("malformed sample entry"));
+        }
+        let sinf = try!(protection_scheme_info(BigEndian::read_u32(&data[4..]), scheme, track, iv));
+        try!(protected.write_u32::<BigEndian>((size + sinf.len()) as u32));
+        try!(protected.write_u32::<BigEndian>(protected_type));
+        protected.extend_from_slice(&data[8..size]);
+        protected.extend(sinf);
+        data = &data[size..];
+    }
+    Ok(protected)
+}
+
+/// The 'senc', 'saiz' and placeholder 'saio' for a track's samples.
+fn auxiliary_information(track: &ProtectedTrack) -> Result<Vec<Node>> {
+    let mut senc = Vec::new();
+    let mut sizes = Vec::new();
+    try!(senc.write_u32::<BigEndian>(track.crypto.len() as u32));
+    for crypto in &track.crypto {
+        let start = senc.len();
+        senc.extend_from_slice(&crypto.iv[..track.iv_size]);
+        if track.nal_units.is_some() {
+            try!(senc.write_u16::<BigEndian>(crypto.subsamples.len() as u16));
+            for subsample in &crypto.subsamples {
+                try!(senc.write_u16::<BigEndian>(subsample.clear_bytes));
+                try!(senc.write_u32::<BigEndian>(subsample.encrypted_bytes));
+            }
+        }
+        let size = senc.len() - start;
+        if size > std::u8::MAX as usize {
+            return Err(Error::Unsupported("sample auxiliary information exceeds 255 bytes"));
+        }
+        sizes.push(size as u8);
+    }
+    let mut senc_box = vec![0, 0, 0, if track.nal_units.is_some() { 2 } else { 0 }];
+    senc_box.extend(senc);
+
+    let mut saiz = Vec::new();
+    match sizes.first() {
+        Some(first) if sizes.iter().all(|size| size == first) => {
+            saiz.push(*first);
+            try!(saiz.write_u32::<BigEndian>(sizes.len() as u32));
+        }
+        _ => {
+            saiz.push(0);
+            try!(saiz.write_u32::<BigEndian>(sizes.len() as u32));
+            saiz.extend(sizes);
+        }
+    }
+    // A single 64-bit offset, filled in once the output layout is known.
+    let saio = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    Ok(vec![
+        Node::Leaf { name: SENC, data: senc_box },
+        fullbox_leaf(SAIZ, 0, saiz),
+        fullbox_leaf(SAIO, 1, saio),
+    ])
+}
+
+/// A 'pssh' box for `pssh`, version 1 if it lists key IDs.
+fn protection_system_header(pssh: &ProtectionSystemSpecificHeaderBox) -> Result<Node> {
+    if pssh.system_id.len() != 16 || pssh.kid.iter().any(|kid| kid.len() != 16) {
+        return Err(Error::InvalidData("invalid pssh system or key ID"));
+    }
+    let mut body = pssh.system_id.clone();
+    if !pssh.kid.is_empty() {
+        try!(body.write_u32::<BigEndian>(pssh.kid.len() as u32));
+        for kid in &pssh.kid {
+            body.extend_from_slice(kid);
+        }
+    }
+    try!(body.write_u32::<BigEndian>(pssh.data.len() as u32));
+    body.extend_from_slice(&pssh.data);
+    Ok(fullbox_leaf(PSSH, if pssh.kid.is_empty() { 0 } else { 1 }, body))
+}
+
+/// Position in the serialized `node` of the descendant reached by
+/// taking the child at each index of `path`.
+fn position_in(node: &Node, path: &[usize], large: &[bool]) -> u64 {
+    let children = match *node {
+        Node::Container { ref children, .. } => children,
+        _ => return 0,
+    };
+    match path.split_first() {
+        None => 0,
+        Some((&index, rest)) => {
+            let payload: u64 = children.iter().map(|c| node_size(c, large)).sum();
+            let before: u64 = children[..index].iter().map(|c| node_size(c, large)).sum();
+            header_size(payload) + before + position_in(&children[index], rest, large)
+        }
+    }
+}
+
+fn child(node: &Node, index: usize) -> &Node {
+    match *node {
+        Node::Container { ref children, .. } => &children[index],
+        _ => unreachable!(),
+    }
+}
+
+/// The index of the last child of `node` named `child`, which is known
+/// to be present.
+fn child_index(node: &Node, child: u32) -> usize {
+    match *node {
+        Node::Container { ref children, .. } => children.iter().rposition(|c| name(c) == Some(child)).unwrap(),
+        _ => unreachable!(),
+    }
+}
+
+/// Write a copy of a clear file with some of its tracks encrypted.
+///
+/// `keys` pairs the ID of each track to encrypt with its content key,
+/// and `scheme` is 'cenc' or 'cbcs'. Each of the track's sample entries
+/// is renamed 'encv' or 'enca' and given a 'sinf' naming its original
+/// format and the scheme, with a 'tenc' holding the key ID. Sample IVs
+/// and subsamples go in an 'senc' in the track's 'stbl', located by
+/// 'saiz' and 'saio', and a 'pssh' is added to the 'moov' for each of
+/// `pssh`, written from its system ID, key IDs and data.
+///
+/// With 'cenc', the first sample's 8-byte IV is the first half of `iv`
+/// and each following sample's is one more, so a fresh `iv` is needed
+/// for each file encrypted under a key. With 'cbcs', `iv` is the
+/// constant IV and video uses a 1:9 pattern.
+///
+/// Audio samples are encrypted whole. AVC and HEVC samples are
+/// encrypted by subsample, leaving the NAL unit lengths and headers
+/// and all but the coded slices clear; slice headers aren't parsed,
+/// so apart from a few bytes after the NAL unit header they're
+/// encrypted too. Other codecs, tracks which are already protected,
+/// fragmented files and files with a top-level 'meta' are not
+/// supported.
+pub fn encrypt_to_cenc<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W, scheme: u32, keys: &[(u32, ContentKey)],
+                                                 iv: &[u8; 16], pssh: &[ProtectionSystemSpecificHeaderBox]) -> Result<()> {
+    if scheme != CENC && scheme != CBCS {
+        return Err(Error::Unsupported("encryption scheme other than cenc or cbcs"));
+    }
+    let mut boxes = Vec::new();
+    for node in TopLevelBoxIter::new(src) {
+        let node = try!(node);
+        if node.name == BoxType::MovieFragmentBox {
+            return Err(Error::Unsupported("encryption of fragmented files"));
+        }
+        if node.name == BoxType::MetadataBox {
+            // Item locations may point into the media data.
+            return Err(Error::Unsupported("encryption with a top-level meta"));
+        }
+        boxes.push(OutputBox::new(&node));
+    }
+    let moov = match boxes.iter().position(|b| b.input.name == BoxType::MovieBox) {
+        Some(moov) => moov,
+        None => return Err(Error::NoMoov),
+    };
+    if boxes[moov].input.size > MOOV_SIZE_LIMIT {
+        return Err(Error::MoovTooLarge(boxes[moov].input.size));
+    }
+    let mut moov_data = Vec::with_capacity(boxes[moov].input.size as usize);
+    try!(copy_box(src, &mut moov_data, &boxes[moov].input));
+    let mut tables = 0;
+    let mut tree = match try!(parse_children(&moov_data, &mut tables)).pop() {
+        Some(tree) => tree,
+        None => return Err(Error::InvalidData("empty moov")),
+    };
+    let mut context = MediaContext::new();
+    try!(read_mp4(&mut Cursor::new(&moov_data), &mut context));
+
+    let mut tracks = Vec::new();
+    let mut counter = BigEndian::read_u64(&iv[..8]);
+    for (i, &(id, ref key)) in keys.iter().enumerate() {
+        if keys[..i].iter().any(|k| k.0 == id) {
+            return Err(Error::InvalidData("track given more than one key"));
+        }
+        let track = match context.track_by_id(id) {
+            Some(track) => track,
+            None => return Err(Error::InvalidData("no track with the given ID")),
+        };
+        if track.protection_info.is_some() {
+            return Err(Error::Unsupported("encryption of a protected track"));
+        }
+        let nal_units = match track.data {
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::AVCConfig(ref avcc) => Some((try!(parse_avcc(avcc)).nal_length_size as usize, false)),
+                VideoCodecSpecific::HEVCConfig(ref hvcc) => Some((try!(parse_hvcc(hvcc)).nal_length_size as usize, true)),
+                _ => return Err(Error::Unsupported("encryption of video other than AVC or HEVC")),
+            },
+            Some(SampleEntry::Audio(_)) => None,
+            _ => return Err(Error::Unsupported("encryption of tracks other than audio or video")),
+        };
+        let samples = try!(track.sample_table());
+        let mut crypto = Vec::with_capacity(samples.len());
+        for sample in &samples {
+            let subsamples = match nal_units {
+                Some((length_size, hevc)) => try!(nal_subsamples(&try!(read_sample(src, sample)), length_size, hevc)),
+                None => Vec::new(),
+            };
+            let sample_iv = if scheme == CENC {
+                let mut sample_iv = vec![0; 8];
+                BigEndian::write_u64(&mut sample_iv, counter);
+                counter = counter.wrapping_add(1);
+                sample_iv
+            } else {
+                iv.to_vec()
+            };
+            crypto.push(SampleCrypto {
+                kid: key.kid.to_vec(),
+                iv: sample_iv,
+                subsamples: subsamples,
+            });
+        }
+        tracks.push(ProtectedTrack {
+            id: id,
+            key: key,
+            nal_units: nal_units,
+            iv_size: if scheme == CENC { 8 } else { 0 },
+            pattern: if scheme == CBCS && nal_units.is_some() { (1, 9) } else { (0, 0) },
+            samples: samples,
+            crypto: crypto,
+        });
+    }
+
+    for track in &tracks {
+        let trak = children_mut(&mut tree, &[]).unwrap().iter_mut().find(|c| track_id(c) == Some(track.id)).unwrap();
+        let stbl = match children_mut(trak, &[MDIA, MINF, STBL]) {
+            Some(stbl) => stbl,
+            None => return Err(Error::InvalidData("trak without stbl")),
+        };
+        match leaf_mut(stbl, STSD) {
+            Some(stsd) => *stsd = try!(protect_sample_entries(stsd, scheme, track, iv)),
+            None => return Err(Error::InvalidData("stbl without stsd")),
+        }
+        if track.has_senc() {
+            stbl.extend(try!(auxiliary_information(track)));
+        }
+    }
+    for pssh in pssh {
+        let node = try!(protection_system_header(pssh));
+        children_mut(&mut tree, &[]).unwrap().push(node);
+    }
+
+    let (positions, large) = try!(fit_tables(&tree, tables, |moov_size| {
+        boxes.iter().enumerate().map(|(i, b)| b.size(if i == moov { Some(moov_size) } else { None })).collect()
+    }, |positions, offset| map_offset(&boxes, positions, Some(moov), offset)));
+
+    // Point each 'saio' at the first sample's entry in its 'senc', past
+    // the version, flags and sample count.
+    for track in tracks.iter().filter(|t| t.has_senc()) {
+        let senc_position = {
+            let trak = children_mut(&mut tree, &[]).unwrap().iter().position(|c| track_id(c) == Some(track.id));
+            let mut path = vec![trak.unwrap()];
+            let mut node = child(&tree, path[0]);
+            for &name in &[MDIA, MINF, STBL, SENC] {
+                let index = child_index(node, name);
+                path.push(index);
+                node = child(node, index);
+            }
+            let senc_payload = match *node {
+                Node::Leaf { ref data, .. } => data.len() as u64,
+                _ => unreachable!(),
+            };
+            positions[moov] + position_in(&tree, &path, &large) + header_size(senc_payload) + 8
+        };
+        let trak = children_mut(&mut tree, &[]).unwrap().iter_mut().find(|c| track_id(c) == Some(track.id)).unwrap();
+        // The saio is the last box added to the stbl.
+        match children_mut(trak, &[MDIA, MINF, STBL]).unwrap().last_mut() {
+            Some(&mut Node::Leaf { name: SAIO, ref mut data }) => BigEndian::write_u64(&mut data[8..], senc_position),
+            _ => unreachable!(),
+        }
+    }
+
+    let mut edits: Vec<(&SampleInfo, &SampleCrypto, &ProtectedTrack)> = tracks.iter()
+        .flat_map(|track| track.samples.iter().zip(track.crypto.iter()).map(move |(s, c)| (s, c, track)))
+        .collect();
+    edits.sort_by_key(|edit| edit.0.offset);
+    let mut edits = edits.into_iter().peekable();
+    for (i, b) in boxes.iter().enumerate() {
+        if i == moov {
+            let map = |offset: u64| map_offset(&boxes, &positions, Some(moov), offset);
+            try!(write_node(dst, &tree, &large, &map));
+            continue;
+        }
+        // An mdat is written with its real size, as `concatenate` does.
+        let end = b.input.position + b.input.size;
+        let mut position = if b.is_mdat() {
+            try!(write_header(dst, MDAT, b.payload));
+            b.input.position + b.header_size
+        } else {
+            b.input.position
+        };
+        while let Some((sample, crypto, track)) = edits.peek().cloned() {
+            if sample.offset >= end {
+                break;
+            }
+            edits.next();
+            let sample_end = match sample.offset.checked_add(sample.size as u64) {
+                Some(sample_end) if sample.offset >= position && sample_end <= end => sample_end,
+                _ => return Err(Error::InvalidData("sample outside media data")),
+            };
+            try!(copy_range(src, dst, position..sample.offset));
+            let mut data = try!(read_sample(src, sample));
+            try!(encrypt_sample(&mut data, crypto, scheme, track.pattern.0, track.pattern.1, &track.key.key));
+            try!(dst.write_all(&data));
+            position = sample_end;
+        }
+        try!(copy_range(src, dst, position..end));
+    }
+    if edits.next().is_some() {
+        return Err(Error::InvalidData("sample outside media data"));
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Exif metadata carried in image items and 'uuid' boxes.
+//!
+//! HEIF and AVIF files store Exif as an item with a 'cdsc' reference
+//! to the image it describes. Video files have no items, and cameras
+//! writing them commonly put the Exif in a 'uuid' box instead. Either
+//! way the result is the TIFF structure holding the Exif tags, for an
+//! Exif library to interpret.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Seek};
+
+use BMFFBox;
+use BUF_SIZE_LIMIT;
+use Error;
+use ItemMetadata;
+use MediaContext;
+use Result;
+use get_debug_mode;
+use read_buf;
+use skip;
+
+/// Item type of Exif items.
+pub const ITEM_TYPE_EXIF: u32 = 0x45786966; // "Exif"
+
+/// Reference type marking an item as describing the referenced items.
+pub const REFERENCE_CONTENT_DESCRIBES: u32 = 0x63647363; // "cdsc"
+
+/// Extended type of 'uuid' boxes holding Exif.
+const EXIF_UUID: [u8; 16] = *b"JpgTiffExif->JP2";
+
+/// The TIFF structure from an Exif payload, without the "Exif\0\0"
+/// prefix of the JPEG APP1 segment if the writer copied it over.
+fn tiff_payload(data: &[u8]) -> &[u8] {
+    if data.starts_with(b"Exif\0\0") {
+        &data[6..]
+    } else {
+        data
+    }
+}
+
+impl ItemMetadata {
+    /// Exif items describing `item_id`, in file order.
+    pub fn exif_items(&self, item_id: u32) -> Vec<u32> {
+        self.referencing_items(REFERENCE_CONTENT_DESCRIBES, item_id)
+            .into_iter()
+            .filter(|&id| self.item(id).map_or(false, |item| item.item_type == ITEM_TYPE_EXIF))
+            .collect()
+    }
+
+    /// Read the TIFF structure from the Exif item `item_id`.
+    pub fn read_exif<T: Read + Seek>(&self, src: &mut T, item_id: u32) -> Result<Vec<u8>> {
+        let data = try!(self.read_item(src, item_id));
+        if data.len() < 4 {
+            return Err(Error::InvalidData("truncated Exif item"));
+        }
+        // Offset of the TIFF header from the end of this field.
+        let offset = (data[0] as usize) << 24 | (data[1] as usize) << 16 |
+                     (data[2] as usize) << 8 | data[3] as usize;
+        match data[4..].get(offset..) {
+            Some(tiff) => Ok(tiff.to_vec()),
+            None => Err(Error::InvalidData("Exif TIFF header offset past end of item")),
+        }
+    }
+
+    /// Read the TIFF structure of the first Exif item describing the
+    /// primary item, if there is one.
+    pub fn read_primary_item_exif<T: Read + Seek>(&self, src: &mut T) -> Result<Option<Vec<u8>>> {
+        let exif = self.primary_item_id.and_then(|id| self.exif_items(id).first().cloned());
+        match exif {
+            Some(id) => self.read_exif(src, id).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parse a 'uuid' box, keeping its payload if it holds Exif.
+pub fn read_uuid<T: Read>(src: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let mut uuid = [0u8; 16];
+    try!(src.read_exact(&mut uuid));
+    let bytes_left = src.bytes_left();
+    if uuid != EXIF_UUID {
+        log!("{:?} (skipped uuid {:?})", src.get_header(), uuid);
+        return skip(src, bytes_left);
+    }
+    if bytes_left as u64 > BUF_SIZE_LIMIT {
+        return Err(Error::InvalidData("Exif uuid box exceeds BUF_SIZE_LIMIT"));
+    }
+    let data = try!(read_buf(src, bytes_left));
+    context.exif.push(tiff_payload(&data).to_vec());
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Relocation of the 'moov' ahead of the media data ("faststart").
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use BoxType;
+use Error;
+use Result;
+use TopLevelBoxIter;
+
+// Arbitrary limit on the moov size we're prepared to hold in memory.
+pub const MOOV_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Box types on the path from the moov to the chunk offset tables, in
+/// nesting order.
+const CONTAINERS: [u32; 5] = [
+    0x6d6f6f76, // "moov"
+    0x7472616b, // "trak"
+    0x6d646961, // "mdia"
+    0x6d696e66, // "minf"
+    0x7374626c, // "stbl"
+];
+const STCO: u32 = 0x7374636f;
+const CO64: u32 = 0x636f3634;
+const SAIO: u32 = 0x7361696f;
+const META: u32 = 0x6d657461;
+const ILOC: u32 = 0x696c6f63;
+
+/// Position of a top-level box in the input.
+#[derive(Debug, Clone, Copy)]
+pub struct TopLevelBox {
+    pub name: BoxType,
+    pub position: u64,
+    pub size: u64,
+}
+
+/// A box inside the moov, held in memory for rewriting.
+///
+/// Chunk offset and auxiliary information offset tables share one
+/// numbering, used to note which need 64-bit entries.
+#[derive(Debug)]
+pub enum Node {
+    Container { name: u32, children: Vec<Node> },
+    ChunkOffsets { index: usize, offsets: Vec<u64> },
+    /// A 'saio' box. `aux_info` holds the optional aux_info_type and
+    /// aux_info_type_parameter fields as read.
+    AuxInfoOffsets { index: usize, flags: u32, aux_info: Vec<u8>, offsets: Vec<u64> },
+    Leaf { name: u32, data: Vec<u8> },
+}
+
+fn split<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(Error::InvalidData("truncated box in moov"));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+/// Read `count` big endian offsets of `width` bytes.
+fn read_offsets(data: &[u8], count: usize, width: usize) -> Result<Vec<u64>> {
+    if data.len() / width < count {
+        return Err(Error::InvalidData("truncated offset table"));
+    }
+    Ok((0..count)
+        .map(|i| if width == 4 {
+            BigEndian::read_u32(&data[i * 4..]) as u64
+        } else {
+            BigEndian::read_u64(&data[i * 8..])
+        })
+        .collect())
+}
+
+/// Check whether the payload of a 'meta' box holds an 'iloc', whose
+/// item offsets may point into the file. Malformed boxes are left to
+/// be copied unchanged.
+fn has_item_locations(mut data: &[u8]) -> bool {
+    // Version and flags.
+    if split(&mut data, 4).is_err() {
+        return false;
+    }
+    while data.len() >= 8 {
+        let size = BigEndian::read_u32(&data[0..4]) as usize;
+        if BigEndian::read_u32(&data[4..8]) == ILOC {
+            return true;
+        }
+        if size < 8 || size > data.len() {
+            return false;
+        }
+        data = &data[size..];
+    }
+    false
+}
+
+/// Parse the boxes in `data`, which holds a moov box, numbering chunk
+/// offset tables in the order found.
+pub fn parse_children(data: &[u8], tables: &mut usize) -> Result<Vec<Node>> {
+    parse_level(data, 0, tables)
+}
+
+/// Parse boxes nested `depth` containers deep. Only the container
+/// expected at this depth is descended into, so nesting is bounded.
+fn parse_level(mut data: &[u8], depth: usize, tables: &mut usize) -> Result<Vec<Node>> {
+    let mut children = Vec::new();
+    while !data.is_empty() {
+        let header = try!(split(&mut data, 8));
+        let size32 = BigEndian::read_u32(&header[0..4]);
+        let name = BigEndian::read_u32(&header[4..8]);
+        let (size, header_len) = match size32 {
+            0 => (data.len() as u64 + 8, 8),
+            1 => (BigEndian::read_u64(try!(split(&mut data, 8))), 16),
+            _ => (size32 as u64, 8),
+        };
+        if size < header_len || size - header_len > data.len() as u64 {
+            return Err(Error::InvalidData("malformed box size in moov"));
+        }
+        let payload = try!(split(&mut data, (size - header_len) as usize));
+        children.push(if CONTAINERS.get(depth) == Some(&name) {
+            Node::Container { name: name, children: try!(parse_level(payload, depth + 1, tables)) }
+        } else if depth == CONTAINERS.len() && (name == STCO || name == CO64) {
+            let mut payload = payload;
+            // Version and flags, then the entry count.
+            try!(split(&mut payload, 4));
+            let count = BigEndian::read_u32(try!(split(&mut payload, 4))) as usize;
+            let offsets = try!(read_offsets(payload, count, if name == STCO { 4 } else { 8 }));
+            *tables += 1;
+            Node::ChunkOffsets { index: *tables - 1, offsets: offsets }
+        } else if depth == CONTAINERS.len() && name == SAIO {
+            let mut payload = payload;
+            let version_flags = BigEndian::read_u32(try!(split(&mut payload, 4)));
+            let width = match version_flags >> 24 {
+                0 => 4,
+                1 => 8,
+                _ => return Err(Error::Unsupported("unknown saio version")),
+            };
+            let flags = version_flags & 0xffffff;
+            let aux_info = try!(split(&mut payload, if flags & 1 != 0 { 8 } else { 0 })).to_vec();
+            let count = BigEndian::read_u32(try!(split(&mut payload, 4))) as usize;
+            let offsets = try!(read_offsets(payload, count, width));
+            *tables += 1;
+            Node::AuxInfoOffsets { index: *tables - 1, flags: flags, aux_info: aux_info, offsets: offsets }
+        } else if depth > 0 && name == META && has_item_locations(payload) {
+            return Err(Error::Unsupported("moov relocation of item locations"));
+        } else {
+            Node::Leaf { name: name, data: payload.to_vec() }
+        });
+    }
+    Ok(children)
+}
+
+pub fn header_size(payload: u64) -> u64 {
+    if payload + 8 > std::u32::MAX as u64 { 16 } else { 8 }
+}
+
+/// Size of a node's payload, given which offset tables are written as co64.
+fn payload_size(node: &Node, large: &[bool]) -> u64 {
+    match *node {
+        Node::Container { ref children, .. } => {
+            children.iter().map(|c| node_size(c, large)).sum()
+        }
+        Node::ChunkOffsets { index, ref offsets } => {
+            8 + offsets.len() as u64 * if large[index] { 8 } else { 4 }
+        }
+        Node::AuxInfoOffsets { index, ref aux_info, ref offsets, .. } => {
+            8 + aux_info.len() as u64 + offsets.len() as u64 * if large[index] { 8 } else { 4 }
+        }
+        Node::Leaf { ref data, .. } => data.len() as u64,
+    }
+}
+
+pub fn node_size(node: &Node, large: &[bool]) -> u64 {
+    let payload = payload_size(node, large);
+    header_size(payload) + payload
+}
+
+pub fn write_header<W: Write>(dst: &mut W, name: u32, payload: u64) -> Result<()> {
+    if header_size(payload) == 16 {
+        try!(dst.write_u32::<BigEndian>(1));
+        try!(dst.write_u32::<BigEndian>(name));
+        try!(dst.write_u64::<BigEndian>(payload + 16));
+    } else {
+        try!(dst.write_u32::<BigEndian>(payload as u32 + 8));
+        try!(dst.write_u32::<BigEndian>(name));
+    }
+    Ok(())
+}
+
+fn write_offsets<W: Write, F: Fn(u64) -> Result<u64>>(dst: &mut W, offsets: &[u64], large: bool, map: &F) -> Result<()> {
+    try!(dst.write_u32::<BigEndian>(offsets.len() as u32));
+    for offset in offsets {
+        let offset = try!(map(*offset));
+        if large {
+            try!(dst.write_u64::<BigEndian>(offset));
+        } else {
+            try!(dst.write_u32::<BigEndian>(offset as u32));
+        }
+    }
+    Ok(())
+}
+
+/// Serialize a node, mapping chunk and auxiliary information offsets
+/// through `map`.
+pub fn write_node<W: Write, F: Fn(u64) -> Result<u64>>(dst: &mut W, node: &Node, large: &[bool], map: &F) -> Result<()> {
+    let payload = payload_size(node, large);
+    match *node {
+        Node::Container { name, ref children } => {
+            try!(write_header(dst, name, payload));
+            for child in children {
+                try!(write_node(dst, child, large, map));
+            }
+        }
+        Node::ChunkOffsets { index, ref offsets } => {
+            try!(write_header(dst, if large[index] { CO64 } else { STCO }, payload));
+            try!(dst.write_u32::<BigEndian>(0));
+            try!(write_offsets(dst, offsets, large[index], map));
+        }
+        Node::AuxInfoOffsets { index, flags, ref aux_info, ref offsets } => {
+            try!(write_header(dst, SAIO, payload));
+            let version = if large[index] { 1 } else { 0 };
+            try!(dst.write_u32::<BigEndian>(version << 24 | flags));
+            try!(dst.write_all(aux_info));
+            try!(write_offsets(dst, offsets, large[index], map));
+        }
+        Node::Leaf { name, ref data } => {
+            try!(write_header(dst, name, payload));
+            try!(dst.write_all(data));
+        }
+    }
+    Ok(())
+}
+
+/// Note which offset tables need 64-bit entries once mapped.
+pub fn find_large_tables<F: Fn(u64) -> Result<u64>>(node: &Node, map: &F, large: &mut [bool]) -> Result<()> {
+    match *node {
+        Node::Container { ref children, .. } => {
+            for child in children {
+                try!(find_large_tables(child, map, large));
+            }
+        }
+        Node::ChunkOffsets { index, ref offsets } |
+        Node::AuxInfoOffsets { index, ref offsets, .. } => {
+            for offset in offsets {
+                if try!(map(*offset)) > std::u32::MAX as u64 {
+                    large[index] = true;
+                }
+            }
+        }
+        Node::Leaf { .. } => {}
+    }
+    Ok(())
+}
+
+/// Move chunk offset `offset` from the input layout to the output layout.
+///
+/// `order` lists the output order of the input boxes and `positions`
+/// their new positions.
+fn map_offset(boxes: &[TopLevelBox], order: &[usize], positions: &[u64], moov: usize, offset: u64) -> Result<u64> {
+    for (i, &index) in order.iter().enumerate() {
+        let b = &boxes[index];
+        if index != moov && offset >= b.position && offset < b.position + b.size {
+            return Ok(offset - b.position + positions[i]);
+        }
+    }
+    Err(Error::InvalidData("chunk offset outside media data"))
+}
+
+/// The output order of the input boxes: everything before the first
+/// mdat, then the moov, then the remaining boxes.
+fn output_order(boxes: &[TopLevelBox], moov: usize) -> Vec<usize> {
+    let first_mdat = boxes.iter()
+        .position(|b| b.name == BoxType::MediaDataBox)
+        .unwrap_or(boxes.len());
+    let mut order: Vec<usize> = (0..first_mdat).filter(|i| *i != moov).collect();
+    order.push(moov);
+    order.extend((first_mdat..boxes.len()).filter(|i| *i != moov));
+    order
+}
+
+/// Choose the moov layout, returning the new box positions and which
+/// offset tables must be upgraded to co64.
+pub fn plan(boxes: &[TopLevelBox], moov: usize, tree: &Node, tables: usize) -> Result<(Vec<usize>, Vec<u64>, Vec<bool>)> {
+    let order = output_order(boxes, moov);
+    let mut large = vec![false; tables];
+    loop {
+        // Upgrading a table grows the moov, moving the media data
+        // further and possibly requiring further upgrades.
+        let moov_size = node_size(tree, &large);
+        let mut positions = Vec::with_capacity(order.len());
+        let mut position = 0u64;
+        for &index in &order {
+            positions.push(position);
+            position += if index == moov { moov_size } else { boxes[index].size };
+        }
+        let mut needed = large.clone();
+        {
+            let map = |offset: u64| map_offset(boxes, &order, &positions, moov, offset);
+            try!(find_large_tables(tree, &map, &mut needed));
+        }
+        if needed == large {
+            return Ok((order, positions, large));
+        }
+        large = needed;
+    }
+}
+
+pub fn copy_box<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W, b: &TopLevelBox) -> Result<()> {
+    try!(src.seek(SeekFrom::Start(b.position)));
+    let copied = try!(std::io::copy(&mut Read::by_ref(src).take(b.size), dst));
+    if copied != b.size {
+        return Err(Error::UnexpectedEOF);
+    }
+    Ok(())
+}
+
+/// Rewrite a file with its 'moov' ahead of the media data.
+///
+/// The moov is moved before the first 'mdat' and every chunk offset
+/// is adjusted for the new layout. Offset tables are upgraded from
+/// 'stco' to 'co64' where the media data moves beyond 4 GiB. Sample
+/// auxiliary information offsets in 'saio' boxes are adjusted the same
+/// way. Files whose moov already precedes the media data are copied
+/// unchanged. Fragmented files and files with 'iloc' item locations in
+/// the moov are not supported.
+pub fn relocate_moov_to_front<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W) -> Result<()> {
+    let mut boxes = Vec::new();
+    for node in TopLevelBoxIter::new(src) {
+        let node = try!(node);
+        if node.name == BoxType::MovieFragmentBox {
+            return Err(Error::Unsupported("moov relocation of fragmented files"));
+        }
+        boxes.push(TopLevelBox {
+            name: node.name,
+            position: node.position,
+            size: node.size,
+        });
+    }
+
+    let moov = match boxes.iter().position(|b| b.name == BoxType::MovieBox) {
+        Some(moov) => moov,
+        None => return Err(Error::NoMoov),
+    };
+    let first_mdat = boxes.iter().position(|b| b.name == BoxType::MediaDataBox);
+    if first_mdat.map_or(true, |mdat| moov < mdat) {
+        for b in &boxes {
+            try!(copy_box(src, dst, b));
+        }
+        return Ok(());
+    }
+
+    if boxes[moov].size > MOOV_SIZE_LIMIT {
+        return Err(Error::MoovTooLarge(boxes[moov].size));
+    }
+    let mut moov_data = Vec::with_capacity(boxes[moov].size as usize);
+    try!(copy_box(src, &mut moov_data, &boxes[moov]));
+    let mut tables = 0;
+    let tree = match try!(parse_children(&moov_data, &mut tables)).pop() {
+        Some(tree) => tree,
+        None => return Err(Error::InvalidData("empty moov")),
+    };
+
+    let (order, positions, large) = try!(plan(&boxes, moov, &tree, tables));
+    for &index in &order {
+        if index == moov {
+            let map = |offset: u64| map_offset(&boxes, &order, &positions, moov, offset);
+            try!(write_node(dst, &tree, &large, &map));
+        } else {
+            try!(copy_box(src, dst, &boxes[index]));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,379 @@
+//! FLAC stream and frame header parsing.
+//!
+//! Covers the parts of a FLAC stream needed to describe it in an ISO
+//! BMFF 'fLaC' sample entry: the metadata blocks, STREAMINFO in
+//! particular, and the header of each audio frame. Streams may be
+//! native or encapsulated in Ogg.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::io::Read;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use elementary::ogg_crc;
+use BitReader;
+use Error;
+use Result;
+use read_buf;
+use read_utf8_coded;
+
+const STREAM_MARKER: [u8; 4] = *b"fLaC";
+
+pub const FLAC_METADATA_STREAMINFO: u8 = 0;
+
+const STREAMINFO_SIZE: usize = 34;
+
+/// Start of the first packet of a FLAC stream in Ogg.
+const OGG_FLAC_SIGNATURE: [u8; 5] = [0x7f, b'F', b'L', b'A', b'C'];
+
+/// Check for the "fLaC" marker starting a native FLAC stream.
+pub fn read_flac_stream_marker<T: Read>(src: &mut T) -> Result<()> {
+    let mut marker = [0; 4];
+    try!(src.read_exact(&mut marker));
+    if marker != STREAM_MARKER {
+        return Err(Error::InvalidData("missing fLaC stream marker"));
+    }
+    Ok(())
+}
+
+/// A metadata block following the stream marker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacMetadataBlock {
+    pub block_type: u8,
+    /// True for the final block before the audio frames.
+    pub last: bool,
+    pub data: Vec<u8>,
+}
+
+pub fn read_flac_metadata_block<T: Read>(src: &mut T) -> Result<FlacMetadataBlock> {
+    let mut header = [0; 4];
+    try!(src.read_exact(&mut header));
+    let block_type = header[0] & 0x7f;
+    if block_type == 127 {
+        return Err(Error::InvalidData("invalid FLAC metadata block type"));
+    }
+    let length = (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+    Ok(FlacMetadataBlock {
+        block_type: block_type,
+        last: header[0] & 0x80 != 0,
+        data: try!(read_buf(src, length)),
+    })
+}
+
+/// The STREAMINFO metadata block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacStreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    /// Frame sizes in bytes, or 0 if unknown.
+    pub min_frame_size: u32,
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    /// Samples per channel, or 0 if unknown.
+    pub total_samples: u64,
+    pub md5: [u8; 16],
+}
+
+/// Parse the data of a STREAMINFO metadata block.
+pub fn parse_flac_stream_info(data: &[u8]) -> Result<FlacStreamInfo> {
+    if data.len() != STREAMINFO_SIZE {
+        return Err(Error::InvalidData("STREAMINFO block has the wrong size"));
+    }
+    let mut bits = BitReader::new(data);
+    let min_block_size = try!(bits.read_bits(16)) as u16;
+    let max_block_size = try!(bits.read_bits(16)) as u16;
+    let min_frame_size = try!(bits.read_bits(24)) as u32;
+    let max_frame_size = try!(bits.read_bits(24)) as u32;
+    let sample_rate = try!(bits.read_bits(20)) as u32;
+    let channels = try!(bits.read_bits(3)) as u8 + 1;
+    let bits_per_sample = try!(bits.read_bits(5)) as u8 + 1;
+    let total_samples = try!(bits.read_bits(36));
+    if min_block_size < 16 || max_block_size < min_block_size {
+        return Err(Error::InvalidData("invalid STREAMINFO block sizes"));
+    }
+    if sample_rate == 0 {
+        return Err(Error::InvalidData("invalid STREAMINFO sample rate"));
+    }
+    let mut md5 = [0; 16];
+    md5.copy_from_slice(&data[STREAMINFO_SIZE - 16..]);
+    Ok(FlacStreamInfo {
+        min_block_size: min_block_size,
+        max_block_size: max_block_size,
+        min_frame_size: min_frame_size,
+        max_frame_size: max_frame_size,
+        sample_rate: sample_rate,
+        channels: channels,
+        bits_per_sample: bits_per_sample,
+        total_samples: total_samples,
+        md5: md5,
+    })
+}
+
+/// How the channels of a frame are coded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlacChannelAssignment {
+    /// The given number of channels, each coded on its own.
+    Independent(u8),
+    LeftSide,
+    RightSide,
+    MidSide,
+}
+
+impl FlacChannelAssignment {
+    pub fn channels(&self) -> u8 {
+        match *self {
+            FlacChannelAssignment::Independent(channels) => channels,
+            _ => 2,
+        }
+    }
+}
+
+/// The header of an audio frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacFrameHeader {
+    /// True if frames may have different block sizes, in which case
+    /// `number` counts samples rather than frames.
+    pub variable_block_size: bool,
+    /// Samples per channel in the frame.
+    pub block_size: u32,
+    /// Sample rate, or None to use the STREAMINFO value.
+    pub sample_rate: Option<u32>,
+    pub channel_assignment: FlacChannelAssignment,
+    /// Bits per sample, or None to use the STREAMINFO value.
+    pub bits_per_sample: Option<u8>,
+    /// Frame number, or the first sample's number for variable block
+    /// size streams.
+    pub number: u64,
+}
+
+/// Reads through to `inner`, keeping the bytes read for the header
+/// CRC.
+struct CrcReader<'a, T: 'a + Read> {
+    inner: &'a mut T,
+    bytes: Vec<u8>,
+}
+
+impl<'a, T: Read> Read for CrcReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, as used by frame headers.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn read_uint<T: Read>(src: &mut T, bytes: usize) -> Result<u32> {
+    let mut buf = [0; 2];
+    try!(src.read_exact(&mut buf[..bytes]));
+    Ok(buf[..bytes].iter().fold(0, |value, &byte| value << 8 | byte as u32))
+}
+
+/// Read a frame header, checking its CRC.
+pub fn read_flac_frame_header<T: Read>(src: &mut T) -> Result<FlacFrameHeader> {
+    let mut src = CrcReader { inner: src, bytes: Vec::new() };
+    let mut fixed = [0; 4];
+    try!(src.read_exact(&mut fixed));
+    if fixed[0] != 0xff || fixed[1] & 0xfe != 0xf8 {
+        return Err(Error::InvalidData("missing FLAC frame sync code"));
+    }
+    if fixed[3] & 0x01 != 0 {
+        return Err(Error::InvalidData("reserved FLAC frame header bit set"));
+    }
+    let variable_block_size = fixed[1] & 0x01 != 0;
+    let block_size_code = fixed[2] >> 4;
+    let sample_rate_code = fixed[2] & 0xf;
+    let channel_assignment = match fixed[3] >> 4 {
+        code @ 0...7 => FlacChannelAssignment::Independent(code + 1),
+        8 => FlacChannelAssignment::LeftSide,
+        9 => FlacChannelAssignment::RightSide,
+        10 => FlacChannelAssignment::MidSide,
+        _ => return Err(Error::InvalidData("reserved FLAC channel assignment")),
+    };
+    let bits_per_sample = match fixed[3] >> 1 & 0x7 {
+        0 => None,
+        1 => Some(8),
+        2 => Some(12),
+        4 => Some(16),
+        5 => Some(20),
+        6 => Some(24),
+        7 => Some(32),
+        _ => return Err(Error::InvalidData("reserved FLAC sample size")),
+    };
+    let number = try!(read_utf8_coded(&mut src));
+    let block_size = match block_size_code {
+        0 => return Err(Error::InvalidData("reserved FLAC block size")),
+        1 => 192,
+        2...5 => 576 << (block_size_code - 2),
+        6 => try!(read_uint(&mut src, 1)) + 1,
+        7 => try!(read_uint(&mut src, 2)) + 1,
+        _ => 256 << (block_size_code - 8),
+    };
+    let sample_rate = match sample_rate_code {
+        0 => None,
+        1 => Some(88200),
+        2 => Some(176400),
+        3 => Some(192000),
+        4 => Some(8000),
+        5 => Some(16000),
+        6 => Some(22050),
+        7 => Some(24000),
+        8 => Some(32000),
+        9 => Some(44100),
+        10 => Some(48000),
+        11 => Some(96000),
+        12 => Some(try!(read_uint(&mut src, 1)) * 1000),
+        13 => Some(try!(read_uint(&mut src, 2))),
+        14 => Some(try!(read_uint(&mut src, 2)) * 10),
+        _ => return Err(Error::InvalidData("invalid FLAC sample rate")),
+    };
+    let crc = crc8(&src.bytes);
+    let mut expected = [0; 1];
+    try!(src.inner.read_exact(&mut expected));
+    if crc != expected[0] {
+        return Err(Error::InvalidData("FLAC frame header CRC mismatch"));
+    }
+    Ok(FlacFrameHeader {
+        variable_block_size: variable_block_size,
+        block_size: block_size,
+        sample_rate: sample_rate,
+        channel_assignment: channel_assignment,
+        bits_per_sample: bits_per_sample,
+        number: number,
+    })
+}
+
+/// Reads the packets of the first logical bitstream in an Ogg file.
+///
+/// Pages of other multiplexed streams are skipped.
+pub struct OggPacketReader<R: Read> {
+    src: R,
+    serial: Option<u32>,
+    /// Complete packets from pages read so far.
+    packets: VecDeque<Vec<u8>>,
+    /// Start of a packet continued on the next page.
+    partial: Vec<u8>,
+    end_of_stream: bool,
+}
+
+impl<R: Read> OggPacketReader<R> {
+    pub fn new(src: R) -> OggPacketReader<R> {
+        OggPacketReader {
+            src: src,
+            serial: None,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+            end_of_stream: false,
+        }
+    }
+
+    /// Return the next packet, or None at the end of the stream.
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        while self.packets.is_empty() {
+            if self.end_of_stream || !try!(self.read_page()) {
+                return Ok(None);
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    /// Read a page, returning false at the end of the input.
+    fn read_page(&mut self) -> Result<bool> {
+        let mut header = [0; 27];
+        match self.src.read(&mut header[..1]) {
+            Ok(0) => return Ok(false),
+            Ok(_) => try!(self.src.read_exact(&mut header[1..])),
+            Err(e) => return Err(e.into()),
+        }
+        if &header[..4] != b"OggS" || header[4] != 0 {
+            return Err(Error::InvalidData("missing Ogg page header"));
+        }
+        let mut lacing = vec![0; header[26] as usize];
+        try!(self.src.read_exact(&mut lacing));
+        let body_size = lacing.iter().map(|&l| l as usize).sum();
+        let body = try!(read_buf(&mut self.src, body_size));
+
+        let expected = LittleEndian::read_u32(&header[22..26]);
+        for byte in &mut header[22..26] {
+            *byte = 0;
+        }
+        let mut page = Vec::with_capacity(header.len() + lacing.len() + body.len());
+        page.extend_from_slice(&header);
+        page.extend_from_slice(&lacing);
+        page.extend_from_slice(&body);
+        if ogg_crc(&page) != expected {
+            return Err(Error::InvalidData("Ogg page CRC mismatch"));
+        }
+
+        let serial = LittleEndian::read_u32(&header[14..18]);
+        if *self.serial.get_or_insert(serial) != serial {
+            return Ok(true);
+        }
+        let continued = header[5] & 0x01 != 0;
+        if !continued {
+            self.partial.clear();
+        }
+        let mut offset = 0;
+        for &length in &lacing {
+            self.partial.extend_from_slice(&body[offset..offset + length as usize]);
+            offset += length as usize;
+            if length < 255 {
+                self.packets.push_back(::std::mem::replace(&mut self.partial, Vec::new()));
+            }
+        }
+        self.end_of_stream = header[5] & 0x04 != 0;
+        Ok(true)
+    }
+}
+
+/// Read the header packets of a FLAC stream in Ogg, returning its
+/// metadata blocks with STREAMINFO first.
+///
+/// Fails if the first packet doesn't use the FLAC mapping. Audio
+/// frames follow as one packet each.
+pub fn read_ogg_flac_headers<R: Read>(packets: &mut OggPacketReader<R>) -> Result<Vec<FlacMetadataBlock>> {
+    let first = match try!(packets.next_packet()) {
+        Some(packet) => packet,
+        None => return Err(Error::InvalidData("empty Ogg stream")),
+    };
+    if first.len() < 13 || first[..5] != OGG_FLAC_SIGNATURE {
+        return Err(Error::InvalidData("Ogg stream is not FLAC"));
+    }
+    if first[5] != 1 {
+        return Err(Error::Unsupported("unknown Ogg FLAC mapping version"));
+    }
+    let mut src = &first[9..];
+    try!(read_flac_stream_marker(&mut src));
+    let stream_info = try!(read_flac_metadata_block(&mut src));
+    if stream_info.block_type != FLAC_METADATA_STREAMINFO {
+        return Err(Error::InvalidData("Ogg FLAC stream doesn't start with STREAMINFO"));
+    }
+    let mut last = stream_info.last;
+    let mut blocks = vec![stream_info];
+    while !last {
+        let packet = match try!(packets.next_packet()) {
+            Some(packet) => packet,
+            None => return Err(Error::UnexpectedEOF),
+        };
+        let block = try!(read_flac_metadata_block(&mut &packet[..]));
+        last = block.last;
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
@@ -0,0 +1,587 @@
+//! Item-based image metadata, as used by HEIF and AVIF.
+//!
+//! Image files keep their pictures as items described by a top-level
+//! 'meta' box rather than as tracks: 'iinf' lists the items, 'iloc'
+//! gives the location of their coded data and 'iref' relates them to
+//! each other, for example marking one item as a thumbnail of another.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Seek, SeekFrom};
+use byteorder::ReadBytesExt;
+
+use AuxiliaryType;
+use BMFFBox;
+use BoxType;
+use CleanAperture;
+use Error;
+use MediaContext;
+use MirrorAxis;
+use Result;
+use be_u16;
+use be_u32;
+use be_u64;
+use auxiliary::read_auxc;
+use get_debug_mode;
+use read_buf;
+use read_fullbox_extra;
+use read_hdlr;
+use read_null_terminated_string;
+use recover_leaf_box;
+use skip;
+use skip_unknown_box;
+use transform::{read_clap, read_imir, read_irot};
+
+/// Largest item we're prepared to read into memory.
+const ITEM_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Largest 'idat' we're prepared to hold in memory.
+const ITEM_DATA_BOX_LIMIT: u64 = 1024 * 1024;
+
+/// Item type of items whose content type is given by a MIME type.
+const ITEM_TYPE_MIME: u32 = 0x6d696d65; // "mime"
+
+/// Reference type marking an item as a thumbnail of the referenced items.
+pub const REFERENCE_THUMBNAIL: u32 = 0x74686d62; // "thmb"
+
+/// Item type of grid derived images.
+pub const ITEM_TYPE_GRID: u32 = 0x67726964; // "grid"
+
+/// Reference type from a derived image to the images it's made from.
+pub const REFERENCE_DERIVED_IMAGE: u32 = 0x64696d67; // "dimg"
+
+/// An item from an 'infe' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemInfoEntry {
+    pub item_id: u32,
+    /// Four character code such as "av01", "hvc1" or "Exif". Zero for
+    /// entries of versions before 2, which don't give one.
+    pub item_type: u32,
+    pub item_name: String,
+    /// MIME type of "mime" items.
+    pub content_type: Option<String>,
+    /// Items flagged as hidden aren't meant to be displayed on their own.
+    pub hidden: bool,
+}
+
+/// A contiguous run of an item's data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ItemExtent {
+    /// Offset relative to the location's base offset.
+    pub offset: u64,
+    /// Length in bytes. Zero means the rest of the data source.
+    pub length: u64,
+}
+
+/// Where an item's data is stored, from an 'iloc' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemLocation {
+    pub item_id: u32,
+    /// 0 for file offsets, 1 for offsets into the 'idat' and 2 for
+    /// offsets into another item.
+    pub construction_method: u8,
+    pub data_reference_index: u16,
+    pub base_offset: u64,
+    pub extents: Vec<ItemExtent>,
+}
+
+/// A typed reference from one item to others, from an 'iref' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemReference {
+    /// Four character code such as "thmb", "cdsc", "auxl" or "dimg".
+    pub reference_type: u32,
+    pub from_item_id: u32,
+    pub to_item_ids: Vec<u32>,
+}
+
+/// Layout of a 'grid' derived image, which is assembled from tiles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageGrid {
+    pub rows: u16,
+    pub columns: u16,
+    /// Size of the assembled image. Tiles on the right and bottom
+    /// edges are cropped to fit.
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Tile items in row-major order, left to right and top to bottom.
+    pub tiles: Vec<u32>,
+}
+
+/// An item property from an 'ipco' box.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemProperty {
+    /// 'auxC', the kind of an auxiliary image.
+    AuxiliaryType(AuxiliaryType),
+    /// 'clap', a crop to apply before any rotation or mirroring.
+    CleanAperture(CleanAperture),
+    /// 'irot', an anticlockwise rotation in degrees.
+    Rotation(u16),
+    /// 'imir', a mirror about an axis.
+    Mirror(MirrorAxis),
+    /// 'pixi', the bit depth of each channel of the decoded image.
+    PixelInformation(Vec<u8>),
+    /// A property which isn't interpreted, or failed to parse in
+    /// lenient mode.
+    Unsupported(BoxType),
+}
+
+/// A property associated with an item, from an 'ipma' box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropertyAssociation {
+    /// 1-based index into the 'ipco' properties.
+    pub property_index: u16,
+    /// Readers must understand essential properties to display the item.
+    pub essential: bool,
+}
+
+/// Properties of one item, from an 'ipma' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemPropertyAssociation {
+    pub item_id: u32,
+    pub associations: Vec<PropertyAssociation>,
+}
+
+/// Contents of a top-level 'meta' box describing items.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ItemMetadata {
+    /// Handler type from the 'hdlr', "pict" for image files.
+    pub handler_type: u32,
+    /// Item to display by default, from the 'pitm'.
+    pub primary_item_id: Option<u32>,
+    pub items: Vec<ItemInfoEntry>,
+    pub locations: Vec<ItemLocation>,
+    pub references: Vec<ItemReference>,
+    /// Contents of the 'idat', for items stored in the meta box itself.
+    pub item_data: Vec<u8>,
+    /// Properties from the 'ipco', in file order.
+    pub properties: Vec<ItemProperty>,
+    /// Assignment of properties to items from the 'ipma'.
+    pub property_associations: Vec<ItemPropertyAssociation>,
+}
+
+impl ItemMetadata {
+    pub fn item(&self, item_id: u32) -> Option<&ItemInfoEntry> {
+        self.items.iter().find(|item| item.item_id == item_id)
+    }
+
+    pub fn primary_item(&self) -> Option<&ItemInfoEntry> {
+        self.primary_item_id.and_then(|id| self.item(id))
+    }
+
+    pub fn location(&self, item_id: u32) -> Option<&ItemLocation> {
+        self.locations.iter().find(|location| location.item_id == item_id)
+    }
+
+    /// Properties of `item_id`, in the order they apply.
+    pub fn item_properties(&self, item_id: u32) -> Vec<&ItemProperty> {
+        self.property_associations.iter()
+            .filter(|a| a.item_id == item_id)
+            .flat_map(|a| a.associations.iter())
+            .filter_map(|a| match a.property_index {
+                0 => None,
+                index => self.properties.get(index as usize - 1),
+            })
+            .collect()
+    }
+
+    /// Bit depth of each channel of `item_id`, from its 'pixi'.
+    pub fn pixel_bit_depths(&self, item_id: u32) -> Option<&[u8]> {
+        self.item_properties(item_id).into_iter()
+            .filter_map(|p| match *p {
+                ItemProperty::PixelInformation(ref bits) => Some(&bits[..]),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Items with a reference of `reference_type` to `item_id`, in
+    /// file order.
+    pub fn referencing_items(&self, reference_type: u32, item_id: u32) -> Vec<u32> {
+        self.references.iter()
+            .filter(|r| r.reference_type == reference_type && r.to_item_ids.contains(&item_id))
+            .map(|r| r.from_item_id)
+            .collect()
+    }
+
+    /// Thumbnails of `item_id`, in file order.
+    pub fn thumbnails(&self, item_id: u32) -> Vec<u32> {
+        self.referencing_items(REFERENCE_THUMBNAIL, item_id)
+    }
+
+    /// Thumbnails of the primary item, in file order.
+    ///
+    /// The thumbnails are usually much smaller than the primary item,
+    /// so they can be decoded to show something while it loads.
+    pub fn primary_item_thumbnails(&self) -> Vec<u32> {
+        self.primary_item_id.map_or(Vec::new(), |id| self.thumbnails(id))
+    }
+
+    /// Read the layout of `item_id`, if it's a 'grid' derived image.
+    ///
+    /// The tiles are the targets of the grid's 'dimg' reference, and
+    /// there must be one for each cell.
+    pub fn image_grid<T: Read + Seek>(&self, src: &mut T, item_id: u32) -> Result<Option<ImageGrid>> {
+        if self.item(item_id).map_or(true, |item| item.item_type != ITEM_TYPE_GRID) {
+            return Ok(None);
+        }
+        let data = try!(self.read_item(src, item_id));
+        let mut grid = try!(parse_image_grid(&data));
+        grid.tiles = self.references.iter()
+            .filter(|r| r.reference_type == REFERENCE_DERIVED_IMAGE && r.from_item_id == item_id)
+            .flat_map(|r| r.to_item_ids.iter().cloned())
+            .collect();
+        if grid.tiles.len() != grid.rows as usize * grid.columns as usize {
+            return Err(Error::InvalidData("grid tile count doesn't match its size"));
+        }
+        Ok(Some(grid))
+    }
+
+    /// Read the coded data of an item, concatenating its extents.
+    ///
+    /// `src` is the file the metadata was parsed from. Items stored in
+    /// other files or constructed from other items are unsupported.
+    pub fn read_item<T: Read + Seek>(&self, src: &mut T, item_id: u32) -> Result<Vec<u8>> {
+        let location = match self.location(item_id) {
+            Some(location) => location,
+            None => return Err(Error::InvalidData("item has no location")),
+        };
+        if location.data_reference_index != 0 {
+            return Err(Error::Unsupported("item data in another file"));
+        }
+        let mut data = Vec::new();
+        for extent in &location.extents {
+            let start = match location.base_offset.checked_add(extent.offset) {
+                Some(start) => start,
+                None => return Err(Error::InvalidData("item extent offset overflow")),
+            };
+            match location.construction_method {
+                0 => {
+                    try!(src.seek(SeekFrom::Start(start)));
+                    let limit = ITEM_SIZE_LIMIT - data.len() as u64;
+                    if extent.length > limit {
+                        return Err(Error::InvalidData("item exceeds ITEM_SIZE_LIMIT"));
+                    }
+                    // A zero length extent runs to the end of the file.
+                    let wanted = if extent.length == 0 { limit + 1 } else { extent.length };
+                    let read = try!(Read::by_ref(src).take(wanted).read_to_end(&mut data)) as u64;
+                    if read > limit {
+                        return Err(Error::InvalidData("item exceeds ITEM_SIZE_LIMIT"));
+                    }
+                    if extent.length != 0 && read != extent.length {
+                        return Err(Error::UnexpectedEOF);
+                    }
+                }
+                1 => {
+                    let end = if extent.length == 0 {
+                        self.item_data.len() as u64
+                    } else {
+                        start.saturating_add(extent.length)
+                    };
+                    if start > end || end > self.item_data.len() as u64 {
+                        return Err(Error::InvalidData("item extent outside idat"));
+                    }
+                    if end - start > ITEM_SIZE_LIMIT - data.len() as u64 {
+                        return Err(Error::InvalidData("item exceeds ITEM_SIZE_LIMIT"));
+                    }
+                    data.extend_from_slice(&self.item_data[start as usize..end as usize]);
+                }
+                _ => return Err(Error::Unsupported("item construction method")),
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Parse the contents of a 'grid' item, leaving the tiles empty.
+fn parse_image_grid(data: &[u8]) -> Result<ImageGrid> {
+    let mut src = data;
+    let version = try!(src.read_u8());
+    if version != 0 {
+        return Err(Error::Unsupported("unknown grid version"));
+    }
+    let flags = try!(src.read_u8());
+    let rows = try!(src.read_u8()) as u16 + 1;
+    let columns = try!(src.read_u8()) as u16 + 1;
+    let (output_width, output_height) = if flags & 1 != 0 {
+        (try!(be_u32(&mut src)), try!(be_u32(&mut src)))
+    } else {
+        (try!(be_u16(&mut src)) as u32, try!(be_u16(&mut src)) as u32)
+    };
+    Ok(ImageGrid {
+        rows: rows,
+        columns: columns,
+        output_width: output_width,
+        output_height: output_height,
+        tiles: Vec::new(),
+    })
+}
+
+/// Read a big-endian unsigned integer of 0, 4 or 8 bytes.
+fn be_sized<T: Read>(src: &mut T, size: u8) -> Result<u64> {
+    match size {
+        0 => Ok(0),
+        4 => be_u32(src).map(|v| v as u64),
+        8 => be_u64(src),
+        _ => Err(Error::InvalidData("invalid iloc field size")),
+    }
+}
+
+/// Read an item ID, 16 bits wide in early box versions.
+fn read_item_id<T: Read>(src: &mut T, wide: bool) -> Result<u32> {
+    if wide {
+        be_u32(src)
+    } else {
+        be_u16(src).map(|id| id as u32)
+    }
+}
+
+fn read_pitm<T: Read>(src: &mut BMFFBox<T>) -> Result<u32> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    read_item_id(src, version > 0)
+}
+
+fn read_infe<T: Read>(src: &mut BMFFBox<T>) -> Result<ItemInfoEntry> {
+    let (version, flags) = try!(read_fullbox_extra(src));
+    let item_id = try!(read_item_id(src, version > 2));
+    let _item_protection_index = try!(be_u16(src));
+    let item_type = if version >= 2 { try!(be_u32(src)) } else { 0 };
+    let bytes_left = src.bytes_left();
+    let item_name = try!(read_null_terminated_string(src, bytes_left));
+    let content_type = if item_type == ITEM_TYPE_MIME {
+        let bytes_left = src.bytes_left();
+        Some(try!(read_null_terminated_string(src, bytes_left)))
+    } else {
+        None
+    };
+    // Content encoding, or the URI type of "uri " items.
+    let bytes_left = src.bytes_left();
+    try!(skip(src, bytes_left));
+    Ok(ItemInfoEntry {
+        item_id: item_id,
+        item_type: item_type,
+        item_name: item_name,
+        content_type: content_type,
+        hidden: flags & 1 != 0,
+    })
+}
+
+fn read_iinf<T: Read>(src: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<Vec<ItemInfoEntry>> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    let _entry_count = if version == 0 { try!(be_u16(src)) as u32 } else { try!(be_u32(src)) };
+    let mut items = Vec::new();
+    let mut iter = src.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::ItemInfoEntry => {
+                if let Some(item) = try_leaf!(b, context, read_infe(&mut b)) {
+                    items.push(item);
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        }
+        check_parser_state!(b.content);
+    }
+    Ok(items)
+}
+
+fn read_iloc<T: Read>(src: &mut BMFFBox<T>) -> Result<Vec<ItemLocation>> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    if version > 2 {
+        return Err(Error::Unsupported("unknown iloc version"));
+    }
+    let sizes = try!(be_u16(src));
+    let offset_size = (sizes >> 12) as u8;
+    let length_size = (sizes >> 8 & 0xf) as u8;
+    let base_offset_size = (sizes >> 4 & 0xf) as u8;
+    let index_size = if version > 0 { (sizes & 0xf) as u8 } else { 0 };
+    let item_count = try!(read_item_id(src, version == 2));
+    let mut locations = Vec::new();
+    for _ in 0..item_count {
+        let item_id = try!(read_item_id(src, version == 2));
+        let construction_method = if version > 0 { (try!(be_u16(src)) & 0xf) as u8 } else { 0 };
+        let data_reference_index = try!(be_u16(src));
+        let base_offset = try!(be_sized(src, base_offset_size));
+        let extent_count = try!(be_u16(src));
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            let _extent_index = try!(be_sized(src, index_size));
+            let offset = try!(be_sized(src, offset_size));
+            let length = try!(be_sized(src, length_size));
+            extents.push(ItemExtent { offset: offset, length: length });
+        }
+        locations.push(ItemLocation {
+            item_id: item_id,
+            construction_method: construction_method,
+            data_reference_index: data_reference_index,
+            base_offset: base_offset,
+            extents: extents,
+        });
+    }
+    Ok(locations)
+}
+
+fn read_iref<T: Read>(src: &mut BMFFBox<T>) -> Result<Vec<ItemReference>> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    let mut references = Vec::new();
+    let mut iter = src.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        let from_item_id = try!(read_item_id(&mut b, version > 0));
+        let reference_count = try!(be_u16(&mut b));
+        let mut to_item_ids = Vec::new();
+        for _ in 0..reference_count {
+            to_item_ids.push(try!(read_item_id(&mut b, version > 0)));
+        }
+        references.push(ItemReference {
+            reference_type: u32::from(b.head.name),
+            from_item_id: from_item_id,
+            to_item_ids: to_item_ids,
+        });
+        check_parser_state!(b.content);
+    }
+    Ok(references)
+}
+
+fn read_ipco<T: Read>(src: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<Vec<ItemProperty>> {
+    let mut properties = Vec::new();
+    let mut iter = src.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        let property = match b.head.name {
+            BoxType::AuxiliaryTypeProperty => {
+                try_leaf!(b, context, read_auxc(&mut b)).map(ItemProperty::AuxiliaryType)
+            }
+            BoxType::CleanApertureBox => {
+                try_leaf!(b, context, read_clap(&mut b)).map(ItemProperty::CleanAperture)
+            }
+            BoxType::ImageRotationProperty => {
+                try_leaf!(b, context, read_irot(&mut b)).map(ItemProperty::Rotation)
+            }
+            BoxType::ImageMirrorProperty => {
+                try_leaf!(b, context, read_imir(&mut b)).map(ItemProperty::Mirror)
+            }
+            BoxType::PixelInformationProperty => {
+                try_leaf!(b, context, read_pixi(&mut b)).map(ItemProperty::PixelInformation)
+            }
+            _ => {
+                try!(skip_unknown_box(&mut b, context));
+                None
+            }
+        };
+        // Properties are referred to by index, so keep a placeholder
+        // for any we can't read.
+        properties.push(property.unwrap_or(ItemProperty::Unsupported(b.head.name)));
+        check_parser_state!(b.content);
+    }
+    Ok(properties)
+}
+
+fn read_pixi<T: Read>(src: &mut BMFFBox<T>) -> Result<Vec<u8>> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    let num_channels = try!(src.read_u8());
+    let mut bits_per_channel = Vec::with_capacity(num_channels as usize);
+    for _ in 0..num_channels {
+        bits_per_channel.push(try!(src.read_u8()));
+    }
+    Ok(bits_per_channel)
+}
+
+fn read_ipma<T: Read>(src: &mut BMFFBox<T>) -> Result<Vec<ItemPropertyAssociation>> {
+    let (version, flags) = try!(read_fullbox_extra(src));
+    let entry_count = try!(be_u32(src));
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let item_id = try!(read_item_id(src, version > 0));
+        let association_count = try!(src.read_u8());
+        let mut associations = Vec::new();
+        for _ in 0..association_count {
+            let (essential, property_index) = if flags & 1 != 0 {
+                let value = try!(be_u16(src));
+                (value & 0x8000 != 0, value & 0x7fff)
+            } else {
+                let value = try!(src.read_u8());
+                (value & 0x80 != 0, (value & 0x7f) as u16)
+            };
+            associations.push(PropertyAssociation {
+                property_index: property_index,
+                essential: essential,
+            });
+        }
+        entries.push(ItemPropertyAssociation {
+            item_id: item_id,
+            associations: associations,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_iprp<T: Read>(src: &mut BMFFBox<T>, meta: &mut ItemMetadata, context: &mut MediaContext) -> Result<()> {
+    let mut iter = src.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::ItemPropertyContainerBox => meta.properties = try!(read_ipco(&mut b, context)),
+            BoxType::ItemPropertyAssociationBox => {
+                if let Some(entries) = try_leaf!(b, context, read_ipma(&mut b)) {
+                    meta.property_associations.extend(entries);
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        }
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+fn read_idat<T: Read>(src: &mut BMFFBox<T>) -> Result<Vec<u8>> {
+    let bytes_left = src.bytes_left();
+    if bytes_left as u64 > ITEM_DATA_BOX_LIMIT {
+        return Err(Error::InvalidData("idat exceeds ITEM_DATA_BOX_LIMIT"));
+    }
+    read_buf(src, bytes_left)
+}
+
+/// Parse a top-level 'meta' box into `context.item_metadata`.
+pub fn read_item_meta<T: Read>(src: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    if version != 0 {
+        warning!("{:?} (skipped unknown version {})", src.get_header(), version);
+        let to_skip = src.bytes_left();
+        return skip(src, to_skip);
+    }
+    let mut meta = ItemMetadata::default();
+    let mut iter = src.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::HandlerBox => {
+                if let Some(hdlr) = try_leaf!(b, context, read_hdlr(&mut b)) {
+                    meta.handler_type = hdlr.handler_type;
+                }
+            }
+            BoxType::PrimaryItemBox => {
+                meta.primary_item_id = try_leaf!(b, context, read_pitm(&mut b));
+            }
+            BoxType::ItemInfoBox => meta.items = try!(read_iinf(&mut b, context)),
+            BoxType::ItemLocationBox => {
+                if let Some(locations) = try_leaf!(b, context, read_iloc(&mut b)) {
+                    meta.locations = locations;
+                }
+            }
+            BoxType::ItemReferenceBox => {
+                if let Some(references) = try_leaf!(b, context, read_iref(&mut b)) {
+                    meta.references = references;
+                }
+            }
+            BoxType::ItemDataBox => {
+                if let Some(data) = try_leaf!(b, context, read_idat(&mut b)) {
+                    meta.item_data = data;
+                }
+            }
+            BoxType::ItemPropertiesBox => try!(read_iprp(&mut b, &mut meta, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
+        }
+        check_parser_state!(b.content);
+    }
+    log!("{:?}", meta);
+    context.item_metadata = Some(meta);
+    Ok(())
+}
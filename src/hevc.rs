@@ -0,0 +1,72 @@
+//! H.265 decoder configuration parsing.
+//!
+//! 'hvc1' and 'hev1' sample entries carry an 'hvcC'
+//! HEVCDecoderConfigurationRecord, which is kept as is for decoders.
+//! Its fixed fields give the profile, tier and level needed to pick
+//! one.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use Error;
+use Result;
+use SampleEntry;
+use Track;
+use VideoCodecSpecific;
+
+/// Fixed fields at the start of an 'hvcC' HEVCDecoderConfigurationRecord.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HevcDecoderConfig {
+    pub general_profile_space: u8,
+    /// Set for the High tier, clear for the Main tier.
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    /// The 48 bits of constraint flags, as in the VPS and SPS.
+    pub general_constraint_indicator_flags: u64,
+    /// Thirty times the level number, so 93 is level 3.1.
+    pub general_level_idc: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    /// Size in bytes of the length prefix on each NAL unit in samples.
+    pub nal_length_size: u8,
+}
+
+/// Parse the fixed fields of the contents of an 'hvcC' box.
+pub fn parse_hvcc(hvcc: &[u8]) -> Result<HevcDecoderConfig> {
+    if hvcc.len() < 23 {
+        return Err(Error::InvalidData("truncated hvcC"));
+    }
+    if hvcc[0] != 1 {
+        return Err(Error::Unsupported("unknown hvcC configuration version"));
+    }
+    let be = |bytes: &[u8]| bytes.iter().fold(0u64, |v, &b| v << 8 | b as u64);
+    Ok(HevcDecoderConfig {
+        general_profile_space: hvcc[1] >> 6,
+        general_tier_flag: hvcc[1] & 0x20 != 0,
+        general_profile_idc: hvcc[1] & 0x1f,
+        general_profile_compatibility_flags: be(&hvcc[2..6]) as u32,
+        general_constraint_indicator_flags: be(&hvcc[6..12]),
+        general_level_idc: hvcc[12],
+        chroma_format_idc: hvcc[16] & 0x03,
+        bit_depth_luma: (hvcc[17] & 0x07) + 8,
+        bit_depth_chroma: (hvcc[18] & 0x07) + 8,
+        nal_length_size: (hvcc[21] & 0x03) + 1,
+    })
+}
+
+impl Track {
+    /// Parse the profile, tier and level from the track's 'hvcC', if
+    /// it has one.
+    pub fn hevc_decoder_config(&self) -> Result<Option<HevcDecoderConfig>> {
+        match self.data {
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::HEVCConfig(ref hvcc) => parse_hvcc(hvcc).map(Some),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Entry points for still image files.
+//!
+//! `read_mp4` accepts anything ISO BMFF shaped and leaves callers to
+//! work out whether it holds the image they expected. `parse_avif` and
+//! `parse_heif` check the 'ftyp' brands first, require a displayable
+//! primary item and return just the item metadata.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Seek};
+
+use BoxIter;
+use BoxType;
+use Error;
+use ItemInfoEntry;
+use ItemMetadata;
+use MediaContext;
+use Result;
+use ITEM_TYPE_GRID;
+use get_debug_mode;
+use heif::read_item_meta;
+use read_ftyp;
+use skip_box_content;
+
+const BRAND_AVIF: u32 = 0x61766966; // "avif"
+const BRAND_MIF1: u32 = 0x6d696631; // "mif1"
+const BRAND_HEIC: u32 = 0x68656963; // "heic"
+const BRAND_HEIX: u32 = 0x68656978; // "heix"
+
+const ITEM_TYPE_AV01: u32 = 0x61763031; // "av01"
+
+/// Handler type of a 'meta' box describing image items.
+const HANDLER_PICT: u32 = 0x70696374; // "pict"
+
+/// Kinds of image file with their own entry point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Avif,
+    Heif,
+}
+
+impl ImageFormat {
+    /// Brands, any of which marks a file as this format.
+    fn brands(&self) -> &'static [u32] {
+        match *self {
+            ImageFormat::Avif => &[BRAND_AVIF],
+            ImageFormat::Heif => &[BRAND_MIF1, BRAND_HEIC, BRAND_HEIX],
+        }
+    }
+}
+
+/// The result of parsing an image file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageContext {
+    pub format: ImageFormat,
+    pub major_brand: u32,
+    pub compatible_brands: Vec<u32>,
+    /// The item to display, which was listed in the 'iinf'.
+    pub primary_item_id: u32,
+    pub metadata: ItemMetadata,
+}
+
+impl ImageContext {
+    pub fn primary_item(&self) -> Option<&ItemInfoEntry> {
+        self.metadata.item(self.primary_item_id)
+    }
+
+    /// Read the coded data of the primary item.
+    pub fn read_primary_item<T: Read + Seek>(&self, src: &mut T) -> Result<Vec<u8>> {
+        self.metadata.read_item(src, self.primary_item_id)
+    }
+}
+
+/// Read an AVIF file, which must have the 'avif' brand and an 'av01'
+/// or 'grid' primary item.
+pub fn parse_avif<T: Read>(f: &mut T) -> Result<ImageContext> {
+    let context = try!(parse_image(f, ImageFormat::Avif));
+    let item_type = context.primary_item().map_or(0, |item| item.item_type);
+    if item_type != ITEM_TYPE_AV01 && item_type != ITEM_TYPE_GRID {
+        return Err(Error::InvalidData("AVIF primary item isn't av01 or grid"));
+    }
+    Ok(context)
+}
+
+/// Read a HEIF file, which must have the 'mif1', 'heic' or 'heix'
+/// brand and a primary item.
+pub fn parse_heif<T: Read>(f: &mut T) -> Result<ImageContext> {
+    parse_image(f, ImageFormat::Heif)
+}
+
+fn parse_image<T: Read>(f: &mut T, format: ImageFormat) -> Result<ImageContext> {
+    // Item parsing records its results in a MediaContext.
+    let mut context = MediaContext::new();
+    let mut brands = None;
+    let mut iter = BoxIter::new(f);
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::FileTypeBox if brands.is_none() => {
+                let ftyp = try!(read_ftyp(&mut b));
+                log!("{:?}", ftyp);
+                let compatible = format.brands().iter().any(|brand| {
+                    ftyp.major_brand == *brand || ftyp.compatible_brands.contains(brand)
+                });
+                if !compatible {
+                    return Err(Error::IncompatibleBrand);
+                }
+                brands = Some((ftyp.major_brand, ftyp.compatible_brands));
+            }
+            _ if brands.is_none() => return Err(Error::InvalidData("image file doesn't start with ftyp")),
+            BoxType::MetadataBox => try!(read_item_meta(&mut b, &mut context)),
+            _ => try!(skip_box_content(&mut b)),
+        }
+        check_parser_state!(b.content);
+    }
+
+    let (major_brand, compatible_brands) = match brands {
+        Some(brands) => brands,
+        None => return Err(Error::InvalidData("image file doesn't start with ftyp")),
+    };
+    let metadata = match context.item_metadata.take() {
+        Some(metadata) => metadata,
+        None => return Err(Error::NoPrimaryItem),
+    };
+    if metadata.handler_type != HANDLER_PICT {
+        return Err(Error::InvalidData("image meta handler isn't pict"));
+    }
+    let primary_item_id = match metadata.primary_item() {
+        Some(item) => item.item_id,
+        None => return Err(Error::NoPrimaryItem),
+    };
+    Ok(ImageContext {
+        format: format,
+        major_brand: major_brand,
+        compatible_brands: compatible_brands,
+        primary_item_id: primary_item_id,
+        metadata: metadata,
+    })
+}
@@ -0,0 +1,349 @@
+//! Synthesis of a fragmented-MP4 initialization segment.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::Write;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use AudioCodecSpecific;
+use AudioSampleEntry;
+use BoxType;
+use Error;
+use MediaContext;
+use Result;
+use SampleEntry;
+use Track;
+use VideoCodecSpecific;
+use VideoSampleEntry;
+use flac_stream_header;
+
+/// Unity transformation matrix for mvhd and tkhd.
+const UNITY_MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+/// Packed ISO-639-2/T code for 'und'.
+const LANGUAGE_UNDETERMINED: u16 = 0x55c4;
+
+/// Movie timescale used when the source has no mvhd.
+const DEFAULT_TIMESCALE: u64 = 1000;
+
+/// Append a box named `name` with the payload written by `f`.
+fn write_box<F>(dst: &mut Vec<u8>, name: &[u8; 4], f: F) -> Result<()>
+    where F: FnOnce(&mut Vec<u8>) -> Result<()>
+{
+    let start = dst.len();
+    try!(dst.write_u32::<BigEndian>(0));
+    try!(dst.write_all(name));
+    try!(f(dst));
+    let size = dst.len() - start;
+    if size > std::u32::MAX as usize {
+        return Err(Error::InvalidData("box too large for init segment"));
+    }
+    BigEndian::write_u32(&mut dst[start..start + 4], size as u32);
+    Ok(())
+}
+
+fn write_full_box<F>(dst: &mut Vec<u8>, name: &[u8; 4], version: u8, flags: u32, f: F) -> Result<()>
+    where F: FnOnce(&mut Vec<u8>) -> Result<()>
+{
+    write_box(dst, name, |dst| {
+        try!(dst.write_u32::<BigEndian>((version as u32) << 24 | flags));
+        f(dst)
+    })
+}
+
+fn write_matrix(dst: &mut Vec<u8>) -> Result<()> {
+    for value in &UNITY_MATRIX {
+        try!(dst.write_u32::<BigEndian>(*value));
+    }
+    Ok(())
+}
+
+/// Four character code of the sample entry to write for `track`.
+fn sample_entry_name(track: &Track) -> Result<&'static [u8; 4]> {
+    // The avc3 and hev1 entries allow parameter sets in samples as
+    // well as the configuration box, so keep whichever was read.
+    let entry = match track.data {
+        Some(SampleEntry::Video(ref video)) => Some(video.name),
+        _ => None,
+    };
+    match track.mime_type.as_str() {
+        "video/avc" if entry == Some(BoxType::AVC3SampleEntry) => Ok(b"avc3"),
+        "video/avc" => Ok(b"avc1"),
+        "video/hevc" if entry == Some(BoxType::HEV1SampleEntry) => Ok(b"hev1"),
+        "video/hevc" => Ok(b"hvc1"),
+        "video/vp8" => Ok(b"vp08"),
+        "video/vp9" => Ok(b"vp09"),
+        "video/av1" => Ok(b"av01"),
+        "audio/mp4a-latm" => Ok(b"mp4a"),
+        "audio/opus" => Ok(b"Opus"),
+        "audio/flac" => Ok(b"fLaC"),
+        "video/crypto" | "audio/crypto" => Err(Error::Unsupported("init segment for protected track")),
+        _ => Err(Error::Unsupported("init segment for unknown codec")),
+    }
+}
+
+fn write_video_entry(dst: &mut Vec<u8>, name: &[u8; 4], video: &VideoSampleEntry) -> Result<()> {
+    write_box(dst, name, |dst| {
+        try!(dst.write_all(&[0; 6]));
+        try!(dst.write_u16::<BigEndian>(1)); // data_reference_index
+        try!(dst.write_all(&[0; 16]));
+        try!(dst.write_u16::<BigEndian>(video.width));
+        try!(dst.write_u16::<BigEndian>(video.height));
+        try!(dst.write_u32::<BigEndian>(0x00480000)); // 72 dpi
+        try!(dst.write_u32::<BigEndian>(0x00480000));
+        try!(dst.write_u32::<BigEndian>(0));
+        try!(dst.write_u16::<BigEndian>(1)); // frame_count
+        try!(dst.write_all(&[0; 32])); // compressorname
+        try!(dst.write_u16::<BigEndian>(0x0018)); // depth
+        try!(dst.write_i16::<BigEndian>(-1));
+        match video.codec_specific {
+            VideoCodecSpecific::AVCConfig(ref avcc) => {
+                write_box(dst, b"avcC", |dst| dst.write_all(avcc).map_err(From::from))
+            }
+            VideoCodecSpecific::HEVCConfig(ref hvcc) => {
+                write_box(dst, b"hvcC", |dst| dst.write_all(hvcc).map_err(From::from))
+            }
+            VideoCodecSpecific::AV1Config(ref av1c) => {
+                write_box(dst, b"av1C", |dst| dst.write_all(av1c).map_err(From::from))
+            }
+            VideoCodecSpecific::VPxConfig(ref vpcc) => {
+                write_full_box(dst, b"vpcC", vpcc.version, 0, |dst| {
+                    try!(dst.write_u8(vpcc.profile));
+                    try!(dst.write_u8(vpcc.level));
+                    if vpcc.version == 0 {
+                        try!(dst.write_u8(vpcc.bit_depth << 4 | vpcc.color_space & 0x0f));
+                        try!(dst.write_u8(vpcc.chroma_subsampling << 4 |
+                                          (vpcc.transfer_function & 0x07) << 1 |
+                                          vpcc.video_full_range as u8));
+                    } else {
+                        try!(dst.write_u8(vpcc.bit_depth << 4 |
+                                          (vpcc.chroma_subsampling & 0x07) << 1 |
+                                          vpcc.video_full_range as u8));
+                        try!(dst.write_u8(vpcc.colour_primaries));
+                        try!(dst.write_u8(vpcc.transfer_function));
+                        try!(dst.write_u8(vpcc.matrix_coefficients));
+                    }
+                    if vpcc.codec_init.len() > std::u16::MAX as usize {
+                        return Err(Error::InvalidData("vpcC codec init data too large"));
+                    }
+                    try!(dst.write_u16::<BigEndian>(vpcc.codec_init.len() as u16));
+                    dst.write_all(&vpcc.codec_init).map_err(From::from)
+                })
+            }
+        }
+    })
+}
+
+fn write_audio_entry(dst: &mut Vec<u8>, name: &[u8; 4], audio: &AudioSampleEntry) -> Result<()> {
+    write_box(dst, name, |dst| {
+        try!(dst.write_all(&[0; 6]));
+        try!(dst.write_u16::<BigEndian>(1)); // data_reference_index
+        try!(dst.write_all(&[0; 8]));
+        try!(dst.write_u16::<BigEndian>(audio.channelcount));
+        try!(dst.write_u16::<BigEndian>(audio.samplesize));
+        try!(dst.write_u32::<BigEndian>(0));
+        try!(dst.write_u32::<BigEndian>(audio.samplerate.0));
+        match audio.codec_specific {
+            AudioCodecSpecific::ES_Descriptor(ref esds) => {
+                write_full_box(dst, b"esds", 0, 0, |dst| dst.write_all(esds).map_err(From::from))
+            }
+            AudioCodecSpecific::OpusSpecificBox(ref opus) => {
+                write_box(dst, b"dOps", |dst| {
+                    try!(dst.write_u8(0)); // version
+                    try!(dst.write_u8(opus.output_channel_count));
+                    try!(dst.write_u16::<BigEndian>(opus.pre_skip));
+                    try!(dst.write_u32::<BigEndian>(opus.input_sample_rate));
+                    try!(dst.write_i16::<BigEndian>(opus.output_gain));
+                    try!(dst.write_u8(opus.channel_mapping_family));
+                    if let Some(ref table) = opus.channel_mapping_table {
+                        try!(dst.write_u8(table.stream_count));
+                        try!(dst.write_u8(table.coupled_count));
+                        try!(dst.write_all(&table.channel_mapping));
+                    }
+                    Ok(())
+                })
+            }
+            AudioCodecSpecific::FLACSpecificBox(ref flac) => {
+                let header = try!(flac_stream_header(flac));
+                // The blocks, without the stream marker.
+                write_full_box(dst, b"dfLa", 0, 0, |dst| dst.write_all(&header[4..]).map_err(From::from))
+            }
+        }
+    })
+}
+
+fn write_stbl(dst: &mut Vec<u8>, track: &Track) -> Result<()> {
+    let name = try!(sample_entry_name(track));
+    write_box(dst, b"stbl", |dst| {
+        try!(write_full_box(dst, b"stsd", 0, 0, |dst| {
+            try!(dst.write_u32::<BigEndian>(1));
+            match track.data {
+                Some(SampleEntry::Video(ref video)) => write_video_entry(dst, name, video),
+                Some(SampleEntry::Audio(ref audio)) => write_audio_entry(dst, name, audio),
+                _ => Err(Error::InvalidData("track has no sample entry")),
+            }
+        }));
+        // The sample tables are empty; samples are in the fragments.
+        for table in &[b"stts", b"stsc", b"stco"] {
+            try!(write_full_box(dst, *table, 0, 0, |dst| {
+                dst.write_u32::<BigEndian>(0).map_err(From::from)
+            }));
+        }
+        write_full_box(dst, b"stsz", 0, 0, |dst| {
+            try!(dst.write_u32::<BigEndian>(0)); // sample_size
+            dst.write_u32::<BigEndian>(0).map_err(From::from)
+        })
+    })
+}
+
+fn write_trak(dst: &mut Vec<u8>, track: &Track, track_id: u32) -> Result<()> {
+    let timescale = match track.timescale {
+        Some(timescale) if timescale.0 > 0 && timescale.0 <= std::u32::MAX as u64 => timescale.0 as u32,
+        _ => return Err(Error::InvalidData("track has no usable timescale")),
+    };
+    let (is_video, width, height) = match track.data {
+        Some(SampleEntry::Video(ref video)) => {
+            (true, (video.width as u32) << 16, (video.height as u32) << 16)
+        }
+        _ => (false, 0, 0),
+    };
+    write_box(dst, b"trak", |dst| {
+        let flags = match track.tkhd {
+            Some(ref tkhd) => {
+                tkhd.enabled as u32 | (tkhd.in_movie as u32) << 1 | (tkhd.in_preview as u32) << 2
+            }
+            None => 0x3,
+        };
+        try!(write_full_box(dst, b"tkhd", 0, flags, |dst| {
+            try!(dst.write_u32::<BigEndian>(0)); // creation_time
+            try!(dst.write_u32::<BigEndian>(0)); // modification_time
+            try!(dst.write_u32::<BigEndian>(track_id));
+            try!(dst.write_u32::<BigEndian>(0));
+            try!(dst.write_u32::<BigEndian>(0)); // duration
+            try!(dst.write_all(&[0; 8]));
+            let (layer, alternate_group) = track.tkhd.as_ref()
+                .map_or((0, 0), |tkhd| (tkhd.layer, tkhd.alternate_group));
+            try!(dst.write_i16::<BigEndian>(layer));
+            try!(dst.write_i16::<BigEndian>(alternate_group));
+            try!(dst.write_i16::<BigEndian>(if is_video { 0 } else { 0x0100 }));
+            try!(dst.write_u16::<BigEndian>(0));
+            try!(write_matrix(dst));
+            // Keep the presentation size from the source, which may
+            // differ from the coded size.
+            let (width, height) = match track.tkhd {
+                Some(ref tkhd) if is_video => (tkhd.width.0, tkhd.height.0),
+                _ => (width, height),
+            };
+            try!(dst.write_u32::<BigEndian>(width));
+            dst.write_u32::<BigEndian>(height).map_err(From::from)
+        }));
+        write_box(dst, b"mdia", |dst| {
+            try!(write_full_box(dst, b"mdhd", 0, 0, |dst| {
+                try!(dst.write_u32::<BigEndian>(0)); // creation_time
+                try!(dst.write_u32::<BigEndian>(0)); // modification_time
+                try!(dst.write_u32::<BigEndian>(timescale));
+                try!(dst.write_u32::<BigEndian>(0)); // duration
+                try!(dst.write_u16::<BigEndian>(LANGUAGE_UNDETERMINED));
+                dst.write_u16::<BigEndian>(0).map_err(From::from)
+            }));
+            try!(write_full_box(dst, b"hdlr", 0, 0, |dst| {
+                try!(dst.write_u32::<BigEndian>(0));
+                try!(dst.write_all(if is_video { b"vide" } else { b"soun" }));
+                try!(dst.write_all(&[0; 12]));
+                let name: &[u8] = if is_video { b"VideoHandler\0" } else { b"SoundHandler\0" };
+                dst.write_all(name).map_err(From::from)
+            }));
+            write_box(dst, b"minf", |dst| {
+                if is_video {
+                    try!(write_full_box(dst, b"vmhd", 0, 1, |dst| {
+                        dst.write_all(&[0; 8]).map_err(From::from)
+                    }));
+                } else {
+                    try!(write_full_box(dst, b"smhd", 0, 0, |dst| {
+                        dst.write_all(&[0; 4]).map_err(From::from)
+                    }));
+                }
+                try!(write_box(dst, b"dinf", |dst| {
+                    write_full_box(dst, b"dref", 0, 0, |dst| {
+                        try!(dst.write_u32::<BigEndian>(1));
+                        // Media data is in the same file.
+                        write_full_box(dst, b"url ", 0, 1, |_| Ok(()))
+                    })
+                }));
+                write_stbl(dst, track)
+            })
+        })
+    })
+}
+
+impl MediaContext {
+    /// Write an initialization segment describing the parsed tracks.
+    ///
+    /// The output is a minimal ftyp and moov with an mvex, suitable
+    /// for appending fragmented media to, e.g. through Media Source
+    /// Extensions. Only audio and video tracks are included; sample
+    /// tables, edit lists and metadata from the source are dropped.
+    /// Protected tracks are not supported.
+    pub fn write_init_segment<W: Write>(&self, dst: &mut W) -> Result<()> {
+        let mut tracks = Vec::new();
+        for track in &self.tracks {
+            match track.data {
+                Some(SampleEntry::Audio(_)) | Some(SampleEntry::Video(_)) => {}
+                _ => continue,
+            }
+            match track.track_id {
+                Some(id) if id > 0 => tracks.push((track, id)),
+                _ => return Err(Error::InvalidData("track has no usable track ID")),
+            }
+        }
+        if tracks.is_empty() {
+            return Err(Error::InvalidData("no audio or video tracks for init segment"));
+        }
+        let next_track_id = tracks.iter().map(|&(_, id)| id).max().unwrap_or(0).saturating_add(1);
+        let timescale = match self.timescale {
+            Some(timescale) if timescale.0 > 0 && timescale.0 <= std::u32::MAX as u64 => timescale.0,
+            _ => DEFAULT_TIMESCALE,
+        };
+
+        let mut out = Vec::new();
+        try!(write_box(&mut out, b"ftyp", |dst| {
+            try!(dst.write_all(b"iso6"));
+            try!(dst.write_u32::<BigEndian>(0));
+            dst.write_all(b"iso6mp41").map_err(From::from)
+        }));
+        try!(write_box(&mut out, b"moov", |dst| {
+            try!(write_full_box(dst, b"mvhd", 0, 0, |dst| {
+                try!(dst.write_u32::<BigEndian>(0)); // creation_time
+                try!(dst.write_u32::<BigEndian>(0)); // modification_time
+                try!(dst.write_u32::<BigEndian>(timescale as u32));
+                try!(dst.write_u32::<BigEndian>(0)); // duration
+                try!(dst.write_u32::<BigEndian>(0x00010000)); // rate
+                try!(dst.write_u16::<BigEndian>(0x0100)); // volume
+                try!(dst.write_all(&[0; 10]));
+                try!(write_matrix(dst));
+                try!(dst.write_all(&[0; 24]));
+                dst.write_u32::<BigEndian>(next_track_id).map_err(From::from)
+            }));
+            for &(track, id) in &tracks {
+                try!(write_trak(dst, track, id));
+            }
+            write_box(dst, b"mvex", |dst| {
+                for &(_, id) in &tracks {
+                    try!(write_full_box(dst, b"trex", 0, 0, |dst| {
+                        try!(dst.write_u32::<BigEndian>(id));
+                        try!(dst.write_u32::<BigEndian>(1)); // default_sample_description_index
+                        try!(dst.write_u32::<BigEndian>(0)); // default_sample_duration
+                        try!(dst.write_u32::<BigEndian>(0)); // default_sample_size
+                        dst.write_u32::<BigEndian>(0).map_err(From::from)
+                    }));
+                }
+                Ok(())
+            })
+        }));
+        try!(dst.write_all(&out));
+        Ok(())
+    }
+}
@@ -7,18 +7,168 @@
 #![cfg_attr(feature = "fuzz", plugin(afl_plugin))]
 #[cfg(feature = "fuzz")]
 extern crate afl;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "decrypt")]
+extern crate aes;
 
 extern crate byteorder;
 use byteorder::ReadBytesExt;
-use std::io::{Read, Take};
+use std::io::{Read, Seek, SeekFrom, Take};
 use std::cmp;
 
+macro_rules! log {
+    ($($args:tt)*) => (
+        if get_debug_mode() {
+            println!( $( $args )* );
+        }
+    )
+}
+
+/// Report a recoverable problem with the input.
+///
+/// This logs in debug mode, and emits a tracing warning event when
+/// the `tracing` feature is enabled.
+macro_rules! warning {
+    ($($args:tt)*) => ({
+        log!($($args)*);
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($args)*);
+    })
+}
+
+macro_rules! check_parser_state {
+    ( $src:expr ) => {
+        if $src.limit() > 0 {
+            warning!("bad parser state: {} content bytes left", $src.limit());
+            return Err(Error::InvalidData("unread box content or bad parser sync"));
+        }
+    }
+}
+
+/// Evaluate a leaf box parse, yielding `Some(value)` on success.
+///
+/// Failures go through `recover_leaf_box`, yielding `None` if the box
+/// was skipped.
+macro_rules! try_leaf {
+    ( $src:expr, $context:expr, $parse:expr ) => {
+        match $parse {
+            Ok(v) => Some(v),
+            Err(e) => {
+                try!(recover_leaf_box(&mut $src, e, $context));
+                None
+            }
+        }
+    }
+}
+
 // Expose C api wrapper.
 pub mod capi;
 pub use capi::*;
 
+mod capture;
+
+mod allocator;
+
 mod boxes;
-use boxes::BoxType;
+pub use boxes::BoxType;
+
+mod sample_table;
+pub use sample_table::{SampleInfo, SampleRange, SampleSizeSummary, SampleTableMismatch};
+
+mod analysis;
+pub use analysis::{DecodeTimeDiscontinuity, InterleaveReport, SampleOutOfBounds, SequenceGap};
+
+mod manifest;
+pub use manifest::{FragmentRange, HlsByteRange, SegmentTimeline, TimelineSegment};
+
+mod cmaf;
+pub use cmaf::{CmafSegment, CmafViolation};
+
+mod diff;
+pub use diff::{compare, Difference};
+
+mod elementary;
+pub use elementary::{read_sample, avcc_to_annexb, sample_to_annexb, AnnexBConfig,
+                     esds_audio_specific_config, adts_header, OggOpusWriter, parse_esds,
+                     parse_audio_specific_config, ESDescriptor, AudioSpecificConfig, flac_stream_header};
+
+mod faststart;
+pub use faststart::relocate_moov_to_front;
+
+mod remux;
+pub use remux::concatenate;
+
+#[cfg(feature = "decrypt")]
+mod decrypt;
+#[cfg(feature = "decrypt")]
+pub use decrypt::{decrypt_sample, decrypt_to_clear, ContentKey};
+#[cfg(feature = "encrypt")]
+pub use decrypt::encrypt_sample;
+
+#[cfg(feature = "encrypt")]
+mod encrypt;
+#[cfg(feature = "encrypt")]
+pub use encrypt::encrypt_to_cenc;
+
+mod init_segment;
+
+mod mse;
+pub use mse::MseViolation;
+
+mod query;
+pub use query::{select_boxes, BoxNode, TopLevelBoxIter};
+
+mod memory;
+
+mod counting;
+pub use counting::{CountingReader, IoCounters};
+
+mod bitstream;
+pub use bitstream::{read_utf8_coded, read_leb128, BitReader};
+
+mod avc;
+pub use avc::{parse_avc_sps, parse_avcc, avcc_sequence_parameter_set, AvcDecoderConfig, AvcSequenceParameterSet,
+              DimensionMismatch};
+
+mod hevc;
+pub use hevc::{parse_hvcc, HevcDecoderConfig};
+
+mod av1;
+pub use av1::{parse_av1_sequence_header, find_av1_sequence_header, av1c_sequence_header, parse_av1c,
+              Av1DecoderConfig, Av1SequenceHeader};
+
+mod flac;
+pub use flac::{read_flac_stream_marker, read_flac_metadata_block, parse_flac_stream_info,
+               read_flac_frame_header, FlacMetadataBlock, FlacStreamInfo, FlacFrameHeader,
+               FlacChannelAssignment, FLAC_METADATA_STREAMINFO, OggPacketReader,
+               read_ogg_flac_headers};
+
+mod random_access;
+pub use random_access::{parse_random_access_index, RandomAccessEntry, TrackFragmentRandomAccess};
+
+mod heif;
+pub use heif::{ImageGrid, ItemExtent, ItemInfoEntry, ItemLocation, ItemMetadata, ItemProperty,
+               ItemPropertyAssociation, ItemReference, PropertyAssociation, ITEM_TYPE_GRID,
+               REFERENCE_DERIVED_IMAGE, REFERENCE_THUMBNAIL};
+
+mod auxiliary;
+pub use auxiliary::{AuxiliaryType, REFERENCE_AUXILIARY, REFERENCE_PREMULTIPLIED};
+use auxiliary::read_auxi;
+
+mod exif;
+pub use exif::{ITEM_TYPE_EXIF, REFERENCE_CONTENT_DESCRIBES};
+
+mod transform;
+pub use transform::{CleanAperture, CropRect, ImageOrientation, ImageTransform, MirrorAxis};
+
+mod image;
+pub use image::{parse_avif, parse_heif, ImageContext, ImageFormat};
+
+#[cfg(feature = "parse_cache")]
+mod cache;
+#[cfg(feature = "parse_cache")]
+pub use cache::{read_parse_cache, write_parse_cache};
 
 // Unit tests.
 #[cfg(test)]
@@ -27,6 +177,14 @@ mod tests;
 // Arbitrary buffer size limit used for raw read_bufs on a box.
 const BUF_SIZE_LIMIT: u64 = 1024 * 1024;
 
+// Arbitrary limit on the samples of a trun without per-sample fields,
+// whose count isn't bounded by the box size.
+const TRUN_SAMPLE_LIMIT: u32 = 1024 * 1024;
+
+// Arbitrary limit on the samples kept from all movie fragments, since
+// a few bytes of trun can describe any number of samples.
+const FRAGMENT_SAMPLE_LIMIT: usize = 4 * TRUN_SAMPLE_LIMIT as usize;
+
 static DEBUG_MODE: std::sync::atomic::AtomicBool = std::sync::atomic::ATOMIC_BOOL_INIT;
 
 pub fn set_debug_mode(mode: bool) {
@@ -38,14 +196,6 @@ fn get_debug_mode() -> bool {
     DEBUG_MODE.load(std::sync::atomic::Ordering::Relaxed)
 }
 
-macro_rules! log {
-    ($($args:tt)*) => (
-        if get_debug_mode() {
-            println!( $( $args )* );
-        }
-    )
-}
-
 /// Describes parser failures.
 ///
 /// This enum wraps the standard `io::Error` type, unified with
@@ -62,6 +212,13 @@ pub enum Error {
     Io(std::io::Error),
     /// read_mp4 terminated without detecting a moov box.
     NoMoov,
+    /// The moov's declared size, given here, exceeds the context's
+    /// `moov_size_limit`.
+    MoovTooLarge(u64),
+    /// parse_avif or parse_heif found no primary image item.
+    NoPrimaryItem,
+    /// The ftyp lacks the brands of the image format being parsed.
+    IncompatibleBrand,
 }
 
 impl From<std::io::Error> for Error {
@@ -82,6 +239,127 @@ impl From<std::string::FromUtf8Error> for Error {
 /// Result shorthand using our Error enum.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// How the parser responds to leaf boxes it can't read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseStrictness {
+    /// Any malformed or unsupported box fails the whole parse.
+    Strict,
+    /// Record the failure, skip the offending box and continue with
+    /// its siblings.
+    Lenient,
+}
+
+impl Default for ParseStrictness {
+    fn default() -> Self { ParseStrictness::Strict }
+}
+
+/// How much of the file the parser reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    /// Read the movie and track headers and the sample tables.
+    Tables,
+    /// Read the movie and track headers and sample descriptions only,
+    /// skipping the sample tables. Enough to identify the tracks and
+    /// their codecs, but sample lookups will find nothing.
+    Metadata,
+    /// Read everything, and also build each track's `samples` index
+    /// combining location, timing, sync and decryption parameters.
+    /// Samples of movie fragments are appended as each 'moof' is read.
+    Full,
+}
+
+impl Default for ParseMode {
+    fn default() -> Self { ParseMode::Tables }
+}
+
+/// The start of an unknown box's payload, kept for diagnostics.
+#[derive(Debug)]
+pub struct UnknownBox {
+    pub name: BoxType,
+    /// Size of the whole box, including the header.
+    pub size: u64,
+    /// Up to `MediaContext::unknown_box_capture` bytes of payload.
+    pub payload: Vec<u8>,
+}
+
+/// A leaf box which was skipped in lenient mode after failing to parse.
+#[derive(Debug)]
+pub struct SkippedBox {
+    pub name: BoxType,
+    pub error: Error,
+}
+
+/// A construct the parser doesn't support, and the box it was found in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnsupportedFeature {
+    /// The description carried by the `Error::Unsupported`.
+    pub feature: &'static str,
+    pub box_type: BoxType,
+}
+
+/// An unsigned 16.16 fixed-point number.
+///
+/// Used for track dimensions and sample rates.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FixedPoint16_16(pub u32);
+
+impl FixedPoint16_16 {
+    pub fn integer_part(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    pub fn fractional_part(&self) -> u16 {
+        (self.0 & 0xffff) as u16
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / 65536.0
+    }
+}
+
+/// A signed 16.16 fixed-point number.
+///
+/// Used for playback rates, where 1.0 is normal forward playback.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct SignedFixedPoint16_16(pub i32);
+
+impl SignedFixedPoint16_16 {
+    pub fn integer_part(&self) -> i16 {
+        (self.0 >> 16) as i16
+    }
+
+    pub fn fractional_part(&self) -> u16 {
+        (self.0 & 0xffff) as u16
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / 65536.0
+    }
+}
+
+/// A signed 8.8 fixed-point number.
+///
+/// Used for audio volumes, where 1.0 is full volume.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FixedPoint8_8(pub i16);
+
+impl FixedPoint8_8 {
+    pub fn integer_part(&self) -> i8 {
+        (self.0 >> 8) as i8
+    }
+
+    pub fn fractional_part(&self) -> u8 {
+        (self.0 & 0xff) as u8
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / 256.0
+    }
+}
+
 /// Basic ISO box structure.
 ///
 /// mp4 files are a sequence of possibly-nested 'box' structures.  Each box
@@ -111,16 +389,32 @@ struct FileTypeBox {
 struct MovieHeaderBox {
     timescale: u32,
     duration: u64,
+    rate: SignedFixedPoint16_16,
+    volume: FixedPoint8_8,
+    next_track_id: u32,
 }
 
 /// Track header box 'tkhd'
 #[derive(Debug, Clone)]
 pub struct TrackHeaderBox {
     track_id: u32,
+    /// True unless the track is both enabled and in the movie.
     pub disabled: bool,
+    /// The track_enabled flag.
+    pub enabled: bool,
+    /// The track_in_movie flag.
+    pub in_movie: bool,
+    /// The track_in_preview flag.
+    pub in_preview: bool,
     pub duration: u64,
-    pub width: u32,
-    pub height: u32,
+    /// Front-to-back ordering of video tracks; lower is closer to the viewer.
+    pub layer: i16,
+    /// Tracks sharing a non-zero group are alternatives to each other.
+    pub alternate_group: i16,
+    /// Relative audio volume; 1.0 is full volume and 0 muted.
+    pub volume: FixedPoint8_8,
+    pub width: FixedPoint16_16,
+    pub height: FixedPoint16_16,
 }
 
 /// Edit list box 'elst'
@@ -142,6 +436,7 @@ struct Edit {
 struct MediaHeaderBox {
     timescale: u32,
     duration: u64,
+    language: Option<String>,
 }
 
 // Chunk offset box 'stco' or 'co64'
@@ -173,6 +468,7 @@ struct SampleToChunk {
 #[derive(Debug)]
 struct SampleSizeBox {
     sample_size: u32,
+    sample_count: u32,
     sample_sizes: Vec<u32>,
 }
 
@@ -188,10 +484,232 @@ struct Sample {
     sample_delta: u32,
 }
 
+// Composition time to sample box 'ctts'
+#[derive(Debug)]
+struct CompositionOffsetBox {
+    samples: Vec<CompositionOffset>,
+}
+
+#[derive(Debug)]
+struct CompositionOffset {
+    sample_count: u32,
+    sample_offset: i64,
+}
+
+// Sample group description box 'sgpd'
+#[derive(Debug)]
+struct SampleGroupDescriptionBox {
+    grouping_type: u32,
+    /// Entries of a 'roll' group; empty for other grouping types.
+    roll_distances: Vec<i16>,
+    /// Entries of a 'seig' group; empty for other grouping types.
+    seig_entries: Vec<TrackEncryptionBox>,
+}
+
+// Sample to group box 'sbgp'
+#[derive(Debug)]
+struct SampleToGroupBox {
+    grouping_type: u32,
+    /// Runs of (sample_count, group_description_index).
+    entries: Vec<(u32, u32)>,
+}
+
+const ROLL_GROUPING_TYPE: u32 = 0x726f6c6c; // 'roll'
+const SEIG_GROUPING_TYPE: u32 = 0x73656967; // 'seig'
+
+/// A subsample from a 'subs' box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubSampleEntry {
+    /// Size in bytes; subsamples are contiguous within their sample.
+    pub size: u32,
+    pub priority: u8,
+    pub discardable: bool,
+    /// Meaning depends on the codec and the box's flags.
+    pub codec_specific_parameters: u32,
+}
+
+// Sub-sample information box 'subs'
+#[derive(Debug)]
+struct SubSampleInformationBox {
+    flags: u32,
+    /// Subsamples of each listed sample, keyed by 0-based sample index.
+    samples: Vec<(u32, Vec<SubSampleEntry>)>,
+}
+
+/// Common encryption schemes: 'cenc', 'cens', 'cbc1' and 'cbcs'.
+const PROTECTION_SCHEMES: [u32; 4] = [0x63656e63, 0x63656e73, 0x63626331, 0x63626373];
+
+/// Track encryption defaults from a 'tenc' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackEncryptionBox {
+    /// Encrypted and clear 16-byte blocks in the pattern used by the
+    /// 'cens' and 'cbcs' schemes, from a version 1 'tenc'. Both are 0
+    /// when the whole of each protected range is encrypted.
+    pub crypt_byte_block: u8,
+    pub skip_byte_block: u8,
+    pub is_encrypted: bool,
+    /// Size of the per-sample IVs in 'senc', or 0 if `constant_iv` is used.
+    pub iv_size: u8,
+    pub kid: Vec<u8>,
+    pub constant_iv: Option<Vec<u8>>,
+}
+
+/// DRM system initialization data from a 'pssh' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectionSystemSpecificHeaderBox {
+    pub system_id: Vec<u8>,
+    /// Key IDs listed by a version 1 box.
+    pub kid: Vec<Vec<u8>>,
+    /// Data specific to the protection system.
+    pub data: Vec<u8>,
+    /// The whole box, header included, as passed to a decryption module.
+    pub box_content: Vec<u8>,
+}
+
+/// Protection scheme information from a 'sinf' box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectionSchemeInfoBox {
+    /// The sample entry type before encryption, from 'frma'.
+    pub original_format: Option<BoxType>,
+    /// The protection scheme from 'schm', e.g. 'cenc' or 'cbcs'.
+    pub scheme_type: Option<u32>,
+    pub tenc: Option<TrackEncryptionBox>,
+}
+
+/// A run of clear bytes followed by a run of encrypted bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Subsample {
+    pub clear_bytes: u16,
+    pub encrypted_bytes: u32,
+}
+
+/// A sample's entry in an 'senc' box.
+#[derive(Debug, Clone)]
+struct SampleEncryptionEntry {
+    /// Empty if the track uses a constant IV.
+    iv: Vec<u8>,
+    subsamples: Vec<Subsample>,
+}
+
+/// Decryption parameters for a single sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleCrypto {
+    /// The key ID, from the sample's 'seig' group or the 'tenc'.
+    pub kid: Vec<u8>,
+    /// The sample's IV, or the track's constant IV.
+    pub iv: Vec<u8>,
+    /// Empty if the whole sample is encrypted.
+    pub subsamples: Vec<Subsample>,
+}
+
+/// A sample's location, timing and decryption parameters, as built
+/// by `ParseMode::Full`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullSample {
+    pub info: SampleInfo,
+    /// None if the track isn't encrypted.
+    pub crypto: Option<SampleCrypto>,
+}
+
+/// Segment index box 'sidx'
+#[derive(Debug, Clone)]
+pub struct SegmentIndexBox {
+    pub reference_id: u32,
+    pub timescale: u32,
+    pub earliest_presentation_time: u64,
+    /// Distance from the end of this box to the first referenced byte.
+    pub first_offset: u64,
+    pub references: Vec<SegmentReference>,
+    /// File offset of the start of this box.
+    pub position: u64,
+    /// Size of this box in bytes.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentReference {
+    /// True if this references another sidx rather than media.
+    pub references_index: bool,
+    pub referenced_size: u32,
+    pub subsegment_duration: u32,
+    pub starts_with_sap: bool,
+    pub sap_type: u8,
+    pub sap_delta_time: u32,
+}
+
+/// Track extends box 'trex'
+#[derive(Debug)]
+struct TrackExtendsBox {
+    track_id: u32,
+    default_sample_duration: u32,
+    default_sample_size: u32,
+    default_sample_flags: u32,
+}
+
+/// Movie fragment box 'moof' and its media data.
+#[derive(Debug, Clone)]
+pub struct MovieFragment {
+    /// File offset of the start of the moof.
+    pub position: u64,
+    /// Size of the moof and any mdat immediately following it.
+    pub size: u64,
+    /// Sequence number from the 'mfhd', if present.
+    pub sequence_number: Option<u32>,
+    /// Index of the most recent styp before this fragment, counting
+    /// styp boxes in file order.
+    pub segment: Option<usize>,
+    pub tracks: Vec<TrackFragment>,
+}
+
+/// Timing and sample layout of a track fragment box 'traf'.
+#[derive(Debug, Clone)]
+pub struct TrackFragment {
+    pub track_id: u32,
+    /// Decode time of the first sample, from the 'tfdt' if present.
+    pub base_decode_time: Option<u64>,
+    pub sample_count: u32,
+    /// Total sample duration, in the track's timescale.
+    pub duration: u64,
+    /// Location and timing of each sample in the fragment. Decode
+    /// times start from the 'tfdt', or from zero without one.
+    pub samples: Vec<SampleInfo>,
+    /// Per-sample decryption parameters from an 'senc' in the 'traf'.
+    senc: Vec<SampleEncryptionEntry>,
+}
+
+/// Track fragment header box 'tfhd'
+#[derive(Debug)]
+struct TrackFragmentHeaderBox {
+    track_id: u32,
+    base_data_offset: Option<u64>,
+    default_sample_duration: Option<u32>,
+    default_sample_size: Option<u32>,
+    default_sample_flags: Option<u32>,
+    /// Data offsets are relative to the moof, rather than following
+    /// on from the previous traf's data.
+    default_base_is_moof: bool,
+}
+
+/// Track run box 'trun'
+#[derive(Debug)]
+struct TrackRunBox {
+    sample_count: u32,
+    /// Offset of the first sample from the base data offset.
+    data_offset: Option<i32>,
+    first_sample_flags: Option<u32>,
+    /// Per-sample fields, each empty if the run uses the default.
+    sample_durations: Vec<u32>,
+    sample_sizes: Vec<u32>,
+    sample_flags: Vec<u32>,
+    sample_composition_offsets: Vec<i64>,
+}
+
 // Handler reference box 'hdlr'
 #[derive(Debug)]
 struct HandlerBox {
     handler_type: u32,
+    name: String,
 }
 
 // Sample description box 'stsd'
@@ -212,6 +730,7 @@ pub enum SampleEntry {
 pub enum AudioCodecSpecific {
     ES_Descriptor(Vec<u8>),
     OpusSpecificBox(OpusSpecificBox),
+    FLACSpecificBox(FLACSpecificBox),
 }
 
 #[derive(Debug, Clone)]
@@ -219,18 +738,22 @@ pub struct AudioSampleEntry {
     data_reference_index: u16,
     channelcount: u16,
     pub samplesize: u16,
-    pub samplerate: u32,
+    pub samplerate: FixedPoint16_16,
     pub codec_specific: AudioCodecSpecific,
 }
 
 #[derive(Debug, Clone)]
 pub enum VideoCodecSpecific {
     AVCConfig(Vec<u8>),
+    HEVCConfig(Vec<u8>),
     VPxConfig(VPxConfigBox),
+    AV1Config(Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
 pub struct VideoSampleEntry {
+    /// Type of the sample entry box, such as 'hvc1' or 'hev1'.
+    pub name: BoxType,
     data_reference_index: u16,
     pub width: u16,
     pub height: u16,
@@ -238,15 +761,25 @@ pub struct VideoSampleEntry {
 }
 
 /// Represent a Video Partition Codec Configuration 'vpcC' box (aka vp9).
+///
+/// Version 0 boxes carry a 4 bit colour space, while version 1 boxes
+/// carry ISO/IEC 23001-8 colour primaries and matrix coefficients
+/// instead, and a wider transfer function.
 #[derive(Debug, Clone)]
 pub struct VPxConfigBox {
-    profile: u8,
-    level: u8,
+    version: u8,
+    pub profile: u8,
+    pub level: u8,
     pub bit_depth: u8,
-    pub color_space: u8, // Really an enum
+    pub color_space: u8, // Really an enum; 0 (unknown) in version 1.
+    /// 0 and 1 for 4:2:0, 2 for 4:2:2 and 3 for 4:4:4.
     pub chroma_subsampling: u8,
     transfer_function: u8,
     video_full_range: bool,
+    /// 2 (unspecified) in version 0.
+    pub colour_primaries: u8,
+    /// 2 (unspecified) in version 0.
+    pub matrix_coefficients: u8,
     pub codec_init: Vec<u8>, // Empty for vp8/vp9.
 }
 
@@ -262,31 +795,308 @@ struct ChannelMappingTable {
 pub struct OpusSpecificBox {
     pub version: u8,
     output_channel_count: u8,
-    pre_skip: u16,
+    /// Samples (at 48 kHz) to discard from the start of the decoded output.
+    pub pre_skip: u16,
     input_sample_rate: u32,
     output_gain: i16,
     channel_mapping_family: u8,
     channel_mapping_table: Option<ChannelMappingTable>,
 }
 
+/// Represent a FLACSpecificBox 'dfLa'
+#[derive(Debug, Clone)]
+pub struct FLACSpecificBox {
+    pub version: u8,
+    /// Metadata blocks of the stream, starting with STREAMINFO.
+    pub blocks: Vec<FlacMetadataBlock>,
+}
+
+/// Set of optional features used by a file.
+///
+/// Returned by `MediaContext::features()` so callers can branch on
+/// file capabilities without inspecting individual boxes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MediaFeatures(u32);
+
+impl MediaFeatures {
+    /// The file contains movie fragments ('mvex' or 'moof').
+    pub const FRAGMENTED: MediaFeatures = MediaFeatures(1 << 0);
+    /// Protected sample entries or 'pssh' boxes are present.
+    pub const ENCRYPTED: MediaFeatures = MediaFeatures(1 << 1);
+    /// At least one track has an edit list.
+    pub const HAS_EDIT_LIST: MediaFeatures = MediaFeatures(1 << 2);
+    /// At least one track has a subtitle or timed text handler.
+    pub const HAS_SUBTITLES: MediaFeatures = MediaFeatures(1 << 3);
+    /// QuickTime chapter track references or a Nero chapter list.
+    pub const HAS_CHAPTERS: MediaFeatures = MediaFeatures(1 << 4);
+    /// Mastering display or content light level metadata.
+    pub const HAS_HDR_METADATA: MediaFeatures = MediaFeatures(1 << 5);
+    /// At least one track uses 'co64' chunk offsets.
+    pub const USES_64BIT_OFFSETS: MediaFeatures = MediaFeatures(1 << 6);
+
+    pub fn empty() -> MediaFeatures {
+        MediaFeatures(0)
+    }
+
+    /// Raw flag bits, for telemetry.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Return true if all the flags in `other` are set.
+    pub fn contains(&self, other: MediaFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: MediaFeatures) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::ops::BitOr for MediaFeatures {
+    type Output = MediaFeatures;
+
+    fn bitor(self, other: MediaFeatures) -> MediaFeatures {
+        MediaFeatures(self.0 | other.0)
+    }
+}
+
+/// Where an `AudioPriming` answer came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrimingSource {
+    /// The track's edit list.
+    EditList,
+    /// The iTunes 'iTunSMPB' metadata tag.
+    ITunSMPB,
+    /// The pre-roll distance of an 'sgpd' 'roll' sample group.
+    RollGroup,
+}
+
+/// Encoder delay and padding for gapless audio playback.
+///
+/// Both counts are in audio samples (per channel) and should be
+/// discarded from the start and end of the decoded output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPriming {
+    pub delay_samples: u64,
+    pub padding_samples: u64,
+    pub source: PrimingSource,
+}
+
 /// Internal data structures.
 #[derive(Debug, Default)]
 pub struct MediaContext {
     pub timescale: Option<MediaTimeScale>,
+    /// Movie duration from the mvhd, if known.
+    pub duration: Option<MediaScaledTime>,
+    /// Preferred playback rate from the mvhd; 1.0 is normal speed.
+    pub rate: Option<SignedFixedPoint16_16>,
+    /// Preferred playback volume from the mvhd; 1.0 is full volume.
+    pub volume: Option<FixedPoint8_8>,
+    /// ID the mvhd says the next new track should use, which must be
+    /// larger than every track ID in the file.
+    pub next_track_id: Option<u32>,
     /// Tracks found in the file.
     pub tracks: Vec<Track>,
+    /// Track IDs used by more than one track, in file order. Lookups
+    /// by track ID treat these as missing, since fragments can't be
+    /// matched to the right track.
+    pub duplicate_track_ids: Vec<u32>,
+    /// How to handle leaf boxes which fail to parse.
+    pub strictness: ParseStrictness,
+    /// How much of the file to read.
+    pub mode: ParseMode,
+    /// Leave hint tracks out of `tracks`, so only tracks meant for
+    /// playback are listed.
+    pub skip_hint_tracks: bool,
+    /// Largest moov to parse, in bytes. Larger moovs fail with
+    /// `Error::MoovTooLarge` before any of their contents are read.
+    pub moov_size_limit: Option<u64>,
+    /// IDs of the tracks whose sample tables, sample descriptions and
+    /// fragment samples are parsed, or None for every track. Other
+    /// tracks are still listed, with only their header information.
+    pub track_filter: Option<Vec<u32>>,
+    /// Leaf boxes skipped in lenient mode, in file order.
+    pub skipped_boxes: Vec<SkippedBox>,
+    /// Unsupported constructs encountered, in file order. If parsing
+    /// failed with `Error::Unsupported`, the last entry is the cause.
+    /// Sample entries which are unsupported are recorded here too,
+    /// although their tracks are still returned.
+    pub unsupported_features: Vec<UnsupportedFeature>,
+    /// Maximum number of payload bytes to keep from each unknown box.
+    /// Defaults to 0, capturing nothing.
+    pub unknown_box_capture: usize,
+    /// Unknown boxes seen while parsing, if capture is enabled.
+    pub unknown_boxes: Vec<UnknownBox>,
+    /// Features noticed while parsing.
+    features: MediaFeatures,
+    /// Encoder delay and padding from an iTunSMPB tag.
+    itunes_smpb: Option<(u64, u64)>,
+    /// Top-level segment index boxes, in file order.
+    pub segment_indexes: Vec<SegmentIndexBox>,
+    /// Movie fragments, in file order.
+    pub fragments: Vec<MovieFragment>,
+    /// Protection system headers from the moov, in file order.
+    pub psshs: Vec<ProtectionSystemSpecificHeaderBox>,
+    /// Per-track fragment defaults from the mvex.
+    track_extends: Vec<TrackExtendsBox>,
+    /// Major and compatible brands from the ftyp.
+    brands: Vec<u32>,
+    /// Major and compatible brands from each styp, in file order.
+    segment_brands: Vec<Vec<u32>>,
+    /// Types of the top-level boxes, in file order.
+    top_level_boxes: Vec<BoxType>,
+    /// Byte ranges of the contents of top-level 'mdat' boxes, in file
+    /// order.
+    pub media_data: Vec<std::ops::Range<u64>>,
+    /// Items described by a top-level 'meta' box, as in HEIF and AVIF
+    /// images.
+    pub item_metadata: Option<ItemMetadata>,
+    /// Exif TIFF structures from 'uuid' boxes at the top level or in
+    /// the moov's udta, in file order. Exif for image items is read
+    /// with `ItemMetadata::read_exif` instead.
+    pub exif: Vec<Vec<u8>>,
 }
 
 impl MediaContext {
     pub fn new() -> MediaContext {
         Default::default()
     }
+
+    /// Return the major brand from the ftyp, if there was one.
+    pub fn major_brand(&self) -> Option<u32> {
+        self.brands.first().cloned()
+    }
+
+    /// Return the set of optional features the parsed file uses.
+    pub fn features(&self) -> MediaFeatures {
+        self.features
+    }
+
+    /// Return the track with ID `track_id`, or None if no track or
+    /// more than one track has that ID.
+    pub fn track_by_id(&self, track_id: u32) -> Option<&Track> {
+        if self.duplicate_track_ids.contains(&track_id) {
+            return None;
+        }
+        self.tracks.iter().find(|t| t.track_id == Some(track_id))
+    }
+
+    /// Return the samples of track `track_id` from all movie fragments,
+    /// in file order.
+    pub fn fragment_samples(&self, track_id: u32) -> Vec<SampleInfo> {
+        self.fragments.iter()
+            .flat_map(|f| f.tracks.iter())
+            .filter(|t| t.track_id == track_id)
+            .flat_map(|t| t.samples.iter().cloned())
+            .collect()
+    }
+
+    /// Return the encoder delay and padding for an audio track.
+    ///
+    /// Several sources may describe this, and they are consulted in
+    /// order of precedence:
+    ///
+    /// 1. The track's edit list, if it trims anything. The media_time
+    ///    gives the delay and any media following the edit the padding.
+    /// 2. An iTunes 'iTunSMPB' tag, which applies to the movie's audio.
+    /// 3. An 'sgpd' 'roll' group, whose pre-roll distance is taken as
+    ///    the delay in 1024-sample frames, with no padding. Only 'mp4a'
+    ///    tracks with an AAC-LC AudioSpecificConfig have frames of that
+    ///    size, so the roll group is ignored for other codecs.
+    ///
+    /// Returns None if the track isn't audio or none of these apply.
+    pub fn audio_priming(&self, track_index: usize) -> Option<AudioPriming> {
+        let track = match self.tracks.get(track_index) {
+            Some(track) => track,
+            None => return None,
+        };
+        let audio = match track.data {
+            Some(SampleEntry::Audio(ref audio)) => audio,
+            _ => return None,
+        };
+
+        if let (Some(media_time), Some(timescale)) = (track.media_time, track.timescale) {
+            let padding = match self.timescale {
+                Some(movie_timescale) => track.end_padding(movie_timescale).map_or(0, |p| p.0),
+                None => 0,
+            };
+            if media_time.0 > 0 || padding > 0 {
+                // Rescale from the media timescale to the sample rate.
+                let rate = audio.samplerate.integer_part() as u64;
+                let to_samples = |t: u64| {
+                    if rate == 0 || rate == timescale.0 {
+                        Some(t)
+                    } else {
+                        t.checked_mul(rate).map(|t| t / timescale.0)
+                    }
+                };
+                if let (Some(delay), Some(padding)) = (to_samples(media_time.0), to_samples(padding)) {
+                    return Some(AudioPriming {
+                        delay_samples: delay,
+                        padding_samples: padding,
+                        source: PrimingSource::EditList,
+                    });
+                }
+            }
+        }
+
+        if let Some((delay, padding)) = self.itunes_smpb {
+            return Some(AudioPriming {
+                delay_samples: delay,
+                padding_samples: padding,
+                source: PrimingSource::ITunSMPB,
+            });
+        }
+
+        // HE-AAC's output frames are twice the size, so an explicit
+        // SBR or PS extension rules it out too.
+        let aac_lc = match audio.audio_specific_config() {
+            Ok(Some(config)) => config.audio_object_type == 2 && config.extension_object_type.is_none(),
+            _ => false,
+        };
+        match track.roll_distance {
+            Some(roll) if roll < 0 && aac_lc => Some(AudioPriming {
+                delay_samples: (-(roll as i32)) as u64 * 1024,
+                padding_samples: 0,
+                source: PrimingSource::RollGroup,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Freeze the parsed context for sharing between threads.
+    pub fn freeze(self) -> MediaSnapshot {
+        MediaSnapshot(std::sync::Arc::new(self))
+    }
 }
 
-#[derive(Debug)]
+/// An immutable, shareable view of a parsed `MediaContext`.
+///
+/// Cloning a snapshot is cheap, and it is `Send` and `Sync`, so each
+/// decoder thread can hold its own handle and query it concurrently.
+#[derive(Debug, Clone)]
+pub struct MediaSnapshot(std::sync::Arc<MediaContext>);
+
+impl std::ops::Deref for MediaSnapshot {
+    type Target = MediaContext;
+
+    fn deref(&self) -> &MediaContext {
+        &self.0
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum TrackType {
     Audio,
     Video,
+    /// A hint track, describing how to packetize other tracks for
+    /// streaming protocols such as RTP. Not meant for playback.
+    Hint,
     Unknown,
 }
 
@@ -316,41 +1126,124 @@ pub struct Track {
     pub track_type: TrackType,
     pub empty_duration: Option<MediaScaledTime>,
     pub media_time: Option<TrackScaledTime>,
+    /// Segment duration of the edit presenting the media.
+    pub edit_duration: Option<MediaScaledTime>,
     pub timescale: Option<TrackTimeScale>,
     pub duration: Option<TrackScaledTime>,
     track_id: Option<u32>,
     pub mime_type: String,
+    /// ISO 639-2/T language code from the mdhd, such as "eng".
+    pub language: Option<String>,
+    /// Title from a udta 'titl' box, or else the hdlr name.
+    pub name: Option<String>,
     pub data: Option<SampleEntry>,
     pub tkhd: Option<TrackHeaderBox>, // TODO(kinetik): find a nicer way to export this.
+    /// Number of entries in the edit list, if any.
+    edit_count: usize,
+    /// First roll distance from an 'sgpd' 'roll' sample group.
+    roll_distance: Option<i16>,
+    // Sample table boxes, used to build the sample index.
+    stts: Option<TimeToSampleBox>,
+    ctts: Option<CompositionOffsetBox>,
+    stsc: Option<SampleToChunkBox>,
+    stsz: Option<SampleSizeBox>,
+    stco: Option<ChunkOffsetBox>,
+    stss: Option<SyncSampleBox>,
+    /// Per-sample decryption parameters from an 'senc' in the 'stbl'.
+    senc: Option<Vec<SampleEncryptionEntry>>,
+    subs: Option<SubSampleInformationBox>,
+    /// Key rotation: 'seig' sample group entries and sample mapping.
+    seig_entries: Vec<TrackEncryptionBox>,
+    seig_groups: Option<SampleToGroupBox>,
+    /// Encryption scheme of a protected sample entry.
+    pub protection_info: Option<ProtectionSchemeInfoBox>,
+    /// Every sample in the track, from the sample tables and then the
+    /// movie fragments, if parsed with `ParseMode::Full`.
+    pub samples: Option<Vec<FullSample>>,
+    /// Track header flags. Whether disabled tracks or tracks not in the
+    /// movie should be presented is up to the caller.
+    pub enabled: bool,
+    pub in_movie: bool,
+    pub in_preview: bool,
+    /// Kind of auxiliary video from the sample entry's 'auxi', for
+    /// tracks with the 'auxv' handler.
+    pub auxiliary_type: Option<AuxiliaryType>,
+    /// IDs of the tracks this one is auxiliary to, from 'auxl' track
+    /// references.
+    pub auxiliary_for: Vec<u32>,
 }
 
 impl Track {
     fn new(id: usize) -> Track {
         Track { id: id, ..Default::default() }
     }
+
+    /// Return the amount of media to trim from the end of the track.
+    ///
+    /// This is whatever follows the presented edit: the media duration
+    /// less the edit list's media_time and segment duration. Returns
+    /// None if the track has no edit list with a usable segment
+    /// duration, or lacks the timing information to compute it.
+    pub fn end_padding(&self, movie_timescale: MediaTimeScale) -> Option<TrackScaledTime> {
+        let (media_time, edit_duration, timescale, duration) =
+            match (self.media_time, self.edit_duration, self.timescale, self.duration) {
+                (Some(m), Some(e), Some(t), Some(d)) => (m, e, t, d),
+                _ => return None,
+            };
+        // A zero segment duration means 'the rest of the media',
+        // which is common in fragmented files.
+        if edit_duration.0 == 0 || movie_timescale.0 == 0 {
+            return None;
+        }
+        let presented = match edit_duration.0.checked_mul(timescale.0) {
+            Some(scaled) => scaled / movie_timescale.0,
+            None => return None,
+        };
+        let end = media_time.0.saturating_add(presented);
+        Some(TrackScaledTime(duration.0.saturating_sub(end), self.id))
+    }
 }
 
 struct BMFFBox<'a, T: 'a + Read> {
     head: BoxHeader,
     content: Take<&'a mut T>,
+    /// Offset of the start of the box from the start of parsing.
+    position: u64,
+    /// Span covering the parse of this box, exited when it's dropped.
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
 }
 
 struct BoxIter<'a, T: 'a + Read> {
     src: &'a mut T,
+    /// Offset of the next box from the start of parsing.
+    position: u64,
 }
 
 impl<'a, T: Read> BoxIter<'a, T> {
     fn new(src: &mut T) -> BoxIter<T> {
-        BoxIter { src: src }
+        BoxIter { src: src, position: 0 }
     }
 
     fn next_box(&mut self) -> Result<Option<BMFFBox<T>>> {
         let r = read_box_header(self.src);
         match r {
-            Ok(h) => Ok(Some(BMFFBox {
-                head: h,
-                content: self.src.take(h.size - h.offset),
-            })),
+            Ok(h) => {
+                // Callers must consume each box before asking for the
+                // next, so the following box starts right after it.
+                let position = self.position;
+                self.position = match position.checked_add(h.size) {
+                    Some(end) => end,
+                    None => return Err(Error::InvalidData("box extends past the end of the file")),
+                };
+                Ok(Some(BMFFBox {
+                    head: h,
+                    content: self.src.take(h.size - h.offset),
+                    position: position,
+                    #[cfg(feature = "tracing")]
+                    _span: tracing::debug_span!("box", name = ?h.name, offset = position, size = h.size).entered(),
+                }))
+            }
             Err(Error::UnexpectedEOF) => Ok(None),
             Err(e) => Err(e),
         }
@@ -373,7 +1266,8 @@ impl<'a, T: Read> BMFFBox<'a, T> {
     }
 
     fn box_iter<'b>(&'b mut self) -> BoxIter<BMFFBox<'a, T>> {
-        BoxIter::new(self)
+        let position = self.position + self.head.size - self.bytes_left() as u64;
+        BoxIter { src: self, position: position }
     }
 }
 
@@ -403,7 +1297,9 @@ fn read_box_header<T: ReadBytesExt>(src: &mut T) -> Result<BoxHeader> {
         1 => 4 + 4 + 8,
         _ => 4 + 4,
     };
-    assert!(offset <= size);
+    if offset > size {
+        return Err(Error::InvalidData("box smaller than its header"));
+    }
     Ok(BoxHeader {
         name: name,
         size: size,
@@ -421,71 +1317,150 @@ fn read_fullbox_extra<T: ReadBytesExt>(src: &mut T) -> Result<(u8, u32)> {
         (flags_a as u32) << 16 | (flags_b as u32) << 8 | (flags_c as u32)))
 }
 
+/// Check that a full box is version 0 or 1, the versions whose field
+/// sizes the helpers below know.
+///
+/// Later versions may lay their fields out differently, so they're
+/// reported as `Error::Unsupported(feature)`. Leaf boxes read through
+/// `try_leaf!` are then skipped in lenient mode.
+fn check_version(version: u8, feature: &'static str) -> Result<()> {
+    match version {
+        0 | 1 => Ok(()),
+        _ => Err(Error::Unsupported(feature)),
+    }
+}
+
+/// Read a field which is 64 bits in version 1 boxes and 32 bits in
+/// version 0 boxes.
+fn be_u32_or_u64<T: ReadBytesExt>(src: &mut T, version: u8) -> Result<u64> {
+    match version {
+        1 => be_u64(src),
+        _ => be_u32(src).map(|v| v as u64),
+    }
+}
+
+/// Read a signed field which is 64 bits in version 1 boxes and 32
+/// bits in version 0 boxes.
+fn be_i32_or_i64<T: ReadBytesExt>(src: &mut T, version: u8) -> Result<i64> {
+    match version {
+        1 => be_i64(src),
+        _ => be_i32(src).map(|v| v as i64),
+    }
+}
+
+/// Read a duration which is 64 bits in version 1 boxes and 32 bits in
+/// version 0 boxes. The all-ones "unknown" value is kept as all ones
+/// when widened.
+fn be_duration<T: ReadBytesExt>(src: &mut T, version: u8) -> Result<u64> {
+    match version {
+        1 => be_u64(src),
+        _ => be_u32(src).map(|d| if d == std::u32::MAX { std::u64::MAX } else { d as u64 }),
+    }
+}
+
+/// Skip the creation and modification times of a header box, which
+/// are 64 bits each in version 1 and 32 bits in version 0.
+fn skip_creation_times<T: Read>(src: &mut T, version: u8) -> Result<()> {
+    skip(src, if version == 1 { 16 } else { 8 })
+}
+
 /// Skip over the entire contents of a box.
 fn skip_box_content<T: Read>(src: &mut BMFFBox<T>) -> Result<()> {
     // Skip the contents of unknown chunks.
     let to_skip = {
         let header = src.get_header();
         log!("{:?} (skipped)", header);
-        (header.size - header.offset) as usize
+        header.size - header.offset
     };
-    assert!(to_skip == src.bytes_left());
-    skip(src, to_skip)
+    if to_skip != src.content.limit() {
+        return Err(Error::InvalidData("box content partly read before skipping"));
+    }
+    skip(src, to_skip as usize)
 }
 
-macro_rules! check_parser_state {
-    ( $src:expr ) => {
-        if $src.limit() > 0 {
-            log!("bad parser state: {} content bytes left", $src.limit());
-            return Err(Error::InvalidData("unread box content or bad parser sync"));
-        }
+/// Skip a box, keeping the start of its payload if it's an unknown
+/// type and the context asks for unknown payloads to be captured.
+fn skip_unknown_box<T: Read>(src: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let header = *src.get_header();
+    if context.unknown_box_capture == 0 {
+        return skip_box_content(src);
+    }
+    match header.name {
+        BoxType::UnknownBox(_) => {}
+        _ => return skip_box_content(src),
     }
+    let captured = cmp::min(context.unknown_box_capture, src.bytes_left());
+    let payload = try!(read_buf(src, captured));
+    let to_skip = src.bytes_left();
+    try!(skip(src, to_skip));
+    log!("{:?} (skipped, captured {} bytes)", header, captured);
+    context.unknown_boxes.push(UnknownBox {
+        name: header.name,
+        size: header.size,
+        payload: payload,
+    });
+    Ok(())
 }
 
-/// Read the contents of a box, including sub boxes.
+/// Record an unsupported construct found in a box of type `name`.
+fn note_unsupported(context: &mut MediaContext, name: BoxType, feature: &'static str) {
+    warning!("{:?}: unsupported {}", name, feature);
+    context.unsupported_features.push(UnsupportedFeature {
+        feature: feature,
+        box_type: name,
+    });
+}
+
+/// Recover from a failure parsing a leaf box.
+///
+/// In strict mode the error is passed through. In lenient mode the
+/// failure is recorded in the context and the rest of the box is
+/// skipped based on its declared size, so the caller can carry on
+/// with the next sibling. I/O errors are never recovered.
+fn recover_leaf_box<T: Read>(src: &mut BMFFBox<T>, err: Error, context: &mut MediaContext) -> Result<()> {
+    if let Error::Unsupported(feature) = err {
+        note_unsupported(context, src.get_header().name, feature);
+    }
+    match (context.strictness, err) {
+        (_, Error::Io(e)) => Err(Error::Io(e)),
+        (ParseStrictness::Strict, e) => Err(e),
+        (ParseStrictness::Lenient, e) => {
+            warning!("{:?} (skipped after error: {:?})", src.get_header(), e);
+            let to_skip = src.bytes_left();
+            try!(skip(src, to_skip));
+            context.skipped_boxes.push(SkippedBox {
+                name: src.get_header().name,
+                error: e,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Read the contents of a box, including sub boxes.
 ///
 /// Metadata is accumulated in the passed-through `MediaContext` struct,
 /// which can be examined later.
 pub fn read_mp4<T: Read>(f: &mut T, context: &mut MediaContext) -> Result<()> {
-    let mut found_ftyp = false;
     let mut found_moov = false;
     // TODO(kinetik): Top-level parsing should handle zero-sized boxes
     // rather than throwing an error.
     let mut iter = BoxIter::new(f);
     while let Some(mut b) = try!(iter.next_box()) {
-        // box ordering: ftyp before any variable length box (inc. moov),
-        // but may not be first box in file if file signatures etc. present
-        // fragmented mp4 order: ftyp, moov, pairs of moof/mdat (1-multiple), mfra
-
-        // "special": uuid, wide (= 8 bytes)
-        // isom: moov, mdat, free, skip, udta, ftyp, moof, mfra
-        // iso2: pdin, meta
-        // iso3: meco
-        // iso5: styp, sidx, ssix, prft
-        // unknown, maybe: id32
-
-        // qt: pnot
-
-        // possibly allow anything where all printable and/or all lowercase printable
-        // "four printable characters from the ISO 8859-1 character set"
-        match b.head.name {
-            BoxType::FileTypeBox => {
-                let ftyp = try!(read_ftyp(&mut b));
-                found_ftyp = true;
-                log!("{:?}", ftyp);
-            }
-            BoxType::MovieBox => {
-                try!(read_moov(&mut b, context));
-                found_moov = true;
-            }
-            _ => try!(skip_box_content(&mut b)),
-        };
+        context.top_level_boxes.push(b.head.name);
+        if b.head.name == BoxType::MediaDataBox {
+            try!(note_media_data(context, b.position, &b.head));
+            try!(skip_box_content(&mut b));
+        } else {
+            try!(read_top_level_box(&mut b, context));
+        }
         check_parser_state!(b.content);
-        if found_moov {
-            log!("found moov {}, could stop pure 'moov' parser now", if found_ftyp {
-                "and ftyp"
-            } else {
+        if b.head.name == BoxType::MovieBox {
+            found_moov = true;
+            log!("found moov {}, could stop pure 'moov' parser now", if context.brands.is_empty() {
                 "but no ftyp"
+            } else {
+                "and ftyp"
             });
         }
     }
@@ -493,13 +1468,198 @@ pub fn read_mp4<T: Read>(f: &mut T, context: &mut MediaContext) -> Result<()> {
     // XXX(kinetik): This isn't perfect, as a "moov" with no contents is
     // treated as okay but we haven't found anything useful.  Needs more
     // thought for clearer behaviour here.
-    if found_moov {
+    check_found_media(context, found_moov)
+}
+
+/// Read a seekable mp4 file into a MediaContext.
+///
+/// Like `read_mp4`, but 'mdat' boxes are skipped by seeking past them
+/// using their declared size rather than by reading them, so a 'moov'
+/// following a large 'mdat' is found without reading the media data.
+/// Box positions are offsets from the start of the input.
+pub fn read_mp4_seekable<T: Read + Seek>(f: &mut T, context: &mut MediaContext) -> Result<()> {
+    let mut found_moov = false;
+    let mut position = try!(f.seek(SeekFrom::Current(0)));
+    loop {
+        let (name, size) = {
+            let mut iter = BoxIter { src: f, position: position };
+            let mut b = match try!(iter.next_box()) {
+                Some(b) => b,
+                None => break,
+            };
+            context.top_level_boxes.push(b.head.name);
+            if b.head.name == BoxType::MediaDataBox {
+                log!("seeking over mdat of {} bytes", b.head.size);
+                try!(note_media_data(context, b.position, &b.head));
+            } else {
+                try!(read_top_level_box(&mut b, context));
+                check_parser_state!(b.content);
+            }
+            (b.head.name, b.head.size)
+        };
+        if name == BoxType::MovieBox {
+            found_moov = true;
+        }
+        position = match position.checked_add(size) {
+            Some(position) => position,
+            None => return Err(Error::InvalidData("box size overflow")),
+        };
+        try!(f.seek(SeekFrom::Start(position)));
+    }
+
+    check_found_media(context, found_moov)
+}
+
+/// Read media segments appended to a fragmented file.
+///
+/// `context` must already hold the initialization segment's tracks, as
+/// from `read_mp4`, and `f` continues the file from offset `position`.
+/// Fragments found are added to `context.fragments`, so a stream can be
+/// fed in a segment at a time as it arrives.
+pub fn read_fragments<T: Read>(f: &mut T, context: &mut MediaContext, position: u64) -> Result<()> {
+    if context.tracks.is_empty() {
+        return Err(Error::NoMoov);
+    }
+    let mut iter = BoxIter { src: f, position: position };
+    while let Some(mut b) = try!(iter.next_box()) {
+        context.top_level_boxes.push(b.head.name);
+        match b.head.name {
+            BoxType::MediaDataBox => {
+                try!(note_media_data(context, b.position, &b.head));
+                try!(skip_box_content(&mut b));
+            }
+            BoxType::MovieBox => return Err(Error::InvalidData("moov in media segment")),
+            _ => try!(read_top_level_box(&mut b, context)),
+        }
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Finish a parse, which must have found a 'moov' or, for image
+/// files, a primary item.
+fn check_found_media(context: &MediaContext, found_moov: bool) -> Result<()> {
+    if found_moov || context.item_metadata.as_ref().map_or(false, |m| m.primary_item_id.is_some()) {
         Ok(())
     } else {
         Err(Error::NoMoov)
     }
 }
 
+/// Record an 'mdat' at `position`, attributing it to the fragment it
+/// follows.
+fn note_media_data(context: &mut MediaContext, position: u64, head: &BoxHeader) -> Result<()> {
+    if let Some(fragment) = context.fragments.last_mut() {
+        if fragment.position.checked_add(fragment.size) == Some(position) {
+            fragment.size = match fragment.size.checked_add(head.size) {
+                Some(size) => size,
+                None => return Err(Error::InvalidData("fragment size too large")),
+            };
+        }
+    }
+    let (start, end) = match (position.checked_add(head.offset), position.checked_add(head.size)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Err(Error::InvalidData("mdat extends past the end of the file")),
+    };
+    context.media_data.push(start..end);
+    Ok(())
+}
+
+/// Parse a top-level box other than 'mdat'.
+fn read_top_level_box<T: Read>(b: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    // box ordering: ftyp before any variable length box (inc. moov),
+    // but may not be first box in file if file signatures etc. present
+    // fragmented mp4 order: ftyp, moov, pairs of moof/mdat (1-multiple), mfra
+
+    // "special": uuid, wide (= 8 bytes)
+    // isom: moov, mdat, free, skip, udta, ftyp, moof, mfra
+    // iso2: pdin, meta
+    // iso3: meco
+    // iso5: styp, sidx, ssix, prft
+    // unknown, maybe: id32
+
+    // qt: pnot
+
+    // possibly allow anything where all printable and/or all lowercase printable
+    // "four printable characters from the ISO 8859-1 character set"
+    match b.head.name {
+        BoxType::FileTypeBox => {
+            if let Some(ftyp) = try_leaf!(*b, context, read_ftyp(b)) {
+                log!("{:?}", ftyp);
+                context.brands.push(ftyp.major_brand);
+                context.brands.extend_from_slice(&ftyp.compatible_brands);
+            }
+        }
+        BoxType::SegmentTypeBox => {
+            // styp has the same layout as ftyp.
+            if let Some(styp) = try_leaf!(*b, context, read_ftyp(b)) {
+                log!("styp {:?}", styp);
+                let mut brands = vec![styp.major_brand];
+                brands.extend_from_slice(&styp.compatible_brands);
+                context.segment_brands.push(brands);
+            }
+        }
+        BoxType::MovieBox => {
+            if context.moov_size_limit.map_or(false, |limit| b.head.size > limit) {
+                return Err(Error::MoovTooLarge(b.head.size));
+            }
+            try!(read_moov(b, context));
+        }
+        BoxType::MovieFragmentBox => {
+            context.features.insert(MediaFeatures::FRAGMENTED);
+            let fragment = try!(read_moof(b, context));
+            if context.mode == ParseMode::Full {
+                try!(append_full_fragment_samples(context, &fragment));
+            }
+            context.fragments.push(fragment);
+        }
+        BoxType::SegmentIndexBox => {
+            if let Some(sidx) = try_leaf!(*b, context, read_sidx(b)) {
+                log!("{:?}", sidx);
+                context.segment_indexes.push(sidx);
+            }
+        }
+        BoxType::MetadataBox => try!(heif::read_item_meta(b, context)),
+        BoxType::UuidBox => {
+            try_leaf!(*b, context, exif::read_uuid(b, context));
+        }
+        _ => try!(skip_unknown_box(b, context)),
+    };
+    Ok(())
+}
+
+/// Append each track fragment's samples to its track's `samples`, as
+/// built by `ParseMode::Full`.
+///
+/// Like a bad sample table, a bad fragment only costs its own track's
+/// sample index in lenient mode.
+fn append_full_fragment_samples(context: &mut MediaContext, fragment: &MovieFragment) -> Result<()> {
+    for traf in &fragment.tracks {
+        let index = match context.tracks.iter().position(|t| t.track_id == Some(traf.track_id)) {
+            Some(index) if !context.duplicate_track_ids.contains(&traf.track_id) => index,
+            _ => continue,
+        };
+        let strictness = context.strictness;
+        let track = &mut context.tracks[index];
+        if track.samples.is_none() {
+            continue;
+        }
+        match (track.full_fragment_samples(traf), strictness) {
+            (Ok(samples), _) => {
+                if let Some(ref mut full) = track.samples {
+                    full.extend(samples);
+                }
+            }
+            (Err(e), ParseStrictness::Strict) => return Err(e),
+            (Err(e), ParseStrictness::Lenient) => {
+                warning!("track {:?} has no sample index: {:?}", track.track_id, e);
+                track.samples = None;
+            }
+        }
+    }
+    Ok(())
+}
+
 fn parse_mvhd<T: Read>(f: &mut BMFFBox<T>) -> Result<(MovieHeaderBox, Option<MediaTimeScale>)> {
     let mvhd = try!(read_mvhd(f));
     if mvhd.timescale == 0 {
@@ -514,71 +1674,511 @@ fn read_moov<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<
     while let Some(mut b) = try!(iter.next_box()) {
         match b.head.name {
             BoxType::MovieHeaderBox => {
-                let (mvhd, timescale) = try!(parse_mvhd(&mut b));
-                context.timescale = timescale;
-                log!("{:?}", mvhd);
+                if let Some((mvhd, timescale)) = try_leaf!(b, context, parse_mvhd(&mut b)) {
+                    context.timescale = timescale;
+                    context.duration = match mvhd.duration {
+                        std::u64::MAX => None,
+                        duration => Some(MediaScaledTime(duration)),
+                    };
+                    context.rate = Some(mvhd.rate);
+                    context.volume = Some(mvhd.volume);
+                    context.next_track_id = Some(mvhd.next_track_id);
+                    log!("{:?}", mvhd);
+                }
             }
             BoxType::TrackBox => {
                 let mut track = Track::new(context.tracks.len());
-                try!(read_trak(&mut b, &mut track));
-                context.tracks.push(track);
+                try!(read_trak(&mut b, &mut track, context));
+                if track.track_type == TrackType::Hint && context.skip_hint_tracks {
+                    log!("skipping hint track {:?}", track.track_id);
+                } else {
+                    if context.mode == ParseMode::Full {
+                        // Like a bad leaf box, a bad sample table only
+                        // costs its own track in lenient mode.
+                        track.samples = match (track.full_sample_table(), context.strictness) {
+                            (Ok(samples), _) => Some(samples),
+                            (Err(e), ParseStrictness::Strict) => return Err(e),
+                            (Err(e), ParseStrictness::Lenient) => {
+                                warning!("track {:?} has no sample index: {:?}", track.track_id, e);
+                                None
+                            }
+                        };
+                    }
+                    if let Some(track_id) = track.track_id {
+                        let duplicate = context.tracks.iter().any(|t| t.track_id == Some(track_id));
+                        if duplicate && !context.duplicate_track_ids.contains(&track_id) {
+                            warning!("track ID {} is used by more than one track", track_id);
+                            context.duplicate_track_ids.push(track_id);
+                        }
+                    }
+                    context.tracks.push(track);
+                }
             }
-            _ => try!(skip_box_content(&mut b)),
+            BoxType::MovieExtendsBox => {
+                context.features.insert(MediaFeatures::FRAGMENTED);
+                try!(read_mvex(&mut b, context));
+            }
+            BoxType::ProtectionSystemSpecificHeaderBox => {
+                context.features.insert(MediaFeatures::ENCRYPTED);
+                if let Some(pssh) = try_leaf!(b, context, read_pssh(&mut b)) {
+                    log!("{:?}", pssh);
+                    context.psshs.push(pssh);
+                }
+            }
+            BoxType::CompressedMovieBox => {
+                note_unsupported(context, b.head.name, "compressed moov");
+                return Err(Error::Unsupported("compressed moov"));
+            }
+            BoxType::UserdataBox => try!(read_udta(&mut b, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
         };
         check_parser_state!(b.content);
     }
+    if let Some(next_track_id) = context.next_track_id {
+        if let Some(max_track_id) = context.tracks.iter().filter_map(|t| t.track_id).max() {
+            if max_track_id >= next_track_id {
+                warning!("mvhd next_track_ID {} isn't above track ID {}", next_track_id, max_track_id);
+            }
+        }
+    }
     Ok(())
 }
 
-fn read_trak<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<()> {
+fn read_mvex<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
     let mut iter = f.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
         match b.head.name {
-            BoxType::TrackHeaderBox => {
-                let tkhd = try!(read_tkhd(&mut b));
-                track.track_id = Some(tkhd.track_id);
-                track.tkhd = Some(tkhd.clone());
-                log!("{:?}", tkhd);
+            BoxType::TrackExtendsBox => {
+                if let Some(trex) = try_leaf!(b, context, read_trex(&mut b)) {
+                    log!("{:?}", trex);
+                    context.track_extends.push(trex);
+                }
             }
-            BoxType::EditBox => try!(read_edts(&mut b, track)),
-            BoxType::MediaBox => try!(read_mdia(&mut b, track)),
-            _ => try!(skip_box_content(&mut b)),
+            _ => try!(skip_unknown_box(&mut b, context)),
         };
         check_parser_state!(b.content);
     }
     Ok(())
 }
 
-fn read_edts<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<()> {
+/// Parse a moof box.
+fn read_moof<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<MovieFragment> {
+    let mut fragment = MovieFragment {
+        position: f.position,
+        size: f.head.size,
+        sequence_number: None,
+        segment: context.segment_brands.len().checked_sub(1),
+        tracks: Vec::new(),
+    };
+    // Without explicit base offsets, the first traf's data is located
+    // relative to the moof and each following traf's data comes after
+    // the previous one's.
+    let mut data_base = f.position;
+    let fragment_samples: usize = context.fragments.iter()
+        .flat_map(|f| f.tracks.iter())
+        .map(|t| t.samples.len())
+        .sum();
+    let mut sample_budget = FRAGMENT_SAMPLE_LIMIT.saturating_sub(fragment_samples);
     let mut iter = f.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
         match b.head.name {
-            BoxType::EditListBox => {
-                let elst = try!(read_elst(&mut b));
-                let mut empty_duration = 0;
-                let mut idx = 0;
-                if elst.edits.len() > 2 {
-                    return Err(Error::Unsupported("more than two edits"));
+            BoxType::MovieFragmentHeaderBox => {
+                if let Some(sequence_number) = try_leaf!(b, context, read_mfhd(&mut b)) {
+                    log!("mfhd {}", sequence_number);
+                    fragment.sequence_number = Some(sequence_number);
                 }
-                if elst.edits[idx].media_time == -1 {
-                    empty_duration = elst.edits[idx].segment_duration;
-                    if elst.edits.len() < 2 {
-                        return Err(Error::InvalidData("expected additional edit"));
+            }
+            BoxType::TrackFragmentBox => {
+                if let Some(traf) = try!(read_traf(&mut b, context, fragment.position, &mut data_base, &mut sample_budget)) {
+                    fragment.tracks.push(traf);
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(fragment)
+}
+
+/// Summarize a traf box. Returns None if it has no usable tfhd.
+///
+/// `moof_position` is the start of the enclosing moof and `data_base`
+/// the base data offset to use if the tfhd doesn't give one, which is
+/// moved past the traf's data. Samples are taken from `sample_budget`,
+/// failing once it runs out. Tracks excluded by the context's track
+/// filter keep their totals but not their samples.
+fn read_traf<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext, moof_position: u64, data_base: &mut u64,
+                      sample_budget: &mut usize) -> Result<Option<TrackFragment>> {
+    let mut tfhd = None;
+    let mut base_decode_time = None;
+    let mut sample_count = 0u32;
+    let mut duration = 0u64;
+    let mut samples = Vec::new();
+    let mut senc = Vec::new();
+    // Where the next run's data starts, if it has no data offset.
+    let mut data_end = None;
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::TrackFragmentHeaderBox => {
+                if let Some(header) = try_leaf!(b, context, read_tfhd(&mut b)) {
+                    log!("{:?}", header);
+                    tfhd = Some(header);
+                }
+            }
+            BoxType::TrackFragmentBaseMediaDecodeTimeBox => {
+                if let Some(time) = try_leaf!(b, context, read_tfdt(&mut b)) {
+                    log!("tfdt {}", time);
+                    base_decode_time = Some(time);
+                }
+            }
+            BoxType::TrackRunBox => {
+                if let Some(trun) = try_leaf!(b, context, read_trun(&mut b)) {
+                    log!("{:?}", trun);
+                    let tfhd = match tfhd {
+                        Some(ref tfhd) => tfhd,
+                        // Nothing to apply the run to.
+                        None => continue,
+                    };
+                    let trex = context.track_extends.iter().find(|t| t.track_id == tfhd.track_id);
+                    let default_duration = tfhd.default_sample_duration
+                        .or_else(|| trex.map(|t| t.default_sample_duration))
+                        .unwrap_or(0);
+                    let default_size = tfhd.default_sample_size
+                        .or_else(|| trex.map(|t| t.default_sample_size))
+                        .unwrap_or(0);
+                    let default_flags = tfhd.default_sample_flags
+                        .or_else(|| trex.map(|t| t.default_sample_flags))
+                        .unwrap_or(0);
+                    let base = match tfhd.base_data_offset {
+                        Some(offset) => offset,
+                        None if tfhd.default_base_is_moof => moof_position,
+                        None => *data_base,
+                    };
+                    let mut offset = match trun.data_offset {
+                        Some(data_offset) => {
+                            if base > i64::max_value() as u64 {
+                                return Err(Error::InvalidData("trun base data offset too large"));
+                            }
+                            match (base as i64).checked_add(data_offset as i64) {
+                                Some(offset) if offset >= 0 => offset as u64,
+                                Some(_) => return Err(Error::InvalidData("trun data offset before start of file")),
+                                None => return Err(Error::InvalidData("trun data offset too large")),
+                            }
+                        }
+                        None => data_end.unwrap_or(base),
+                    };
+                    let filtered = match context.track_filter {
+                        Some(ref ids) => !ids.contains(&tfhd.track_id),
+                        None => false,
+                    };
+                    if !filtered {
+                        if trun.sample_count as usize > *sample_budget {
+                            return Err(Error::InvalidData("fragment samples exceed FRAGMENT_SAMPLE_LIMIT"));
+                        }
+                        *sample_budget -= trun.sample_count as usize;
                     }
-                    idx += 1;
+                    let mut time = base_decode_time.unwrap_or(0).saturating_add(duration);
+                    for i in 0..trun.sample_count as usize {
+                        let sample_duration = trun.sample_durations.get(i).cloned().unwrap_or(default_duration);
+                        let size = trun.sample_sizes.get(i).cloned().unwrap_or(default_size);
+                        let flags = match (trun.sample_flags.get(i), trun.first_sample_flags) {
+                            (Some(flags), _) => *flags,
+                            (None, Some(flags)) if i == 0 => flags,
+                            _ => default_flags,
+                        };
+                        let composition_offset = trun.sample_composition_offsets.get(i).cloned().unwrap_or(0);
+                        if !filtered {
+                            samples.push(SampleInfo {
+                                offset: offset,
+                                size: size,
+                                decode_time: time,
+                                composition_time: (time as i64).saturating_add(composition_offset),
+                                duration: sample_duration,
+                                // sample_is_non_sync_sample
+                                sync: flags & 0x10000 == 0,
+                            });
+                        }
+                        offset = offset.saturating_add(size as u64);
+                        time = time.saturating_add(sample_duration as u64);
+                    }
+                    data_end = Some(offset);
+                    sample_count = sample_count.saturating_add(trun.sample_count);
+                    duration = duration.saturating_add(if trun.sample_durations.is_empty() {
+                        (trun.sample_count as u64).saturating_mul(default_duration as u64)
+                    } else {
+                        trun.sample_durations.iter().fold(0u64, |sum, d| sum.saturating_add(*d as u64))
+                    });
                 }
-                track.empty_duration = Some(MediaScaledTime(empty_duration));
-                if elst.edits[idx].media_time < 0 {
-                    return Err(Error::InvalidData("unexpected negative media time in edit"));
+            }
+            BoxType::SampleEncryptionBox => {
+                let track_id = match tfhd {
+                    Some(ref tfhd) => tfhd.track_id,
+                    None => {
+                        try!(skip_box_content(&mut b));
+                        continue;
+                    }
+                };
+                if context.track_filter.as_ref().map_or(false, |ids| !ids.contains(&track_id)) {
+                    try!(skip_box_content(&mut b));
+                    continue;
+                }
+                let iv_size = context.track_by_id(track_id)
+                    .and_then(|track| track.protection_info.as_ref())
+                    .and_then(|sinf| sinf.tenc.as_ref())
+                    .map(|tenc| tenc.iv_size);
+                if let Some(entries) = try_leaf!(b, context, read_senc(&mut b, iv_size)) {
+                    log!("senc with {} samples", entries.len());
+                    senc = entries;
                 }
-                track.media_time = Some(TrackScaledTime(elst.edits[idx].media_time as u64,
-                                                        track.id));
-                log!("{:?}", elst);
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    if let Some(end) = data_end {
+        *data_base = end;
+    }
+    Ok(tfhd.map(|tfhd| TrackFragment {
+        track_id: tfhd.track_id,
+        base_decode_time: base_decode_time,
+        sample_count: sample_count,
+        duration: duration,
+        samples: samples,
+        senc: senc,
+    }))
+}
+
+fn read_trak<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::TrackHeaderBox => {
+                if let Some(tkhd) = try_leaf!(b, context, read_tkhd(&mut b)) {
+                    track.track_id = Some(tkhd.track_id);
+                    track.enabled = tkhd.enabled;
+                    track.in_movie = tkhd.in_movie;
+                    track.in_preview = tkhd.in_preview;
+                    track.tkhd = Some(tkhd.clone());
+                    log!("{:?}", tkhd);
+                }
+            }
+            BoxType::EditBox => try!(read_edts(&mut b, track, context)),
+            BoxType::MediaBox => try!(read_mdia(&mut b, track, context)),
+            BoxType::TrackReferenceBox => try!(read_tref(&mut b, track, context)),
+            BoxType::UserdataBox => try!(read_track_udta(&mut b, track, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+fn read_tref<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        if b.head.name == BoxType::ChapterTrackReferenceBox {
+            context.features.insert(MediaFeatures::HAS_CHAPTERS);
+        }
+        if u32::from(b.head.name) == REFERENCE_AUXILIARY {
+            while b.bytes_left() >= 4 {
+                track.auxiliary_for.push(try!(be_u32(&mut b)));
+            }
+        }
+        let to_skip = b.bytes_left();
+        try!(skip(&mut b, to_skip));
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Parse a trak 'udta' box, picking out the track title.
+fn read_track_udta<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::TitleBox => {
+                if let Some(title) = try_leaf!(b, context, read_titl(&mut b)) {
+                    log!("titl {:?}", title);
+                    if !title.is_empty() {
+                        track.name = Some(title);
+                    }
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Parse a 3GPP 'titl' box, returning the title.
+fn read_titl<T: Read>(src: &mut BMFFBox<T>) -> Result<String> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    // Skip the title's language.
+    try!(skip(src, 2));
+    let bytes_left = src.bytes_left();
+    if bytes_left as u64 > BUF_SIZE_LIMIT {
+        return Err(Error::InvalidData("titl box exceeds BUF_SIZE_LIMIT"));
+    }
+    let buf = try!(read_buf(src, bytes_left));
+    // The title is UTF-8, or UTF-16 starting with a byte order mark.
+    if buf.starts_with(&[0xfe, 0xff]) {
+        let units: Vec<u16> = buf[2..].chunks(2)
+            .filter(|unit| unit.len() == 2)
+            .map(|unit| (unit[0] as u16) << 8 | unit[1] as u16)
+            .take_while(|&unit| unit != 0)
+            .collect();
+        return String::from_utf16(&units).map_err(|_| Error::InvalidData("invalid utf16 in titl"));
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..end].to_vec()).map_err(From::from)
+}
+
+fn read_udta<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::ChapterListBox => {
+                context.features.insert(MediaFeatures::HAS_CHAPTERS);
+                try!(skip_box_content(&mut b));
+            }
+            BoxType::MetadataBox => try!(read_meta(&mut b, context)),
+            BoxType::UuidBox => {
+                try_leaf!(b, context, exif::read_uuid(&mut b, context));
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Parse an iTunes-style udta 'meta' box.
+fn read_meta<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let (version, _) = try!(read_fullbox_extra(f));
+    if version != 0 {
+        warning!("{:?} (skipped unknown version {})", f.get_header(), version);
+        let to_skip = f.bytes_left();
+        return skip(f, to_skip);
+    }
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::MetadataItemListBox => try!(read_ilst(&mut b, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Parse an 'ilst' box, picking out the tags we understand.
+fn read_ilst<T: Read>(f: &mut BMFFBox<T>, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::FreeformMetadataItem => {
+                let (name, data) = try!(read_freeform_item(&mut b));
+                if name == "iTunSMPB" {
+                    context.itunes_smpb = parse_itunsmpb(&data);
+                    log!("iTunSMPB {:?}", context.itunes_smpb);
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(())
+}
+
+/// Parse a freeform '----' tag, returning its name and data value.
+fn read_freeform_item<T: Read>(f: &mut BMFFBox<T>) -> Result<(String, Vec<u8>)> {
+    let mut name = String::new();
+    let mut data = Vec::new();
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::MetadataNameBox => {
+                let (_, _) = try!(read_fullbox_extra(&mut b));
+                let bytes_left = b.bytes_left();
+                if bytes_left as u64 > BUF_SIZE_LIMIT {
+                    return Err(Error::InvalidData("name box exceeds BUF_SIZE_LIMIT"));
+                }
+                let buf = try!(read_buf(&mut b, bytes_left));
+                name = String::from_utf8_lossy(&buf).into_owned();
+            }
+            BoxType::MetadataDataBox => {
+                // Type indicator and locale.
+                try!(skip(&mut b, 8));
+                let bytes_left = b.bytes_left();
+                if bytes_left as u64 > BUF_SIZE_LIMIT {
+                    return Err(Error::InvalidData("data box exceeds BUF_SIZE_LIMIT"));
+                }
+                data = try!(read_buf(&mut b, bytes_left));
             }
             _ => try!(skip_box_content(&mut b)),
         };
         check_parser_state!(b.content);
     }
+    Ok((name, data))
+}
+
+/// Parse the encoder delay and padding from an iTunSMPB tag value.
+///
+/// The value is a list of space separated hex fields; the second and
+/// third are the delay and padding in samples.
+fn parse_itunsmpb(data: &[u8]) -> Option<(u64, u64)> {
+    let text = String::from_utf8_lossy(data);
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    if fields.len() < 3 {
+        return None;
+    }
+    match (u64::from_str_radix(fields[1], 16), u64::from_str_radix(fields[2], 16)) {
+        (Ok(delay), Ok(padding)) => Some((delay, padding)),
+        _ => None,
+    }
+}
+
+fn parse_elst<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<EditListBox> {
+    let elst = try!(read_elst(f));
+    track.edit_count = elst.edits.len();
+    let mut empty_duration = 0;
+    let mut idx = 0;
+    if elst.edits.len() > 2 {
+        return Err(Error::Unsupported("more than two edits"));
+    }
+    if elst.edits[idx].media_time == -1 {
+        empty_duration = elst.edits[idx].segment_duration;
+        if elst.edits.len() < 2 {
+            return Err(Error::InvalidData("expected additional edit"));
+        }
+        idx += 1;
+    }
+    track.empty_duration = Some(MediaScaledTime(empty_duration));
+    if elst.edits[idx].media_time < 0 {
+        return Err(Error::InvalidData("unexpected negative media time in edit"));
+    }
+    track.media_time = Some(TrackScaledTime(elst.edits[idx].media_time as u64,
+                                            track.id));
+    track.edit_duration = Some(MediaScaledTime(elst.edits[idx].segment_duration));
+    Ok(elst)
+}
+
+fn read_edts<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::EditListBox => {
+                if let Some(elst) = try_leaf!(b, context, parse_elst(&mut b, track)) {
+                    context.features.insert(MediaFeatures::HAS_EDIT_LIST);
+                    log!("{:?}", elst);
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
+        };
+        check_parser_state!(b.content);
+    }
     Ok(())
 }
 
@@ -595,78 +2195,169 @@ fn parse_mdhd<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<(MediaHe
     Ok((mdhd, duration, timescale))
 }
 
-fn read_mdia<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<()> {
+fn read_mdia<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
     let mut iter = f.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
         match b.head.name {
             BoxType::MediaHeaderBox => {
-                let (mdhd, duration, timescale) = try!(parse_mdhd(&mut b, track));
-                track.duration = duration;
-                track.timescale = timescale;
-                log!("{:?}", mdhd);
+                if let Some((mdhd, duration, timescale)) = try_leaf!(b, context, parse_mdhd(&mut b, track)) {
+                    track.duration = duration;
+                    track.timescale = timescale;
+                    track.language = mdhd.language.clone();
+                    log!("{:?}", mdhd);
+                }
             }
             BoxType::HandlerBox => {
-                let hdlr = try!(read_hdlr(&mut b));
-                match hdlr.handler_type {
-                    0x76696465 /* 'vide' */ => track.track_type = TrackType::Video,
-                    0x736f756e /* 'soun' */ => track.track_type = TrackType::Audio,
-                    _ => (),
+                if let Some(hdlr) = try_leaf!(b, context, read_hdlr(&mut b)) {
+                    match hdlr.handler_type {
+                        0x76696465 /* 'vide' */ => track.track_type = TrackType::Video,
+                        0x736f756e /* 'soun' */ => track.track_type = TrackType::Audio,
+                        0x68696e74 /* 'hint' */ => track.track_type = TrackType::Hint,
+                        0x61757876 /* 'auxv' */ => track.track_type = TrackType::Video,
+                        0x73627466 /* 'sbtl' */ |
+                        0x73756274 /* 'subt' */ |
+                        0x74657874 /* 'text' */ => context.features.insert(MediaFeatures::HAS_SUBTITLES),
+                        _ => (),
+                    }
+                    // A 'titl' in the trak's udta takes precedence.
+                    if track.name.is_none() && !hdlr.name.is_empty() {
+                        track.name = Some(hdlr.name.clone());
+                    }
+                    log!("{:?}", hdlr);
                 }
-                log!("{:?}", hdlr);
             }
-            BoxType::MediaInformationBox => try!(read_minf(&mut b, track)),
-            _ => try!(skip_box_content(&mut b)),
+            BoxType::MediaInformationBox => try!(read_minf(&mut b, track, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
         };
         check_parser_state!(b.content);
     }
     Ok(())
 }
 
-fn read_minf<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<()> {
+fn read_minf<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
     let mut iter = f.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
         match b.head.name {
-            BoxType::SampleTableBox => try!(read_stbl(&mut b, track)),
-            _ => try!(skip_box_content(&mut b)),
+            BoxType::SampleTableBox => try!(read_stbl(&mut b, track, context)),
+            _ => try!(skip_unknown_box(&mut b, context)),
         };
         check_parser_state!(b.content);
     }
     Ok(())
 }
 
-fn read_stbl<T: Read>(f: &mut BMFFBox<T>, track: &mut Track) -> Result<()> {
+fn read_stbl<T: Read>(f: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<()> {
+    // The tkhd comes first, so the track ID is normally known here;
+    // tracks without one are parsed in full.
+    let filtered = match (&context.track_filter, track.track_id) {
+        (&Some(ref ids), Some(id)) => !ids.contains(&id),
+        _ => false,
+    };
+    if filtered {
+        log!("skipping stbl of filtered track {:?}", track.track_id);
+        return skip_box_content(f);
+    }
     let mut iter = f.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
+        if context.mode == ParseMode::Metadata && b.head.name != BoxType::SampleDescriptionBox {
+            try!(skip_box_content(&mut b));
+            check_parser_state!(b.content);
+            continue;
+        }
         match b.head.name {
             BoxType::SampleDescriptionBox => {
-                let stsd = try!(read_stsd(&mut b, track));
-                log!("{:?}", stsd);
+                if let Some(stsd) = try_leaf!(b, context, read_stsd(&mut b, track, context)) {
+                    log!("{:?}", stsd);
+                }
             }
             BoxType::TimeToSampleBox => {
-                let stts = try!(read_stts(&mut b));
-                log!("{:?}", stts);
+                if let Some(stts) = try_leaf!(b, context, read_stts(&mut b)) {
+                    log!("{:?}", stts);
+                    track.stts = Some(stts);
+                }
+            }
+            BoxType::CompositionOffsetBox => {
+                if let Some(ctts) = try_leaf!(b, context, read_ctts(&mut b)) {
+                    log!("{:?}", ctts);
+                    track.ctts = Some(ctts);
+                }
             }
             BoxType::SampleToChunkBox => {
-                let stsc = try!(read_stsc(&mut b));
-                log!("{:?}", stsc);
+                if let Some(stsc) = try_leaf!(b, context, read_stsc(&mut b)) {
+                    log!("{:?}", stsc);
+                    track.stsc = Some(stsc);
+                }
             }
             BoxType::SampleSizeBox => {
-                let stsz = try!(read_stsz(&mut b));
-                log!("{:?}", stsz);
+                if let Some(stsz) = try_leaf!(b, context, read_stsz(&mut b)) {
+                    log!("{:?}", stsz);
+                    track.stsz = Some(stsz);
+                }
+            }
+            BoxType::CompactSampleSizeBox => {
+                if let Some(stz2) = try_leaf!(b, context, read_stz2(&mut b)) {
+                    log!("stz2 {:?}", stz2);
+                    track.stsz = Some(stz2);
+                }
             }
             BoxType::ChunkOffsetBox => {
-                let stco = try!(read_stco(&mut b));
-                log!("{:?}", stco);
+                if let Some(stco) = try_leaf!(b, context, read_stco(&mut b)) {
+                    log!("{:?}", stco);
+                    track.stco = Some(stco);
+                }
             }
             BoxType::ChunkLargeOffsetBox => {
-                let co64 = try!(read_co64(&mut b));
-                log!("{:?}", co64);
+                if let Some(co64) = try_leaf!(b, context, read_co64(&mut b)) {
+                    context.features.insert(MediaFeatures::USES_64BIT_OFFSETS);
+                    log!("{:?}", co64);
+                    track.stco = Some(co64);
+                }
             }
             BoxType::SyncSampleBox => {
-                let stss = try!(read_stss(&mut b));
-                log!("{:?}", stss);
+                if let Some(stss) = try_leaf!(b, context, read_stss(&mut b)) {
+                    log!("{:?}", stss);
+                    track.stss = Some(stss);
+                }
             }
-            _ => try!(skip_box_content(&mut b)),
+            BoxType::SubSampleInformationBox => {
+                if let Some(subs) = try_leaf!(b, context, read_subs(&mut b)) {
+                    log!("{:?}", subs);
+                    if track.subs.is_none() {
+                        track.subs = Some(subs);
+                    } else {
+                        log!("** ignoring additional subs box **");
+                    }
+                }
+            }
+            BoxType::SampleEncryptionBox => {
+                let iv_size = track.protection_info.as_ref()
+                    .and_then(|sinf| sinf.tenc.as_ref())
+                    .map(|tenc| tenc.iv_size);
+                if let Some(senc) = try_leaf!(b, context, read_senc(&mut b, iv_size)) {
+                    log!("senc with {} samples", senc.len());
+                    track.senc = Some(senc);
+                }
+            }
+            BoxType::SampleGroupDescriptionBox => {
+                if let Some(sgpd) = try_leaf!(b, context, read_sgpd(&mut b)) {
+                    log!("{:?}", sgpd);
+                    if sgpd.grouping_type == ROLL_GROUPING_TYPE && track.roll_distance.is_none() {
+                        track.roll_distance = sgpd.roll_distances.first().cloned();
+                    }
+                    if sgpd.grouping_type == SEIG_GROUPING_TYPE {
+                        track.seig_entries = sgpd.seig_entries;
+                    }
+                }
+            }
+            BoxType::SampleToGroupBox => {
+                if let Some(sbgp) = try_leaf!(b, context, read_sbgp(&mut b)) {
+                    log!("{:?}", sbgp);
+                    if sbgp.grouping_type == SEIG_GROUPING_TYPE {
+                        track.seig_groups = Some(sbgp);
+                    }
+                }
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
         };
         check_parser_state!(b.content);
     }
@@ -697,68 +2388,55 @@ fn read_ftyp<T: Read>(src: &mut BMFFBox<T>) -> Result<FileTypeBox> {
 /// Parse an mvhd box.
 fn read_mvhd<T: Read>(src: &mut BMFFBox<T>) -> Result<MovieHeaderBox> {
     let (version, _) = try!(read_fullbox_extra(src));
-    match version {
-        // 64 bit creation and modification times.
-        1 => {
-            try!(skip(src, 16));
-        }
-        // 32 bit creation and modification times.
-        0 => {
-            try!(skip(src, 8));
-        }
-        _ => return Err(Error::InvalidData("unhandled mvhd version")),
-    }
+    try!(check_version(version, "unknown mvhd version"));
+    try!(skip_creation_times(src, version));
     let timescale = try!(be_u32(src));
-    let duration = match version {
-        1 => try!(be_u64(src)),
-        0 => {
-            let d = try!(be_u32(src));
-            if d == std::u32::MAX {
-                std::u64::MAX
-            } else {
-                d as u64
-            }
-        }
-        _ => return Err(Error::InvalidData("unhandled mvhd version")),
-    };
-    // Skip remaining fields.
-    try!(skip(src, 80));
+    let duration = try!(be_duration(src, version));
+    let rate = SignedFixedPoint16_16(try!(be_i32(src)));
+    let volume = FixedPoint8_8(try!(be_i16(src)));
+    // Skip reserved fields, matrix and pre_defined.
+    try!(skip(src, 70));
+    let next_track_id = try!(be_u32(src));
     Ok(MovieHeaderBox {
         timescale: timescale,
         duration: duration,
+        rate: rate,
+        volume: volume,
+        next_track_id: next_track_id,
     })
 }
 
 /// Parse a tkhd box.
 fn read_tkhd<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackHeaderBox> {
     let (version, flags) = try!(read_fullbox_extra(src));
-    let disabled = flags & 0x1u32 == 0 || flags & 0x2u32 == 0;
-    match version {
-        // 64 bit creation and modification times.
-        1 => {
-            try!(skip(src, 16));
-        }
-        // 32 bit creation and modification times.
-        0 => {
-            try!(skip(src, 8));
-        }
-        _ => return Err(Error::InvalidData("unhandled tkhd version")),
-    }
+    let enabled = flags & 0x1u32 != 0;
+    let in_movie = flags & 0x2u32 != 0;
+    let in_preview = flags & 0x4u32 != 0;
+    let disabled = !enabled || !in_movie;
+    try!(check_version(version, "unknown tkhd version"));
+    try!(skip_creation_times(src, version));
     let track_id = try!(be_u32(src));
     try!(skip(src, 4));
-    let duration = match version {
-        1 => try!(be_u64(src)),
-        0 => try!(be_u32(src)) as u64,
-        _ => return Err(Error::InvalidData("unhandled tkhd version")),
-    };
+    let duration = try!(be_duration(src, version));
     // Skip uninteresting fields.
-    try!(skip(src, 52));
-    let width = try!(be_u32(src));
-    let height = try!(be_u32(src));
+    try!(skip(src, 8));
+    let layer = try!(be_i16(src));
+    let alternate_group = try!(be_i16(src));
+    let volume = FixedPoint8_8(try!(be_i16(src)));
+    // Skip reserved field and matrix.
+    try!(skip(src, 38));
+    let width = FixedPoint16_16(try!(be_u32(src)));
+    let height = FixedPoint16_16(try!(be_u32(src)));
     Ok(TrackHeaderBox {
         track_id: track_id,
         disabled: disabled,
+        enabled: enabled,
+        in_movie: in_movie,
+        in_preview: in_preview,
         duration: duration,
+        layer: layer,
+        alternate_group: alternate_group,
+        volume: volume,
         width: width,
         height: height,
     })
@@ -767,23 +2445,15 @@ fn read_tkhd<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackHeaderBox> {
 /// Parse a elst box.
 fn read_elst<T: Read>(src: &mut BMFFBox<T>) -> Result<EditListBox> {
     let (version, _) = try!(read_fullbox_extra(src));
+    try!(check_version(version, "unknown elst version"));
     let edit_count = try!(be_u32(src));
     if edit_count == 0 {
         return Err(Error::InvalidData("invalid edit count"));
     }
     let mut edits = Vec::new();
     for _ in 0..edit_count {
-        let (segment_duration, media_time) = match version {
-            1 => {
-                // 64 bit segment duration and media times.
-                (try!(be_u64(src)), try!(be_i64(src)))
-            }
-            0 => {
-                // 32 bit segment duration and media times.
-                (try!(be_u32(src)) as u64, try!(be_i32(src)) as i64)
-            }
-            _ => return Err(Error::InvalidData("unhandled elst version")),
-        };
+        let segment_duration = try!(be_u32_or_u64(src, version));
+        let media_time = try!(be_i32_or_i64(src, version));
         let media_rate_integer = try!(be_i16(src));
         let media_rate_fraction = try!(be_i16(src));
         edits.push(Edit {
@@ -802,45 +2472,40 @@ fn read_elst<T: Read>(src: &mut BMFFBox<T>) -> Result<EditListBox> {
 /// Parse a mdhd box.
 fn read_mdhd<T: Read>(src: &mut BMFFBox<T>) -> Result<MediaHeaderBox> {
     let (version, _) = try!(read_fullbox_extra(src));
-    let (timescale, duration) = match version {
-        1 => {
-            // Skip 64-bit creation and modification times.
-            try!(skip(src, 16));
+    try!(check_version(version, "unknown mdhd version"));
+    try!(skip_creation_times(src, version));
+    let timescale = try!(be_u32(src));
+    let duration = try!(be_duration(src, version));
 
-            // 64 bit duration.
-            (try!(be_u32(src)), try!(be_u64(src)))
-        }
-        0 => {
-            // Skip 32-bit creation and modification times.
-            try!(skip(src, 8));
-
-            // 32 bit duration.
-            let timescale = try!(be_u32(src));
-            let duration = {
-                // Since we convert the 32-bit duration to 64-bit by
-                // upcasting, we need to preserve the special all-1s
-                // ("unknown") case by hand.
-                let d = try!(be_u32(src));
-                if d == std::u32::MAX {
-                    std::u64::MAX
-                } else {
-                    d as u64
-                }
-            };
-            (timescale, duration)
-        }
-        _ => return Err(Error::InvalidData("unhandled mdhd version")),
-    };
+    let language = decode_language(try!(be_u16(src)));
 
     // Skip uninteresting fields.
-    try!(skip(src, 4));
+    try!(skip(src, 2));
 
     Ok(MediaHeaderBox {
         timescale: timescale,
         duration: duration,
+        language: language,
     })
 }
 
+/// Decode an ISO 639-2/T language code packed as three 5-bit letters.
+///
+/// Values below 0x400 are QuickTime language codes, which aren't mapped.
+fn decode_language(packed: u16) -> Option<String> {
+    let packed = packed & 0x7fff;
+    if packed < 0x400 {
+        return None;
+    }
+    let letters: Vec<u8> = [10, 5, 0].iter()
+        .map(|shift| ((packed >> shift) & 0x1f) as u8 + 0x60)
+        .collect();
+    if letters.iter().any(|&c| c < b'a' || c > b'z') {
+        return None;
+    }
+    String::from_utf8(letters).ok()
+}
+
 /// Parse a stco box.
 fn read_stco<T: Read>(src: &mut BMFFBox<T>) -> Result<ChunkOffsetBox> {
     let (_, _) = try!(read_fullbox_extra(src));
@@ -918,10 +2583,113 @@ fn read_stsz<T: Read>(src: &mut BMFFBox<T>) -> Result<SampleSizeBox> {
 
     Ok(SampleSizeBox {
         sample_size: sample_size,
+        sample_count: sample_count,
+        sample_sizes: sample_sizes,
+    })
+}
+
+/// Parse a subs box.
+fn read_subs<T: Read>(src: &mut BMFFBox<T>) -> Result<SubSampleInformationBox> {
+    let (version, flags) = try!(read_fullbox_extra(src));
+    let entry_count = try!(be_u32(src));
+    let mut samples = Vec::new();
+    let mut sample_number = 0u32;
+    for _ in 0..entry_count {
+        let sample_delta = try!(be_u32(src));
+        sample_number = match sample_number.checked_add(sample_delta) {
+            Some(n) if n > 0 => n,
+            _ => return Err(Error::InvalidData("invalid subs sample delta")),
+        };
+        let subsample_count = try!(be_u16(src));
+        let mut subsamples = Vec::new();
+        for _ in 0..subsample_count {
+            let size = match version {
+                1 => try!(be_u32(src)),
+                _ => try!(be_u16(src)) as u32,
+            };
+            let priority = try!(src.read_u8());
+            let discardable = try!(src.read_u8()) != 0;
+            let codec_specific_parameters = try!(be_u32(src));
+            subsamples.push(SubSampleEntry {
+                size: size,
+                priority: priority,
+                discardable: discardable,
+                codec_specific_parameters: codec_specific_parameters,
+            });
+        }
+        samples.push((sample_number - 1, subsamples));
+    }
+
+    Ok(SubSampleInformationBox {
+        flags: flags,
+        samples: samples,
+    })
+}
+
+/// Parse a stz2 box, expanding it to the equivalent stsz.
+fn read_stz2<T: Read>(src: &mut BMFFBox<T>) -> Result<SampleSizeBox> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    // Skip reserved bytes.
+    try!(skip(src, 3));
+    let field_size = try!(src.read_u8());
+    let sample_count = try!(be_u32(src));
+    let mut sample_sizes = Vec::new();
+    match field_size {
+        4 => {
+            // Two sizes per byte, the first in the high nibble.
+            for i in 0..sample_count {
+                if i % 2 == 0 {
+                    let pair = try!(src.read_u8());
+                    sample_sizes.push((pair >> 4) as u32);
+                    if i + 1 < sample_count {
+                        sample_sizes.push((pair & 0xf) as u32);
+                    }
+                }
+            }
+        }
+        8 => {
+            for _ in 0..sample_count {
+                sample_sizes.push(try!(src.read_u8()) as u32);
+            }
+        }
+        16 => {
+            for _ in 0..sample_count {
+                sample_sizes.push(try!(be_u16(src)) as u32);
+            }
+        }
+        _ => return Err(Error::InvalidData("invalid stz2 field size")),
+    }
+
+    Ok(SampleSizeBox {
+        sample_size: 0,
+        sample_count: sample_count,
         sample_sizes: sample_sizes,
     })
 }
 
+/// Parse a ctts box.
+fn read_ctts<T: Read>(src: &mut BMFFBox<T>) -> Result<CompositionOffsetBox> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    try!(check_version(version, "unknown ctts version"));
+    let entry_count = try!(be_u32(src));
+    let mut samples = Vec::new();
+    for _ in 0..entry_count {
+        let sample_count = try!(be_u32(src));
+        let sample_offset = match version {
+            0 => try!(be_u32(src)) as i64,
+            _ => try!(be_i32(src)) as i64,
+        };
+        samples.push(CompositionOffset {
+            sample_count: sample_count,
+            sample_offset: sample_offset,
+        });
+    }
+
+    Ok(CompositionOffsetBox {
+        samples: samples,
+    })
+}
+
 /// Parse a stts box.
 fn read_stts<T: Read>(src: &mut BMFFBox<T>) -> Result<TimeToSampleBox> {
     let (_, _) = try!(read_fullbox_extra(src));
@@ -941,22 +2709,431 @@ fn read_stts<T: Read>(src: &mut BMFFBox<T>) -> Result<TimeToSampleBox> {
     })
 }
 
+/// Parse a sgpd box.
+///
+/// Only 'roll' and 'seig' group entries are interpreted; the content
+/// of other grouping types is skipped.
+fn read_sgpd<T: Read>(src: &mut BMFFBox<T>) -> Result<SampleGroupDescriptionBox> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    let grouping_type = try!(be_u32(src));
+    let default_length = match version {
+        0 => 0,
+        _ => try!(be_u32(src)),
+    };
+    if version >= 2 {
+        // Skip default_sample_description_index.
+        try!(skip(src, 4));
+    }
+    let mut roll_distances = Vec::new();
+    let mut seig_entries = Vec::new();
+    if grouping_type == ROLL_GROUPING_TYPE {
+        let entry_count = try!(be_u32(src));
+        for _ in 0..entry_count {
+            let length = match (version, default_length) {
+                (1, 0) => try!(be_u32(src)),
+                (0, _) => 2,
+                (_, length) => length,
+            };
+            if length < 2 {
+                return Err(Error::InvalidData("invalid roll group entry length"));
+            }
+            roll_distances.push(try!(be_i16(src)));
+            try!(skip(src, (length - 2) as usize));
+        }
+    } else if grouping_type == SEIG_GROUPING_TYPE {
+        let entry_count = try!(be_u32(src));
+        for _ in 0..entry_count {
+            let length = match (version, default_length) {
+                (1, 0) => Some(try!(be_u32(src)) as usize),
+                (0, _) => None,
+                (_, length) => Some(length as usize),
+            };
+            let start = src.bytes_left();
+            seig_entries.push(try!(read_encryption_defaults(src, true)));
+            if let Some(length) = length {
+                let read = start - src.bytes_left();
+                if length < read {
+                    return Err(Error::InvalidData("invalid seig group entry length"));
+                }
+                try!(skip(src, length - read));
+            }
+        }
+    } else {
+        let bytes_left = src.bytes_left();
+        try!(skip(src, bytes_left));
+    }
+
+    Ok(SampleGroupDescriptionBox {
+        grouping_type: grouping_type,
+        roll_distances: roll_distances,
+        seig_entries: seig_entries,
+    })
+}
+
+/// Parse a sbgp box.
+fn read_sbgp<T: Read>(src: &mut BMFFBox<T>) -> Result<SampleToGroupBox> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    let grouping_type = try!(be_u32(src));
+    if version == 1 {
+        // Skip grouping_type_parameter.
+        try!(skip(src, 4));
+    }
+    let entry_count = try!(be_u32(src));
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let sample_count = try!(be_u32(src));
+        let group_description_index = try!(be_u32(src));
+        entries.push((sample_count, group_description_index));
+    }
+
+    Ok(SampleToGroupBox {
+        grouping_type: grouping_type,
+        entries: entries,
+    })
+}
+
+/// Parse a sinf box.
+fn read_sinf<T: Read>(f: &mut BMFFBox<T>) -> Result<ProtectionSchemeInfoBox> {
+    let mut sinf = ProtectionSchemeInfoBox {
+        original_format: None,
+        scheme_type: None,
+        tenc: None,
+    };
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::OriginalFormatBox => {
+                sinf.original_format = Some(BoxType::from(try!(be_u32(&mut b))));
+            }
+            BoxType::SchemeTypeBox => {
+                let (_, flags) = try!(read_fullbox_extra(&mut b));
+                let scheme_type = try!(be_u32(&mut b));
+                if !PROTECTION_SCHEMES.contains(&scheme_type) {
+                    return Err(Error::Unsupported("unknown protection scheme"));
+                }
+                sinf.scheme_type = Some(scheme_type);
+                // Skip scheme_version and, if present, scheme_uri.
+                let bytes_left = b.bytes_left();
+                try!(skip(&mut b, bytes_left));
+                log!("schm flags {:#x}", flags);
+            }
+            BoxType::SchemeInformationBox => {
+                let mut schi = b.box_iter();
+                while let Some(mut c) = try!(schi.next_box()) {
+                    match c.head.name {
+                        BoxType::TrackEncryptionBox => sinf.tenc = Some(try!(read_tenc(&mut c))),
+                        _ => try!(skip_box_content(&mut c)),
+                    }
+                    check_parser_state!(c.content);
+                }
+            }
+            _ => try!(skip_box_content(&mut b)),
+        }
+        check_parser_state!(b.content);
+    }
+    Ok(sinf)
+}
+
+/// Parse a pssh box.
+fn read_pssh<T: Read>(src: &mut BMFFBox<T>) -> Result<ProtectionSystemSpecificHeaderBox> {
+    let size = src.bytes_left();
+    if size as u64 > BUF_SIZE_LIMIT {
+        return Err(Error::InvalidData("pssh box exceeds BUF_SIZE_LIMIT"));
+    }
+    let content = try!(read_buf(src, size));
+
+    // Rebuild the box with a compact header for box_content.
+    let mut box_content = Vec::with_capacity(size + 8);
+    try!(byteorder::WriteBytesExt::write_u32::<byteorder::BigEndian>(&mut box_content, size as u32 + 8));
+    box_content.extend_from_slice(b"pssh");
+    box_content.extend_from_slice(&content);
+
+    let mut src = std::io::Cursor::new(&content[..]);
+    let (version, _) = try!(read_fullbox_extra(&mut src));
+    let system_id = try!(read_buf(&mut src, 16));
+    let mut kid = Vec::new();
+    if version > 0 {
+        let count = try!(be_u32(&mut src));
+        if count as u64 * 16 > size as u64 {
+            return Err(Error::InvalidData("pssh key ID count exceeds box size"));
+        }
+        for _ in 0..count {
+            kid.push(try!(read_buf(&mut src, 16)));
+        }
+    }
+    let data_size = try!(be_u32(&mut src));
+    if data_size as u64 > size as u64 - src.position() {
+        return Err(Error::InvalidData("pssh data size exceeds box size"));
+    }
+    let data = try!(read_buf(&mut src, data_size as usize));
+
+    Ok(ProtectionSystemSpecificHeaderBox {
+        system_id: system_id,
+        kid: kid,
+        data: data,
+        box_content: box_content,
+    })
+}
+
+/// Parse a tenc box.
+fn read_tenc<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackEncryptionBox> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    read_encryption_defaults(src, version > 0)
+}
+
+/// Parse the fields shared by a tenc box and a seig group entry.
+fn read_encryption_defaults<T: Read>(src: &mut BMFFBox<T>, has_pattern: bool) -> Result<TrackEncryptionBox> {
+    // Skip a reserved byte.
+    try!(skip(src, 1));
+    let pattern = try!(src.read_u8());
+    let (crypt_byte_block, skip_byte_block) = match has_pattern {
+        false => (0, 0),
+        true => (pattern >> 4, pattern & 0xf),
+    };
+    let is_encrypted = try!(src.read_u8());
+    let iv_size = try!(src.read_u8());
+    if iv_size != 0 && iv_size != 8 && iv_size != 16 {
+        return Err(Error::InvalidData("invalid tenc IV size"));
+    }
+    let kid = try!(read_buf(src, 16));
+    let constant_iv = if is_encrypted != 0 && iv_size == 0 {
+        let constant_iv_size = try!(src.read_u8());
+        Some(try!(read_buf(src, constant_iv_size as usize)))
+    } else {
+        None
+    };
+    Ok(TrackEncryptionBox {
+        crypt_byte_block: crypt_byte_block,
+        skip_byte_block: skip_byte_block,
+        is_encrypted: is_encrypted != 0,
+        iv_size: iv_size,
+        kid: kid,
+        constant_iv: constant_iv,
+    })
+}
+
+/// Parse a senc box, given the track's per-sample IV size from 'tenc'.
+fn read_senc<T: Read>(src: &mut BMFFBox<T>, iv_size: Option<u8>) -> Result<Vec<SampleEncryptionEntry>> {
+    let iv_size = match iv_size {
+        Some(iv_size) => iv_size,
+        None => return Err(Error::InvalidData("senc without tenc")),
+    };
+    let (_, flags) = try!(read_fullbox_extra(src));
+    let sample_count = try!(be_u32(src));
+    let mut samples = Vec::new();
+    for _ in 0..sample_count {
+        let iv = try!(read_buf(src, iv_size as usize));
+        let mut subsamples = Vec::new();
+        if flags & 0x2 != 0 {
+            let subsample_count = try!(be_u16(src));
+            for _ in 0..subsample_count {
+                subsamples.push(Subsample {
+                    clear_bytes: try!(be_u16(src)),
+                    encrypted_bytes: try!(be_u32(src)),
+                });
+            }
+        }
+        samples.push(SampleEncryptionEntry {
+            iv: iv,
+            subsamples: subsamples,
+        });
+    }
+    Ok(samples)
+}
+
+/// Parse a trex box.
+fn read_trex<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackExtendsBox> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    let track_id = try!(be_u32(src));
+    // Skip default_sample_description_index.
+    try!(skip(src, 4));
+    let default_sample_duration = try!(be_u32(src));
+    let default_sample_size = try!(be_u32(src));
+    let default_sample_flags = try!(be_u32(src));
+
+    Ok(TrackExtendsBox {
+        track_id: track_id,
+        default_sample_duration: default_sample_duration,
+        default_sample_size: default_sample_size,
+        default_sample_flags: default_sample_flags,
+    })
+}
+
+/// Parse a tfhd box.
+fn read_tfhd<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackFragmentHeaderBox> {
+    let (_, flags) = try!(read_fullbox_extra(src));
+    let track_id = try!(be_u32(src));
+    let base_data_offset = if flags & 0x01 != 0 {
+        Some(try!(be_u64(src)))
+    } else {
+        None
+    };
+    if flags & 0x02 != 0 {
+        // Skip sample_description_index.
+        try!(skip(src, 4));
+    }
+    let default_sample_duration = if flags & 0x08 != 0 {
+        Some(try!(be_u32(src)))
+    } else {
+        None
+    };
+    let default_sample_size = if flags & 0x10 != 0 {
+        Some(try!(be_u32(src)))
+    } else {
+        None
+    };
+    let default_sample_flags = if flags & 0x20 != 0 {
+        Some(try!(be_u32(src)))
+    } else {
+        None
+    };
+
+    Ok(TrackFragmentHeaderBox {
+        track_id: track_id,
+        base_data_offset: base_data_offset,
+        default_sample_duration: default_sample_duration,
+        default_sample_size: default_sample_size,
+        default_sample_flags: default_sample_flags,
+        default_base_is_moof: flags & 0x020000 != 0,
+    })
+}
+
+/// Parse an mfhd box, returning the sequence number.
+fn read_mfhd<T: Read>(src: &mut BMFFBox<T>) -> Result<u32> {
+    let (_, _) = try!(read_fullbox_extra(src));
+    be_u32(src)
+}
+
+/// Parse a tfdt box.
+fn read_tfdt<T: Read>(src: &mut BMFFBox<T>) -> Result<u64> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    try!(check_version(version, "unknown tfdt version"));
+    be_u32_or_u64(src, version)
+}
+
+/// Parse a trun box.
+fn read_trun<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackRunBox> {
+    let (version, flags) = try!(read_fullbox_extra(src));
+    let sample_count = try!(be_u32(src));
+    let data_offset = if flags & 0x01 != 0 {
+        Some(try!(be_i32(src)))
+    } else {
+        None
+    };
+    let first_sample_flags = if flags & 0x04 != 0 {
+        Some(try!(be_u32(src)))
+    } else {
+        None
+    };
+    let mut sample_durations = Vec::new();
+    let mut sample_sizes = Vec::new();
+    let mut sample_flags = Vec::new();
+    let mut sample_composition_offsets = Vec::new();
+    // Each optional per-sample field is 4 bytes.
+    let fields = [0x100, 0x200, 0x400, 0x800].iter().filter(|f| flags & **f != 0).count() as u64;
+    if fields == 0 {
+        if sample_count > TRUN_SAMPLE_LIMIT {
+            return Err(Error::InvalidData("trun sample_count exceeds TRUN_SAMPLE_LIMIT"));
+        }
+    } else {
+        if (src.bytes_left() as u64) < sample_count as u64 * fields * 4 {
+            return Err(Error::InvalidData("trun too small for sample_count"));
+        }
+        for _ in 0..sample_count {
+            if flags & 0x100 != 0 {
+                sample_durations.push(try!(be_u32(src)));
+            }
+            if flags & 0x200 != 0 {
+                sample_sizes.push(try!(be_u32(src)));
+            }
+            if flags & 0x400 != 0 {
+                sample_flags.push(try!(be_u32(src)));
+            }
+            if flags & 0x800 != 0 {
+                // Version 1 allows negative offsets.
+                sample_composition_offsets.push(match version {
+                    0 => try!(be_u32(src)) as i64,
+                    _ => try!(be_i32(src)) as i64,
+                });
+            }
+        }
+    }
+    // Skip any trailing data.
+    let to_skip = src.bytes_left();
+    try!(skip(src, to_skip));
+
+    Ok(TrackRunBox {
+        sample_count: sample_count,
+        data_offset: data_offset,
+        first_sample_flags: first_sample_flags,
+        sample_durations: sample_durations,
+        sample_sizes: sample_sizes,
+        sample_flags: sample_flags,
+        sample_composition_offsets: sample_composition_offsets,
+    })
+}
+
+/// Parse a sidx box.
+fn read_sidx<T: Read>(src: &mut BMFFBox<T>) -> Result<SegmentIndexBox> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    try!(check_version(version, "unknown sidx version"));
+    let reference_id = try!(be_u32(src));
+    let timescale = try!(be_u32(src));
+    let earliest_presentation_time = try!(be_u32_or_u64(src, version));
+    let first_offset = try!(be_u32_or_u64(src, version));
+    // Skip reserved field.
+    try!(skip(src, 2));
+    let reference_count = try!(be_u16(src));
+    let mut references = Vec::new();
+    for _ in 0..reference_count {
+        let size = try!(be_u32(src));
+        let subsegment_duration = try!(be_u32(src));
+        let sap = try!(be_u32(src));
+        references.push(SegmentReference {
+            references_index: size >> 31 == 1,
+            referenced_size: size & 0x7fffffff,
+            subsegment_duration: subsegment_duration,
+            starts_with_sap: sap >> 31 == 1,
+            sap_type: ((sap >> 28) & 0x7) as u8,
+            sap_delta_time: sap & 0x0fffffff,
+        });
+    }
+
+    Ok(SegmentIndexBox {
+        reference_id: reference_id,
+        timescale: timescale,
+        earliest_presentation_time: earliest_presentation_time,
+        first_offset: first_offset,
+        references: references,
+        position: src.position,
+        size: src.get_header().size,
+    })
+}
+
 /// Parse a VPx Config Box.
 fn read_vpcc<T: Read>(src: &mut BMFFBox<T>) -> Result<VPxConfigBox> {
     let (version, _) = try!(read_fullbox_extra(src));
-    if version != 0 {
+    if version > 1 {
         return Err(Error::Unsupported("unknown vpcC version"));
     }
 
     let profile = try!(src.read_u8());
     let level = try!(src.read_u8());
-    let (bit_depth, color_space) = {
+    let (bit_depth, color_space, chroma_subsampling, transfer_function, video_full_range,
+         colour_primaries, matrix_coefficients) = if version == 0 {
+        let (bit_depth, color_space) = {
+            let byte = try!(src.read_u8());
+            ((byte >> 4) & 0x0f, byte & 0x0f)
+        };
         let byte = try!(src.read_u8());
-        ((byte >> 4) & 0x0f, byte & 0x0f)
-    };
-    let (chroma_subsampling, transfer_function, video_full_range) = {
+        (bit_depth, color_space, (byte >> 4) & 0x0f, (byte >> 1) & 0x07, (byte & 1) == 1, 2, 2)
+    } else {
         let byte = try!(src.read_u8());
-        ((byte >> 4) & 0x0f, (byte >> 1) & 0x07, (byte & 1) == 1)
+        let colour_primaries = try!(src.read_u8());
+        let transfer_characteristics = try!(src.read_u8());
+        let matrix_coefficients = try!(src.read_u8());
+        ((byte >> 4) & 0x0f, 0, (byte >> 1) & 0x07, transfer_characteristics, (byte & 1) == 1,
+         colour_primaries, matrix_coefficients)
     };
 
     let codec_init_size = try!(be_u16(src));
@@ -964,6 +3141,7 @@ fn read_vpcc<T: Read>(src: &mut BMFFBox<T>) -> Result<VPxConfigBox> {
 
     // TODO(rillian): validate field value ranges.
     Ok(VPxConfigBox {
+        version: version,
         profile: profile,
         level: level,
         bit_depth: bit_depth,
@@ -971,6 +3149,8 @@ fn read_vpcc<T: Read>(src: &mut BMFFBox<T>) -> Result<VPxConfigBox> {
         chroma_subsampling: chroma_subsampling,
         transfer_function: transfer_function,
         video_full_range: video_full_range,
+        colour_primaries: colour_primaries,
+        matrix_coefficients: matrix_coefficients,
         codec_init: codec_init,
     })
 }
@@ -983,17 +3163,33 @@ fn read_dops<T: Read>(src: &mut BMFFBox<T>) -> Result<OpusSpecificBox> {
     }
 
     let output_channel_count = try!(src.read_u8());
+    if output_channel_count == 0 {
+        return Err(Error::InvalidData("dOps has no output channels"));
+    }
     let pre_skip = try!(be_u16(src));
     let input_sample_rate = try!(be_u32(src));
     let output_gain = try!(be_i16(src));
     let channel_mapping_family = try!(src.read_u8());
 
     let channel_mapping_table = if channel_mapping_family == 0 {
+        // Family 0 is mono or stereo in a single stream.
+        if output_channel_count > 2 {
+            return Err(Error::InvalidData("dOps mapping family 0 with more than two channels"));
+        }
         None
     } else {
         let stream_count = try!(src.read_u8());
         let coupled_count = try!(src.read_u8());
+        if stream_count == 0 || coupled_count > stream_count {
+            return Err(Error::InvalidData("invalid dOps stream counts"));
+        }
         let channel_mapping = try!(read_buf(src, output_channel_count as usize));
+        // Each output channel comes from a decoded channel, or is
+        // silent if 255.
+        let decoded_channels = stream_count as u32 + coupled_count as u32;
+        if channel_mapping.iter().any(|&c| c != 255 && c as u32 >= decoded_channels) {
+            return Err(Error::InvalidData("dOps channel mapping out of range"));
+        }
 
         Some(ChannelMappingTable {
             stream_count: stream_count,
@@ -1002,7 +3198,6 @@ fn read_dops<T: Read>(src: &mut BMFFBox<T>) -> Result<OpusSpecificBox> {
         })
     };
 
-    // TODO(kinetik): validate field value ranges.
     Ok(OpusSpecificBox {
         version: version,
         output_channel_count: output_channel_count,
@@ -1014,6 +3209,35 @@ fn read_dops<T: Read>(src: &mut BMFFBox<T>) -> Result<OpusSpecificBox> {
     })
 }
 
+/// Parse a FLACSpecificBox, which holds the metadata blocks of the
+/// native stream header.
+fn read_dfla<T: Read>(src: &mut BMFFBox<T>) -> Result<FLACSpecificBox> {
+    let (version, flags) = try!(read_fullbox_extra(src));
+    if version != 0 {
+        return Err(Error::Unsupported("unknown dfLa version"));
+    }
+    if flags != 0 {
+        return Err(Error::InvalidData("non-zero dfLa flags"));
+    }
+    if src.bytes_left() as u64 > BUF_SIZE_LIMIT {
+        return Err(Error::InvalidData("dfLa box exceeds BUF_SIZE_LIMIT"));
+    }
+    let mut blocks = Vec::new();
+    while src.bytes_left() > 0 {
+        blocks.push(try!(read_flac_metadata_block(src)));
+    }
+    match blocks.first() {
+        Some(block) if block.block_type == FLAC_METADATA_STREAMINFO => {
+            try!(parse_flac_stream_info(&block.data));
+        }
+        _ => return Err(Error::InvalidData("dfLa without STREAMINFO")),
+    }
+    Ok(FLACSpecificBox {
+        version: version,
+        blocks: blocks,
+    })
+}
+
 /// Re-serialize the Opus codec-specific config data as an `OpusHead` packet.
 ///
 /// Some decoders expect the initialization data in the format used by the
@@ -1070,20 +3294,23 @@ fn read_hdlr<T: Read>(src: &mut BMFFBox<T>) -> Result<HandlerBox> {
     try!(skip(src, 12));
 
     let bytes_left = src.bytes_left();
-    let _name = try!(read_null_terminated_string(src, bytes_left));
+    let name = try!(read_null_terminated_string(src, bytes_left));
 
     Ok(HandlerBox {
         handler_type: handler_type,
+        name: name,
     })
 }
 
 /// Parse an video description inside an stsd box.
-fn read_video_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<SampleEntry> {
+fn read_video_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<SampleEntry> {
     let name = src.get_header().name;
     track.mime_type = match name {
         BoxType::AVCSampleEntry | BoxType::AVC3SampleEntry => String::from("video/avc"),
+        BoxType::HEVCSampleEntry | BoxType::HEV1SampleEntry => String::from("video/hevc"),
         BoxType::VP8SampleEntry => String::from("video/vp8"),
         BoxType::VP9SampleEntry => String::from("video/vp9"),
+        BoxType::AV1SampleEntry => String::from("video/av1"),
         BoxType::ProtectedVisualSampleEntry => String::from("video/crypto"),
         _ => return Err(Error::Unsupported("unhandled video sample entry type")),
     };
@@ -1124,9 +3351,24 @@ fn read_video_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
                     return Err(Error::InvalidData("avcC box exceeds BUF_SIZE_LIMIT"));
                 }
                 let avcc = try!(read_buf(&mut b.content, avcc_size as usize));
-                // TODO(kinetik): Parse avcC box?  For now we just stash the data.
+                // Keep the record as is for decoders; parse_avcc reads
+                // its fields on demand.
                 codec_specific = Some(VideoCodecSpecific::AVCConfig(avcc));
             }
+            BoxType::HEVCConfigurationBox => {
+                if (name != BoxType::HEVCSampleEntry &&
+                    name != BoxType::HEV1SampleEntry &&
+                    name != BoxType::ProtectedVisualSampleEntry) ||
+                    codec_specific.is_some() {
+                        return Err(Error::InvalidData("malformed video sample entry"));
+                    }
+                let hvcc_size = b.head.size - b.head.offset;
+                if hvcc_size > BUF_SIZE_LIMIT {
+                    return Err(Error::InvalidData("hvcC box exceeds BUF_SIZE_LIMIT"));
+                }
+                let hvcc = try!(read_buf(&mut b.content, hvcc_size as usize));
+                codec_specific = Some(VideoCodecSpecific::HEVCConfig(hvcc));
+            }
             BoxType::VPCodecConfigurationBox => { // vpcC
                 if (name != BoxType::VP8SampleEntry &&
                     name != BoxType::VP9SampleEntry) ||
@@ -1136,13 +3378,47 @@ fn read_video_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
                 let vpcc = try!(read_vpcc(&mut b));
                 codec_specific = Some(VideoCodecSpecific::VPxConfig(vpcc));
             }
-            _ => try!(skip_box_content(&mut b)),
+            BoxType::AV1CodecConfigurationBox => {
+                if (name != BoxType::AV1SampleEntry &&
+                    name != BoxType::ProtectedVisualSampleEntry) ||
+                    codec_specific.is_some() {
+                        return Err(Error::InvalidData("malformed video sample entry"));
+                    }
+                let av1c_size = b.head.size - b.head.offset;
+                if av1c_size > BUF_SIZE_LIMIT {
+                    return Err(Error::InvalidData("av1C box exceeds BUF_SIZE_LIMIT"));
+                }
+                let av1c = try!(read_buf(&mut b.content, av1c_size as usize));
+                codec_specific = Some(VideoCodecSpecific::AV1Config(av1c));
+            }
+            BoxType::MasteringDisplayColourVolumeBox |
+            BoxType::ContentLightLevelBox |
+            BoxType::SMPTE2086MasteringDisplayMetadataBox |
+            BoxType::ContentLightLevelInfoBox => {
+                context.features.insert(MediaFeatures::HAS_HDR_METADATA);
+                try!(skip_box_content(&mut b));
+            }
+            BoxType::AuxiliaryTypeInfoBox => {
+                if let Some(aux) = try_leaf!(b, context, read_auxi(&mut b)) {
+                    track.auxiliary_type = Some(aux);
+                }
+            }
+            BoxType::ProtectionSchemeInfoBox => {
+                if name != BoxType::ProtectedVisualSampleEntry {
+                    return Err(Error::InvalidData("malformed video sample entry"));
+                }
+                let sinf = try!(read_sinf(&mut b));
+                log!("{:?}", sinf);
+                track.protection_info = Some(sinf);
+            }
+            _ => try!(skip_unknown_box(&mut b, context)),
         }
         check_parser_state!(b.content);
     }
 
     codec_specific
         .map(|codec_specific| SampleEntry::Video(VideoSampleEntry {
+            name: name,
             data_reference_index: data_reference_index,
             width: width,
             height: height,
@@ -1159,6 +3435,7 @@ fn read_audio_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
         BoxType::MP4AudioSampleEntry => String::from("audio/mp4a-latm"),
         // TODO(kinetik): stagefright doesn't have a MIME mapping for this, revisit.
         BoxType::OpusSampleEntry => String::from("audio/opus"),
+        BoxType::FLACSampleEntry => String::from("audio/flac"),
         BoxType::ProtectedAudioSampleEntry => String::from("audio/crypto"),
         _ => return Err(Error::Unsupported("unhandled audio sample entry type")),
     };
@@ -1183,7 +3460,7 @@ fn read_audio_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
     // Skip uninteresting fields.
     try!(skip(src, 4));
 
-    let samplerate = try!(be_u32(src));
+    let samplerate = FixedPoint16_16(try!(be_u32(src)));
 
     match version {
         0 => (),
@@ -1207,7 +3484,8 @@ fn read_audio_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
                     return Err(Error::InvalidData("esds box exceeds BUF_SIZE_LIMIT"));
                 }
                 let esds = try!(read_buf(&mut b.content, esds_size as usize));
-                // TODO(kinetik): Parse esds box?  For now we just stash the data.
+                // Keep the descriptor as is; parse_esds reads its fields
+                // on demand.
                 codec_specific = Some(AudioCodecSpecific::ES_Descriptor(esds));
             }
             BoxType::OpusSpecificBox => {
@@ -1218,6 +3496,22 @@ fn read_audio_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
                 let dops = try!(read_dops(&mut b));
                 codec_specific = Some(AudioCodecSpecific::OpusSpecificBox(dops));
             }
+            BoxType::FLACSpecificBox => {
+                if name != BoxType::FLACSampleEntry ||
+                    codec_specific.is_some() {
+                    return Err(Error::InvalidData("malformed audio sample entry"));
+                }
+                let dfla = try!(read_dfla(&mut b));
+                codec_specific = Some(AudioCodecSpecific::FLACSpecificBox(dfla));
+            }
+            BoxType::ProtectionSchemeInfoBox => {
+                if name != BoxType::ProtectedAudioSampleEntry {
+                    return Err(Error::InvalidData("malformed audio sample entry"));
+                }
+                let sinf = try!(read_sinf(&mut b));
+                log!("{:?}", sinf);
+                track.protection_info = Some(sinf);
+            }
             _ => try!(skip_box_content(&mut b)),
         }
         check_parser_state!(b.content);
@@ -1234,8 +3528,22 @@ fn read_audio_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<S
         .ok_or_else(|| Error::InvalidData("malformed audio sample entry"))
 }
 
+/// Identify a hint description inside an stsd box.
+///
+/// Hint sample entries aren't parsed further, as hint tracks aren't
+/// played.
+fn read_hint_desc<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<SampleEntry> {
+    track.mime_type = match src.get_header().name {
+        BoxType::RtpHintSampleEntry => String::from("application/x-rtp-hint"),
+        BoxType::SrtpHintSampleEntry => String::from("application/x-srtp-hint"),
+        _ => return Err(Error::Unsupported("unhandled hint sample entry type")),
+    };
+    try!(skip_box_content(src));
+    Ok(SampleEntry::Unknown)
+}
+
 /// Parse a stsd box.
-fn read_stsd<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<SampleDescriptionBox> {
+fn read_stsd<T: Read>(src: &mut BMFFBox<T>, track: &mut Track, context: &mut MediaContext) -> Result<SampleDescriptionBox> {
     let (_, _) = try!(read_fullbox_extra(src));
 
     let description_count = try!(be_u32(src));
@@ -1244,14 +3552,21 @@ fn read_stsd<T: Read>(src: &mut BMFFBox<T>, track: &mut Track) -> Result<SampleD
     // TODO(kinetik): check if/when more than one desc per track? do we need to support?
     let mut iter = src.box_iter();
     while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::ProtectedVisualSampleEntry |
+            BoxType::ProtectedAudioSampleEntry => context.features.insert(MediaFeatures::ENCRYPTED),
+            _ => (),
+        }
         let description = match track.track_type {
-            TrackType::Video => read_video_desc(&mut b, track),
+            TrackType::Video => read_video_desc(&mut b, track, context),
             TrackType::Audio => read_audio_desc(&mut b, track),
+            TrackType::Hint => read_hint_desc(&mut b, track),
             TrackType::Unknown => Err(Error::Unsupported("unknown track type")),
         };
         let description = match description {
             Ok(desc) => desc,
-            Err(Error::Unsupported(_)) => {
+            Err(Error::Unsupported(feature)) => {
+                note_unsupported(context, b.head.name, feature);
                 // read_{audio,video}_desc may have returned Unsupported
                 // after partially reading the box content, so we can't
                 // simply use skip_box_content here.
@@ -1296,9 +3611,15 @@ fn skip<T: Read>(src: &mut T, mut bytes: usize) -> Result<()> {
 /// Read size bytes into a Vector or return error.
 fn read_buf<T: ReadBytesExt>(src: &mut T, size: usize) -> Result<Vec<u8>> {
     let mut buf = vec![0; size];
-    let r = try!(src.read(&mut buf));
-    if r != size {
-        return Err(Error::InvalidData("failed buffer read"));
+    let mut filled = 0;
+    // Readers may return less than asked for, so keep going until the
+    // input runs out.
+    while filled < size {
+        let r = try!(src.read(&mut buf[filled..]));
+        if r == 0 {
+            return Err(Error::InvalidData("failed buffer read"));
+        }
+        filled += r;
     }
     Ok(buf)
 }
@@ -0,0 +1,216 @@
+//! Streaming manifest data derived from segment indexes.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Range;
+
+use analysis::to_microseconds;
+use MediaContext;
+use SegmentIndexBox;
+
+/// Timing and location of a single media segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineSegment {
+    /// Earliest presentation time, in the timeline's timescale.
+    pub start: u64,
+    pub duration: u64,
+    /// Byte range of the segment from the start of the file.
+    pub byte_range: Range<u64>,
+    /// True if the segment begins with a stream access point.
+    pub starts_with_sap: bool,
+}
+
+/// The data needed to describe a file's segments in a DASH
+/// SegmentTimeline or SegmentBase manifest entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentTimeline {
+    /// Track ID of the stream the segments belong to.
+    pub reference_id: u32,
+    pub timescale: u32,
+    /// Byte range of the first segment index, for SegmentBase@indexRange.
+    pub index_range: Range<u64>,
+    pub segments: Vec<TimelineSegment>,
+}
+
+/// A media segment of a fragmented file, as used for an HLS
+/// EXT-X-BYTERANGE playlist entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HlsByteRange {
+    /// Byte offset of the fragment from the start of the file.
+    pub offset: u64,
+    /// Length in bytes of the moof and its mdat.
+    pub length: u64,
+    /// Fragment duration in seconds, for EXTINF.
+    pub duration: f64,
+}
+
+/// Time span and byte range of one movie fragment or indexed segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentRange {
+    /// Start time in microseconds, from the 'tfdt' or segment index.
+    pub start_us: u64,
+    pub duration_us: u64,
+    /// Byte range from the start of the file, covering the moof and
+    /// its mdat.
+    pub byte_range: Range<u64>,
+}
+
+// Bound recursion through hierarchical indexes in malformed files.
+const MAX_INDEX_DEPTH: usize = 8;
+
+impl MediaContext {
+    /// Build a segment timeline from the top-level 'sidx' boxes.
+    ///
+    /// References to other segment indexes are followed, so files
+    /// using hierarchical or daisy-chained indexes produce a flat list
+    /// of media segments. Returns None if the file has no usable
+    /// segment index.
+    pub fn segment_timeline(&self) -> Option<SegmentTimeline> {
+        let first = match self.segment_indexes.first() {
+            Some(sidx) if sidx.timescale > 0 => sidx,
+            _ => return None,
+        };
+        let mut segments = Vec::new();
+        // Each index is referenced at most once in a well-formed file.
+        let mut references = self.segment_indexes.iter().map(|sidx| sidx.references.len()).sum();
+        if !self.expand_sidx(first, first.earliest_presentation_time, 0, &mut references, &mut segments) {
+            return None;
+        }
+        Some(SegmentTimeline {
+            reference_id: first.reference_id,
+            timescale: first.timescale,
+            index_range: first.position..first.position + first.size,
+            segments: segments,
+        })
+    }
+
+    /// List the byte range and duration of each movie fragment.
+    ///
+    /// Durations are taken from the fragments' track runs for the
+    /// track with ID `track_id`. Fragments without a run for that
+    /// track are omitted. Returns None if the track is missing or
+    /// ambiguous or has no timescale.
+    pub fn hls_byte_ranges(&self, track_id: u32) -> Option<Vec<HlsByteRange>> {
+        let timescale = match self.track_by_id(track_id) {
+            Some(track) => match track.timescale {
+                Some(timescale) if timescale.0 > 0 => timescale.0,
+                _ => return None,
+            },
+            None => return None,
+        };
+        Some(self.fragments.iter()
+            .filter_map(|fragment| {
+                fragment.tracks.iter()
+                    .find(|traf| traf.track_id == track_id)
+                    .map(|traf| HlsByteRange {
+                        offset: fragment.position,
+                        length: fragment.size,
+                        duration: traf.duration as f64 / timescale as f64,
+                    })
+            })
+            .collect())
+    }
+
+    /// Map time ranges of the track with ID `track_id` to the byte
+    /// ranges holding them, for ranged fetching of fragmented files.
+    ///
+    /// The parsed movie fragments are used if any carry a run for the
+    /// track, otherwise the segment index. Fragments without a 'tfdt'
+    /// start where the previous one ended. Returns None if the track
+    /// is missing or ambiguous, has no timescale or a fragment's byte
+    /// range overflows.
+    pub fn fragment_index(&self, track_id: u32) -> Option<Vec<FragmentRange>> {
+        let timescale = match self.track_by_id(track_id) {
+            Some(track) => match track.timescale {
+                Some(timescale) if timescale.0 > 0 => timescale.0,
+                _ => return None,
+            },
+            None => return None,
+        };
+        let mut index = Vec::new();
+        let mut next = 0u64;
+        for fragment in &self.fragments {
+            let traf = match fragment.tracks.iter().find(|traf| traf.track_id == track_id) {
+                Some(traf) => traf,
+                None => continue,
+            };
+            let end = match fragment.position.checked_add(fragment.size) {
+                Some(end) => end,
+                None => return None,
+            };
+            let start = traf.base_decode_time.unwrap_or(next);
+            next = start.saturating_add(traf.duration);
+            index.push(FragmentRange {
+                start_us: to_microseconds(start, timescale),
+                duration_us: to_microseconds(traf.duration, timescale),
+                byte_range: fragment.position..end,
+            });
+        }
+        if index.is_empty() {
+            if let Some(timeline) = self.segment_timeline() {
+                if timeline.reference_id == track_id {
+                    let timescale = timeline.timescale as u64;
+                    index = timeline.segments.into_iter()
+                        .map(|segment| FragmentRange {
+                            start_us: to_microseconds(segment.start, timescale),
+                            duration_us: to_microseconds(segment.duration, timescale),
+                            byte_range: segment.byte_range,
+                        })
+                        .collect();
+                }
+            }
+        }
+        Some(index)
+    }
+
+    /// Append the media segments referenced by `sidx` to `segments`.
+    ///
+    /// At most `references` references are followed, so repeated
+    /// references to the same index can't multiply the work. Returns
+    /// false if a referenced index is missing or inconsistent, or an
+    /// offset or time overflows.
+    fn expand_sidx(&self, sidx: &SegmentIndexBox, start: u64, depth: usize,
+                   references: &mut usize, segments: &mut Vec<TimelineSegment>) -> bool {
+        if depth > MAX_INDEX_DEPTH || sidx.references.len() > *references {
+            return false;
+        }
+        *references -= sidx.references.len();
+        let mut offset = match sidx.position.checked_add(sidx.size)
+                                            .and_then(|end| end.checked_add(sidx.first_offset)) {
+            Some(offset) => offset,
+            None => return false,
+        };
+        let mut time = start;
+        for reference in &sidx.references {
+            let end = match offset.checked_add(reference.referenced_size as u64) {
+                Some(end) => end,
+                None => return false,
+            };
+            if reference.references_index {
+                let child = match self.segment_indexes.iter().find(|s| s.position == offset) {
+                    Some(child) => child,
+                    None => return false,
+                };
+                if child.timescale != sidx.timescale ||
+                   !self.expand_sidx(child, time, depth + 1, references, segments) {
+                    return false;
+                }
+            } else {
+                segments.push(TimelineSegment {
+                    start: time,
+                    duration: reference.subsegment_duration as u64,
+                    byte_range: offset..end,
+                    starts_with_sap: reference.starts_with_sap,
+                });
+            }
+            offset = end;
+            time = match time.checked_add(reference.subsegment_duration as u64) {
+                Some(time) => time,
+                None => return false,
+            };
+        }
+        true
+    }
+}
@@ -0,0 +1,154 @@
+//! Accounting of the memory held by parsed data.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::mem::size_of;
+
+use AudioCodecSpecific;
+use AuxiliaryType;
+use Error;
+use ItemProperty;
+use MediaContext;
+use SampleCrypto;
+use SampleEncryptionEntry;
+use SampleEntry;
+use Track;
+use VideoCodecSpecific;
+
+/// Bytes held by the elements of `v`.
+///
+/// This counts length rather than capacity so the total depends only
+/// on the input, not on allocation growth strategy.
+fn vec_bytes<T>(v: &[T]) -> usize {
+    v.len() * size_of::<T>()
+}
+
+fn crypto_bytes(crypto: &SampleCrypto) -> usize {
+    crypto.kid.len() + crypto.iv.len() + vec_bytes(&crypto.subsamples)
+}
+
+/// Bytes held by a recorded error. Only `Error::Io` owns heap data, and
+/// then only when it carries a custom payload.
+fn error_bytes(error: &Error) -> usize {
+    match *error {
+        Error::Io(ref err) => err.get_ref().map_or(0, |inner| inner.to_string().len()),
+        _ => 0,
+    }
+}
+
+fn senc_bytes(senc: &[SampleEncryptionEntry]) -> usize {
+    vec_bytes(senc) + senc.iter().map(|e| e.iv.len() + vec_bytes(&e.subsamples)).sum::<usize>()
+}
+
+impl Track {
+    /// Heap bytes held by this track, excluding the Track itself.
+    fn memory_usage(&self) -> usize {
+        let mut total = self.mime_type.len();
+        total += self.language.as_ref().map_or(0, |l| l.len());
+        total += self.name.as_ref().map_or(0, |n| n.len());
+        total += match self.data {
+            Some(SampleEntry::Audio(ref audio)) => match audio.codec_specific {
+                AudioCodecSpecific::ES_Descriptor(ref esds) => esds.len(),
+                AudioCodecSpecific::OpusSpecificBox(ref opus) => {
+                    opus.channel_mapping_table.as_ref().map_or(0, |t| t.channel_mapping.len())
+                }
+                AudioCodecSpecific::FLACSpecificBox(ref flac) => {
+                    vec_bytes(&flac.blocks) + flac.blocks.iter().map(|b| b.data.len()).sum::<usize>()
+                }
+            },
+            Some(SampleEntry::Video(ref video)) => match video.codec_specific {
+                VideoCodecSpecific::AVCConfig(ref avcc) => avcc.len(),
+                VideoCodecSpecific::HEVCConfig(ref hvcc) => hvcc.len(),
+                VideoCodecSpecific::VPxConfig(ref vpx) => vpx.codec_init.len(),
+                VideoCodecSpecific::AV1Config(ref av1c) => av1c.len(),
+            },
+            _ => 0,
+        };
+        total += self.stts.as_ref().map_or(0, |b| vec_bytes(&b.samples));
+        total += self.ctts.as_ref().map_or(0, |b| vec_bytes(&b.samples));
+        total += self.stsc.as_ref().map_or(0, |b| vec_bytes(&b.samples));
+        total += self.stsz.as_ref().map_or(0, |b| vec_bytes(&b.sample_sizes));
+        total += self.stco.as_ref().map_or(0, |b| vec_bytes(&b.offsets));
+        total += self.stss.as_ref().map_or(0, |b| vec_bytes(&b.samples));
+        total += self.subs.as_ref().map_or(0, |subs| {
+            vec_bytes(&subs.samples) + subs.samples.iter().map(|s| vec_bytes(&s.1)).sum::<usize>()
+        });
+        total += self.protection_info.as_ref()
+            .and_then(|sinf| sinf.tenc.as_ref())
+            .map_or(0, |tenc| tenc.kid.len() + tenc.constant_iv.as_ref().map_or(0, |iv| iv.len()));
+        total += self.senc.as_ref().map_or(0, |senc| senc_bytes(senc));
+        total += vec_bytes(&self.seig_entries);
+        total += self.seig_entries.iter()
+            .map(|e| e.kid.len() + e.constant_iv.as_ref().map_or(0, |iv| iv.len()))
+            .sum::<usize>();
+        total += self.seig_groups.as_ref().map_or(0, |sbgp| vec_bytes(&sbgp.entries));
+        total += vec_bytes(&self.auxiliary_for);
+        total += self.samples.as_ref().map_or(0, |samples| {
+            vec_bytes(samples) + samples.iter()
+                .filter_map(|s| s.crypto.as_ref())
+                .map(crypto_bytes)
+                .sum::<usize>()
+        });
+        total
+    }
+}
+
+impl MediaContext {
+    /// Return the number of heap bytes held by the parsed data.
+    ///
+    /// This covers sample tables, codec configuration, metadata and
+    /// fragment indexes. The value is deterministic for a given input,
+    /// so embedders can compare it against a memory budget.
+    pub fn memory_usage(&self) -> usize {
+        let mut total = vec_bytes(&self.tracks);
+        total += self.tracks.iter().map(|t| t.memory_usage()).sum::<usize>();
+        total += vec_bytes(&self.duplicate_track_ids);
+        total += vec_bytes(&self.skipped_boxes);
+        total += self.skipped_boxes.iter().map(|b| error_bytes(&b.error)).sum::<usize>();
+        total += vec_bytes(&self.unsupported_features);
+        total += vec_bytes(&self.unknown_boxes);
+        total += self.unknown_boxes.iter().map(|b| b.payload.len()).sum::<usize>();
+        total += vec_bytes(&self.psshs);
+        total += self.psshs.iter()
+            .map(|p| p.system_id.len() + vec_bytes(&p.kid) + p.kid.len() * 16 + p.data.len() + p.box_content.len())
+            .sum::<usize>();
+        total += vec_bytes(&self.segment_indexes);
+        total += self.segment_indexes.iter().map(|s| vec_bytes(&s.references)).sum::<usize>();
+        total += vec_bytes(&self.fragments);
+        total += self.fragments.iter().map(|f| vec_bytes(&f.tracks)).sum::<usize>();
+        total += self.fragments.iter()
+            .flat_map(|f| f.tracks.iter())
+            .map(|t| vec_bytes(&t.samples) + senc_bytes(&t.senc))
+            .sum::<usize>();
+        total += vec_bytes(&self.track_extends);
+        total += vec_bytes(&self.brands);
+        total += vec_bytes(&self.segment_brands);
+        total += self.segment_brands.iter().map(|b| vec_bytes(b)).sum::<usize>();
+        total += vec_bytes(&self.top_level_boxes);
+        total += vec_bytes(&self.media_data);
+        total += vec_bytes(&self.exif);
+        total += self.exif.iter().map(|e| e.len()).sum::<usize>();
+        total += self.item_metadata.as_ref().map_or(0, |meta| {
+            vec_bytes(&meta.items) +
+                meta.items.iter()
+                    .map(|i| i.item_name.len() + i.content_type.as_ref().map_or(0, |t| t.len()))
+                    .sum::<usize>() +
+                vec_bytes(&meta.locations) +
+                meta.locations.iter().map(|l| vec_bytes(&l.extents)).sum::<usize>() +
+                vec_bytes(&meta.references) +
+                meta.references.iter().map(|r| vec_bytes(&r.to_item_ids)).sum::<usize>() +
+                meta.item_data.len() +
+                vec_bytes(&meta.properties) +
+                meta.properties.iter().map(|p| match *p {
+                    ItemProperty::AuxiliaryType(AuxiliaryType::Other(ref urn)) => urn.len(),
+                    ItemProperty::PixelInformation(ref bits) => bits.len(),
+                    _ => 0,
+                }).sum::<usize>() +
+                vec_bytes(&meta.property_associations) +
+                meta.property_associations.iter().map(|a| vec_bytes(&a.associations)).sum::<usize>()
+        });
+        total
+    }
+}
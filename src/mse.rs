@@ -0,0 +1,92 @@
+//! Checks against the ISO BMFF byte stream format used by Media
+//! Source Extensions.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use BoxType;
+use MediaContext;
+
+/// Top-level boxes which may appear in initialization and media
+/// segments.
+const SEGMENT_BOXES: [u32; 12] = [
+    0x66747970, // "ftyp"
+    0x6d6f6f76, // "moov"
+    0x73747970, // "styp"
+    0x73696478, // "sidx"
+    0x73736978, // "ssix"
+    0x70726674, // "prft"
+    0x656d7367, // "emsg"
+    0x6d6f6f66, // "moof"
+    0x6d646174, // "mdat"
+    0x66726565, // "free"
+    0x736b6970, // "skip"
+    0x75756964, // "uuid"
+];
+
+/// A way in which a file breaks the rules of the ISO BMFF byte stream
+/// format, which would make a SourceBuffer append fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MseViolation {
+    /// The moov has no mvex, so the file is not fragmented.
+    NotFragmented,
+    /// Media data comes before the moov.
+    MediaBeforeMovieBox,
+    /// The track's sample tables in the moov are not empty.
+    SamplesInMovieBox { track: usize },
+    /// A track fragment lacks a 'tfdt' base media decode time.
+    MissingDecodeTime { fragment: usize },
+    /// A track fragment refers to a track the moov doesn't describe.
+    UnknownTrack { fragment: usize, track_id: u32 },
+    /// A top-level box which doesn't belong in a segment.
+    UnexpectedBox { name: BoxType },
+}
+
+impl MediaContext {
+    /// Check the parsed file against the byte stream format rules.
+    ///
+    /// Returns every violation found. An empty list means the file can
+    /// be appended to a SourceBuffer as-is; codec support is not
+    /// checked.
+    pub fn check_mse(&self) -> Vec<MseViolation> {
+        let mut violations = Vec::new();
+        if self.track_extends.is_empty() {
+            violations.push(MseViolation::NotFragmented);
+        }
+        let moov = self.top_level_boxes.iter().position(|name| *name == BoxType::MovieBox);
+        let media = self.top_level_boxes.iter()
+            .position(|name| *name == BoxType::MovieFragmentBox || *name == BoxType::MediaDataBox);
+        if let (Some(moov), Some(media)) = (moov, media) {
+            if media < moov {
+                violations.push(MseViolation::MediaBeforeMovieBox);
+            }
+        }
+        for (index, track) in self.tracks.iter().enumerate() {
+            if track.stts.as_ref().map_or(false, |stts| !stts.samples.is_empty()) ||
+               track.stsc.as_ref().map_or(false, |stsc| !stsc.samples.is_empty()) ||
+               track.stco.as_ref().map_or(false, |stco| !stco.offsets.is_empty()) {
+                violations.push(MseViolation::SamplesInMovieBox { track: index });
+            }
+        }
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            if fragment.tracks.iter().any(|traf| traf.base_decode_time.is_none()) {
+                violations.push(MseViolation::MissingDecodeTime { fragment: index });
+            }
+            for traf in &fragment.tracks {
+                if !self.tracks.iter().any(|track| track.track_id == Some(traf.track_id)) {
+                    violations.push(MseViolation::UnknownTrack {
+                        fragment: index,
+                        track_id: traf.track_id,
+                    });
+                }
+            }
+        }
+        for name in &self.top_level_boxes {
+            if !SEGMENT_BOXES.contains(&u32::from(*name)) {
+                violations.push(MseViolation::UnexpectedBox { name: *name });
+            }
+        }
+        violations
+    }
+}
@@ -0,0 +1,282 @@
+//! Selection of boxes by path, for ad-hoc inspection of files.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Take};
+use std::ops::Range;
+use byteorder::{BigEndian, ByteOrder};
+
+use BoxType;
+use Error;
+use Result;
+use read_box_header;
+
+/// A box found by `select_boxes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoxNode {
+    pub name: BoxType,
+    /// Offset of the start of the box from the start of the input.
+    pub position: u64,
+    /// Size of the whole box, including the header.
+    pub size: u64,
+    /// Size of the box header.
+    pub header_size: u64,
+}
+
+impl BoxNode {
+    /// Byte range of the box payload, from the start of the input.
+    pub fn payload_range(&self) -> Range<u64> {
+        self.position + self.header_size..self.position + self.size
+    }
+
+    /// Seek `src` to the box payload and return a reader limited to it.
+    pub fn payload<'a, T: Read + Seek>(&self, src: &'a mut T) -> Result<Take<&'a mut T>> {
+        let range = self.payload_range();
+        try!(src.seek(SeekFrom::Start(range.start)));
+        Ok(src.take(range.end - range.start))
+    }
+
+    /// Render up to `max_bytes` of the box payload as a hexdump.
+    ///
+    /// Each line shows the offset from the start of the input, sixteen
+    /// bytes in hex and their printable ASCII characters. A final line
+    /// notes how many bytes were left out.
+    pub fn hexdump<T: Read + Seek>(&self, src: &mut T, max_bytes: usize) -> Result<String> {
+        let range = self.payload_range();
+        let mut data = Vec::new();
+        {
+            let payload = try!(self.payload(src));
+            try!(payload.take(max_bytes as u64).read_to_end(&mut data));
+        }
+        let mut out = String::new();
+        for (line, chunk) in data.chunks(16).enumerate() {
+            out.push_str(&format!("{:08x} ", range.start + line as u64 * 16));
+            for i in 0..16 {
+                match chunk.get(i) {
+                    Some(byte) => out.push_str(&format!(" {:02x}", byte)),
+                    None => out.push_str("   "),
+                }
+                if i == 7 {
+                    out.push(' ');
+                }
+            }
+            out.push_str("  |");
+            out.extend(chunk.iter().map(|&b| if b >= 0x20 && b < 0x7f { b as char } else { '.' }));
+            out.push_str("|\n");
+        }
+        let omitted = (range.end - range.start).saturating_sub(data.len() as u64);
+        if omitted > 0 {
+            out.push_str(&format!("... {} more bytes\n", omitted));
+        }
+        Ok(out)
+    }
+}
+
+/// Iterator over the top-level boxes of a seekable input.
+///
+/// Only box headers are read, seeking past each payload, so walking a
+/// file costs a few reads per box however large its media data.
+/// Iteration ends at the end of the input, or after yielding the
+/// first error.
+pub struct TopLevelBoxIter<'a, T: 'a> {
+    src: &'a mut T,
+    position: u64,
+    done: bool,
+}
+
+impl<'a, T: Read + Seek> TopLevelBoxIter<'a, T> {
+    /// Iterate over the boxes of `src` from its start.
+    pub fn new(src: &'a mut T) -> TopLevelBoxIter<'a, T> {
+        TopLevelBoxIter {
+            src: src,
+            position: 0,
+            done: false,
+        }
+    }
+
+    fn next_box(&mut self) -> Result<Option<BoxNode>> {
+        try!(self.src.seek(SeekFrom::Start(self.position)));
+        let mut start = [0; 8];
+        match self.src.read_exact(&mut start) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        }
+        // A size of 0 means the box runs to the end of the input, as a
+        // trailing mdat from a streaming recorder may.
+        let node = if BigEndian::read_u32(&start[..4]) == 0 {
+            let end = try!(self.src.seek(SeekFrom::End(0)));
+            BoxNode {
+                name: BoxType::from(BigEndian::read_u32(&start[4..])),
+                position: self.position,
+                size: end - self.position,
+                header_size: 8,
+            }
+        } else {
+            try!(self.src.seek(SeekFrom::Start(self.position)));
+            let header = match read_box_header(self.src) {
+                Ok(header) => header,
+                Err(Error::UnexpectedEOF) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            BoxNode {
+                name: header.name,
+                position: self.position,
+                size: header.size,
+                header_size: header.offset,
+            }
+        };
+        self.position = match self.position.checked_add(node.size) {
+            Some(position) => position,
+            None => return Err(Error::InvalidData("box size overflow")),
+        };
+        Ok(Some(node))
+    }
+}
+
+impl<'a, T: Read + Seek> Iterator for TopLevelBoxIter<'a, T> {
+    type Item = Result<BoxNode>;
+
+    fn next(&mut self) -> Option<Result<BoxNode>> {
+        if self.done {
+            return None;
+        }
+        match self.next_box() {
+            Ok(Some(node)) => Some(Ok(node)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// One component of a path: a box type, or any type for '*', and an
+/// optional 1-based index among the matching siblings.
+struct Step {
+    name: Option<BoxType>,
+    index: Option<usize>,
+}
+
+fn parse_step(step: &str) -> Result<Step> {
+    let (name, index) = match step.find('[') {
+        Some(open) => {
+            if !step.ends_with(']') {
+                return Err(Error::InvalidData("unterminated index in box path"));
+            }
+            let index = match step[open + 1..step.len() - 1].parse::<usize>() {
+                Ok(index) if index > 0 => index,
+                _ => return Err(Error::InvalidData("invalid index in box path")),
+            };
+            (&step[..open], Some(index))
+        }
+        None => (step, None),
+    };
+    let name = match name {
+        "*" => None,
+        _ if name.len() == 4 => {
+            let bytes = name.as_bytes();
+            Some(BoxType::from((bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+                               (bytes[2] as u32) << 8 | bytes[3] as u32))
+        }
+        _ => return Err(Error::InvalidData("box path names must be four characters")),
+    };
+    Ok(Step { name: name, index: index })
+}
+
+/// Bytes preceding the child boxes in the payload of `name`.
+fn children_offset(name: BoxType) -> u64 {
+    match name {
+        // Full box header and entry count.
+        BoxType::SampleDescriptionBox => 8,
+        BoxType::MetadataBox => 4,
+        BoxType::AVCSampleEntry |
+        BoxType::AVC3SampleEntry |
+        BoxType::HEVCSampleEntry |
+        BoxType::HEV1SampleEntry |
+        BoxType::VP8SampleEntry |
+        BoxType::VP9SampleEntry |
+        BoxType::AV1SampleEntry |
+        BoxType::ProtectedVisualSampleEntry => 78,
+        BoxType::MP4AudioSampleEntry |
+        BoxType::OpusSampleEntry |
+        BoxType::ProtectedAudioSampleEntry => 28,
+        _ => 0,
+    }
+}
+
+/// List the boxes in `range` of the input.
+fn read_children<T: Read + Seek>(src: &mut T, range: Range<u64>) -> Result<Vec<BoxNode>> {
+    let mut children = Vec::new();
+    let mut position = range.start;
+    while range.end - position >= 8 {
+        try!(src.seek(SeekFrom::Start(position)));
+        let header = try!(read_box_header(src));
+        if header.size > range.end - position {
+            return Err(Error::InvalidData("box extends past its parent"));
+        }
+        children.push(BoxNode {
+            name: header.name,
+            position: position,
+            size: header.size,
+            header_size: header.offset,
+        });
+        position += header.size;
+    }
+    Ok(children)
+}
+
+/// Append the boxes among `siblings` matching `steps` to `matches`,
+/// descending into the payloads of those matching the first step.
+fn select_steps<T: Read + Seek>(src: &mut T, siblings: Vec<BoxNode>, steps: &[Step],
+                                matches: &mut Vec<BoxNode>) -> Result<()> {
+    let step = &steps[0];
+    let found = siblings.into_iter()
+        .filter(|child| step.name.map_or(true, |name| child.name == name));
+    let found: Vec<BoxNode> = match step.index {
+        Some(index) => found.skip(index - 1).take(1).collect(),
+        None => found.collect(),
+    };
+    for node in found {
+        if steps.len() == 1 {
+            matches.push(node);
+            continue;
+        }
+        let payload = node.payload_range();
+        let offset = children_offset(node.name);
+        if offset > payload.end - payload.start {
+            continue;
+        }
+        let children = try!(read_children(src, payload.start + offset..payload.end));
+        try!(select_steps(src, children, &steps[1..], matches));
+    }
+    Ok(())
+}
+
+/// Find the boxes matching `path` in a seekable input.
+///
+/// The path lists box types from the top level down, separated by
+/// '/', as in "moov/trak[2]/mdia/minf/stbl/stsd". A step without an
+/// index matches every sibling of that type and '*' matches any type.
+/// Indices count from 1, among siblings matching the step's type.
+/// The top-level boxes are walked once, reading only their headers,
+/// and only the payloads of boxes on the path are examined.
+pub fn select_boxes<T: Read + Seek>(src: &mut T, path: &str) -> Result<Vec<BoxNode>> {
+    let steps = try!(path.split('/')
+        .filter(|step| !step.is_empty())
+        .map(parse_step)
+        .collect::<Result<Vec<Step>>>());
+    let mut matches = Vec::new();
+    if steps.is_empty() {
+        return Ok(matches);
+    }
+    let top_level = try!(TopLevelBoxIter::new(src).collect::<Result<Vec<BoxNode>>>());
+    try!(select_steps(src, top_level, &steps, &mut matches));
+    Ok(matches)
+}
@@ -0,0 +1,136 @@
+//! Fragment random access index ('mfra') parsing.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use BMFFBox;
+use BoxIter;
+use BoxType;
+use Error;
+use Result;
+use be_u32;
+use be_u32_or_u64;
+use check_version;
+use read_fullbox_extra;
+use skip_box_content;
+use get_debug_mode;
+
+/// A sync sample location from a 'tfra' box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RandomAccessEntry {
+    /// Presentation time of the sample, in the track's timescale.
+    pub time: u64,
+    /// File offset of the moof holding the sample.
+    pub moof_offset: u64,
+    /// 1-based indices of the traf, trun and sample within the moof.
+    pub traf_number: u32,
+    pub trun_number: u32,
+    pub sample_number: u32,
+}
+
+/// Track fragment random access box 'tfra'
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackFragmentRandomAccess {
+    pub track_id: u32,
+    pub entries: Vec<RandomAccessEntry>,
+}
+
+/// Read a big-endian unsigned integer of 1 to 4 bytes.
+fn be_uint<T: Read>(src: &mut T, bytes: usize) -> Result<u32> {
+    let mut value = 0u32;
+    for _ in 0..bytes {
+        let mut byte = [0u8];
+        try!(src.read_exact(&mut byte));
+        value = value << 8 | byte[0] as u32;
+    }
+    Ok(value)
+}
+
+fn read_tfra<T: Read>(src: &mut BMFFBox<T>) -> Result<TrackFragmentRandomAccess> {
+    let (version, _) = try!(read_fullbox_extra(src));
+    try!(check_version(version, "unknown tfra version"));
+    let track_id = try!(be_u32(src));
+    let sizes = try!(be_u32(src));
+    let traf_bytes = ((sizes >> 4) & 0x3) as usize + 1;
+    let trun_bytes = ((sizes >> 2) & 0x3) as usize + 1;
+    let sample_bytes = (sizes & 0x3) as usize + 1;
+    let entry_count = try!(be_u32(src));
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let time = try!(be_u32_or_u64(src, version));
+        let moof_offset = try!(be_u32_or_u64(src, version));
+        entries.push(RandomAccessEntry {
+            time: time,
+            moof_offset: moof_offset,
+            traf_number: try!(be_uint(src, traf_bytes)),
+            trun_number: try!(be_uint(src, trun_bytes)),
+            sample_number: try!(be_uint(src, sample_bytes)),
+        });
+    }
+    Ok(TrackFragmentRandomAccess {
+        track_id: track_id,
+        entries: entries,
+    })
+}
+
+fn read_mfra<T: Read>(f: &mut BMFFBox<T>) -> Result<Vec<TrackFragmentRandomAccess>> {
+    let mut tracks = Vec::new();
+    let mut iter = f.box_iter();
+    while let Some(mut b) = try!(iter.next_box()) {
+        match b.head.name {
+            BoxType::TrackFragmentRandomAccessBox => {
+                let tfra = try!(read_tfra(&mut b));
+                log!("{:?}", tfra);
+                tracks.push(tfra);
+            }
+            _ => try!(skip_box_content(&mut b)),
+        };
+        check_parser_state!(b.content);
+    }
+    Ok(tracks)
+}
+
+/// Read the fragment random access index of a fragmented file.
+///
+/// The trailing 'mfro' box gives the size of the 'mfra' box which
+/// precedes it at the end of the file, so only the index itself is
+/// read rather than scanning the whole file. Returns None if the file
+/// doesn't end with an mfro.
+pub fn parse_random_access_index<T: Read + Seek>(f: &mut T) -> Result<Option<Vec<TrackFragmentRandomAccess>>> {
+    let end = try!(f.seek(SeekFrom::End(0)));
+    if end < 16 {
+        return Ok(None);
+    }
+    try!(f.seek(SeekFrom::Start(end - 16)));
+    let mfra_size = {
+        let mut iter = BoxIter::new(f);
+        let mut b = match try!(iter.next_box()) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        if b.head.name != BoxType::MovieFragmentRandomAccessOffsetBox || b.head.size != 16 {
+            return Ok(None);
+        }
+        let (_, _) = try!(read_fullbox_extra(&mut b));
+        try!(be_u32(&mut b)) as u64
+    };
+    if mfra_size < 16 || mfra_size > end {
+        return Err(Error::InvalidData("invalid mfro size"));
+    }
+
+    try!(f.seek(SeekFrom::Start(end - mfra_size)));
+    let mut iter = BoxIter::new(f);
+    let mut b = match try!(iter.next_box()) {
+        Some(b) => b,
+        None => return Err(Error::UnexpectedEOF),
+    };
+    if b.head.name != BoxType::MovieFragmentRandomAccessBox || b.head.size != mfra_size {
+        return Err(Error::InvalidData("mfro doesn't point at mfra"));
+    }
+    let tracks = try!(read_mfra(&mut b));
+    check_parser_state!(b.content);
+    Ok(Some(tracks))
+}
@@ -0,0 +1,804 @@
+//! Rewriting files without some of their tracks, or joined to another.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::io::{Cursor, Read, Seek, Write};
+use std::ops::Range;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use faststart::{copy_box, find_large_tables, header_size, node_size, parse_children, write_header,
+                write_node, Node, TopLevelBox, MOOV_SIZE_LIMIT};
+use read_mp4;
+use BoxNode;
+use BoxType;
+use Error;
+use MediaContext;
+use SampleInfo;
+use Result;
+use TopLevelBoxIter;
+
+const TRAK: u32 = 0x7472616b;
+const TKHD: u32 = 0x746b6864;
+const TREF: u32 = 0x74726566;
+const MDAT: u32 = 0x6d646174;
+const MVHD: u32 = 0x6d766864;
+const MDIA: u32 = 0x6d646961;
+const MDHD: u32 = 0x6d646864;
+const MINF: u32 = 0x6d696e66;
+const STBL: u32 = 0x7374626c;
+const EDTS: u32 = 0x65647473;
+const ELST: u32 = 0x656c7374;
+const STSD: u32 = 0x73747364;
+const STTS: u32 = 0x73747473;
+const CTTS: u32 = 0x63747473;
+const STSS: u32 = 0x73747373;
+const STSZ: u32 = 0x7374737a;
+const STSC: u32 = 0x73747363;
+
+/// Sample table boxes rebuilt when concatenating. Any other box in an
+/// 'stbl', such as a sample group, describes samples in a way which
+/// isn't rebuilt, so isn't supported.
+const SAMPLE_TABLES: [u32; 8] = [
+    STSD, STTS, CTTS, STSS, STSZ, STSC,
+    0x73747a32, // "stz2"
+    0x636f3634, // "co64"
+];
+
+/// A top-level box of the input and its size in the output.
+pub struct OutputBox {
+    pub input: TopLevelBox,
+    /// Header size in the input.
+    pub header_size: u64,
+    /// Byte ranges cut from an mdat's payload, in order.
+    pub cuts: Vec<Range<u64>>,
+    /// Payload size in the output, for an mdat.
+    pub payload: u64,
+}
+
+impl OutputBox {
+    pub fn new(node: &BoxNode) -> OutputBox {
+        OutputBox {
+            input: TopLevelBox {
+                name: node.name,
+                position: node.position,
+                size: node.size,
+            },
+            header_size: node.header_size,
+            cuts: Vec::new(),
+            payload: node.size - node.header_size,
+        }
+    }
+
+    pub fn is_mdat(&self) -> bool {
+        self.input.name == BoxType::MediaDataBox
+    }
+
+    pub fn size(&self, moov_size: Option<u64>) -> u64 {
+        match moov_size {
+            Some(size) => size,
+            None if self.is_mdat() => header_size(self.payload) + self.payload,
+            None => self.input.size,
+        }
+    }
+}
+
+/// The track ID from a trak node's 'tkhd', if it has one.
+pub fn track_id(node: &Node) -> Option<u32> {
+    let children = match *node {
+        Node::Container { name: TRAK, ref children } => children,
+        _ => return None,
+    };
+    children.iter().filter_map(|child| match *child {
+        Node::Leaf { name: TKHD, ref data } => {
+            // Version and flags, then the creation and modification
+            // times, which are 64-bit in version 1.
+            let offset = if data.first() == Some(&1) { 20 } else { 12 };
+            data.get(offset..offset + 4).map(BigEndian::read_u32)
+        }
+        _ => None,
+    }).next()
+}
+
+/// Sort and merge byte ranges.
+fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => {
+                last.end = std::cmp::max(last.end, range.end);
+                continue;
+            }
+            _ => {}
+        }
+        merged.push(range);
+    }
+    merged
+}
+
+/// Move chunk offset `offset` from the input layout to the output
+/// layout, given the output position of each box.
+pub fn map_offset(boxes: &[OutputBox], positions: &[u64], moov: Option<usize>, offset: u64) -> Result<u64> {
+    for (i, b) in boxes.iter().enumerate() {
+        if Some(i) == moov || offset < b.input.position || offset >= b.input.position + b.input.size {
+            continue;
+        }
+        if !b.is_mdat() {
+            return Ok(offset - b.input.position + positions[i]);
+        }
+        let payload_start = b.input.position + b.header_size;
+        if offset < payload_start {
+            break;
+        }
+        let mut removed = 0;
+        for cut in &b.cuts {
+            if offset >= cut.end {
+                removed += cut.end - cut.start;
+            } else if offset >= cut.start {
+                return Err(Error::InvalidData("chunk offset in removed media data"));
+            }
+        }
+        return Ok(positions[i] + header_size(b.payload) + offset - payload_start - removed);
+    }
+    Err(Error::InvalidData("chunk offset outside media data"))
+}
+
+/// Fit the offset tables of `tree` to an output layout which moves as
+/// they change size, returning the box positions and which tables
+/// need 64-bit entries.
+///
+/// `sizes` gives the output box sizes for a moov of the given size,
+/// and `map` moves an offset given the box positions.
+pub fn fit_tables<S, M>(tree: &Node, tables: usize, sizes: S, map: M) -> Result<(Vec<u64>, Vec<bool>)>
+    where S: Fn(u64) -> Vec<u64>,
+          M: Fn(&[u64], u64) -> Result<u64>
+{
+    let mut large = vec![false; tables];
+    loop {
+        let mut positions = Vec::new();
+        let mut position = 0u64;
+        for size in sizes(node_size(tree, &large)) {
+            positions.push(position);
+            position += size;
+        }
+        let mut needed = large.clone();
+        try!(find_large_tables(tree, &|offset| map(&positions, offset), &mut needed));
+        if needed == large {
+            return Ok((positions, large));
+        }
+        large = needed;
+    }
+}
+
+pub fn copy_range<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W, range: Range<u64>) -> Result<()> {
+    copy_box(src, dst, &TopLevelBox {
+        name: BoxType::MediaDataBox,
+        position: range.start,
+        size: range.end - range.start,
+    })
+}
+
+/// Copy a box other than the moov to the output. An mdat is written
+/// with its output size, so one which ran to the end of the input
+/// doesn't take in what follows it in the output.
+fn write_box<R: Read + Seek, W: Write>(src: &mut R, dst: &mut W, b: &OutputBox) -> Result<()> {
+    if !b.is_mdat() {
+        return copy_box(src, dst, &b.input);
+    }
+    try!(write_header(dst, MDAT, b.payload));
+    let mut position = b.input.position + b.header_size;
+    for cut in &b.cuts {
+        try!(copy_range(src, dst, position..cut.start));
+        position = cut.end;
+    }
+    copy_range(src, dst, position..b.input.position + b.input.size)
+}
+
+/// Drop references to removed tracks from a 'tref' payload, along with
+/// any reference type left without references.
+pub fn filter_references<F: Fn(u32) -> bool>(mut data: &[u8], is_removed: F) -> Result<Vec<u8>> {
+    let mut filtered = Vec::new();
+    while !data.is_empty() {
+        let size = if data.len() >= 8 { BigEndian::read_u32(data) as usize } else { 0 };
+        if size < 8 || size > data.len() || (size - 8) % 4 != 0 {
+            return Err(Error::InvalidData("malformed tref"));
+        }
+        let ids: Vec<u32> = data[8..size].chunks(4)
+            .map(BigEndian::read_u32)
+            .filter(|id| !is_removed(*id))
+            .collect();
+        if !ids.is_empty() {
+            try!(filtered.write_u32::<BigEndian>(8 + ids.len() as u32 * 4));
+            filtered.extend_from_slice(&data[4..8]);
+            for id in ids {
+                try!(filtered.write_u32::<BigEndian>(id));
+            }
+        }
+        data = &data[size..];
+    }
+    Ok(filtered)
+}
+
+impl MediaContext {
+    /// Rewrite `src` without the track with ID `track_id`.
+    ///
+    /// See `keep_only`, which this shares its behaviour with.
+    pub fn remove_track<R: Read + Seek, W: Write>(&self, src: &mut R, dst: &mut W, track_id: u32) -> Result<()> {
+        remove_tracks(self, src, dst, &[track_id], false)
+    }
+
+    /// Rewrite `src`, which this context was read from, keeping only
+    /// the tracks whose IDs are in `track_ids`.
+    ///
+    /// The other tracks' 'trak' boxes are dropped from the 'moov' and
+    /// their samples, located by this context's sample tables, are cut
+    /// from the 'mdat' boxes. The remaining chunk and auxiliary
+    /// information offsets are adjusted for the new layout, as
+    /// `relocate_moov_to_front` does. References to removed tracks are
+    /// dropped from the kept tracks' 'tref' boxes, and the 'mvhd'
+    /// duration becomes that of the longest kept track. Everything else
+    /// is copied unchanged. Every ID must name a track, and at least
+    /// one track must remain. Fragmented files, files with a top-level
+    /// 'meta', and files where a removed track's samples overlap a kept
+    /// track's are not supported.
+    pub fn keep_only<R: Read + Seek, W: Write>(&self, src: &mut R, dst: &mut W, track_ids: &[u32]) -> Result<()> {
+        remove_tracks(self, src, dst, track_ids, true)
+    }
+}
+
+/// Remove the tracks listed in `track_ids`, or all the others if `keep`.
+fn remove_tracks<R: Read + Seek, W: Write>(context: &MediaContext, src: &mut R, dst: &mut W,
+                                           track_ids: &[u32], keep: bool) -> Result<()> {
+    let is_removed = |id: u32| track_ids.contains(&id) != keep;
+    let mut boxes = Vec::new();
+    for node in TopLevelBoxIter::new(src) {
+        let node = try!(node);
+        if node.name == BoxType::MovieFragmentBox {
+            return Err(Error::Unsupported("track removal from fragmented files"));
+        }
+        if node.name == BoxType::MetadataBox {
+            // Item locations may point into the media data.
+            return Err(Error::Unsupported("track removal with a top-level meta"));
+        }
+        boxes.push(OutputBox::new(&node));
+    }
+    let moov = match boxes.iter().position(|b| b.input.name == BoxType::MovieBox) {
+        Some(moov) => moov,
+        None => return Err(Error::NoMoov),
+    };
+    if boxes[moov].input.size > MOOV_SIZE_LIMIT {
+        return Err(Error::MoovTooLarge(boxes[moov].input.size));
+    }
+    let mut moov_data = Vec::with_capacity(boxes[moov].input.size as usize);
+    try!(copy_box(src, &mut moov_data, &boxes[moov].input));
+    let mut tables = 0;
+    let mut tree = match try!(parse_children(&moov_data, &mut tables)).pop() {
+        Some(tree) => tree,
+        None => return Err(Error::InvalidData("empty moov")),
+    };
+
+    // The context's tracks must be those of the input.
+    let ids: Vec<Option<u32>> = children_mut(&mut tree, &[]).unwrap().iter().map(track_id).filter(|id| id.is_some()).collect();
+    if ids != context.tracks.iter().map(|t| t.track_id).filter(|id| id.is_some()).collect::<Vec<_>>() {
+        return Err(Error::InvalidData("tracks differ from the parsed context"));
+    }
+    if track_ids.iter().any(|id| !context.tracks.iter().any(|t| t.track_id == Some(*id))) {
+        return Err(Error::InvalidData("no track with the given ID"));
+    }
+    let mut removed = Vec::new();
+    let mut kept = Vec::new();
+    for track in &context.tracks {
+        let ranges = if track.track_id.map_or(false, &is_removed) { &mut removed } else { &mut kept };
+        for sample in try!(track.sample_table()) {
+            match sample.offset.checked_add(sample.size as u64) {
+                Some(end) => ranges.push(sample.offset..end),
+                None => return Err(Error::InvalidData("sample offset overflow")),
+            }
+        }
+    }
+    if context.tracks.iter().all(|t| t.track_id.map_or(false, &is_removed)) {
+        return Err(Error::InvalidData("no tracks left"));
+    }
+    let removed = merge_ranges(removed);
+    for range in &kept {
+        let next = match removed.binary_search_by_key(&range.start, |r| r.start) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        };
+        if removed[next..].iter().take(2).any(|r| r.start < range.end && range.start < r.end) {
+            return Err(Error::Unsupported("removed track shares media data with a kept track"));
+        }
+    }
+
+    for b in boxes.iter_mut().filter(|b| b.is_mdat()) {
+        let payload = b.input.position + b.header_size..b.input.position + b.input.size;
+        for r in &removed {
+            let cut = std::cmp::max(r.start, payload.start)..std::cmp::min(r.end, payload.end);
+            if cut.start < cut.end {
+                b.payload -= cut.end - cut.start;
+                b.cuts.push(cut);
+            }
+        }
+    }
+
+    {
+        let children = children_mut(&mut tree, &[]).unwrap();
+        children.retain(|child| !track_id(child).map_or(false, &is_removed));
+        let mut movie_duration = None;
+        for trak in children.iter_mut().filter(|c| name(c) == Some(TRAK)) {
+            let trak = children_mut(trak, &[]).unwrap();
+            if let Some(tref) = leaf_mut(trak, TREF) {
+                *tref = try!(filter_references(tref, &is_removed));
+            }
+            trak.retain(|child| match *child {
+                Node::Leaf { name: TREF, ref data } => !data.is_empty(),
+                _ => true,
+            });
+            if let Some(tkhd) = leaf_mut(trak, TKHD) {
+                let duration = try!(read_duration(tkhd, TKHD));
+                movie_duration = Some(std::cmp::max(movie_duration.unwrap_or(0), duration));
+            }
+        }
+        if let (Some(mvhd), Some(duration)) = (leaf_mut(children, MVHD), movie_duration) {
+            try!(set_duration(mvhd, MVHD, duration));
+        }
+    }
+
+    let (positions, large) = try!(fit_tables(&tree, tables, |moov_size| {
+        boxes.iter().enumerate().map(|(i, b)| b.size(if i == moov { Some(moov_size) } else { None })).collect()
+    }, |positions, offset| map_offset(&boxes, positions, Some(moov), offset)));
+
+    for (i, b) in boxes.iter().enumerate() {
+        if i == moov {
+            let map = |offset: u64| map_offset(&boxes, &positions, Some(moov), offset);
+            try!(write_node(dst, &tree, &large, &map));
+        } else {
+            try!(write_box(src, dst, b));
+        }
+    }
+    Ok(())
+}
+
+pub fn name(node: &Node) -> Option<u32> {
+    match *node {
+        Node::Container { name, .. } | Node::Leaf { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// The children of the container at `path` below `node`.
+pub fn children_mut<'a>(node: &'a mut Node, path: &[u32]) -> Option<&'a mut Vec<Node>> {
+    let children = match *node {
+        Node::Container { ref mut children, .. } => children,
+        _ => return None,
+    };
+    match path.split_first() {
+        None => Some(children),
+        Some((first, rest)) => {
+            children.iter_mut().find(|c| name(c) == Some(*first)).and_then(|c| children_mut(c, rest))
+        }
+    }
+}
+
+pub fn leaf_mut(children: &mut [Node], leaf: u32) -> Option<&mut Vec<u8>> {
+    children.iter_mut().filter_map(|child| match *child {
+        Node::Leaf { name, ref mut data } if name == leaf => Some(data),
+        _ => None,
+    }).next()
+}
+
+/// Position of the duration in an 'mvhd', 'tkhd' or 'mdhd' payload,
+/// and whether it's 64-bit. The timescale of an mvhd or mdhd precedes
+/// it.
+fn duration_position(data: &[u8], name: u32) -> Result<(usize, bool)> {
+    let large = data.first() == Some(&1);
+    let position = match (name, large) {
+        (TKHD, false) => 20,
+        (TKHD, true) => 28,
+        (_, false) => 16,
+        (_, true) => 24,
+    };
+    if data.len() < position + 8 {
+        return Err(Error::InvalidData("truncated header box"));
+    }
+    Ok((position, large))
+}
+
+fn read_field(data: &[u8], position: usize, large: bool) -> u64 {
+    if large {
+        BigEndian::read_u64(&data[position..])
+    } else {
+        BigEndian::read_u32(&data[position..]) as u64
+    }
+}
+
+fn write_field(data: &mut [u8], position: usize, large: bool, value: u64) -> Result<()> {
+    if large {
+        BigEndian::write_u64(&mut data[position..], value);
+    } else if value <= std::u32::MAX as u64 {
+        BigEndian::write_u32(&mut data[position..], value as u32);
+    } else {
+        return Err(Error::Unsupported("concatenated duration exceeds 32 bits"));
+    }
+    Ok(())
+}
+
+fn read_duration(data: &[u8], name: u32) -> Result<u64> {
+    let (position, large) = try!(duration_position(data, name));
+    Ok(read_field(data, position, large))
+}
+
+fn read_timescale(data: &[u8], name: u32) -> Result<u32> {
+    let (position, _) = try!(duration_position(data, name));
+    Ok(BigEndian::read_u32(&data[position - 4..]))
+}
+
+fn set_duration(data: &mut [u8], name: u32, duration: u64) -> Result<()> {
+    let (position, large) = try!(duration_position(data, name));
+    write_field(data, position, large, duration)
+}
+
+/// Extend the segment of an 'edts' holding a single edit by `extra`.
+fn extend_edit(edts: &mut [u8], extra: u64) -> Result<()> {
+    // The 'elst' header, version and flags, and entry count.
+    if edts.len() < 16 || BigEndian::read_u32(&edts[4..]) != ELST {
+        return Err(Error::InvalidData("edts without elst"));
+    }
+    if BigEndian::read_u32(&edts[12..]) != 1 {
+        return Err(Error::Unsupported("concatenation of multiple edits"));
+    }
+    let large = edts[8] == 1;
+    if edts.len() < 16 + if large { 8 } else { 4 } {
+        return Err(Error::InvalidData("truncated elst"));
+    }
+    let duration = match read_field(edts, 16, large).checked_add(extra) {
+        Some(duration) => duration,
+        None => return Err(Error::InvalidData("duration overflow")),
+    };
+    write_field(edts, 16, large, duration)
+}
+
+/// Run-length encode `values` as (count, value) pairs.
+fn runs<T: PartialEq + Copy>(values: &[T]) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some(run) if run.1 == *value => {
+                run.0 += 1;
+                continue;
+            }
+            _ => {}
+        }
+        runs.push((1, *value));
+    }
+    runs
+}
+
+pub fn fullbox_leaf(name: u32, version: u8, body: Vec<u8>) -> Node {
+    let mut data = vec![version, 0, 0, 0];
+    data.extend(body);
+    Node::Leaf { name: name, data: data }
+}
+
+/// Build the sample tables for `samples`, whose offsets are in the
+/// combined input space, numbering the chunk offset table `index`.
+fn build_sample_tables(samples: &[SampleInfo], index: usize) -> Result<Vec<Node>> {
+    let mut tables = Vec::new();
+    let mut body = Vec::new();
+    let durations: Vec<u32> = samples.iter().map(|s| s.duration).collect();
+    let stts = runs(&durations);
+    try!(body.write_u32::<BigEndian>(stts.len() as u32));
+    for (count, delta) in stts {
+        try!(body.write_u32::<BigEndian>(count));
+        try!(body.write_u32::<BigEndian>(delta));
+    }
+    tables.push(fullbox_leaf(STTS, 0, body));
+
+    let offsets: Vec<i64> = samples.iter().map(|s| s.composition_time - s.decode_time as i64).collect();
+    if offsets.iter().any(|o| *o != 0) {
+        if offsets.iter().any(|o| *o < std::i32::MIN as i64 || *o > std::u32::MAX as i64) {
+            return Err(Error::InvalidData("composition offset out of range"));
+        }
+        let negative = offsets.iter().any(|o| *o < 0);
+        if negative && offsets.iter().any(|o| *o > std::i32::MAX as i64) {
+            return Err(Error::InvalidData("composition offset out of range"));
+        }
+        let ctts = runs(&offsets);
+        let mut body = Vec::new();
+        try!(body.write_u32::<BigEndian>(ctts.len() as u32));
+        for (count, offset) in ctts {
+            try!(body.write_u32::<BigEndian>(count));
+            try!(body.write_u32::<BigEndian>(offset as u32));
+        }
+        tables.push(fullbox_leaf(CTTS, if negative { 1 } else { 0 }, body));
+    }
+
+    if samples.iter().any(|s| !s.sync) {
+        let mut body = Vec::new();
+        try!(body.write_u32::<BigEndian>(samples.iter().filter(|s| s.sync).count() as u32));
+        for (i, _) in samples.iter().enumerate().filter(|&(_, s)| s.sync) {
+            try!(body.write_u32::<BigEndian>(i as u32 + 1));
+        }
+        tables.push(fullbox_leaf(STSS, 0, body));
+    }
+
+    let mut body = Vec::new();
+    match samples.first() {
+        Some(first) if samples.iter().all(|s| s.size == first.size) => {
+            try!(body.write_u32::<BigEndian>(first.size));
+            try!(body.write_u32::<BigEndian>(samples.len() as u32));
+        }
+        _ => {
+            try!(body.write_u32::<BigEndian>(0));
+            try!(body.write_u32::<BigEndian>(samples.len() as u32));
+            for sample in samples {
+                try!(body.write_u32::<BigEndian>(sample.size));
+            }
+        }
+    }
+    tables.push(fullbox_leaf(STSZ, 0, body));
+
+    // Each run of contiguous samples is a chunk.
+    let mut chunks = Vec::new();
+    let mut sizes = Vec::new();
+    let mut end = None;
+    for sample in samples {
+        if end != Some(sample.offset) {
+            chunks.push(sample.offset);
+            sizes.push(0u32);
+        }
+        *sizes.last_mut().unwrap() += 1;
+        end = sample.offset.checked_add(sample.size as u64);
+        if end.is_none() {
+            return Err(Error::InvalidData("sample offset overflow"));
+        }
+    }
+    let mut body = Vec::new();
+    let mut entries = Vec::new();
+    let mut first_chunk = 1;
+    for (count, samples_per_chunk) in runs(&sizes) {
+        entries.push((first_chunk, samples_per_chunk));
+        first_chunk += count;
+    }
+    try!(body.write_u32::<BigEndian>(entries.len() as u32));
+    for (first_chunk, samples_per_chunk) in entries {
+        try!(body.write_u32::<BigEndian>(first_chunk));
+        try!(body.write_u32::<BigEndian>(samples_per_chunk));
+        try!(body.write_u32::<BigEndian>(1));
+    }
+    tables.push(fullbox_leaf(STSC, 0, body));
+    tables.push(Node::ChunkOffsets { index: index, offsets: chunks });
+    Ok(tables)
+}
+
+/// The trak with ID `id`, which is known to be present.
+fn trak_mut(tree: &mut Node, id: u32) -> &mut Node {
+    let children = children_mut(tree, &[]).unwrap();
+    children.iter_mut().find(|c| track_id(c) == Some(id)).unwrap()
+}
+
+fn media_timescale(trak: &mut Node) -> Result<u32> {
+    match children_mut(trak, &[MDIA]).and_then(|mdia| leaf_mut(mdia, MDHD)) {
+        Some(mdhd) => read_timescale(mdhd, MDHD),
+        None => Err(Error::InvalidData("trak without mdhd")),
+    }
+}
+
+/// The children of a trak's 'stbl', which must only hold the boxes
+/// concatenation rebuilds.
+fn sample_table_boxes(trak: &mut Node) -> Result<&mut Vec<Node>> {
+    let stbl = match children_mut(trak, &[MDIA, MINF, STBL]) {
+        Some(stbl) => stbl,
+        None => return Err(Error::InvalidData("trak without stbl")),
+    };
+    for child in stbl.iter() {
+        match *child {
+            Node::ChunkOffsets { .. } => {}
+            Node::Leaf { name, .. } if SAMPLE_TABLES.contains(&name) => {}
+            _ => return Err(Error::Unsupported("concatenation of sample groups or auxiliary information")),
+        }
+    }
+    Ok(stbl)
+}
+
+/// A trak's 'stsd' payload, which must hold a single entry.
+fn sample_description(trak: &mut Node) -> Result<Vec<u8>> {
+    let stbl = try!(sample_table_boxes(trak));
+    match leaf_mut(stbl, STSD) {
+        Some(stsd) if stsd.len() >= 8 && BigEndian::read_u32(&stsd[4..]) == 1 => Ok(stsd.clone()),
+        Some(_) => Err(Error::Unsupported("concatenation of tracks with several sample descriptions")),
+        None => Err(Error::InvalidData("stbl without stsd")),
+    }
+}
+
+/// Move a chunk offset to the output layout: the first file's boxes,
+/// with its moov rewritten, followed by the second file's mdats.
+/// Offsets at or beyond `first_len` are into the second file.
+fn map_joined(boxes: &[OutputBox], mdats: &[OutputBox], first_len: u64, moov: usize,
+              positions: &[u64], offset: u64) -> Result<u64> {
+    if offset < first_len {
+        map_offset(boxes, &positions[..boxes.len()], Some(moov), offset)
+    } else {
+        map_offset(mdats, &positions[boxes.len()..], None, offset - first_len)
+    }
+}
+
+/// The top-level boxes of a file to concatenate, and its moov tree,
+/// parsed context and moov index.
+fn read_input<R: Read + Seek>(src: &mut R, tables: &mut usize) -> Result<(Vec<OutputBox>, Node, MediaContext, usize)> {
+    let mut boxes = Vec::new();
+    for node in TopLevelBoxIter::new(src) {
+        let node = try!(node);
+        if node.name == BoxType::MovieFragmentBox {
+            return Err(Error::Unsupported("concatenation of fragmented files"));
+        }
+        if node.name == BoxType::MetadataBox {
+            return Err(Error::Unsupported("concatenation with a top-level meta"));
+        }
+        boxes.push(OutputBox::new(&node));
+    }
+    let moov = match boxes.iter().position(|b| b.input.name == BoxType::MovieBox) {
+        Some(moov) => moov,
+        None => return Err(Error::NoMoov),
+    };
+    if boxes[moov].input.size > MOOV_SIZE_LIMIT {
+        return Err(Error::MoovTooLarge(boxes[moov].input.size));
+    }
+    let mut moov_data = Vec::with_capacity(boxes[moov].input.size as usize);
+    try!(copy_box(src, &mut moov_data, &boxes[moov].input));
+    let tree = match try!(parse_children(&moov_data, tables)).pop() {
+        Some(tree) => tree,
+        None => return Err(Error::InvalidData("empty moov")),
+    };
+    // Only the moov is needed, and parsing it alone allows an mdat
+    // which runs to the end of the file.
+    let mut context = MediaContext::new();
+    try!(read_mp4(&mut Cursor::new(&moov_data), &mut context));
+    Ok((boxes, tree, context, moov))
+}
+
+/// Join two files with the same tracks into one, playing `second`
+/// after `first`.
+///
+/// Tracks are matched by ID, and each pair must have identical sample
+/// descriptions with a single entry and the same media timescale.
+/// Each track of `second` must begin with a sync sample. The output is
+/// `first` with its sample tables rebuilt to cover both files' samples
+/// and the 'mdat' boxes of `second` appended, so neither is re-encoded.
+/// Track, media and movie durations are extended, as is a single edit
+/// in the first file's edit list; edits in `second` are ignored.
+/// Fragmented files, files with a top-level 'meta', and tracks with
+/// sample tables other than those describing timing, sync samples,
+/// sizes and chunks, such as sample groups, are not supported.
+pub fn concatenate<R: Read + Seek, S: Read + Seek, W: Write>(first: &mut R, second: &mut S, dst: &mut W) -> Result<()> {
+    let mut tables = 0;
+    let (boxes, mut tree, context, moov) = try!(read_input(first, &mut tables));
+    let (second_boxes, mut second_tree, second_context, _) = try!(read_input(second, &mut tables));
+    let first_len = boxes.last().map_or(0, |b| b.input.position + b.input.size);
+    let mdats: Vec<OutputBox> = second_boxes.into_iter().filter(|b| b.is_mdat()).collect();
+
+    let movie_timescale = match leaf_mut(children_mut(&mut tree, &[]).unwrap(), MVHD) {
+        Some(mvhd) => try!(read_timescale(mvhd, MVHD)) as u64,
+        None => return Err(Error::InvalidData("moov without mvhd")),
+    };
+    if movie_timescale == 0 {
+        return Err(Error::InvalidData("zero movie timescale"));
+    }
+    let ids: Vec<Option<u32>> = children_mut(&mut tree, &[]).unwrap().iter().filter(|c| name(c) == Some(TRAK)).map(track_id).collect();
+    let second_ids: Vec<Option<u32>> = children_mut(&mut second_tree, &[]).unwrap().iter().filter(|c| name(c) == Some(TRAK)).map(track_id).collect();
+    if ids != second_ids || ids.iter().any(|id| id.is_none()) ||
+       ids.iter().enumerate().any(|(i, id)| ids[..i].contains(id)) {
+        return Err(Error::Unsupported("concatenation of files with different tracks"));
+    }
+
+    let mut movie_duration = 0;
+    let mut index = tables;
+    for id in ids.into_iter().filter_map(|id| id) {
+        let (second_stsd, second_timescale) = {
+            let trak = trak_mut(&mut second_tree, id);
+            (try!(sample_description(trak)), try!(media_timescale(trak)))
+        };
+        let trak = trak_mut(&mut tree, id);
+        let timescale = try!(media_timescale(trak));
+        if try!(sample_description(trak)) != second_stsd || timescale != second_timescale {
+            return Err(Error::Unsupported("concatenation of tracks with different sample descriptions"));
+        }
+        if timescale == 0 {
+            return Err(Error::InvalidData("zero media timescale"));
+        }
+
+        let (mut samples, second_samples) = match (context.track_by_id(id), second_context.track_by_id(id)) {
+            (Some(track), Some(second_track)) => (try!(track.sample_table()), try!(second_track.sample_table())),
+            _ => return Err(Error::InvalidData("trak not parsed")),
+        };
+        if !second_samples.first().map_or(true, |s| s.sync) {
+            return Err(Error::Unsupported("concatenation at a non-sync sample"));
+        }
+        // The second file's samples follow on in time, and their
+        // offsets follow the first file's.
+        let start = samples.last().map_or(0, |s| s.decode_time + s.duration as u64);
+        if start > std::i64::MAX as u64 {
+            return Err(Error::InvalidData("sample decode time overflow"));
+        }
+        for sample in &second_samples {
+            let offset = match first_len.checked_add(sample.offset) {
+                Some(offset) => offset,
+                None => return Err(Error::InvalidData("sample offset overflow")),
+            };
+            let decode_time = match start.checked_add(sample.decode_time) {
+                Some(time) => time,
+                None => return Err(Error::InvalidData("sample decode time overflow")),
+            };
+            let composition_time = match (start as i64).checked_add(sample.composition_time) {
+                Some(time) => time,
+                None => return Err(Error::InvalidData("sample composition time overflow")),
+            };
+            samples.push(SampleInfo {
+                offset: offset,
+                decode_time: decode_time,
+                composition_time: composition_time,
+                ..*sample
+            });
+        }
+        let media_duration = match samples.last() {
+            Some(s) => match s.decode_time.checked_add(s.duration as u64) {
+                Some(duration) => duration,
+                None => return Err(Error::InvalidData("sample decode time overflow")),
+            },
+            None => 0,
+        };
+        let extra = match (media_duration - start).checked_mul(movie_timescale) {
+            Some(extra) => extra / timescale as u64,
+            None => return Err(Error::InvalidData("duration overflow")),
+        };
+
+        {
+            let stbl = try!(sample_table_boxes(trak));
+            *stbl = vec![Node::Leaf { name: STSD, data: second_stsd }];
+            stbl.extend(try!(build_sample_tables(&samples, index)));
+            index += 1;
+        }
+        if let Some(mdhd) = children_mut(trak, &[MDIA]).and_then(|mdia| leaf_mut(mdia, MDHD)) {
+            try!(set_duration(mdhd, MDHD, media_duration));
+        }
+        let children = children_mut(trak, &[]).unwrap();
+        if let Some(tkhd) = leaf_mut(children, TKHD) {
+            let duration = match try!(read_duration(tkhd, TKHD)).checked_add(extra) {
+                Some(duration) => duration,
+                None => return Err(Error::InvalidData("duration overflow")),
+            };
+            try!(set_duration(tkhd, TKHD, duration));
+            movie_duration = std::cmp::max(movie_duration, duration);
+        }
+        if let Some(edts) = leaf_mut(children, EDTS) {
+            try!(extend_edit(edts, extra));
+        }
+    }
+    {
+        let mvhd = leaf_mut(children_mut(&mut tree, &[]).unwrap(), MVHD).unwrap();
+        let duration = std::cmp::max(try!(read_duration(mvhd, MVHD)), movie_duration);
+        try!(set_duration(mvhd, MVHD, duration));
+    }
+
+    let (positions, large) = try!(fit_tables(&tree, index, |moov_size| {
+        boxes.iter().enumerate()
+            .map(|(i, b)| b.size(if i == moov { Some(moov_size) } else { None }))
+            .chain(mdats.iter().map(|b| b.size(None)))
+            .collect()
+    }, |positions, offset| map_joined(&boxes, &mdats, first_len, moov, positions, offset)));
+    for (i, b) in boxes.iter().enumerate() {
+        if i == moov {
+            let map = |offset: u64| map_joined(&boxes, &mdats, first_len, moov, &positions, offset);
+            try!(write_node(dst, &tree, &large, &map));
+        } else {
+            try!(write_box(first, dst, b));
+        }
+    }
+    for b in &mdats {
+        try!(write_box(second, dst, b));
+    }
+    Ok(())
+}
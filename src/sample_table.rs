@@ -0,0 +1,503 @@
+//! Sample index construction from the track sample tables.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std;
+use std::ops::Range;
+
+use BoxType;
+use Error;
+use FullSample;
+use Result;
+use SampleCrypto;
+use SampleEncryptionEntry;
+use SubSampleEntry;
+use Track;
+use TrackEncryptionBox;
+use TrackFragment;
+use TRUN_SAMPLE_LIMIT;
+
+// Arbitrary limit on the samples indexed from a track's sample tables,
+// since a constant-size 'stsz' can declare any count in a few bytes.
+const SAMPLE_TABLE_LIMIT: usize = 16 * TRUN_SAMPLE_LIMIT as usize;
+
+/// Location and timing of a single sample.
+///
+/// Times are in the track's (mdhd) timescale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleInfo {
+    /// Byte offset of the sample data from the start of the file.
+    pub offset: u64,
+    pub size: u32,
+    pub decode_time: u64,
+    /// Decode time adjusted by the 'ctts' composition offset, if any.
+    pub composition_time: i64,
+    pub duration: u32,
+    /// True for sync samples (keyframes).
+    pub sync: bool,
+}
+
+/// The samples needed to present a time range of a track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRange {
+    /// Index of the first entry of `samples` within the track.
+    pub first_sample: usize,
+    /// Samples in decode order.
+    pub samples: Vec<SampleInfo>,
+    /// Byte ranges holding the sample data, with adjacent samples merged.
+    pub byte_ranges: Vec<Range<u64>>,
+}
+
+/// Sample sizes of a track, from its 'stsz'.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleSizeSummary {
+    pub sample_count: u32,
+    /// Size of the largest sample, in bytes.
+    pub max_size: u32,
+    /// Combined size of every sample, in bytes.
+    pub total_size: u64,
+}
+
+/// A way in which a track's sample tables disagree with each other.
+///
+/// Counts are numbers of samples, except for chunk counts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SampleTableMismatch {
+    /// A table needed to index the track's samples is absent.
+    MissingTable { table: BoxType },
+    /// The 'stts' runs cover a different number of samples than 'stsz'.
+    TimeToSampleCount { stsz: u64, stts: u64 },
+    /// The 'stsc' runs, applied to the 'stco' chunks, place a different
+    /// number of samples than 'stsz'.
+    ChunkSampleCount { stsz: u64, stsc: u64 },
+    /// An 'stsc' entry starts at chunk 0, at or before the previous
+    /// entry, or beyond the last 'stco' chunk.
+    InvalidFirstChunk { entry: usize, first_chunk: u32, chunks: u64 },
+    /// The 'ctts' runs cover a different number of samples than 'stsz'.
+    CompositionOffsetCount { stsz: u64, ctts: u64 },
+    /// An 'stss' entry names a sample which doesn't exist.
+    SyncSampleOutOfRange { sample: u32, stsz: u64 },
+}
+
+/// Decryption parameters of a sample with encryption defaults
+/// `defaults` and, if the track has one, `entry` from its 'senc'.
+fn sample_crypto(defaults: &TrackEncryptionBox, entry: Option<&SampleEncryptionEntry>) -> SampleCrypto {
+    let iv = match entry {
+        Some(entry) if !entry.iv.is_empty() => entry.iv.clone(),
+        _ => defaults.constant_iv.clone().unwrap_or_default(),
+    };
+    SampleCrypto {
+        kid: defaults.kid.clone(),
+        iv: iv,
+        subsamples: entry.map_or_else(Vec::new, |entry| entry.subsamples.clone()),
+    }
+}
+
+impl Track {
+    /// Cross-check the sample tables, returning every inconsistency.
+    ///
+    /// `sample_table()` fails on the first problem it meets, and some,
+    /// like a short 'ctts', it tolerates; this reports them all with
+    /// the counts involved. A track without samples is consistent.
+    pub fn check_sample_tables(&self) -> Vec<SampleTableMismatch> {
+        let mut mismatches = Vec::new();
+        let stsz = match self.stsz {
+            Some(ref stsz) if stsz.sample_size == 0 => stsz.sample_sizes.len() as u64,
+            Some(ref stsz) => stsz.sample_count as u64,
+            None => 0,
+        };
+
+        let stts = self.stts.as_ref().map(|stts| stts.samples.iter().map(|run| run.sample_count as u64).sum());
+        match stts {
+            Some(stts) if stts != stsz => {
+                mismatches.push(SampleTableMismatch::TimeToSampleCount { stsz: stsz, stts: stts });
+            }
+            None if stsz > 0 => {
+                mismatches.push(SampleTableMismatch::MissingTable { table: BoxType::TimeToSampleBox });
+            }
+            _ => (),
+        }
+
+        match (&self.stsc, &self.stco) {
+            (&Some(ref stsc), &Some(ref stco)) => {
+                let chunks = stco.offsets.len() as u64;
+                let mut placed = 0u64;
+                for (i, entry) in stsc.samples.iter().enumerate() {
+                    let next = stsc.samples.get(i + 1).map(|next| next.first_chunk as u64);
+                    let invalid = entry.first_chunk == 0 ||
+                                  entry.first_chunk as u64 > chunks ||
+                                  next.map_or(false, |next| next <= entry.first_chunk as u64);
+                    if invalid {
+                        mismatches.push(SampleTableMismatch::InvalidFirstChunk {
+                            entry: i,
+                            first_chunk: entry.first_chunk,
+                            chunks: chunks,
+                        });
+                        continue;
+                    }
+                    let last = std::cmp::min(next.map_or(chunks, |next| next - 1), chunks);
+                    let run = last + 1 - entry.first_chunk as u64;
+                    placed = placed.saturating_add(run.saturating_mul(entry.samples_per_chunk as u64));
+                }
+                if placed != stsz {
+                    mismatches.push(SampleTableMismatch::ChunkSampleCount { stsz: stsz, stsc: placed });
+                }
+            }
+            _ if stsz == 0 => (),
+            (&None, _) => mismatches.push(SampleTableMismatch::MissingTable { table: BoxType::SampleToChunkBox }),
+            (_, &None) => mismatches.push(SampleTableMismatch::MissingTable { table: BoxType::ChunkOffsetBox }),
+        }
+
+        if let Some(ref ctts) = self.ctts {
+            let covered = ctts.samples.iter().map(|run| run.sample_count as u64).sum();
+            if covered != stsz {
+                mismatches.push(SampleTableMismatch::CompositionOffsetCount { stsz: stsz, ctts: covered });
+            }
+        }
+
+        if let Some(ref stss) = self.stss {
+            for sample in &stss.samples {
+                if *sample == 0 || *sample as u64 > stsz {
+                    mismatches.push(SampleTableMismatch::SyncSampleOutOfRange { sample: *sample, stsz: stsz });
+                }
+            }
+        }
+
+        mismatches
+    }
+
+    /// Build an index of every sample in the track from its sample tables.
+    ///
+    /// Tracks without an 'stsz', or whose 'stsz' is empty, have no
+    /// samples; this is usual for fragmented files, whose samples are
+    /// described in movie fragments instead.
+    pub fn sample_table(&self) -> Result<Vec<SampleInfo>> {
+        let sample_count = match self.stsz {
+            Some(ref stsz) if stsz.sample_size == 0 => stsz.sample_sizes.len(),
+            Some(ref stsz) => stsz.sample_count as usize,
+            None => 0,
+        };
+        if sample_count == 0 {
+            return Ok(Vec::new());
+        }
+        if sample_count > SAMPLE_TABLE_LIMIT {
+            return Err(Error::InvalidData("stsz sample count exceeds SAMPLE_TABLE_LIMIT"));
+        }
+        let (stts, stsc, stsz, stco) = match (&self.stts, &self.stsc, &self.stsz, &self.stco) {
+            (&Some(ref stts), &Some(ref stsc), &Some(ref stsz), &Some(ref stco)) => (stts, stsc, stsz, stco),
+            _ => return Err(Error::InvalidData("missing sample table")),
+        };
+        let has_sync_table = self.stss.is_some();
+
+        // Lay out samples in their chunks.
+        let mut samples = Vec::new();
+        let chunk_count = stco.offsets.len() as u64;
+        for (i, entry) in stsc.samples.iter().enumerate() {
+            if entry.first_chunk == 0 {
+                return Err(Error::InvalidData("invalid stsc first chunk"));
+            }
+            let last_chunk = match stsc.samples.get(i + 1) {
+                Some(next) if next.first_chunk > entry.first_chunk => next.first_chunk as u64 - 1,
+                Some(_) => return Err(Error::InvalidData("stsc chunks out of order")),
+                None => chunk_count,
+            };
+            for chunk in entry.first_chunk as u64..last_chunk + 1 {
+                let mut offset = match stco.offsets.get(chunk as usize - 1) {
+                    Some(offset) => *offset,
+                    None => return Err(Error::InvalidData("stsc references missing chunk")),
+                };
+                for _ in 0..entry.samples_per_chunk {
+                    if samples.len() >= sample_count {
+                        return Err(Error::InvalidData("stsc describes more samples than stsz"));
+                    }
+                    let size = if stsz.sample_size == 0 {
+                        stsz.sample_sizes[samples.len()]
+                    } else {
+                        stsz.sample_size
+                    };
+                    samples.push(SampleInfo {
+                        offset: offset,
+                        size: size,
+                        decode_time: 0,
+                        composition_time: 0,
+                        duration: 0,
+                        sync: !has_sync_table,
+                    });
+                    offset = match offset.checked_add(size as u64) {
+                        Some(offset) => offset,
+                        None => return Err(Error::InvalidData("sample offset overflow")),
+                    };
+                }
+            }
+        }
+        if samples.len() < sample_count {
+            return Err(Error::InvalidData("stsc describes fewer samples than stsz"));
+        }
+
+        // Assign decode times and durations.
+        let mut deltas = stts.samples.iter()
+            .flat_map(|run| std::iter::repeat(run.sample_delta).take(run.sample_count as usize));
+        let mut time = 0u64;
+        for sample in samples.iter_mut() {
+            let delta = match deltas.next() {
+                Some(delta) => delta,
+                None => return Err(Error::InvalidData("stts describes fewer samples than stsz")),
+            };
+            sample.decode_time = time;
+            sample.duration = delta;
+            time = match time.checked_add(delta as u64) {
+                Some(time) => time,
+                None => return Err(Error::InvalidData("sample decode time overflow")),
+            };
+        }
+
+        // Apply composition offsets. Samples not covered get none.
+        let mut offsets = self.ctts.iter()
+            .flat_map(|ctts| ctts.samples.iter())
+            .flat_map(|run| std::iter::repeat(run.sample_offset).take(run.sample_count as usize));
+        for sample in samples.iter_mut() {
+            let offset = offsets.next().unwrap_or(0);
+            sample.composition_time = match (sample.decode_time as i64).checked_add(offset) {
+                Some(time) => time,
+                None => return Err(Error::InvalidData("sample composition time overflow")),
+            };
+        }
+
+        // Mark sync samples. Sample numbers are 1-based.
+        if let Some(ref stss) = self.stss {
+            for number in &stss.samples {
+                if *number == 0 {
+                    continue;
+                }
+                if let Some(sample) = samples.get_mut(*number as usize - 1) {
+                    sample.sync = true;
+                }
+            }
+        }
+
+        Ok(samples)
+    }
+
+    /// Build the sample index along with each sample's decryption
+    /// parameters, as stored by `ParseMode::Full`.
+    ///
+    /// Samples of an encrypted track take their IV and subsamples from
+    /// the track's 'senc', falling back to the constant IV. The key ID
+    /// and constant IV come from the sample's 'seig' group, if any,
+    /// otherwise from the 'tenc'.
+    pub fn full_sample_table(&self) -> Result<Vec<FullSample>> {
+        let table = try!(self.sample_table());
+        if let Some(ref senc) = self.senc {
+            if senc.len() != table.len() {
+                return Err(Error::InvalidData("senc sample count doesn't match stsz"));
+            }
+        }
+        let groups = self.seig_group_indices(table.len());
+
+        let mut samples = Vec::with_capacity(table.len());
+        for (i, info) in table.into_iter().enumerate() {
+            let defaults = match try!(self.encryption_defaults_for_group(groups[i])) {
+                Some(defaults) if defaults.is_encrypted => Some(defaults),
+                _ => None,
+            };
+            let entry = self.senc.as_ref().map(|senc| &senc[i]);
+            samples.push(FullSample {
+                info: info,
+                crypto: defaults.map(|defaults| sample_crypto(defaults, entry)),
+            });
+        }
+        Ok(samples)
+    }
+
+    /// Build the samples of one of the track's fragments along with
+    /// their decryption parameters, as appended by `ParseMode::Full`.
+    ///
+    /// IVs and subsamples come from the traf's 'senc', and the key ID
+    /// and constant IV from the 'tenc'. Sample groups in the traf aren't
+    /// applied. Auxiliary information located only by 'saiz' and 'saio'
+    /// isn't read, since it may lie outside the moof and the parser
+    /// doesn't seek, so without an senc samples get the constant IV,
+    /// if any, and no subsamples.
+    pub fn full_fragment_samples(&self, traf: &TrackFragment) -> Result<Vec<FullSample>> {
+        if !traf.senc.is_empty() && traf.senc.len() != traf.samples.len() {
+            return Err(Error::InvalidData("senc sample count doesn't match trun"));
+        }
+        let defaults = match try!(self.encryption_defaults_for_group(0)) {
+            Some(defaults) if defaults.is_encrypted => Some(defaults),
+            _ => None,
+        };
+        Ok(traf.samples.iter().enumerate().map(|(i, info)| FullSample {
+            info: *info,
+            crypto: defaults.map(|defaults| sample_crypto(defaults, traf.senc.get(i))),
+        }).collect())
+    }
+
+    /// Summarize the track's sample sizes without building the index,
+    /// so callers can size buffers or estimate the bitrate cheaply.
+    ///
+    /// Like `sample_table()` this only covers the 'stsz', so fragmented
+    /// tracks report no samples.
+    pub fn sample_size_summary(&self) -> SampleSizeSummary {
+        match self.stsz {
+            Some(ref stsz) if stsz.sample_size == 0 => SampleSizeSummary {
+                sample_count: stsz.sample_sizes.len() as u32,
+                max_size: stsz.sample_sizes.iter().cloned().max().unwrap_or(0),
+                total_size: stsz.sample_sizes.iter().map(|&size| size as u64).sum(),
+            },
+            Some(ref stsz) if stsz.sample_count > 0 => SampleSizeSummary {
+                sample_count: stsz.sample_count,
+                max_size: stsz.sample_size,
+                total_size: stsz.sample_size as u64 * stsz.sample_count as u64,
+            },
+            _ => Default::default(),
+        }
+    }
+
+    /// Return the encryption parameters for the sample at `index`: its
+    /// 'seig' sample group entry if it has one, otherwise the 'tenc'.
+    /// Returns None if the track isn't protected.
+    pub fn encryption_defaults(&self, index: usize) -> Result<Option<&TrackEncryptionBox>> {
+        let mut group = 0;
+        if let Some(ref sbgp) = self.seig_groups {
+            let mut first = 0usize;
+            for &(sample_count, group_description_index) in &sbgp.entries {
+                if index < first + sample_count as usize {
+                    group = group_description_index;
+                    break;
+                }
+                first += sample_count as usize;
+            }
+        }
+        self.encryption_defaults_for_group(group)
+    }
+
+    /// Resolve a 'seig' group description index, where 0 means the
+    /// sample belongs to no group.
+    fn encryption_defaults_for_group(&self, group: u32) -> Result<Option<&TrackEncryptionBox>> {
+        if group == 0 {
+            return Ok(self.protection_info.as_ref().and_then(|sinf| sinf.tenc.as_ref()));
+        }
+        match self.seig_entries.get(group as usize - 1) {
+            Some(seig) => Ok(Some(seig)),
+            None => Err(Error::InvalidData("sbgp references missing seig entry")),
+        }
+    }
+
+    /// The 'seig' group description index of each of `count` samples.
+    fn seig_group_indices(&self, count: usize) -> Vec<u32> {
+        let mut groups: Vec<u32> = self.seig_groups.iter()
+            .flat_map(|sbgp| sbgp.entries.iter())
+            .flat_map(|&(sample_count, index)| std::iter::repeat(index).take(sample_count as usize))
+            .take(count)
+            .collect();
+        // Samples beyond the last run belong to no group.
+        groups.resize(count, 0);
+        groups
+    }
+
+    /// Return the flags of the track's 'subs' box, which select the
+    /// codec-specific meaning of its subsamples.
+    pub fn subsample_flags(&self) -> Option<u32> {
+        self.subs.as_ref().map(|subs| subs.flags)
+    }
+
+    /// Return the subsamples of the sample at `index`, in decode order,
+    /// from the track's 'subs' box. Samples not listed have none.
+    pub fn subsamples(&self, index: usize) -> &[SubSampleEntry] {
+        let subs = match self.subs {
+            Some(ref subs) => subs,
+            None => return &[],
+        };
+        match subs.samples.binary_search_by_key(&(index as u64), |entry| entry.0 as u64) {
+            Ok(i) => &subs.samples[i].1,
+            Err(_) => &[],
+        }
+    }
+
+    /// Return the byte ranges of the subsamples of `sample`, the entry
+    /// at `index` of `sample_table()`.
+    ///
+    /// Fails if the subsamples don't exactly cover the sample.
+    pub fn subsample_ranges(&self, index: usize, sample: &SampleInfo) -> Result<Vec<Range<u64>>> {
+        let mut ranges = Vec::new();
+        let mut offset = sample.offset;
+        for subsample in self.subsamples(index) {
+            let end = match offset.checked_add(subsample.size as u64) {
+                Some(end) => end,
+                None => return Err(Error::InvalidData("subsample extends past the end of the file")),
+            };
+            ranges.push(offset..end);
+            offset = end;
+        }
+        if !ranges.is_empty() && Some(offset) != sample.offset.checked_add(sample.size as u64) {
+            return Err(Error::InvalidData("subsample sizes don't match sample size"));
+        }
+        Ok(ranges)
+    }
+
+    /// Return the samples needed to present `start..end` of the track.
+    ///
+    /// Times are in the track's timescale and compared against sample
+    /// composition times. The result includes every sample in decode
+    /// order between the first and last overlapping samples, extended
+    /// backwards to the preceding sync sample so decoding can begin
+    /// cleanly. An empty range yields no samples. Times above
+    /// `i64::max_value()` are rejected as `Error::InvalidData`.
+    pub fn extract_range(&self, start: u64, end: u64) -> Result<SampleRange> {
+        let table = try!(self.sample_table());
+
+        if start > i64::max_value() as u64 || end > i64::max_value() as u64 {
+            return Err(Error::InvalidData("range time exceeds i64"));
+        }
+        let (start, end) = (start as i64, end as i64);
+        let mut overlap: Option<(usize, usize)> = None;
+        for (i, sample) in table.iter().enumerate() {
+            let sample_start = sample.composition_time;
+            let sample_end = match sample_start.checked_add(sample.duration as i64) {
+                Some(end) => end,
+                None => return Err(Error::InvalidData("sample end time overflows")),
+            };
+            let overlaps = sample_start < end &&
+                (sample_end > start || (sample.duration == 0 && sample_start >= start));
+            if overlaps {
+                overlap = Some(match overlap {
+                    Some((first, last)) => (std::cmp::min(first, i), std::cmp::max(last, i)),
+                    None => (i, i),
+                });
+            }
+        }
+
+        let (mut first, last) = match overlap {
+            Some(range) => range,
+            None => return Ok(SampleRange {
+                first_sample: 0,
+                samples: Vec::new(),
+                byte_ranges: Vec::new(),
+            }),
+        };
+        while first > 0 && !table[first].sync {
+            first -= 1;
+        }
+
+        let samples = table[first..last + 1].to_vec();
+        let mut byte_ranges: Vec<Range<u64>> = Vec::new();
+        for sample in &samples {
+            let sample_end = sample.offset + sample.size as u64;
+            if let Some(range) = byte_ranges.last_mut() {
+                if range.end == sample.offset {
+                    range.end = sample_end;
+                    continue;
+                }
+            }
+            byte_ranges.push(sample.offset..sample_end);
+        }
+
+        Ok(SampleRange {
+            first_sample: first,
+            samples: samples,
+            byte_ranges: byte_ranges,
+        })
+    }
+}
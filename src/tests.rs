@@ -5,6 +5,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std;
 use std::io::Cursor;
 use super::read_mp4;
 use super::MediaContext;
@@ -117,6 +118,22 @@ fn read_box_header_long_invalid_size() {
     };
 }
 
+#[test]
+fn box_position_overflow() {
+    // A wide box whose end lies beyond u64::MAX once the first box
+    // is counted.
+    let mut data = Vec::new();
+    data.extend_from_slice(make_box(BoxSize::Short(8), b"free", |s| s).get_ref());
+    data.extend_from_slice(&[0, 0, 0, 1]);
+    data.extend_from_slice(b"free");
+    data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xf8]);
+    let mut context = MediaContext::new();
+    match read_mp4(&mut Cursor::new(data), &mut context) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "box extends past the end of the file"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
 #[test]
 fn read_ftyp() {
     let mut stream = make_box(BoxSize::Short(24), b"ftyp", |s| {
@@ -306,7 +323,10 @@ fn read_mvhd_v0() {
          .B32(0)
          .B32(1234)
          .B32(5678)
-         .append_repeated(0, 80)
+         .B32(0x00008000) // rate
+         .B16(0x0100) // volume
+         .append_repeated(0, 70)
+         .B32(3) // next track id
     });
     let mut iter = super::BoxIter::new(&mut stream);
     let mut stream = iter.next_box().unwrap().unwrap();
@@ -315,6 +335,30 @@ fn read_mvhd_v0() {
     let parsed = super::read_mvhd(&mut stream).unwrap();
     assert_eq!(parsed.timescale, 1234);
     assert_eq!(parsed.duration, 5678);
+    assert_eq!(parsed.rate.to_f64(), 0.5);
+    assert_eq!(parsed.volume.to_f64(), 1.0);
+    assert_eq!(parsed.next_track_id, 3);
+}
+
+#[test]
+fn read_mvhd_negative_rate() {
+    let mut stream = make_fullbox(BoxSize::Short(108), b"mvhd", 0, |s| {
+        s.B32(0)
+         .B32(0)
+         .B32(1234)
+         .B32(5678)
+         .B32(0xfffe8000) // rate, -1.5
+         .B16(0x0100) // volume
+         .append_repeated(0, 70)
+         .B32(3) // next track id
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let parsed = super::read_mvhd(&mut stream).unwrap();
+    assert_eq!(parsed.rate, super::SignedFixedPoint16_16(-0x18000));
+    assert_eq!(parsed.rate.to_f64(), -1.5);
+    assert_eq!(parsed.rate.integer_part(), -2);
+    assert_eq!(parsed.rate.fractional_part(), 0x8000);
 }
 
 #[test]
@@ -370,6 +414,102 @@ fn read_mvhd_unknown_duration() {
     assert_eq!(parsed.duration, ::std::u64::MAX);
 }
 
+fn make_tkhd(flags: u8) -> Cursor<Vec<u8>> {
+    make_box(BoxSize::Short(92), b"tkhd", |s| {
+        s.B8(0) // version
+         .B8(0)
+         .B8(0)
+         .B8(flags)
+         .B32(0) // creation time
+         .B32(0) // modification time
+         .B32(1) // track id
+         .B32(0)
+         .B32(1234) // duration
+         .append_repeated(0, 8)
+         .B16(0xffff) // layer
+         .B16(1) // alternate group
+         .B16(0x0080) // volume
+         .append_repeated(0, 38)
+         .B32(320 << 16) // width
+         .B32(240 << 16) // height
+    })
+}
+
+#[test]
+fn read_tkhd_flags() {
+    let mut stream = make_tkhd(0x5);
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    assert_eq!(stream.head.name, BoxType::TrackHeaderBox);
+    let parsed = super::read_tkhd(&mut stream).unwrap();
+    assert_eq!(parsed.track_id, 1);
+    assert_eq!(parsed.duration, 1234);
+    assert!(parsed.enabled);
+    assert!(!parsed.in_movie);
+    assert!(parsed.in_preview);
+    assert!(parsed.disabled);
+    assert_eq!(parsed.layer, -1);
+    assert_eq!(parsed.alternate_group, 1);
+    assert_eq!(parsed.volume, super::FixedPoint8_8(0x0080));
+    assert_eq!(parsed.width.integer_part(), 320);
+    assert_eq!(parsed.height.integer_part(), 240);
+
+    let mut stream = make_tkhd(0x3);
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let parsed = super::read_tkhd(&mut stream).unwrap();
+    assert!(parsed.enabled);
+    assert!(parsed.in_movie);
+    assert!(!parsed.in_preview);
+    assert!(!parsed.disabled);
+}
+
+#[test]
+fn read_tkhd_v1_unknown_duration() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"tkhd", 1, |s| {
+        s.B64(0) // creation time
+         .B64(0) // modification time
+         .B32(7) // track id
+         .B32(0)
+         .B64(::std::u64::MAX) // duration
+         .append_repeated(0, 60)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let parsed = super::read_tkhd(&mut stream).unwrap();
+    assert_eq!(parsed.track_id, 7);
+    assert_eq!(parsed.duration, ::std::u64::MAX);
+
+    // A 32 bit unknown duration widens to the 64 bit one, as in mvhd.
+    let mut stream = make_fullbox(BoxSize::Auto, b"tkhd", 0, |s| {
+        s.B32(0)
+         .B32(0)
+         .B32(7)
+         .B32(0)
+         .B32(::std::u32::MAX)
+         .append_repeated(0, 60)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let parsed = super::read_tkhd(&mut stream).unwrap();
+    assert_eq!(parsed.duration, ::std::u64::MAX);
+}
+
+#[test]
+fn version_dependent_fields() {
+    let data = [0xff, 0xff, 0xff, 0xfe, 0, 0, 0, 1];
+    assert_eq!(super::be_u32_or_u64(&mut Cursor::new(&data[..]), 0).unwrap(), 0xfffffffe);
+    assert_eq!(super::be_u32_or_u64(&mut Cursor::new(&data[..]), 1).unwrap(), 0xfffffffe00000001);
+    assert_eq!(super::be_i32_or_i64(&mut Cursor::new(&data[..]), 0).unwrap(), -2);
+    assert_eq!(super::be_duration(&mut Cursor::new(&[0xff; 4][..]), 0).unwrap(), ::std::u64::MAX);
+    assert!(super::be_u32_or_u64(&mut Cursor::new(&data[..4]), 1).is_err());
+    assert!(super::check_version(1, "bad").is_ok());
+    match super::check_version(2, "unknown test version") {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown test version"),
+        _ => panic!("expected an error"),
+    }
+}
+
 #[test]
 fn read_vpcc() {
     let data_length = 12u16;
@@ -386,6 +526,61 @@ fn read_vpcc() {
     assert_eq!(stream.head.name, BoxType::VPCodecConfigurationBox);
     let r = super::read_vpcc(&mut stream);
     assert!(r.is_ok());
+
+    // Version 1 swaps the colour space for 23001-8 colour fields.
+    let mut stream = make_fullbox(BoxSize::Auto, b"vpcC", 1, |s| {
+        s.B8(2) // profile
+         .B8(31) // level
+         .B8(0xa3) // 10 bit, 4:2:0 colocated, full range
+         .B8(9) // BT.2020 primaries
+         .B8(16) // PQ transfer
+         .B8(9) // BT.2020 non-constant luminance matrix
+         .B16(0)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let vpcc = super::read_vpcc(&mut stream).unwrap();
+    assert_eq!((vpcc.profile, vpcc.level, vpcc.bit_depth), (2, 31, 10));
+    assert_eq!((vpcc.chroma_subsampling, vpcc.color_space), (1, 0));
+    assert_eq!((vpcc.colour_primaries, vpcc.transfer_function, vpcc.matrix_coefficients), (9, 16, 9));
+    assert!(vpcc.video_full_range);
+}
+
+#[test]
+fn av1c_sample_entry() {
+    // Main profile, level 4.0 (8), Main tier, 10 bit 4:2:0, followed
+    // by no configOBUs.
+    let av1c = [0x81, 0x08, 0x4c, 0x00];
+    let mut stream = make_box(BoxSize::Auto, b"av01", |s| {
+        s.append_repeated(0, 6)
+         .B16(1)
+         .append_repeated(0, 16)
+         .B16(1920)
+         .B16(1080)
+         .append_repeated(0, 14)
+         .append_repeated(0, 32)
+         .append_repeated(0, 4)
+         .append_bytes(make_box(BoxSize::Auto, b"av1C", |s| s.append_bytes(&av1c)).get_ref())
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let mut track = super::Track::new(0);
+    let mut context = MediaContext::new();
+    track.data = Some(super::read_video_desc(&mut stream, &mut track, &mut context).unwrap());
+    assert_eq!(track.mime_type, "video/av1");
+    assert_eq!(track.av1_decoder_config().unwrap(), Some(super::Av1DecoderConfig {
+        seq_profile: 0,
+        seq_level_idx_0: 8,
+        seq_tier_0: 0,
+        bit_depth: 10,
+        monochrome: false,
+        chroma_subsampling_x: true,
+        chroma_subsampling_y: true,
+        chroma_sample_position: 0,
+    }));
+    assert!(track.hevc_decoder_config().unwrap().is_none());
+    assert!(super::parse_av1c(&[0x01, 0x08, 0x4c, 0x00]).is_err());
+    assert!(super::parse_av1c(&av1c[..3]).is_err());
 }
 
 #[test]
@@ -484,6 +679,40 @@ fn read_dops() {
     assert!(r.is_ok());
 }
 
+#[test]
+fn read_dops_invalid() {
+    let dops = |channels: u8, family: u8, table: &[u8]| {
+        let mut stream = make_box(BoxSize::Auto, b"dOps", |s| {
+            s.B8(0)
+             .B8(channels)
+             .B16(312)
+             .B32(48000)
+             .B16(0)
+             .B8(family)
+             .append_bytes(table)
+        });
+        let mut iter = super::BoxIter::new(&mut stream);
+        let mut stream = iter.next_box().unwrap().unwrap();
+        super::read_dops(&mut stream)
+    };
+    assert!(dops(6, 1, &[4, 2, 0, 4, 1, 2, 3, 5]).is_ok());
+    // A silent channel.
+    assert!(dops(3, 1, &[1, 1, 0, 1, 255]).is_ok());
+    let errors = [
+        dops(0, 0, &[]),
+        dops(6, 0, &[]),
+        dops(2, 1, &[0, 0, 0, 1]),
+        dops(2, 1, &[1, 2, 0, 1]),
+        dops(6, 1, &[4, 2, 0, 4, 1, 2, 3, 6]),
+    ];
+    for r in errors.iter() {
+        match *r {
+            Err(Error::InvalidData(_)) => (),
+            ref r => panic!("expected invalid data, got {:?}", r),
+        }
+    }
+}
+
 #[test]
 fn serialize_opus_header() {
     let opus = super::OpusSpecificBox {
@@ -547,13 +776,63 @@ fn avcc_limit() {
     let mut iter = super::BoxIter::new(&mut stream);
     let mut stream = iter.next_box().unwrap().unwrap();
     let mut track = super::Track::new(0);
-    match super::read_video_desc(&mut stream, &mut track) {
+    let mut context = MediaContext::new();
+    match super::read_video_desc(&mut stream, &mut track, &mut context) {
         Err(Error::InvalidData(s)) => assert_eq!(s, "avcC box exceeds BUF_SIZE_LIMIT"),
         Ok(_) => assert!(false, "expected an error result"),
         _ => assert!(false, "expected a different error result"),
     }
 }
 
+#[test]
+fn hvcc_sample_entry() {
+    // Main 10, Main tier, level 4.1, 4:2:0, 4 byte NAL lengths, no
+    // parameter set arrays.
+    let hvcc = [0x01, 0x02, 0x20, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x7b, 0xf0, 0x00, 0xfc, 0xfd, 0xfa, 0xfa, 0x00, 0x00, 0x0f, 0x00];
+    let mut stream = make_box(BoxSize::Auto, b"hvc1", |s| {
+        s.append_repeated(0, 6)
+         .B16(1)
+         .append_repeated(0, 16)
+         .B16(1920)
+         .B16(1080)
+         .append_repeated(0, 14)
+         .append_repeated(0, 32)
+         .append_repeated(0, 4)
+         .append_bytes(make_box(BoxSize::Auto, b"hvcC", |s| s.append_bytes(&hvcc)).get_ref())
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let mut track = super::Track::new(0);
+    let mut context = MediaContext::new();
+    track.data = Some(super::read_video_desc(&mut stream, &mut track, &mut context).unwrap());
+    assert_eq!(track.mime_type, "video/hevc");
+    match track.data {
+        Some(super::SampleEntry::Video(ref video)) => match video.codec_specific {
+            super::VideoCodecSpecific::HEVCConfig(ref config) => {
+                assert_eq!(video.name, BoxType::HEVCSampleEntry);
+                assert_eq!(config[..], hvcc[..]);
+            }
+            _ => panic!("expected an hvcC"),
+        },
+        _ => panic!("expected a video sample entry"),
+    }
+    assert_eq!(track.hevc_decoder_config().unwrap(), Some(super::HevcDecoderConfig {
+        general_profile_space: 0,
+        general_tier_flag: false,
+        general_profile_idc: 2,
+        general_profile_compatibility_flags: 0x20000000,
+        general_constraint_indicator_flags: 0x900000000000,
+        general_level_idc: 123,
+        chroma_format_idc: 1,
+        bit_depth_luma: 10,
+        bit_depth_chroma: 10,
+        nal_length_size: 4,
+    }));
+    assert!(track.avc_decoder_config().unwrap().is_none());
+    assert!(super::parse_hvcc(&hvcc[..22]).is_err());
+}
+
 #[test]
 fn esds_limit() {
     let mut stream = make_box(BoxSize::Auto, b"mp4a", |s| {
@@ -622,6 +901,18 @@ fn read_elst_zero_entries() {
     }
 }
 
+#[test]
+fn read_ctts_unknown_version() {
+    // Rejected even when there are no entries to read.
+    let mut stream = make_fullbox(BoxSize::Auto, b"ctts", 2, |s| s.B32(0));
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    match super::read_ctts(&mut stream) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown ctts version"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
 fn make_elst() -> Cursor<Vec<u8>> {
     make_fullbox(BoxSize::Auto, b"elst", 1, |s| {
         s.B32(1)
@@ -643,21 +934,3645 @@ fn read_edts_bogus() {
     let mut iter = super::BoxIter::new(&mut stream);
     let mut stream = iter.next_box().unwrap().unwrap();
     let mut track = super::Track::new(0);
-    match super::read_edts(&mut stream, &mut track) {
+    let mut context = MediaContext::new();
+    match super::read_edts(&mut stream, &mut track, &mut context) {
         Err(Error::InvalidData(s)) => assert_eq!(s, "expected additional edit"),
         Ok(_) => assert!(false, "expected an error result"),
         _ => assert!(false, "expected a different error result"),
     }
 }
 
+fn make_moov_with_bad_mvhd() -> Cursor<Vec<u8>> {
+    let bad_mvhd = make_fullbox(BoxSize::Short(108), b"mvhd", 2, |s| {
+        s.append_repeated(0, 96)
+    });
+    let mvhd = make_fullbox(BoxSize::Short(108), b"mvhd", 0, |s| {
+        s.B32(0)
+         .B32(0)
+         .B32(1234)
+         .B32(5678)
+         .append_repeated(0, 80)
+    });
+    make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&bad_mvhd.get_ref())
+         .append_bytes(&mvhd.get_ref())
+    })
+}
+
 #[test]
-fn invalid_pascal_string() {
-    // String claims to be 32 bytes long (we provide 33 bytes to account for
-    // the 1 byte length prefix).
-    let pstr = "\x20xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
-    let mut stream = Cursor::new(pstr);
-    // Reader wants to limit the total read length to 32 bytes, so any
-    // returned string must be no longer than 31 bytes.
-    let s = super::read_fixed_length_pascal_string(&mut stream, 32).unwrap();
-    assert_eq!(s.len(), 31);
+fn read_moov_bad_leaf_strict() {
+    let mut stream = make_moov_with_bad_mvhd();
+    let mut context = MediaContext::new();
+    match read_mp4(&mut stream, &mut context) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown mvhd version"),
+        Ok(_) => assert!(false, "expected an error result"),
+        _ => assert!(false, "expected a different error result"),
+    }
+}
+
+#[test]
+fn read_moov_bad_leaf_lenient() {
+    let mut stream = make_moov_with_bad_mvhd();
+    let mut context = MediaContext::new();
+    context.strictness = super::ParseStrictness::Lenient;
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.timescale, Some(super::MediaTimeScale(1234)));
+    assert_eq!(context.skipped_boxes.len(), 1);
+    assert_eq!(context.skipped_boxes[0].name, BoxType::MovieHeaderBox);
+    match context.skipped_boxes[0].error {
+        Error::Unsupported(s) => assert_eq!(s, "unknown mvhd version"),
+        _ => assert!(false, "expected a different recorded error"),
+    }
+    assert_eq!(context.unsupported_features.len(), 1);
+    assert_eq!(context.unsupported_features[0].box_type, BoxType::MovieHeaderBox);
+}
+
+#[test]
+fn read_trak_unknown_tkhd_version() {
+    let tkhd = make_fullbox(BoxSize::Auto, b"tkhd", 2, |s| s.append_repeated(0, 96));
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(tkhd.get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(trak.get_ref()));
+
+    let mut context = MediaContext::new();
+    match read_mp4(&mut stream, &mut context) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown tkhd version"),
+        _ => panic!("expected an unsupported error"),
+    }
+
+    stream.set_position(0);
+    let mut context = MediaContext::new();
+    context.strictness = super::ParseStrictness::Lenient;
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 1);
+    assert!(context.tracks[0].tkhd.is_none());
+    assert_eq!(context.skipped_boxes[0].name, BoxType::TrackHeaderBox);
+}
+
+#[test]
+fn read_moov_features() {
+    let mvex = make_box(BoxSize::Auto, b"mvex", |s| s);
+    let udta = make_box(BoxSize::Auto, b"udta", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"chpl", |s| s.B32(0)).get_ref())
+    });
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&mvex.get_ref())
+         .append_bytes(&udta.get_ref())
+    });
+    let mut context = MediaContext::new();
+    assert!(context.features().is_empty());
+    read_mp4(&mut stream, &mut context).unwrap();
+    let features = context.features();
+    assert!(features.contains(super::MediaFeatures::FRAGMENTED |
+                              super::MediaFeatures::HAS_CHAPTERS));
+    assert!(!features.contains(super::MediaFeatures::ENCRYPTED));
+    assert!(!features.contains(super::MediaFeatures::USES_64BIT_OFFSETS));
+}
+
+#[test]
+fn track_end_padding() {
+    let mut track = super::Track::new(0);
+    track.timescale = Some(super::TrackTimeScale(48000, 0));
+    track.duration = Some(super::TrackScaledTime(48000 + 312 + 500, 0));
+    track.media_time = Some(super::TrackScaledTime(312, 0));
+    let movie_timescale = super::MediaTimeScale(1000);
+    // No edit list, so no padding information.
+    assert_eq!(track.end_padding(movie_timescale), None);
+    track.edit_duration = Some(super::MediaScaledTime(1000));
+    assert_eq!(track.end_padding(movie_timescale),
+               Some(super::TrackScaledTime(500, 0)));
+    // A zero segment duration covers the rest of the media.
+    track.edit_duration = Some(super::MediaScaledTime(0));
+    assert_eq!(track.end_padding(movie_timescale), None);
+}
+
+#[test]
+fn read_sgpd_roll() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"sgpd", 1, |s| {
+        s.append_bytes(b"roll")
+         .B32(2) // default length
+         .B32(1) // entry count
+         .B16(0xffff) // roll distance of -1
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    assert_eq!(stream.head.name, BoxType::SampleGroupDescriptionBox);
+    let parsed = super::read_sgpd(&mut stream).unwrap();
+    assert_eq!(parsed.grouping_type, super::ROLL_GROUPING_TYPE);
+    assert_eq!(parsed.roll_distances, vec![-1]);
+}
+
+#[test]
+fn parse_itunsmpb() {
+    let value = b" 00000000 00000840 000001CA 00000000000422F8 00000000 00000000";
+    assert_eq!(super::parse_itunsmpb(value), Some((0x840, 0x1ca)));
+    assert_eq!(super::parse_itunsmpb(b" 00000000"), None);
+    assert_eq!(super::parse_itunsmpb(b" 00000000 zzz 000001CA"), None);
+}
+
+#[test]
+fn audio_priming_from_itunsmpb() {
+    let value = b" 00000000 00000840 000001CA 00000000000422F8";
+    let freeform = make_box(BoxSize::Auto, b"----", |s| {
+        s.append_bytes(&make_fullbox(BoxSize::Auto, b"mean", 0, |s| {
+             s.append_bytes(b"com.apple.iTunes")
+         }).get_ref())
+         .append_bytes(&make_fullbox(BoxSize::Auto, b"name", 0, |s| {
+             s.append_bytes(b"iTunSMPB")
+         }).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"data", |s| {
+             s.B32(1) // UTF-8 type indicator
+              .B32(0) // locale
+              .append_bytes(value)
+         }).get_ref())
+    });
+    let meta = make_fullbox(BoxSize::Auto, b"meta", 0, |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"ilst", |s| {
+            s.append_bytes(&freeform.get_ref())
+        }).get_ref())
+    });
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"udta", |s| {
+            s.append_bytes(&meta.get_ref())
+        }).get_ref())
+    });
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+
+    let mut track = super::Track::new(0);
+    track.track_type = super::TrackType::Audio;
+    track.data = Some(super::SampleEntry::Audio(super::AudioSampleEntry {
+        data_reference_index: 1,
+        channelcount: 2,
+        samplesize: 16,
+        samplerate: super::FixedPoint16_16(44100 << 16),
+        codec_specific: super::AudioCodecSpecific::ES_Descriptor(Vec::new()),
+    }));
+    // A roll group has lower precedence than the iTunSMPB tag.
+    track.roll_distance = Some(-1);
+    context.tracks.push(track);
+
+    assert_eq!(context.audio_priming(0), Some(super::AudioPriming {
+        delay_samples: 0x840,
+        padding_samples: 0x1ca,
+        source: super::PrimingSource::ITunSMPB,
+    }));
+    assert_eq!(context.audio_priming(1), None);
+}
+
+#[test]
+fn audio_priming_from_roll_group() {
+    fn track_with(codec_specific: super::AudioCodecSpecific) -> super::Track {
+        let mut track = super::Track::new(0);
+        track.track_type = super::TrackType::Audio;
+        track.data = Some(super::SampleEntry::Audio(super::AudioSampleEntry {
+            data_reference_index: 1,
+            channelcount: 2,
+            samplesize: 16,
+            samplerate: super::FixedPoint16_16(44100 << 16),
+            codec_specific: codec_specific,
+        }));
+        track.roll_distance = Some(-2);
+        track
+    }
+    // AAC-LC, 44.1 kHz stereo.
+    let aac_lc = vec![0x03, 0x19, 0x00, 0x01, 0x00,
+                      0x04, 0x11, 0x40, 0x15, 0x00, 0x00, 0x00,
+                      0x00, 0x01, 0xf4, 0x00, 0x00, 0x00, 0xfa, 0x00,
+                      0x05, 0x02, 0x12, 0x10,
+                      0x06, 0x01, 0x02];
+    // HE-AAC v2, whose frames are 2048 samples.
+    let he_aac = vec![0x03, 0x1a, 0x00, 0x01, 0x00,
+                      0x04, 0x12, 0x40, 0x15, 0x00, 0x00, 0x00,
+                      0x00, 0x01, 0xf4, 0x00, 0x00, 0x00, 0xfa, 0x00,
+                      0x05, 0x03, 0xeb, 0x09, 0x88,
+                      0x06, 0x01, 0x02];
+    let mut context = MediaContext::new();
+    context.tracks.push(track_with(super::AudioCodecSpecific::ES_Descriptor(aac_lc)));
+    context.tracks.push(track_with(super::AudioCodecSpecific::ES_Descriptor(he_aac)));
+    context.tracks.push(track_with(super::AudioCodecSpecific::FLACSpecificBox(super::FLACSpecificBox {
+        version: 0,
+        blocks: Vec::new(),
+    })));
+
+    assert_eq!(context.audio_priming(0), Some(super::AudioPriming {
+        delay_samples: 2048,
+        padding_samples: 0,
+        source: super::PrimingSource::RollGroup,
+    }));
+    assert_eq!(context.audio_priming(1), None);
+    assert_eq!(context.audio_priming(2), None);
+}
+
+#[test]
+fn skip_partly_read_box() {
+    let mut stream = make_box(BoxSize::Auto, b"free", |s| s.B32(0).B32(0));
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    super::skip(&mut stream, 4).unwrap();
+    match super::skip_box_content(&mut stream) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "box content partly read before skipping"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn freeform_name_limit() {
+    let mut stream = make_box(BoxSize::Auto, b"----", |s| {
+        s.append_bytes(&make_fullbox(BoxSize::Auto, b"name", 0, |s| {
+             s.append_repeated(b'a', super::BUF_SIZE_LIMIT as usize + 1)
+         }).get_ref())
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    match super::read_freeform_item(&mut stream) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "name box exceeds BUF_SIZE_LIMIT"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn fixed_point() {
+    let width = super::FixedPoint16_16(0x0140_8000);
+    assert_eq!(width.integer_part(), 320);
+    assert_eq!(width.fractional_part(), 0x8000);
+    assert_eq!(width.to_f64(), 320.5);
+    let volume = super::FixedPoint8_8(0x0100);
+    assert_eq!(volume.integer_part(), 1);
+    assert_eq!(volume.fractional_part(), 0);
+    assert_eq!(volume.to_f64(), 1.0);
+    let volume = super::FixedPoint8_8(-0x0080);
+    assert_eq!(volume.to_f64(), -0.5);
+}
+
+fn make_sample_table_track() -> super::Track {
+    let mut track = super::Track::new(0);
+    track.stts = Some(super::TimeToSampleBox {
+        samples: vec![super::Sample { sample_count: 6, sample_delta: 10 }],
+    });
+    track.stsc = Some(super::SampleToChunkBox {
+        samples: vec![super::SampleToChunk {
+            first_chunk: 1,
+            samples_per_chunk: 3,
+            sample_description_index: 1,
+        }],
+    });
+    track.stsz = Some(super::SampleSizeBox {
+        sample_size: 0,
+        sample_count: 6,
+        sample_sizes: vec![10, 20, 30, 40, 50, 60],
+    });
+    track.stco = Some(super::ChunkOffsetBox {
+        offsets: vec![100, 1000],
+    });
+    track.stss = Some(super::SyncSampleBox {
+        samples: vec![1, 4],
+    });
+    track
+}
+
+#[test]
+fn read_stz2() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"stz2", 0, |s| {
+        s.B8(0).B8(0).B8(0)
+         .B8(4) // field size
+         .B32(3)
+         .B8(0x12)
+         .B8(0x30)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    assert_eq!(stream.head.name, BoxType::CompactSampleSizeBox);
+    let parsed = super::read_stz2(&mut stream).unwrap();
+    assert_eq!(parsed.sample_size, 0);
+    assert_eq!(parsed.sample_sizes, vec![1, 2, 3]);
+
+    let mut stream = make_fullbox(BoxSize::Auto, b"stz2", 0, |s| {
+        s.B8(0).B8(0).B8(0)
+         .B8(16) // field size
+         .B32(2)
+         .B16(1000)
+         .B16(2000)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let parsed = super::read_stz2(&mut stream).unwrap();
+    assert_eq!(parsed.sample_sizes, vec![1000, 2000]);
+
+    let mut stream = make_fullbox(BoxSize::Auto, b"stz2", 0, |s| {
+        s.B8(0).B8(0).B8(0)
+         .B8(12) // field size
+         .B32(0)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    match super::read_stz2(&mut stream) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "invalid stz2 field size"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn read_subs() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"subs", 0, |s| {
+        s.B32(2)
+         .B32(1) // sample delta
+         .B16(2)
+         .B16(4).B8(0).B8(0).B32(0)
+         .B16(6).B8(1).B8(1).B32(7)
+         .B32(2) // sample delta
+         .B16(1)
+         .B16(10).B8(0).B8(0).B32(0)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    assert_eq!(stream.head.name, BoxType::SubSampleInformationBox);
+    let subs = super::read_subs(&mut stream).unwrap();
+    assert_eq!(subs.samples.len(), 2);
+    assert_eq!(subs.samples[1].0, 2);
+
+    let mut track = make_sample_table_track();
+    track.subs = Some(subs);
+    assert_eq!(track.subsample_flags(), Some(0));
+    assert!(track.subsamples(1).is_empty());
+    assert_eq!(track.subsamples(0)[1], super::SubSampleEntry {
+        size: 6,
+        priority: 1,
+        discardable: true,
+        codec_specific_parameters: 7,
+    });
+    let table = track.sample_table().unwrap();
+    assert_eq!(track.subsample_ranges(0, &table[0]).unwrap(), vec![100..104, 104..110]);
+    assert!(track.subsample_ranges(1, &table[1]).unwrap().is_empty());
+    match track.subsample_ranges(2, &table[2]) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "subsample sizes don't match sample size"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    let mut sample = table[0];
+    sample.offset = std::u64::MAX - 5;
+    match track.subsample_ranges(0, &sample) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "subsample extends past the end of the file"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn sample_table() {
+    let track = make_sample_table_track();
+    let table = track.sample_table().unwrap();
+    assert_eq!(table.len(), 6);
+    assert_eq!(table[1], super::SampleInfo {
+        offset: 110,
+        size: 20,
+        decode_time: 10,
+        composition_time: 10,
+        duration: 10,
+        sync: false,
+    });
+    assert_eq!(table[3].offset, 1000);
+    assert!(table[3].sync);
+    assert_eq!(table[5].offset, 1090);
+    assert_eq!(table[5].decode_time, 50);
+}
+
+#[test]
+fn check_sample_tables() {
+    use super::SampleTableMismatch;
+    let mut track = make_sample_table_track();
+    assert!(track.check_sample_tables().is_empty());
+
+    track.stts = Some(super::TimeToSampleBox {
+        samples: vec![super::Sample { sample_count: 5, sample_delta: 10 }],
+    });
+    track.stco = Some(super::ChunkOffsetBox { offsets: vec![100] });
+    track.ctts = Some(super::CompositionOffsetBox {
+        samples: vec![super::CompositionOffset { sample_count: 4, sample_offset: 10 }],
+    });
+    track.stss = Some(super::SyncSampleBox { samples: vec![1, 7] });
+    assert_eq!(track.check_sample_tables(), vec![
+        SampleTableMismatch::TimeToSampleCount { stsz: 6, stts: 5 },
+        SampleTableMismatch::ChunkSampleCount { stsz: 6, stsc: 3 },
+        SampleTableMismatch::CompositionOffsetCount { stsz: 6, ctts: 4 },
+        SampleTableMismatch::SyncSampleOutOfRange { sample: 7, stsz: 6 },
+    ]);
+
+    let mut track = make_sample_table_track();
+    track.stsc = Some(super::SampleToChunkBox {
+        samples: vec![super::SampleToChunk {
+            first_chunk: 3,
+            samples_per_chunk: 3,
+            sample_description_index: 1,
+        }],
+    });
+    track.stts = None;
+    assert_eq!(track.check_sample_tables(), vec![
+        SampleTableMismatch::MissingTable { table: BoxType::TimeToSampleBox },
+        SampleTableMismatch::InvalidFirstChunk { entry: 0, first_chunk: 3, chunks: 2 },
+        SampleTableMismatch::ChunkSampleCount { stsz: 6, stsc: 0 },
+    ]);
+
+    // Tables without samples, as in fragmented files, are consistent.
+    assert!(super::Track::new(0).check_sample_tables().is_empty());
+}
+
+#[test]
+fn sample_table_large_offsets() {
+    let stts = make_fullbox(BoxSize::Auto, b"stts", 0, |s| s.B32(1).B32(4).B32(10));
+    let stsc = make_fullbox(BoxSize::Auto, b"stsc", 0, |s| s.B32(1).B32(1).B32(2).B32(1));
+    let stsz = make_fullbox(BoxSize::Auto, b"stsz", 0, |s| s.B32(100).B32(4));
+    let co64 = make_fullbox(BoxSize::Auto, b"co64", 0, |s| {
+        s.B32(2).B64(0xffff_ffc0).B64(0x1_0000_0000 * 5)
+    });
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| {
+        s.append_bytes(stts.get_ref())
+         .append_bytes(stsc.get_ref())
+         .append_bytes(stsz.get_ref())
+         .append_bytes(co64.get_ref())
+    });
+    let minf = make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref()));
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| s.append_bytes(minf.get_ref()));
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(mdia.get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(trak.get_ref()));
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert!(context.features().contains(super::MediaFeatures::USES_64BIT_OFFSETS));
+
+    let table = context.tracks[0].sample_table().unwrap();
+    let offsets: Vec<u64> = table.iter().map(|s| s.offset).collect();
+    // The first chunk straddles the 4 GiB boundary.
+    assert_eq!(offsets, vec![0xffff_ffc0, 0x1_0000_0024, 0x5_0000_0000, 0x5_0000_0064]);
+    assert_eq!(context.tracks[0].extract_range(20, 40).unwrap().byte_ranges,
+               vec![0x5_0000_0000..0x5_0000_00c8]);
+}
+
+#[test]
+fn sample_table_missing_chunk() {
+    let mut track = make_sample_table_track();
+    track.stco = Some(super::ChunkOffsetBox {
+        offsets: vec![100],
+    });
+    match track.sample_table() {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "stsc describes fewer samples than stsz"),
+        Ok(_) => assert!(false, "expected an error result"),
+        _ => assert!(false, "expected a different error result"),
+    }
+}
+
+#[test]
+fn sample_table_limit() {
+    let mut track = make_sample_table_track();
+    track.stsz = Some(super::SampleSizeBox {
+        sample_size: 10,
+        sample_count: 0xffffffff,
+        sample_sizes: Vec::new(),
+    });
+    match track.sample_table() {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "stsz sample count exceeds SAMPLE_TABLE_LIMIT"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn sample_table_empty() {
+    let mut track = super::Track::new(0);
+    assert!(track.sample_table().unwrap().is_empty());
+    // An empty stsz needs no other tables.
+    track.stsz = Some(super::SampleSizeBox {
+        sample_size: 0,
+        sample_count: 0,
+        sample_sizes: Vec::new(),
+    });
+    assert!(track.sample_table().unwrap().is_empty());
+    assert!(track.extract_range(0, 100).unwrap().samples.is_empty());
+    // But samples need somewhere to live.
+    track.stsz = Some(super::SampleSizeBox {
+        sample_size: 10,
+        sample_count: 1,
+        sample_sizes: Vec::new(),
+    });
+    match track.sample_table() {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "missing sample table"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn extract_range() {
+    let track = make_sample_table_track();
+    // Starts on a sync sample.
+    let range = track.extract_range(35, 45).unwrap();
+    assert_eq!(range.first_sample, 3);
+    assert_eq!(range.samples.len(), 2);
+    assert_eq!(range.byte_ranges, vec![1000..1090]);
+    // Extends back to the previous sync sample.
+    let range = track.extract_range(15, 25).unwrap();
+    assert_eq!(range.first_sample, 0);
+    assert_eq!(range.samples.len(), 3);
+    assert_eq!(range.byte_ranges, vec![100..160]);
+    // Spans both chunks.
+    let range = track.extract_range(25, 35).unwrap();
+    assert_eq!(range.samples.len(), 4);
+    assert_eq!(range.byte_ranges, vec![100..160, 1000..1040]);
+    // Past the end of the track.
+    let range = track.extract_range(100, 200).unwrap();
+    assert!(range.samples.is_empty());
+    assert!(range.byte_ranges.is_empty());
+    // Times that don't fit the signed composition times.
+    match track.extract_range(1 << 63, u64::max_value()) {
+        Err(Error::InvalidData(_)) => (),
+        _ => panic!("unexpected result extracting an out of range time"),
+    }
+    assert!(track.extract_range(0, u64::max_value()).is_err());
+}
+
+#[test]
+fn full_sample_table() {
+    let mut track = make_sample_table_track();
+    let table = track.sample_table().unwrap();
+    assert!(track.full_sample_table().unwrap().iter().all(|s| s.crypto.is_none()));
+
+    let frma = make_box(BoxSize::Auto, b"frma", |s| s.append_bytes(b"avc1"));
+    let schm = make_fullbox(BoxSize::Auto, b"schm", 0, |s| s.append_bytes(b"cenc").B32(0x10000));
+    let tenc = make_fullbox(BoxSize::Auto, b"tenc", 0, |s| {
+        s.B16(0).B8(1).B8(8).append_repeated(0xaa, 16)
+    });
+    let schi = make_box(BoxSize::Auto, b"schi", |s| s.append_bytes(tenc.get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"sinf", |s| {
+        s.append_bytes(frma.get_ref())
+         .append_bytes(schm.get_ref())
+         .append_bytes(schi.get_ref())
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let sinf = super::read_sinf(&mut stream).unwrap();
+    assert_eq!(sinf.original_format, Some(BoxType::AVCSampleEntry));
+    assert_eq!(sinf.scheme_type, Some(0x63656e63));
+    assert_eq!(sinf.tenc.as_ref().map(|t| t.iv_size), Some(8));
+    track.protection_info = Some(sinf);
+
+    let mut stream = make_fullbox(BoxSize::Auto, b"senc", 0, |s| {
+        let mut s = s.B32(6);
+        for i in 0..6 {
+            s = s.B64(i).B16(1).B16(5).B32(5);
+        }
+        s
+    });
+    // The subsample flag is in the fullbox header.
+    stream.get_mut()[11] = 0x2;
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    track.senc = Some(super::read_senc(&mut stream, Some(8)).unwrap());
+
+    let samples = track.full_sample_table().unwrap();
+    assert_eq!(samples.len(), 6);
+    assert_eq!(samples[2].info, table[2]);
+    let crypto = samples[2].crypto.as_ref().unwrap();
+    assert_eq!(crypto.iv, vec![0, 0, 0, 0, 0, 0, 0, 2]);
+    assert_eq!(crypto.subsamples, vec![super::Subsample { clear_bytes: 5, encrypted_bytes: 5 }]);
+
+    // An senc must cover every sample.
+    track.senc.as_mut().unwrap().pop();
+    match track.full_sample_table() {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "senc sample count doesn't match stsz"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn seig_key_rotation() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"sgpd", 1, |s| {
+        s.append_bytes(b"seig")
+         .B32(0) // variable length entries
+         .B32(2)
+         .B32(20).B8(0).B8(0).B8(1).B8(8).append_repeated(1, 16)
+         .B32(20).B8(0).B8(0).B8(0).B8(0).append_repeated(0, 16)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let sgpd = super::read_sgpd(&mut stream).unwrap();
+    assert_eq!(sgpd.seig_entries.len(), 2);
+    assert_eq!(sgpd.seig_entries[0].kid, vec![1; 16]);
+    assert!(!sgpd.seig_entries[1].is_encrypted);
+
+    let mut stream = make_fullbox(BoxSize::Auto, b"sbgp", 0, |s| {
+        s.append_bytes(b"seig")
+         .B32(3)
+         .B32(2).B32(0)
+         .B32(2).B32(1)
+         .B32(1).B32(2)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let sbgp = super::read_sbgp(&mut stream).unwrap();
+    assert_eq!(sbgp.entries, vec![(2, 0), (2, 1), (1, 2)]);
+
+    let mut track = make_sample_table_track();
+    track.protection_info = Some(super::ProtectionSchemeInfoBox {
+        original_format: None,
+        scheme_type: None,
+        tenc: Some(super::TrackEncryptionBox {
+            crypt_byte_block: 0,
+            skip_byte_block: 0,
+            is_encrypted: true,
+            iv_size: 0,
+            kid: vec![9; 16],
+            constant_iv: Some(vec![3; 16]),
+        }),
+    });
+    track.seig_entries = sgpd.seig_entries;
+    track.seig_groups = Some(sbgp);
+    assert_eq!(track.encryption_defaults(2).unwrap().unwrap().kid, vec![1; 16]);
+
+    let samples = track.full_sample_table().unwrap();
+    let kids: Vec<Option<u8>> = samples.iter()
+        .map(|s| s.crypto.as_ref().map(|c| c.kid[0]))
+        .collect();
+    // The last sample is past the sbgp runs, so reverts to the tenc.
+    assert_eq!(kids, vec![Some(9), Some(9), Some(1), Some(1), None, Some(9)]);
+
+    track.seig_entries.pop();
+    match track.full_sample_table() {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "sbgp references missing seig entry"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn interleave_analysis() {
+    let mut video = make_sample_table_track();
+    video.timescale = Some(super::TrackTimeScale(1000, 0));
+    let mut audio = super::Track::new(1);
+    audio.timescale = Some(super::TrackTimeScale(1000, 1));
+    audio.stts = Some(super::TimeToSampleBox {
+        samples: vec![super::Sample { sample_count: 2, sample_delta: 30 }],
+    });
+    audio.stsc = Some(super::SampleToChunkBox {
+        samples: vec![super::SampleToChunk {
+            first_chunk: 1,
+            samples_per_chunk: 1,
+            sample_description_index: 1,
+        }],
+    });
+    audio.stsz = Some(super::SampleSizeBox {
+        sample_size: 10,
+        sample_count: 2,
+        sample_sizes: Vec::new(),
+    });
+    audio.stco = Some(super::ChunkOffsetBox {
+        offsets: vec![5000, 6000],
+    });
+
+    let mut context = MediaContext::new();
+    context.tracks.push(video);
+    assert_eq!(context.interleave_analysis().tracks, None);
+    context.tracks.push(audio);
+    assert_eq!(context.interleave_analysis(), super::InterleaveReport {
+        max_distance: 5000,
+        max_distance_time_us: 30000,
+        tracks: Some((0, 1)),
+    });
+}
+
+fn make_sidx(first_offset: u32, references: &[(u32, u32)]) -> Cursor<Vec<u8>> {
+    make_fullbox(BoxSize::Auto, b"sidx", 0, |s| {
+        let mut s = s.B32(1) // reference_ID
+                     .B32(1000) // timescale
+                     .B32(500) // earliest_presentation_time
+                     .B32(first_offset)
+                     .B16(0) // reserved
+                     .B16(references.len() as u16);
+        for &(size, duration) in references {
+            s = s.B32(size)
+                 .B32(duration)
+                 .B32(0x90000000); // starts_with_SAP, SAP_type 1
+        }
+        s
+    })
+}
+
+#[test]
+fn segment_timeline() {
+    // A top-level index referencing a second index which describes
+    // the media segments.
+    let child = make_sidx(0, &[(100, 2000), (200, 3000)]);
+    let root = make_sidx(0, &[(0x80000000 | child.get_ref().len() as u32, 5000)]);
+    assert_eq!(root.get_ref().len(), 44);
+    let mut stream = Cursor::new(Vec::new());
+    stream.get_mut().extend_from_slice(root.get_ref());
+    stream.get_mut().extend_from_slice(child.get_ref());
+    stream.get_mut().extend_from_slice(make_box(BoxSize::Auto, b"moov", |s| s).get_ref());
+    let mut context = MediaContext::new();
+    assert_eq!(context.segment_timeline(), None);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.segment_indexes.len(), 2);
+    assert_eq!(context.segment_indexes[1].position, 44);
+    assert_eq!(context.segment_indexes[1].references[0].sap_type, 1);
+    let timeline = context.segment_timeline().unwrap();
+    assert_eq!(timeline.timescale, 1000);
+    assert_eq!(timeline.index_range, 0..44);
+    assert_eq!(timeline.segments, vec![
+        super::TimelineSegment {
+            start: 500,
+            duration: 2000,
+            byte_range: 100..200,
+            starts_with_sap: true,
+        },
+        super::TimelineSegment {
+            start: 2500,
+            duration: 3000,
+            byte_range: 200..400,
+            starts_with_sap: true,
+        },
+    ]);
+
+    // A reference to a missing index invalidates the timeline.
+    context.segment_indexes.pop();
+    assert_eq!(context.segment_timeline(), None);
+}
+
+#[test]
+fn segment_timeline_repeated_references() {
+    // Zero-sized references all point at the same index, so each
+    // level would double the segments without a bound.
+    let child = make_sidx(0, &[(100, 2000), (200, 3000)]);
+    let root = make_sidx(0, &[(0x80000000, 1000), (0x80000000, 1000)]);
+    let mut stream = Cursor::new(Vec::new());
+    stream.get_mut().extend_from_slice(root.get_ref());
+    stream.get_mut().extend_from_slice(child.get_ref());
+    stream.get_mut().extend_from_slice(make_box(BoxSize::Auto, b"moov", |s| s).get_ref());
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.segment_indexes.len(), 2);
+    assert_eq!(context.segment_timeline(), None);
+
+    // As is an offset past the end of the file.
+    context.segment_indexes[0].references.pop();
+    assert_eq!(context.segment_timeline().unwrap().segments.len(), 2);
+    context.segment_indexes[0].first_offset = std::u64::MAX;
+    assert_eq!(context.segment_timeline(), None);
+}
+
+#[test]
+fn fragment_index() {
+    let sidx = make_sidx(0, &[(100, 2000), (200, 3000)]);
+    let mut stream = Cursor::new(sidx.into_inner());
+    stream.get_mut().extend_from_slice(make_box(BoxSize::Auto, b"moov", |s| s).get_ref());
+    let mut context = MediaContext::new();
+    let mut track = super::Track::new(0);
+    track.track_id = Some(1);
+    track.timescale = Some(super::TrackTimeScale(90000, 0));
+    context.tracks.push(track);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.fragment_index(2), None);
+
+    // Without fragments the segment index is used.
+    assert_eq!(context.fragment_index(1).unwrap(), vec![
+        super::FragmentRange { start_us: 500000, duration_us: 2000000, byte_range: 56..156 },
+        super::FragmentRange { start_us: 2500000, duration_us: 3000000, byte_range: 156..356 },
+    ]);
+
+    // Fragments take precedence, and continue from the previous
+    // fragment when they lack a tfdt.
+    for &(position, base_decode_time) in &[(56, Some(90000)), (156, None)] {
+        context.fragments.push(super::MovieFragment {
+            position: position,
+            size: 100,
+            sequence_number: None,
+            segment: None,
+            tracks: vec![super::TrackFragment {
+                track_id: 1,
+                base_decode_time: base_decode_time,
+                sample_count: 3,
+                duration: 45000,
+                samples: Vec::new(),
+                senc: Vec::new(),
+            }],
+        });
+    }
+    assert_eq!(context.fragment_index(1).unwrap(), vec![
+        super::FragmentRange { start_us: 1000000, duration_us: 500000, byte_range: 56..156 },
+        super::FragmentRange { start_us: 1500000, duration_us: 500000, byte_range: 156..256 },
+    ]);
+
+    // A byte range past the end of the file invalidates the index.
+    context.fragments[1].size = std::u64::MAX;
+    assert_eq!(context.fragment_index(1), None);
+}
+
+#[test]
+fn hls_byte_ranges() {
+    let trex = make_fullbox(BoxSize::Auto, b"trex", 0, |s| {
+        s.B32(1) // track_ID
+         .B32(1) // default_sample_description_index
+         .B32(100) // default_sample_duration
+         .B32(0) // default_sample_size
+         .B32(0) // default_sample_flags
+    });
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"mvex", |s| {
+            s.append_bytes(&trex.get_ref())
+        }).get_ref())
+    });
+    // First fragment uses the trex default duration.
+    let traf = make_box(BoxSize::Auto, b"traf", |s| {
+        s.append_bytes(&make_fullbox(BoxSize::Auto, b"tfhd", 0, |s| s.B32(1)).get_ref())
+         .append_bytes(&make_fullbox(BoxSize::Auto, b"tfdt", 1, |s| s.B64(0)).get_ref())
+         .append_bytes(&make_fullbox(BoxSize::Auto, b"trun", 0, |s| s.B32(3)).get_ref())
+    });
+    let moof = make_box(BoxSize::Auto, b"moof", |s| s.append_bytes(&traf.get_ref()));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_repeated(0, 30));
+    // Second fragment has explicit sample durations and sizes.
+    let traf2 = make_box(BoxSize::Auto, b"traf", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"tfhd", |s| {
+            s.B32(0x08).B32(1).B32(50)
+        }).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| {
+            s.B32(0x300)
+             .B32(2)
+             .B32(200).B32(10)
+             .B32(300).B32(10)
+        }).get_ref())
+    });
+    let moof2 = make_box(BoxSize::Auto, b"moof", |s| s.append_bytes(&traf2.get_ref()));
+
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&moov, &moof, &mdat, &moof2] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+    let first = moov.get_ref().len() as u64;
+    let first_size = (moof.get_ref().len() + mdat.get_ref().len()) as u64;
+    let second_size = moof2.get_ref().len() as u64;
+
+    let mut context = MediaContext::new();
+    let mut track = super::Track::new(0);
+    track.track_id = Some(1);
+    track.timescale = Some(super::TrackTimeScale(1000, 0));
+    context.tracks.push(track);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.fragments.len(), 2);
+    assert_eq!(context.fragments[0].tracks[0].base_decode_time, Some(0));
+    assert_eq!(context.fragments[1].tracks[0].sample_count, 2);
+    assert_eq!(context.hls_byte_ranges(2), None);
+    assert_eq!(context.hls_byte_ranges(1).unwrap(), vec![
+        super::HlsByteRange {
+            offset: first,
+            length: first_size,
+            duration: 0.3,
+        },
+        super::HlsByteRange {
+            offset: first + first_size,
+            length: second_size,
+            duration: 0.5,
+        },
+    ]);
+}
+
+#[test]
+fn fragment_samples() {
+    let trex = make_fullbox(BoxSize::Auto, b"trex", 0, |s| {
+        s.B32(1) // track_ID
+         .B32(1) // default_sample_description_index
+         .B32(100) // default_sample_duration
+         .B32(10) // default_sample_size
+         .B32(0x10000) // default_sample_flags, non-sync
+    });
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"mvex", |s| {
+            s.append_bytes(&trex.get_ref())
+        }).get_ref())
+    });
+    // Data offsets relative to the moof. The first run has explicit
+    // sizes and a sync first sample, the second follows on from it
+    // with explicit durations and a negative composition offset.
+    let make_moof = |data_offset: u32| {
+        make_box(BoxSize::Auto, b"moof", |s| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"mfhd", 0, |s| s.B32(1)).get_ref())
+             .append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+                s.append_bytes(&make_box(BoxSize::Auto, b"tfhd", |s| {
+                    s.B32(0x020000).B32(1)
+                }).get_ref())
+                 .append_bytes(&make_fullbox(BoxSize::Auto, b"tfdt", 1, |s| s.B64(1000)).get_ref())
+                 .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| {
+                    s.B32(0x205)
+                     .B32(2)
+                     .B32(data_offset)
+                     .B32(0) // first_sample_flags
+                     .B32(20)
+                     .B32(30)
+                }).get_ref())
+                 .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| {
+                    s.B32(0x01000900)
+                     .B32(1)
+                     .B32(50)
+                     .B32(-10i32 as u32)
+                }).get_ref())
+            }).get_ref())
+        })
+    };
+    let moof_size = make_moof(0).get_ref().len() as u32;
+    let moof = make_moof(moof_size + 8);
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_repeated(0, 60));
+
+    let mut context = MediaContext::new();
+    match super::read_fragments(&mut Cursor::new(moof.get_ref().clone()), &mut context, 0) {
+        Err(Error::NoMoov) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let mut track = super::Track::new(0);
+    track.track_id = Some(1);
+    context.tracks.push(track);
+    let mut stream = Cursor::new(moov.get_ref().clone());
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert!(context.fragment_samples(1).is_empty());
+
+    // Feed the media segment on its own, as if appended to the stream.
+    let position = moov.get_ref().len() as u64;
+    let mut segment = Cursor::new(moof.get_ref().clone());
+    segment.get_mut().extend_from_slice(mdat.get_ref());
+    super::read_fragments(&mut segment, &mut context, position).unwrap();
+    assert_eq!(context.fragments.len(), 1);
+    assert_eq!(context.fragments[0].position, position);
+    assert_eq!(context.fragments[0].tracks[0].sample_count, 3);
+    assert_eq!(context.fragments[0].tracks[0].duration, 250);
+
+    let data = position + moof_size as u64 + 8;
+    let samples = context.fragment_samples(1);
+    assert_eq!(samples, vec![
+        super::SampleInfo {
+            offset: data, size: 20, decode_time: 1000, composition_time: 1000, duration: 100, sync: true,
+        },
+        super::SampleInfo {
+            offset: data + 20, size: 30, decode_time: 1100, composition_time: 1100, duration: 100, sync: false,
+        },
+        super::SampleInfo {
+            offset: data + 50, size: 10, decode_time: 1200, composition_time: 1190, duration: 50, sync: false,
+        },
+    ]);
+    assert!(context.fragment_samples(2).is_empty());
+
+    // A moov can't appear in a media segment.
+    match super::read_fragments(&mut Cursor::new(moov.get_ref().clone()), &mut context, 0) {
+        Err(Error::InvalidData(_)) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn full_mode_fragment_samples() {
+    // Two samples of track 1, with an senc giving their IVs and
+    // subsamples, or with saiz and saio alone.
+    let make_moof = |with_senc: bool, senc_count: u32| {
+        make_box(BoxSize::Auto, b"moof", |s| {
+            s.append_bytes(make_box(BoxSize::Auto, b"traf", |s| {
+                let s = s.append_bytes(make_fullbox(BoxSize::Auto, b"tfhd", 0, |s| s.B32(1)).get_ref())
+                    .append_bytes(make_box(BoxSize::Auto, b"trun", |s| {
+                        s.B32(0x200).B32(2).B32(20).B32(30)
+                    }).get_ref());
+                if with_senc {
+                    let mut senc = make_fullbox(BoxSize::Auto, b"senc", 0, |s| {
+                        (0..senc_count).fold(s.B32(senc_count), |s, i| s.B64(i as u64 + 1).B16(1).B16(4).B32(16))
+                    });
+                    // The subsample flag is in the fullbox header.
+                    senc.get_mut()[11] = 0x2;
+                    s.append_bytes(senc.get_ref())
+                } else {
+                    s.append_bytes(make_fullbox(BoxSize::Auto, b"saiz", 0, |s| s.B8(8).B32(2)).get_ref())
+                     .append_bytes(make_fullbox(BoxSize::Auto, b"saio", 0, |s| s.B32(1).B32(1000)).get_ref())
+                }
+            }).get_ref())
+        })
+    };
+    let make_context = || {
+        let mut track = super::Track::new(0);
+        track.track_id = Some(1);
+        track.protection_info = Some(super::ProtectionSchemeInfoBox {
+            original_format: None,
+            scheme_type: None,
+            tenc: Some(super::TrackEncryptionBox {
+                crypt_byte_block: 0,
+                skip_byte_block: 0,
+                is_encrypted: true,
+                iv_size: 8,
+                kid: vec![9; 16],
+                constant_iv: None,
+            }),
+        });
+        track.samples = Some(Vec::new());
+        let mut context = MediaContext::new();
+        context.mode = super::ParseMode::Full;
+        context.tracks.push(track);
+        context
+    };
+
+    let mut context = make_context();
+    super::read_fragments(&mut Cursor::new(make_moof(true, 2).into_inner()), &mut context, 0).unwrap();
+    let samples = context.tracks[0].samples.as_ref().unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[1].info, context.fragment_samples(1)[1]);
+    let crypto = samples[1].crypto.as_ref().unwrap();
+    assert_eq!(crypto.kid, vec![9; 16]);
+    assert_eq!(crypto.iv, vec![0, 0, 0, 0, 0, 0, 0, 2]);
+    assert_eq!(crypto.subsamples, vec![super::Subsample { clear_bytes: 4, encrypted_bytes: 16 }]);
+
+    // Auxiliary information located only by saio isn't read, so the
+    // samples have no IVs.
+    super::read_fragments(&mut Cursor::new(make_moof(false, 0).into_inner()), &mut context, 0).unwrap();
+    let samples = context.tracks[0].samples.as_ref().unwrap();
+    assert_eq!(samples.len(), 4);
+    let crypto = samples[3].crypto.as_ref().unwrap();
+    assert!(crypto.iv.is_empty());
+    assert!(crypto.subsamples.is_empty());
+
+    // An senc must cover every sample of the traf.
+    let mut context = make_context();
+    match super::read_fragments(&mut Cursor::new(make_moof(true, 1).into_inner()), &mut context, 0) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "senc sample count doesn't match trun"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    let mut context = make_context();
+    context.strictness = super::ParseStrictness::Lenient;
+    super::read_fragments(&mut Cursor::new(make_moof(true, 1).into_inner()), &mut context, 0).unwrap();
+    assert!(context.tracks[0].samples.is_none());
+}
+
+#[test]
+fn media_data_fragment_size_overflow() {
+    let mut context = MediaContext::new();
+    context.fragments.push(super::MovieFragment {
+        position: 0,
+        size: std::u64::MAX - 5,
+        sequence_number: None,
+        segment: None,
+        tracks: Vec::new(),
+    });
+    let head = super::BoxHeader { name: BoxType::MediaDataBox, size: 10, offset: 8 };
+    match super::note_media_data(&mut context, std::u64::MAX - 5, &head) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "fragment size too large"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn media_data_range_overflow() {
+    let mut context = MediaContext::new();
+    let head = super::BoxHeader { name: BoxType::MediaDataBox, size: 10, offset: 8 };
+    match super::note_media_data(&mut context, std::u64::MAX - 5, &head) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "mdat extends past the end of the file"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(context.media_data.is_empty());
+}
+
+#[test]
+fn fragment_sample_budget() {
+    // Runs without per-sample fields cost a few bytes however many
+    // samples they describe.
+    let mut stream = make_box(BoxSize::Auto, b"traf", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"tfhd", |s| s.B32(0).B32(1)).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| s.B32(0).B32(4)).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| s.B32(0).B32(4)).get_ref())
+    });
+    let mut context = MediaContext::new();
+    let mut budget = 10;
+    {
+        let mut iter = super::BoxIter::new(&mut stream);
+        let mut traf = iter.next_box().unwrap().unwrap();
+        let traf = super::read_traf(&mut traf, &mut context, 0, &mut 0, &mut budget).unwrap().unwrap();
+        assert_eq!(traf.samples.len(), 8);
+    }
+    assert_eq!(budget, 2);
+
+    stream.set_position(0);
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut traf = iter.next_box().unwrap().unwrap();
+    match super::read_traf(&mut traf, &mut context, 0, &mut 0, &mut budget) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "fragment samples exceed FRAGMENT_SAMPLE_LIMIT"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn fragment_data_offset_overflow() {
+    let moof = |base_data_offset: u64| {
+        make_box(BoxSize::Auto, b"moof", |s| {
+            s.append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+                s.append_bytes(&make_box(BoxSize::Auto, b"tfhd", |s| {
+                    s.B32(0x000001).B32(1).B64(base_data_offset)
+                }).get_ref())
+                 .append_bytes(&make_box(BoxSize::Auto, b"trun", |s| {
+                    s.B32(0x001).B32(1).B32(1)
+                }).get_ref())
+            }).get_ref())
+        })
+    };
+    for &(base, error) in &[(i64::max_value() as u64, "trun data offset too large"),
+                            (::std::u64::MAX, "trun base data offset too large")] {
+        let mut context = MediaContext::new();
+        match read_mp4(&mut moof(base), &mut context) {
+            Err(Error::InvalidData(s)) => assert_eq!(s, error),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn fragment_sequence_gaps() {
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s);
+    let mut stream = Cursor::new(moov.into_inner());
+    for sequence_number in &[1, 2, 4, 4, 5] {
+        let moof = make_box(BoxSize::Auto, b"moof", |s| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"mfhd", 0, |s| {
+                s.B32(*sequence_number)
+            }).get_ref())
+        });
+        stream.get_mut().extend_from_slice(moof.get_ref());
+    }
+    // A fragment without an mfhd doesn't interrupt the sequence.
+    let moof = make_box(BoxSize::Auto, b"moof", |s| s);
+    stream.get_mut().extend_from_slice(moof.get_ref());
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.fragments.len(), 6);
+    assert_eq!(context.fragments[2].sequence_number, Some(4));
+    assert_eq!(context.fragments[5].sequence_number, None);
+    assert_eq!(context.fragment_sequence_gaps(), vec![
+        super::SequenceGap { fragment: 2, expected: 3, found: 4 },
+        super::SequenceGap { fragment: 3, expected: 5, found: 4 },
+    ]);
+}
+
+#[test]
+fn decode_time_discontinuities() {
+    fn traf(track_id: u32, tfdt: Option<u64>, samples: u32) -> Cursor<Vec<u8>> {
+        make_box(BoxSize::Auto, b"traf", |s| {
+            let s = s.append_bytes(&make_box(BoxSize::Auto, b"tfhd", |s| {
+                s.B32(0x08).B32(track_id).B32(100)
+            }).get_ref());
+            let s = match tfdt {
+                Some(time) => s.append_bytes(&make_fullbox(BoxSize::Auto, b"tfdt", 1, |s| s.B64(time)).get_ref()),
+                None => s,
+            };
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"trun", 0, |s| s.B32(samples)).get_ref())
+        })
+    }
+    let fragments = vec![
+        vec![traf(1, Some(0), 3), traf(2, Some(0), 2)],
+        // Track 1 skips 100 units; track 2 has no tfdt.
+        vec![traf(1, Some(400), 3), traf(2, None, 2)],
+        // Track 1 overlaps by 100 units; track 2 is contiguous.
+        vec![traf(1, Some(600), 3), traf(2, Some(400), 2)],
+    ];
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s);
+    let mut stream = Cursor::new(moov.into_inner());
+    for trafs in &fragments {
+        let moof = make_box(BoxSize::Auto, b"moof", |s| {
+            trafs.iter().fold(s, |s, traf| s.append_bytes(traf.get_ref()))
+        });
+        stream.get_mut().extend_from_slice(moof.get_ref());
+    }
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let discontinuities = context.decode_time_discontinuities();
+    assert_eq!(discontinuities, vec![
+        super::DecodeTimeDiscontinuity { track_id: 1, fragment: 1, expected: 300, found: 400 },
+        super::DecodeTimeDiscontinuity { track_id: 1, fragment: 2, expected: 700, found: 600 },
+    ]);
+    assert!(discontinuities[0].is_gap());
+    assert!(!discontinuities[1].is_gap());
+}
+
+#[test]
+fn cmaf_segments() {
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s);
+    let segment_styp = make_box(BoxSize::Auto, b"styp", |s| {
+        s.append_bytes(b"cmfs").B32(0).append_bytes(b"cmfs").append_bytes(b"cmfl")
+    });
+    let chunk_styp = make_box(BoxSize::Auto, b"styp", |s| {
+        s.append_bytes(b"cmfl").B32(0).append_bytes(b"cmfl")
+    });
+    let plain_styp = make_box(BoxSize::Auto, b"styp", |s| {
+        s.append_bytes(b"cmfs").B32(0).append_bytes(b"cmfs")
+    });
+    let moof = make_box(BoxSize::Auto, b"moof", |s| s);
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_repeated(0, 16));
+    let parts = [
+        &moov,
+        // A fragment outside any segment.
+        &moof, &mdat,
+        // A chunked segment, the last chunk with its own styp.
+        &segment_styp, &moof, &mdat, &moof, &mdat, &chunk_styp, &moof, &mdat,
+        // A segment delivered whole.
+        &plain_styp, &moof, &mdat,
+    ];
+    let mut stream = Cursor::new(Vec::new());
+    let mut chunks = Vec::new();
+    for part in &parts {
+        let start = stream.get_ref().len() as u64;
+        if part.get_ref()[4..8] == *b"moof" {
+            chunks.push(start..start + (moof.get_ref().len() + mdat.get_ref().len()) as u64);
+        }
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.fragments.len(), 5);
+    assert_eq!(context.fragments[3].segment, Some(1));
+    assert_eq!(context.cmaf_segments(), vec![
+        super::CmafSegment { first_fragment: 0, chunks: vec![chunks[0].clone()], chunked: false },
+        super::CmafSegment { first_fragment: 1, chunks: chunks[1..4].to_vec(), chunked: true },
+        super::CmafSegment { first_fragment: 4, chunks: vec![chunks[4].clone()], chunked: false },
+    ]);
+
+    // Grouping stops at a byte range past the end of the file.
+    context.fragments[2].size = std::u64::MAX;
+    assert_eq!(context.cmaf_segments(), vec![
+        super::CmafSegment { first_fragment: 0, chunks: vec![chunks[0].clone()], chunked: false },
+        super::CmafSegment { first_fragment: 1, chunks: vec![chunks[1].clone()], chunked: true },
+    ]);
+}
+
+#[test]
+fn check_cmaf() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| {
+        s.append_bytes(b"iso6").B32(0).append_bytes(b"cmfc")
+    });
+    let trex = make_fullbox(BoxSize::Auto, b"trex", 0, |s| {
+        s.B32(1).B32(1).B32(100).B32(0).B32(0)
+    });
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"mvex", |s| {
+            s.append_bytes(&trex.get_ref())
+        }).get_ref())
+    });
+    let styp = make_box(BoxSize::Auto, b"styp", |s| {
+        s.append_bytes(b"msdh").B32(0).append_bytes(b"msdh")
+    });
+    let tfhd = make_fullbox(BoxSize::Auto, b"tfhd", 0, |s| s.B32(1));
+    let tfdt = make_fullbox(BoxSize::Auto, b"tfdt", 0, |s| s.B32(0));
+    let moof = make_box(BoxSize::Auto, b"moof", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+            s.append_bytes(&tfhd.get_ref())
+        }).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+            s.append_bytes(&tfhd.get_ref())
+             .append_bytes(&tfdt.get_ref())
+        }).get_ref())
+    });
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&ftyp, &moov, &styp, &moof] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+
+    let mut context = MediaContext::new();
+    let mut track = super::Track::new(0);
+    track.empty_duration = Some(super::MediaScaledTime(10));
+    track.edit_count = 2;
+    context.tracks.push(track);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.check_cmaf(), vec![
+        super::CmafViolation::EmptyEdit { track: 0 },
+        super::CmafViolation::MultipleEdits { track: 0 },
+        super::CmafViolation::SegmentBrand { segment: 0 },
+        super::CmafViolation::FragmentTrackCount { fragment: 0, count: 2 },
+        super::CmafViolation::MissingDecodeTime { fragment: 0 },
+    ]);
+
+    let context = MediaContext::new();
+    assert_eq!(context.check_cmaf(), vec![
+        super::CmafViolation::MissingCmafBrand,
+        super::CmafViolation::TrackCount(0),
+        super::CmafViolation::MissingMovieExtends,
+    ]);
+}
+
+#[test]
+fn compare_contexts() {
+    let mut left = MediaContext::new();
+    let mut right = MediaContext::new();
+    assert!(super::compare(&left, &right).is_empty());
+    left.timescale = Some(super::MediaTimeScale(1000));
+    right.timescale = Some(super::MediaTimeScale(1000));
+    left.tracks.push(make_sample_table_track());
+    right.tracks.push(make_sample_table_track());
+    assert!(super::compare(&left, &right).is_empty());
+
+    right.volume = Some(super::FixedPoint8_8(0x80));
+    if let Some(ref mut stsz) = right.tracks[0].stsz {
+        stsz.sample_sizes[2] = 35;
+    }
+    right.tracks[0].enabled = true;
+    let differences = super::compare(&left, &right);
+    assert_eq!(differences.len(), 3);
+    assert_eq!(differences[0].track, None);
+    assert_eq!(differences[0].field, "volume");
+    assert_eq!(differences[1].track, Some(0));
+    assert_eq!(differences[1].field, "enabled");
+    assert_eq!(differences[2].to_string(), "track 0 sample size: (2, 30) != (2, 35)");
+}
+
+#[test]
+fn ogg_opus_writer() {
+    let opus = super::OpusSpecificBox {
+        version: 0,
+        output_channel_count: 1,
+        pre_skip: 312,
+        input_sample_rate: 48000,
+        output_gain: 0,
+        channel_mapping_family: 0,
+        channel_mapping_table: None,
+    };
+    let mut writer = super::OggOpusWriter::new(Vec::new(), 1, &opus).unwrap();
+    writer.write_packet(&[0xfc; 10], 960).unwrap();
+    writer.write_packet(&[0xfc; 300], 960).unwrap();
+    let ogg = writer.finish().unwrap();
+    // OpusHead page, with beginning of stream flag.
+    assert_eq!(&ogg[..6], b"OggS\x00\x02");
+    assert_eq!(&ogg[28..36], b"OpusHead");
+    assert_eq!(&ogg[22..26], &[0x1c, 0x9f, 0xf5, 0xd9]);
+    // Final page has the end of stream flag, the total granule
+    // position and a two segment lacing table.
+    let last = ogg.len() - (27 + 2 + 300);
+    assert_eq!(&ogg[last..last + 6], b"OggS\x00\x04");
+    assert_eq!(&ogg[last + 6..last + 14], &[0x80, 0x07, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&ogg[last + 26..last + 29], &[2, 255, 45]);
+}
+
+fn make_moov_with_stco(offsets: &[u32]) -> Cursor<Vec<u8>> {
+    let stco = make_fullbox(BoxSize::Auto, b"stco", 0, |s| {
+        let mut s = s.B32(offsets.len() as u32);
+        for offset in offsets {
+            s = s.B32(*offset);
+        }
+        s
+    });
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| s.append_bytes(&stco.get_ref()));
+    let minf = make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(&stbl.get_ref()));
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| s.append_bytes(&minf.get_ref()));
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(&mdia.get_ref()));
+    make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(&trak.get_ref()))
+}
+
+#[test]
+fn full_mode_bad_sample_table() {
+    // An stsz without the other sample tables, then a track without samples.
+    let stsz = make_fullbox(BoxSize::Auto, b"stsz", 0, |s| s.B32(10).B32(2));
+    let bad = make_box(BoxSize::Auto, b"trak", |s| {
+        s.append_bytes(make_box(BoxSize::Auto, b"mdia", |s| {
+            s.append_bytes(make_box(BoxSize::Auto, b"minf", |s| {
+                s.append_bytes(make_box(BoxSize::Auto, b"stbl", |s| s.append_bytes(stsz.get_ref())).get_ref())
+            }).get_ref())
+        }).get_ref())
+    });
+    let good = make_box(BoxSize::Auto, b"trak", |s| s);
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(bad.get_ref()).append_bytes(good.get_ref()));
+
+    let mut context = MediaContext::new();
+    context.mode = super::ParseMode::Full;
+    match read_mp4(&mut Cursor::new(moov.get_ref()), &mut context) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "missing sample table"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Lenient parsing keeps both tracks, without the bad one's samples.
+    let mut context = MediaContext::new();
+    context.mode = super::ParseMode::Full;
+    context.strictness = super::ParseStrictness::Lenient;
+    read_mp4(&mut Cursor::new(moov.get_ref()), &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 2);
+    assert!(context.tracks[0].samples.is_none());
+    assert_eq!(context.tracks[1].samples, Some(Vec::new()));
+}
+
+#[test]
+fn relocate_moov_to_front() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_bytes(b"chunk one chunk two"));
+    let free = make_box(BoxSize::Auto, b"free", |s| s.B32(0));
+    let base = ftyp.get_ref().len() as u32 + 8;
+    let moov = make_moov_with_stco(&[base, base + 10]);
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov, &free] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    let mut output = Vec::new();
+    super::relocate_moov_to_front(&mut input, &mut output).unwrap();
+    assert_eq!(output.len(), input.get_ref().len());
+    let moov_start = ftyp.get_ref().len();
+    assert_eq!(&output[moov_start + 4..moov_start + 8], b"moov");
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(&output), &mut context).unwrap();
+    let offsets = &context.tracks[0].stco.as_ref().unwrap().offsets;
+    let shift = moov.get_ref().len() as u64;
+    assert_eq!(offsets, &vec![base as u64 + shift, base as u64 + 10 + shift]);
+    assert_eq!(&output[offsets[1] as usize..offsets[1] as usize + 9], b"chunk two");
+
+    // A moov which already precedes the media data is copied as is.
+    let mut again = Vec::new();
+    super::relocate_moov_to_front(&mut Cursor::new(&output), &mut again).unwrap();
+    assert_eq!(again, output);
+
+    // Offsets must point into a top-level box.
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &make_moov_with_stco(&[1000])] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    match super::relocate_moov_to_front(&mut input, &mut Vec::new()) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "chunk offset outside media data"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn relocate_moov_auxiliary_info_offsets() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_bytes(b"sampleaux"));
+    let base = ftyp.get_ref().len() as u64 + 8;
+    let stco = make_fullbox(BoxSize::Auto, b"stco", 0, |s| s.B32(1).B32(base as u32));
+    // Version 1 with aux_info_type and aux_info_type_parameter.
+    let saio = make_box(BoxSize::Auto, b"saio", |s| {
+        s.B32(0x01000001).append_bytes(b"cenc").B32(0).B32(1).B64(base + 6)
+    });
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| s.append_bytes(stco.get_ref()).append_bytes(saio.get_ref()));
+    let minf = make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref()));
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| s.append_bytes(minf.get_ref()));
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(mdia.get_ref()));
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(trak.get_ref()));
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    let mut output = Vec::new();
+    super::relocate_moov_to_front(&mut input, &mut output).unwrap();
+
+    // Written as version 0, since the offset fits in 32 bits.
+    let saio = output.windows(4).position(|w| w == b"saio").unwrap() - 4;
+    let saio = &output[saio..saio + 28];
+    assert_eq!(&saio[..4], &[0, 0, 0, 28]);
+    assert_eq!(&saio[8..24], b"\x00\x00\x00\x01cenc\x00\x00\x00\x00\x00\x00\x00\x01");
+    let offset = (&saio[24..28]).iter().fold(0usize, |v, &b| v << 8 | b as usize);
+    assert_eq!(offset, base as usize + 6 + moov.get_ref().len() - 4);
+    assert_eq!(&output[offset..offset + 3], b"aux");
+
+    // Item locations in the moov may point into the file, so are
+    // rejected.
+    let iloc = make_fullbox(BoxSize::Auto, b"iloc", 0, |s| s.B16(0x4400).B16(0));
+    let meta = make_fullbox(BoxSize::Auto, b"meta", 0, |s| s.append_bytes(iloc.get_ref()));
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(meta.get_ref()));
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    match super::relocate_moov_to_front(&mut input, &mut Vec::new()) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "moov relocation of item locations"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn relocate_moov_upgrades_to_co64() {
+    use super::faststart::{parse_children, plan, TopLevelBox};
+    // Layout of a large file: the last chunk ends just under 4 GiB, so
+    // moving the moov ahead of it needs 64-bit offsets.
+    let mdat_size = 0xffff_ffe0u64;
+    let last_chunk = 24 + mdat_size - 16;
+    let moov = make_moov_with_stco(&[32, last_chunk as u32]);
+    let boxes = [
+        TopLevelBox { name: BoxType::FileTypeBox, position: 0, size: 24 },
+        TopLevelBox { name: BoxType::MediaDataBox, position: 24, size: mdat_size },
+        TopLevelBox { name: BoxType::MovieBox, position: 24 + mdat_size, size: moov.get_ref().len() as u64 },
+    ];
+    let mut tables = 0;
+    let tree = parse_children(moov.get_ref(), &mut tables).unwrap().pop().unwrap();
+    assert_eq!(tables, 1);
+    let (order, positions, large) = plan(&boxes, 2, &tree, tables).unwrap();
+    assert_eq!(order, vec![0, 2, 1]);
+    assert_eq!(large, vec![true]);
+    // The upgraded moov is 8 bytes larger, one per offset.
+    assert_eq!(positions, vec![0, 24, 24 + moov.get_ref().len() as u64 + 8]);
+}
+
+#[test]
+fn relocate_moov_nested_containers() {
+    use super::faststart::{parse_children, Node};
+    // Containers are only descended into on the path to the stbl, so
+    // deep nesting can't exhaust the stack.
+    let depth = 200_000;
+    let moov = make_box(BoxSize::Auto, b"moov", |mut s| {
+        for i in 0..depth {
+            s = s.B32((depth - i) * 8).append_bytes(b"trak");
+        }
+        s
+    }).into_inner();
+    let mut tables = 0;
+    let tree = parse_children(&moov, &mut tables).unwrap().pop().unwrap();
+    let trak = match tree {
+        Node::Container { children, .. } => children.into_iter().next().unwrap(),
+        node => panic!("unexpected node {:?}", node),
+    };
+    match trak {
+        Node::Container { children, .. } => match children[0] {
+            Node::Leaf { name, .. } => assert_eq!(name, 0x7472616b),
+            ref node => panic!("unexpected node {:?}", node),
+        },
+        node => panic!("unexpected node {:?}", node),
+    }
+    assert_eq!(tables, 0);
+
+    // The same moov after the media data can be relocated.
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_bytes(b"chunk"));
+    let mut input = Cursor::new(Vec::new());
+    for part in &[ftyp.get_ref(), mdat.get_ref(), &moov] {
+        input.get_mut().extend_from_slice(part);
+    }
+    let mut output = Vec::new();
+    super::relocate_moov_to_front(&mut input, &mut output).unwrap();
+    assert_eq!(output.len(), input.get_ref().len());
+}
+
+/// A trak with one sample per chunk at each of `offsets`, four bytes
+/// each and one tick long in a timescale of 10, presented by a single
+/// edit. Samples are all sync samples unless `sync` lists them.
+/// Offsets are written to a 'co64' if any needs 64 bits.
+fn make_trak_with_chunks(track_id: u32, codec: &[u8; 4], offsets: &[u64], sync: Option<&[u32]>) -> Cursor<Vec<u8>> {
+    let count = offsets.len() as u32;
+    let tkhd = make_fullbox(BoxSize::Auto, b"tkhd", 0, |s| {
+        s.B32(0).B32(0).B32(track_id).B32(0).B32(count).append_repeated(0, 60)
+    });
+    let edts = make_box(BoxSize::Auto, b"edts", |s| {
+        s.append_bytes(make_fullbox(BoxSize::Auto, b"elst", 0, |s| s.B32(1).B32(count).B32(0).B32(0x10000)).get_ref())
+    });
+    let stsd = make_fullbox(BoxSize::Auto, b"stsd", 0, |s| {
+        s.B32(1).append_bytes(make_box(BoxSize::Auto, codec, |s| s.append_repeated(0, 8)).get_ref())
+    });
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| {
+        let s = s.append_bytes(stsd.get_ref())
+         .append_bytes(make_fullbox(BoxSize::Auto, b"stts", 0, |s| s.B32(1).B32(count).B32(1)).get_ref());
+        let s = match sync {
+            Some(sync) => s.append_bytes(make_fullbox(BoxSize::Auto, b"stss", 0, |s| {
+                let mut s = s.B32(sync.len() as u32);
+                for number in sync {
+                    s = s.B32(*number);
+                }
+                s
+            }).get_ref()),
+            None => s,
+        };
+        s.append_bytes(make_fullbox(BoxSize::Auto, b"stsc", 0, |s| s.B32(1).B32(1).B32(1).B32(1)).get_ref())
+         .append_bytes(make_fullbox(BoxSize::Auto, b"stsz", 0, |s| s.B32(4).B32(count)).get_ref())
+         .append_bytes(if offsets.iter().any(|o| *o > std::u32::MAX as u64) {
+             make_fullbox(BoxSize::Auto, b"co64", 0, |s| {
+                 let mut s = s.B32(count);
+                 for offset in offsets {
+                     s = s.B64(*offset);
+                 }
+                 s
+             })
+         } else {
+             make_fullbox(BoxSize::Auto, b"stco", 0, |s| {
+                 let mut s = s.B32(count);
+                 for offset in offsets {
+                     s = s.B32(*offset as u32);
+                 }
+                 s
+             })
+         }.get_ref())
+    });
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| {
+        s.append_bytes(make_fullbox(BoxSize::Auto, b"mdhd", 0, |s| {
+             s.B32(0).B32(0).B32(10).B32(count).B16(0x55c4).B16(0)
+         }).get_ref())
+         .append_bytes(make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref())).get_ref())
+    });
+    make_box(BoxSize::Auto, b"trak", |s| {
+        s.append_bytes(tkhd.get_ref()).append_bytes(edts.get_ref()).append_bytes(mdia.get_ref())
+    })
+}
+
+/// An mvhd with a timescale of 10.
+fn make_mvhd(duration: u32) -> Cursor<Vec<u8>> {
+    make_fullbox(BoxSize::Auto, b"mvhd", 0, |s| {
+        s.B32(0).B32(0).B32(10).B32(duration).B32(0x10000).B16(0x100).append_repeated(0, 70).B32(3)
+    })
+}
+
+#[test]
+fn remove_tracks() {
+    // Interleaved chunks of two tracks, with the moov after them. The
+    // audio track is longer, and each track references the other.
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_bytes(b"vid1aud1vid2aud2aud3"));
+    let base = ftyp.get_ref().len() as u64 + 8;
+    let with_tref = |trak: Cursor<Vec<u8>>, reference: &[u8; 4], id: u32| {
+        let tref = make_box(BoxSize::Auto, b"tref", |s| {
+            s.append_bytes(make_box(BoxSize::Auto, reference, |s| s.B32(id)).get_ref())
+        });
+        make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(&trak.get_ref()[8..]).append_bytes(tref.get_ref()))
+    };
+    let video = with_tref(make_trak_with_chunks(1, b"avc1", &[base, base + 8], None), b"chap", 2);
+    let audio = with_tref(make_trak_with_chunks(2, b"mp4a", &[base + 4, base + 12, base + 16], None), b"auxl", 1);
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(make_mvhd(3).get_ref())
+         .append_bytes(video.get_ref())
+         .append_bytes(audio.get_ref())
+    });
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(input.get_ref()), &mut context).unwrap();
+    assert!(context.features.contains(super::MediaFeatures::HAS_CHAPTERS));
+    assert_eq!(context.tracks[1].auxiliary_for, vec![1]);
+    let input_len = input.get_ref().len();
+
+    let check = |output: &[u8], track_id: u32, samples: &[&[u8]], removed: usize| -> MediaContext {
+        let mut context = MediaContext::new();
+        read_mp4(&mut Cursor::new(output), &mut context).unwrap();
+        assert_eq!(context.tracks.len(), 1);
+        assert_eq!(context.tracks[0].track_id, Some(track_id));
+        assert_eq!(context.duration, Some(super::MediaScaledTime(samples.len() as u64)));
+        let table = context.tracks[0].sample_table().unwrap();
+        assert_eq!(table.len(), samples.len());
+        for (sample, data) in table.iter().zip(samples) {
+            assert_eq!(&output[sample.offset as usize..sample.offset as usize + 4], *data);
+        }
+        // The trak, its samples and the reference to it are gone, but
+        // nothing else.
+        assert_eq!(output.len(), input_len - removed);
+        let mdat_size = 8 + 4 * samples.len() as u8;
+        assert_eq!(&output[base as usize - 8..base as usize], &[0, 0, 0, mdat_size, b'm', b'd', b'a', b't']);
+        context
+    };
+    let mut output = Vec::new();
+    context.remove_track(&mut input, &mut output, 2).unwrap();
+    let removed = audio.get_ref().len() + 12 + 20;
+    let result = check(&output, 1, &[b"vid1", b"vid2"], removed);
+    assert!(!result.features.contains(super::MediaFeatures::HAS_CHAPTERS));
+    let mut output = Vec::new();
+    context.keep_only(&mut input, &mut output, &[2]).unwrap();
+    let removed = video.get_ref().len() + 20 + 8;
+    let result = check(&output, 2, &[b"aud1", b"aud2", b"aud3"], removed);
+    assert!(result.tracks[0].auxiliary_for.is_empty());
+
+    match context.remove_track(&mut input, &mut Vec::new(), 3) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "no track with the given ID"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match context.keep_only(&mut input, &mut Vec::new(), &[]) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "no tracks left"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match MediaContext::new().remove_track(&mut input, &mut Vec::new(), 2) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "tracks differ from the parsed context"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Samples shared between a kept and a removed track can't be cut.
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(make_trak_with_chunks(1, b"avc1", &[base, base + 8], None).get_ref())
+         .append_bytes(make_trak_with_chunks(2, b"mp4a", &[base + 6], None).get_ref())
+    });
+    let mut input = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov] {
+        input.get_mut().extend_from_slice(part.get_ref());
+    }
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(input.get_ref()), &mut context).unwrap();
+    match context.remove_track(&mut input, &mut Vec::new(), 2) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "removed track shares media data with a kept track"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn filter_track_references() {
+    use super::remux::filter_references;
+    let tref = make_box(BoxSize::Auto, b"tref", |s| {
+        s.append_bytes(make_box(BoxSize::Auto, b"cdsc", |s| s.B32(1).B32(2).B32(3)).get_ref())
+         .append_bytes(make_box(BoxSize::Auto, b"chap", |s| s.B32(2)).get_ref())
+    }).into_inner();
+    let filtered = filter_references(&tref[8..], |id| id == 2).unwrap();
+    assert_eq!(filtered, make_box(BoxSize::Auto, b"cdsc", |s| s.B32(1).B32(3)).into_inner());
+    assert!(filter_references(&tref[8..], |_| true).unwrap().is_empty());
+    assert_eq!(filter_references(&tref[8..], |_| false).unwrap(), &tref[8..]);
+    match filter_references(&tref[8..tref.len() - 2], |_| false) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "malformed tref"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn concatenate() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let base = ftyp.get_ref().len() as u64 + 8;
+    let make_file = |media: &[u8], video: &[u64], audio: &[u64], sync: Option<&[u32]>| {
+        let count = video.len() as u32;
+        let moov = make_box(BoxSize::Auto, b"moov", |s| {
+            s.append_bytes(make_mvhd(count).get_ref())
+             .append_bytes(make_trak_with_chunks(1, b"avc1", video, sync).get_ref())
+             .append_bytes(make_trak_with_chunks(2, b"mp4a", audio, None).get_ref())
+        });
+        let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_bytes(media));
+        let mut file = ftyp.get_ref().clone();
+        file.extend_from_slice(mdat.get_ref());
+        file.extend_from_slice(moov.get_ref());
+        file
+    };
+    // Video samples are in a chunk of their own, while the audio
+    // samples are contiguous, so form one chunk.
+    let first = make_file(b"vid1aud1aud2vid2", &[base, base + 12], &[base + 4, base + 8], Some(&[1]));
+    let second = make_file(b"VID1AUD1", &[base], &[base + 4], None);
+
+    let mut output = Vec::new();
+    super::concatenate(&mut Cursor::new(&first), &mut Cursor::new(&second), &mut output).unwrap();
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(&output), &mut context).unwrap();
+    assert_eq!(context.duration, Some(super::MediaScaledTime(3)));
+    for (track, samples) in context.tracks.iter().zip(&[[b"vid1", b"vid2", b"VID1"], [b"aud1", b"aud2", b"AUD1"]]) {
+        assert_eq!(track.duration, Some(super::TrackScaledTime(3, track.id)));
+        assert_eq!(track.edit_duration, Some(super::MediaScaledTime(3)));
+        assert_eq!(track.tkhd.as_ref().unwrap().duration, 3);
+        let table = track.sample_table().unwrap();
+        assert_eq!(table.iter().map(|s| s.decode_time).collect::<Vec<_>>(), vec![0, 1, 2]);
+        for (sample, data) in table.iter().zip(samples) {
+            assert_eq!(&output[sample.offset as usize..sample.offset as usize + 4], &data[..]);
+        }
+    }
+    let video = context.tracks[0].sample_table().unwrap();
+    assert_eq!(video.iter().map(|s| s.sync).collect::<Vec<_>>(), vec![true, false, true]);
+    assert_eq!(context.tracks[1].stco.as_ref().unwrap().offsets.len(), 2);
+
+    // A trailing mdat running to the end of the first file is given
+    // its size, so it ends before the second file's media data.
+    let moov = |base: u64| make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(make_mvhd(2).get_ref())
+         .append_bytes(make_trak_with_chunks(1, b"avc1", &[base, base + 8], None).get_ref())
+         .append_bytes(make_trak_with_chunks(2, b"mp4a", &[base + 4, base + 12], None).get_ref())
+    }).into_inner();
+    let mut trailing = ftyp.get_ref().clone();
+    trailing.extend(moov(base + moov(0).len() as u64));
+    trailing.extend_from_slice(b"\x00\x00\x00\x00mdatvid1aud1vid2aud2");
+    let mut output = Vec::new();
+    super::concatenate(&mut Cursor::new(&trailing), &mut Cursor::new(&second), &mut output).unwrap();
+    let boxes = super::TopLevelBoxIter::new(&mut Cursor::new(&output)).collect::<super::Result<Vec<_>>>().unwrap();
+    assert_eq!(boxes.iter().map(|b| b.name).collect::<Vec<_>>(),
+               vec![BoxType::FileTypeBox, BoxType::MovieBox, BoxType::MediaDataBox, BoxType::MediaDataBox]);
+    assert_eq!(boxes[2].size, 24);
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(&output), &mut context).unwrap();
+    let video = context.tracks[0].sample_table().unwrap();
+    assert_eq!(&output[video[2].offset as usize..video[2].offset as usize + 4], b"VID1");
+
+    // The second file must start with a sync sample.
+    let second = make_file(b"VID1AUD1", &[base], &[base + 4], Some(&[]));
+    match super::concatenate(&mut Cursor::new(&first), &mut Cursor::new(&second), &mut Vec::new()) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "concatenation at a non-sync sample"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Offsets into the second file must stay within range once moved
+    // after the first.
+    let second = make_file(b"VID1AUD1", &[std::u64::MAX - 10], &[base + 4], None);
+    match super::concatenate(&mut Cursor::new(&first), &mut Cursor::new(&second), &mut Vec::new()) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "sample offset overflow"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Codec configurations must match.
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(make_mvhd(1).get_ref())
+         .append_bytes(make_trak_with_chunks(1, b"hev1", &[base], None).get_ref())
+         .append_bytes(make_trak_with_chunks(2, b"mp4a", &[base + 4], None).get_ref())
+    });
+    let mut second = make_file(b"VID1AUD1", &[base], &[base + 4], None);
+    let moov_start = second.len() - moov.get_ref().len();
+    second.truncate(moov_start);
+    second.extend_from_slice(moov.get_ref());
+    match super::concatenate(&mut Cursor::new(&first), &mut Cursor::new(&second), &mut Vec::new()) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "concatenation of tracks with different sample descriptions"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn media_snapshot() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    let mut context = MediaContext::new();
+    context.tracks.push(make_sample_table_track());
+    let snapshot = context.freeze();
+    assert_send_sync(&snapshot);
+    let shared = snapshot.clone();
+    let samples = std::thread::spawn(move || shared.tracks[0].sample_table().unwrap().len())
+        .join()
+        .unwrap();
+    assert_eq!(samples, snapshot.tracks[0].sample_table().unwrap().len());
+}
+
+#[test]
+fn memory_usage() {
+    let mut context = MediaContext::new();
+    assert_eq!(context.memory_usage(), 0);
+    context.tracks.push(make_sample_table_track());
+    let usage = context.memory_usage();
+    // The track itself, six u32 sample sizes and two u64 chunk offsets.
+    assert!(usage >= std::mem::size_of::<super::Track>() + 6 * 4 + 2 * 8);
+    let mut other = MediaContext::new();
+    other.tracks.push(make_sample_table_track());
+    assert_eq!(other.memory_usage(), usage);
+}
+
+#[test]
+fn memory_usage_counts_owned_fields() {
+    let mut context = MediaContext::new();
+    context.tracks.push(super::Track::new(0));
+    let base = context.memory_usage();
+
+    context.tracks[0].protection_info = Some(super::ProtectionSchemeInfoBox {
+        original_format: None,
+        scheme_type: None,
+        tenc: Some(super::TrackEncryptionBox {
+            crypt_byte_block: 0,
+            skip_byte_block: 0,
+            is_encrypted: true,
+            iv_size: 0,
+            kid: vec![7; 16],
+            constant_iv: Some(vec![1; 8]),
+        }),
+    });
+    let with_tenc = context.memory_usage();
+    assert_eq!(with_tenc, base + 16 + 8);
+
+    context.tracks[0].data = Some(super::SampleEntry::Audio(super::AudioSampleEntry {
+        data_reference_index: 1,
+        channelcount: 2,
+        samplesize: 16,
+        samplerate: super::FixedPoint16_16(48000 << 16),
+        codec_specific: super::AudioCodecSpecific::FLACSpecificBox(super::FLACSpecificBox {
+            version: 0,
+            blocks: vec![super::FlacMetadataBlock {
+                block_type: 0,
+                last: true,
+                data: vec![0; 34],
+            }],
+        }),
+    }));
+    let with_flac = context.memory_usage();
+    assert_eq!(with_flac, with_tenc + std::mem::size_of::<super::FlacMetadataBlock>() + 34);
+
+    context.skipped_boxes.push(super::SkippedBox {
+        name: BoxType::EditListBox,
+        error: Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "short read")),
+    });
+    assert_eq!(context.memory_usage(),
+               with_flac + std::mem::size_of::<super::SkippedBox>() + "short read".len());
+}
+
+#[test]
+fn capture_unknown_boxes() {
+    let make_stream = || make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"abcd", |s| s.append_bytes(b"0123456789")).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"udta", |s| s).get_ref())
+    });
+    let mut context = MediaContext::new();
+    read_mp4(&mut make_stream(), &mut context).unwrap();
+    assert!(context.unknown_boxes.is_empty());
+
+    let mut context = MediaContext::new();
+    context.unknown_box_capture = 4;
+    read_mp4(&mut make_stream(), &mut context).unwrap();
+    assert_eq!(context.unknown_boxes.len(), 1);
+    assert_eq!(context.unknown_boxes[0].name, BoxType::UnknownBox(0x61626364));
+    assert_eq!(context.unknown_boxes[0].size, 18);
+    assert_eq!(context.unknown_boxes[0].payload, b"0123");
+}
+
+#[test]
+fn parse_random_access_index() {
+    let tfra = make_fullbox(BoxSize::Auto, b"tfra", 1, |s| {
+        s.B32(2) // track_ID
+         .B32(0x01) // 1 byte traf and trun numbers, 2 byte sample numbers
+         .B32(2) // number_of_entry
+         .B64(0).B64(100).B8(1).B8(1).B16(1)
+         .B64(9000).B64(5000).B8(1).B8(2).B16(300)
+    });
+    let mfra_size = tfra.get_ref().len() as u32 + 8 + 16;
+    let mfra = make_box(BoxSize::Auto, b"mfra", |s| {
+        s.append_bytes(&tfra.get_ref())
+         .append_bytes(&make_fullbox(BoxSize::Auto, b"mfro", 0, |s| s.B32(mfra_size)).get_ref())
+    });
+    let mut stream = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"iso6").B32(0));
+    assert_eq!(super::parse_random_access_index(&mut stream).unwrap(), None);
+    stream.get_mut().extend_from_slice(mfra.get_ref());
+    let index = super::parse_random_access_index(&mut stream).unwrap().unwrap();
+    assert_eq!(index.len(), 1);
+    assert_eq!(index[0].track_id, 2);
+    assert_eq!(index[0].entries[1], super::RandomAccessEntry {
+        time: 9000,
+        moof_offset: 5000,
+        traf_number: 1,
+        trun_number: 2,
+        sample_number: 300,
+    });
+
+    // An mfro pointing elsewhere is an error.
+    let len = stream.get_ref().len();
+    stream.get_mut()[len - 1] = 20;
+    match super::parse_random_access_index(&mut stream) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "mfro doesn't point at mfra"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn check_sample_bounds() {
+    let mut context = MediaContext::new();
+    context.tracks.push(make_sample_table_track());
+    // Without any mdat there's nothing to check against.
+    assert!(context.check_sample_bounds().is_empty());
+
+    context.media_data = vec![90..150, 1000..1100];
+    assert_eq!(context.check_sample_bounds(), vec![
+        super::SampleOutOfBounds { track: 0, sample: 2, range: 130..160, clipped: Some(130..150) },
+        super::SampleOutOfBounds { track: 0, sample: 5, range: 1090..1150, clipped: Some(1090..1100) },
+    ]);
+
+    // A sample spanning two mdats includes the second one's header.
+    context.media_data = vec![100..160, 1000..1040, 1048..1150];
+    assert_eq!(context.check_sample_bounds(), vec![
+        super::SampleOutOfBounds { track: 0, sample: 4, range: 1040..1090, clipped: None },
+    ]);
+}
+
+#[test]
+fn read_mp4_seekable_tail_moov() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.append_repeated(0, 64 * 1024));
+    let base = ftyp.get_ref().len() as u32 + 8;
+    let moov = make_moov_with_stco(&[base]);
+    let mut data = Vec::new();
+    for part in &[&ftyp, &mdat, &moov] {
+        data.extend_from_slice(part.get_ref());
+    }
+
+    let mut input = super::CountingReader::new(Cursor::new(&data));
+    let mut context = MediaContext::new();
+    super::read_mp4_seekable(&mut input, &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 1);
+    assert_eq!(context.tracks[0].stco.as_ref().unwrap().offsets, vec![base as u64]);
+    assert_eq!(context.media_data, vec![base as u64..base as u64 + 64 * 1024]);
+    // Only the box headers and non-mdat boxes are read.
+    assert!(input.counters().consumed < (data.len() - mdat.get_ref().len() + 16) as u64);
+
+    // A sequential parse has to read through the mdat.
+    let mut input = super::CountingReader::new(Cursor::new(&data));
+    read_mp4(&mut input, &mut MediaContext::new()).unwrap();
+    assert_eq!(input.counters().consumed, data.len() as u64);
+    assert!(input.counters().requested >= input.counters().consumed);
+
+    // The result matches a sequential parse.
+    let mut sequential = MediaContext::new();
+    read_mp4(&mut Cursor::new(&data), &mut sequential).unwrap();
+    assert!(super::compare(&context, &sequential).is_empty());
+
+    // A truncated file without a moov after the mdat fails as before.
+    let truncated = &data[..ftyp.get_ref().len() + mdat.get_ref().len()];
+    let mut context = MediaContext::new();
+    match super::read_mp4_seekable(&mut Cursor::new(truncated), &mut context) {
+        Err(Error::NoMoov) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn metadata_parse_mode() {
+    let mut stream = make_moov_with_stco(&[16, 32]);
+    let mut context = MediaContext::new();
+    context.mode = super::ParseMode::Metadata;
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 1);
+    assert!(context.tracks[0].stco.is_none());
+
+    stream.set_position(0);
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.tracks[0].stco.as_ref().unwrap().offsets, vec![16, 32]);
+}
+
+#[test]
+fn track_filter() {
+    let tkhd = make_tkhd(0x07);
+    let stco = make_fullbox(BoxSize::Auto, b"stco", 0, |s| s.B32(1).B32(16));
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| s.append_bytes(stco.get_ref()));
+    let minf = make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref()));
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| s.append_bytes(minf.get_ref()));
+    let trak = make_box(BoxSize::Auto, b"trak", |s| {
+        s.append_bytes(tkhd.get_ref()).append_bytes(mdia.get_ref())
+    });
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(trak.get_ref()));
+
+    let mut context = MediaContext::new();
+    context.track_filter = Some(vec![9]);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 1);
+    assert_eq!(context.tracks[0].track_id, Some(1));
+    assert!(context.tracks[0].stco.is_none());
+
+    stream.set_position(0);
+    let mut context = MediaContext::new();
+    context.track_filter = Some(vec![1]);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert!(context.tracks[0].stco.is_some());
+}
+
+#[test]
+fn track_filter_fragments() {
+    let traf = |track_id| make_box(BoxSize::Auto, b"traf", |s| {
+        s.append_bytes(make_box(BoxSize::Auto, b"tfhd", |s| s.B32(0).B32(track_id)).get_ref())
+         .append_bytes(make_box(BoxSize::Auto, b"trun", |s| {
+             s.B32(0x200) // sample_size_present
+              .B32(2).B32(10).B32(20)
+         }).get_ref())
+    });
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s);
+    let moof = make_box(BoxSize::Auto, b"moof", |s| {
+        s.append_bytes(traf(1).get_ref()).append_bytes(traf(2).get_ref())
+    });
+    stream.get_mut().extend_from_slice(moof.get_ref());
+
+    let mut context = MediaContext::new();
+    context.track_filter = Some(vec![2]);
+    read_mp4(&mut stream, &mut context).unwrap();
+    let tracks = &context.fragments[0].tracks;
+    assert_eq!(tracks[0].sample_count, 2);
+    assert!(tracks[0].samples.is_empty());
+    // The filtered track's data still comes first.
+    let offsets: Vec<u64> = tracks[1].samples.iter().map(|s| s.offset).collect();
+    assert_eq!(offsets, vec![8 + 30, 8 + 40]);
+}
+
+#[test]
+fn duplicate_track_ids() {
+    let mvhd = make_fullbox(BoxSize::Auto, b"mvhd", 0, |s| {
+        s.append_repeated(0, 8)
+         .B32(1000) // timescale
+         .B32(0) // duration
+         .append_repeated(0, 76)
+         .B32(2) // next track id
+    });
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(make_tkhd(0x07).get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(mvhd.get_ref())
+         .append_bytes(trak.get_ref())
+         .append_bytes(trak.get_ref())
+    });
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.next_track_id, Some(2));
+    assert_eq!(context.tracks.len(), 2);
+    assert_eq!(context.duplicate_track_ids, vec![1]);
+    assert!(context.track_by_id(1).is_none());
+    assert!(context.fragment_index(1).is_none());
+}
+
+#[test]
+fn moov_size_limit() {
+    let mut stream = make_moov_with_stco(&[16, 32]);
+    let size = stream.get_ref().len() as u64;
+    let mut context = MediaContext::new();
+    context.moov_size_limit = Some(size - 1);
+    match read_mp4(&mut stream, &mut context) {
+        Err(Error::MoovTooLarge(s)) => assert_eq!(s, size),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(context.tracks.is_empty());
+
+    stream.set_position(0);
+    let mut context = MediaContext::new();
+    context.moov_size_limit = Some(size);
+    read_mp4(&mut stream, &mut context).unwrap();
+}
+
+#[test]
+fn unsupported_features() {
+    let cmov = make_box(BoxSize::Auto, b"cmov", |s| s.B32(0));
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(cmov.get_ref()));
+    let mut context = MediaContext::new();
+    match read_mp4(&mut stream, &mut context) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "compressed moov"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(context.unsupported_features, vec![super::UnsupportedFeature {
+        feature: "compressed moov",
+        box_type: BoxType::CompressedMovieBox,
+    }]);
+
+    let schm = make_fullbox(BoxSize::Auto, b"schm", 0, |s| s.append_bytes(b"abcd").B32(0));
+    let mut stream = make_box(BoxSize::Auto, b"sinf", |s| s.append_bytes(schm.get_ref()));
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    match super::read_sinf(&mut stream) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown protection scheme"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn read_tenc_pattern() {
+    let mut stream = make_fullbox(BoxSize::Auto, b"tenc", 1, |s| {
+        s.B8(0)
+         .B8(0x19) // crypt and skip byte blocks
+         .B8(1)
+         .B8(0) // constant IV
+         .append_repeated(0xaa, 16)
+         .B8(16)
+         .append_repeated(0x55, 16)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let tenc = super::read_tenc(&mut stream).unwrap();
+    assert_eq!((tenc.crypt_byte_block, tenc.skip_byte_block), (1, 9));
+    assert!(tenc.is_encrypted);
+    assert_eq!(tenc.iv_size, 0);
+    assert_eq!(tenc.constant_iv, Some(vec![0x55; 16]));
+
+    // Version 0 has no pattern.
+    let mut stream = make_fullbox(BoxSize::Auto, b"tenc", 0, |s| {
+        s.B8(0).B8(0x19).B8(1).B8(8).append_repeated(0xaa, 16)
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    let tenc = super::read_tenc(&mut stream).unwrap();
+    assert_eq!((tenc.crypt_byte_block, tenc.skip_byte_block), (0, 0));
+    assert_eq!(tenc.constant_iv, None);
+}
+
+#[test]
+fn hint_tracks() {
+    let hdlr = make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+        s.B32(0).append_bytes(b"hint").B32(0).B32(0).B32(0).B8(0)
+    });
+    let rtp = make_box(BoxSize::Auto, b"rtp ", |s| s.append_repeated(0, 16));
+    let stsd = make_fullbox(BoxSize::Auto, b"stsd", 0, |s| s.B32(1).append_bytes(rtp.get_ref()));
+    let stbl = make_box(BoxSize::Auto, b"stbl", |s| s.append_bytes(stsd.get_ref()));
+    let minf = make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref()));
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| {
+        s.append_bytes(hdlr.get_ref()).append_bytes(minf.get_ref())
+    });
+    let trak = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(mdia.get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| s.append_bytes(trak.get_ref()));
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.tracks.len(), 1);
+    assert_eq!(context.tracks[0].track_type, super::TrackType::Hint);
+    assert_eq!(context.tracks[0].mime_type, "application/x-rtp-hint");
+    assert!(context.unsupported_features.is_empty());
+
+    stream.set_position(0);
+    let mut context = MediaContext::new();
+    context.skip_hint_tracks = true;
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert!(context.tracks.is_empty());
+}
+
+#[test]
+fn write_init_segment() {
+    let mut context = MediaContext::new();
+    let mut video = super::Track::new(0);
+    video.track_id = Some(1);
+    video.timescale = Some(super::TrackTimeScale(90000, 0));
+    video.mime_type = String::from("video/vp9");
+    video.data = Some(super::SampleEntry::Video(super::VideoSampleEntry {
+        name: BoxType::VP9SampleEntry,
+        data_reference_index: 1,
+        width: 640,
+        height: 360,
+        codec_specific: super::VideoCodecSpecific::VPxConfig(super::VPxConfigBox {
+            version: 0,
+            profile: 2,
+            level: 31,
+            bit_depth: 10,
+            color_space: 9,
+            chroma_subsampling: 1,
+            transfer_function: 3,
+            video_full_range: true,
+            colour_primaries: 2,
+            matrix_coefficients: 2,
+            codec_init: Vec::new(),
+        }),
+    }));
+    context.tracks.push(video);
+    let mut audio = super::Track::new(1);
+    audio.track_id = Some(3);
+    audio.timescale = Some(super::TrackTimeScale(48000, 1));
+    audio.mime_type = String::from("audio/opus");
+    audio.data = Some(super::SampleEntry::Audio(super::AudioSampleEntry {
+        data_reference_index: 1,
+        channelcount: 6,
+        samplesize: 16,
+        samplerate: super::FixedPoint16_16(48000 << 16),
+        codec_specific: super::AudioCodecSpecific::OpusSpecificBox(super::OpusSpecificBox {
+            version: 0,
+            output_channel_count: 6,
+            pre_skip: 312,
+            input_sample_rate: 48000,
+            output_gain: -3,
+            channel_mapping_family: 1,
+            channel_mapping_table: Some(super::ChannelMappingTable {
+                stream_count: 4,
+                coupled_count: 2,
+                channel_mapping: vec![0, 4, 1, 2, 3, 5],
+            }),
+        }),
+    }));
+    context.tracks.push(audio);
+    // Tracks without a sample entry are left out.
+    context.tracks.push(super::Track::new(2));
+
+    let mut init = Vec::new();
+    context.write_init_segment(&mut init).unwrap();
+    let mut parsed = MediaContext::new();
+    read_mp4(&mut Cursor::new(&init), &mut parsed).unwrap();
+    assert_eq!(parsed.brands, vec![0x69736f36, 0x69736f36, 0x6d703431]);
+    assert!(parsed.features().contains(super::MediaFeatures::FRAGMENTED));
+    assert_eq!(parsed.tracks.len(), 2);
+    assert_eq!(parsed.track_extends.len(), 2);
+
+    let video = &parsed.tracks[0];
+    assert_eq!(video.track_id, Some(1));
+    assert_eq!(video.track_type, super::TrackType::Video);
+    assert_eq!(video.timescale, Some(super::TrackTimeScale(90000, 0)));
+    assert_eq!(video.mime_type, "video/vp9");
+    assert!(video.enabled && video.in_movie);
+    assert_eq!(video.sample_table().unwrap().len(), 0);
+    match video.data {
+        Some(super::SampleEntry::Video(ref v)) => {
+            assert_eq!((v.width, v.height), (640, 360));
+            match v.codec_specific {
+                super::VideoCodecSpecific::VPxConfig(ref vpcc) => {
+                    assert_eq!((vpcc.profile, vpcc.level, vpcc.bit_depth), (2, 31, 10));
+                    assert_eq!((vpcc.color_space, vpcc.chroma_subsampling), (9, 1));
+                    assert_eq!(vpcc.transfer_function, 3);
+                    assert!(vpcc.video_full_range);
+                }
+                _ => panic!("expected vpcC"),
+            }
+        }
+        _ => panic!("expected video sample entry"),
+    }
+
+    let audio = &parsed.tracks[1];
+    assert_eq!(audio.track_id, Some(3));
+    assert_eq!(audio.track_type, super::TrackType::Audio);
+    assert_eq!(audio.mime_type, "audio/opus");
+    match audio.data {
+        Some(super::SampleEntry::Audio(ref a)) => {
+            assert_eq!(a.channelcount, 6);
+            assert_eq!(a.samplerate, super::FixedPoint16_16(48000 << 16));
+            match a.codec_specific {
+                super::AudioCodecSpecific::OpusSpecificBox(ref opus) => {
+                    assert_eq!(opus.pre_skip, 312);
+                    assert_eq!(opus.output_gain, -3);
+                    assert_eq!(opus.channel_mapping_table.as_ref().unwrap().channel_mapping,
+                               vec![0, 4, 1, 2, 3, 5]);
+                }
+                _ => panic!("expected dOps"),
+            }
+        }
+        _ => panic!("expected audio sample entry"),
+    }
+
+    // Protected tracks would need their sinf rewritten.
+    context.tracks[0].mime_type = String::from("video/crypto");
+    match context.write_init_segment(&mut Vec::new()) {
+        Err(Error::Unsupported(_)) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(MediaContext::new().write_init_segment(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn init_segment_sample_entry_name() {
+    let hvcc = [0x01, 0x02, 0x20, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x7b, 0xf0, 0x00, 0xfc, 0xfd, 0xfa, 0xfa, 0x00, 0x00, 0x0f, 0x00];
+    for &name in &[BoxType::HEVCSampleEntry, BoxType::HEV1SampleEntry] {
+        let mut context = MediaContext::new();
+        let mut track = super::Track::new(0);
+        track.track_id = Some(1);
+        track.timescale = Some(super::TrackTimeScale(90000, 0));
+        track.mime_type = String::from("video/hevc");
+        track.data = Some(super::SampleEntry::Video(super::VideoSampleEntry {
+            name: name,
+            data_reference_index: 1,
+            width: 1920,
+            height: 1080,
+            codec_specific: super::VideoCodecSpecific::HEVCConfig(hvcc.to_vec()),
+        }));
+        context.tracks.push(track);
+
+        let mut init = Vec::new();
+        context.write_init_segment(&mut init).unwrap();
+        let mut parsed = MediaContext::new();
+        read_mp4(&mut Cursor::new(&init), &mut parsed).unwrap();
+        match parsed.tracks[0].data {
+            Some(super::SampleEntry::Video(ref v)) => assert_eq!(v.name, name),
+            _ => panic!("expected video sample entry"),
+        }
+    }
+}
+
+#[test]
+fn check_mse() {
+    let mdat = make_box(BoxSize::Auto, b"mdat", |s| s.B32(0));
+    let trex = make_fullbox(BoxSize::Auto, b"trex", 0, |s| {
+        s.B32(1).B32(1).B32(100).B32(0).B32(0)
+    });
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"mvex", |s| {
+            s.append_bytes(&trex.get_ref())
+        }).get_ref())
+    });
+    let moof = make_box(BoxSize::Auto, b"moof", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"tfhd", 0, |s| s.B32(1)).get_ref())
+        }).get_ref())
+    });
+    let moof2 = make_box(BoxSize::Auto, b"moof", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"traf", |s| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"tfhd", 0, |s| s.B32(2)).get_ref())
+             .append_bytes(&make_fullbox(BoxSize::Auto, b"tfdt", 0, |s| s.B32(0)).get_ref())
+        }).get_ref())
+    });
+    let unknown = make_box(BoxSize::Auto, b"abcd", |s| s);
+    let free = make_box(BoxSize::Auto, b"free", |s| s);
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&mdat, &moov, &moof, &moof2, &unknown, &free] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+
+    let mut context = MediaContext::new();
+    let mut track = super::Track::new(0);
+    track.track_id = Some(1);
+    context.tracks.push(track);
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.check_mse(), vec![
+        super::MseViolation::MediaBeforeMovieBox,
+        super::MseViolation::MissingDecodeTime { fragment: 0 },
+        super::MseViolation::UnknownTrack { fragment: 1, track_id: 2 },
+        super::MseViolation::UnexpectedBox { name: BoxType::UnknownBox(0x61626364) },
+    ]);
+
+    let mut context = MediaContext::new();
+    let mut track = super::Track::new(0);
+    track.stts = Some(super::TimeToSampleBox {
+        samples: vec![super::Sample { sample_count: 1, sample_delta: 100 }],
+    });
+    context.tracks.push(track);
+    assert_eq!(context.check_mse(), vec![
+        super::MseViolation::NotFragmented,
+        super::MseViolation::SamplesInMovieBox { track: 0 },
+    ]);
+}
+
+#[test]
+fn select_boxes() {
+    let avc1 = make_box(BoxSize::Auto, b"avc1", |s| {
+        s.append_repeated(0, 78)
+         .append_bytes(&make_box(BoxSize::Auto, b"avcC", |s| s.append_bytes(b"config")).get_ref())
+    });
+    let trak = |entry: &[u8]| make_box(BoxSize::Auto, b"trak", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"tkhd", |s| s).get_ref())
+         .append_bytes(&make_box(BoxSize::Auto, b"stsd", |s| {
+            s.B32(0).B32(1).append_bytes(entry)
+        }).get_ref())
+    });
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let moov = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(&trak(&make_box(BoxSize::Auto, b"mp4a", |s| s.append_repeated(0, 28)).get_ref()).get_ref())
+         .append_bytes(&trak(avc1.get_ref()).get_ref())
+    });
+    let mut stream = Cursor::new(Vec::new());
+    stream.get_mut().extend_from_slice(ftyp.get_ref());
+    stream.get_mut().extend_from_slice(moov.get_ref());
+
+    let traks = super::select_boxes(&mut stream, "moov/trak").unwrap();
+    assert_eq!(traks.len(), 2);
+    assert_eq!(traks[0].position, ftyp.get_ref().len() as u64 + 8);
+    assert_eq!(super::select_boxes(&mut stream, "moov/*/tkhd").unwrap().len(), 2);
+    assert_eq!(super::select_boxes(&mut stream, "moov/trak[3]").unwrap(), vec![]);
+
+    let avcc = super::select_boxes(&mut stream, "/moov/trak[2]/stsd/avc1/avcC").unwrap();
+    assert_eq!(avcc.len(), 1);
+    assert_eq!(avcc[0].name, BoxType::AVCConfigurationBox);
+    assert_eq!(avcc[0].size, 14);
+    let mut payload = Vec::new();
+    std::io::Read::read_to_end(&mut avcc[0].payload(&mut stream).unwrap(), &mut payload).unwrap();
+    assert_eq!(payload, b"config");
+
+    // Boxes on other paths are matched in file order.
+    let entries = super::select_boxes(&mut stream, "moov/trak/stsd/*").unwrap();
+    assert_eq!(entries.iter().map(|b| b.name).collect::<Vec<_>>(),
+               vec![BoxType::MP4AudioSampleEntry, BoxType::AVCSampleEntry]);
+    assert_eq!(super::select_boxes(&mut stream, "/").unwrap(), vec![]);
+
+    assert!(super::select_boxes(&mut stream, "moov/trak[0]").is_err());
+    assert!(super::select_boxes(&mut stream, "moov/trak[1").is_err());
+    assert!(super::select_boxes(&mut stream, "moov/tr").is_err());
+}
+
+#[test]
+fn top_level_box_iter() {
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let mdat = make_box(BoxSize::Long(24), b"mdat", |s| s.append_bytes(b"mediadat"));
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s);
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&ftyp, &mdat, &moov] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+    let boxes: Vec<_> = super::TopLevelBoxIter::new(&mut stream)
+        .map(|b| b.unwrap())
+        .map(|b| (b.name, b.position, b.size, b.header_size))
+        .collect();
+    assert_eq!(boxes, vec![
+        (BoxType::FileTypeBox, 0, 16, 8),
+        (BoxType::MediaDataBox, 16, 24, 16),
+        (BoxType::MovieBox, 40, 8, 8),
+    ]);
+
+    // Payloads aren't read, so a box running past the end of the
+    // input is still listed.
+    stream.get_mut().extend_from_slice(&[0, 0, 1, 0, b'f', b'r', b'e', b'e']);
+    assert_eq!(super::TopLevelBoxIter::new(&mut stream).count(), 4);
+
+    stream.get_mut().truncate(40);
+    stream.get_mut().extend_from_slice(&[0, 0, 0, 2, b'm', b'o', b'o', b'v']);
+    let mut iter = super::TopLevelBoxIter::new(&mut stream).skip(2);
+    match iter.next() {
+        Some(Err(Error::InvalidData(s))) => assert_eq!(s, "malformed size"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn top_level_box_iter_size_zero() {
+    // A final box of size 0 runs to the end of the input.
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"isom").B32(0));
+    let moov = make_box(BoxSize::Auto, b"moov", |s| s);
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&ftyp, &moov] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+    stream.get_mut().extend_from_slice(&[0, 0, 0, 0, b'm', b'd', b'a', b't']);
+    stream.get_mut().extend_from_slice(b"mediadata");
+    let boxes: Vec<_> = super::TopLevelBoxIter::new(&mut stream)
+        .map(|b| b.unwrap())
+        .map(|b| (b.name, b.position, b.size, b.header_size))
+        .collect();
+    assert_eq!(boxes, vec![
+        (BoxType::FileTypeBox, 0, 16, 8),
+        (BoxType::MovieBox, 16, 8, 8),
+        (BoxType::MediaDataBox, 24, 17, 8),
+    ]);
+
+    let mdat = super::select_boxes(&mut stream, "mdat").unwrap();
+    assert_eq!(mdat.len(), 1);
+    assert_eq!(mdat[0].payload_range(), 32..41);
+
+    // The moov already precedes the media data, so it's copied as is.
+    let mut output = Vec::new();
+    super::relocate_moov_to_front(&mut stream, &mut output).unwrap();
+    assert_eq!(&output, stream.get_ref());
+}
+
+#[test]
+fn box_hexdump() {
+    let free = make_box(BoxSize::Auto, b"free", |s| s.append_bytes(b"Hello, world!\x00\x01\x7f and more"));
+    let mut stream = Cursor::new(free.into_inner());
+    let node = super::select_boxes(&mut stream, "free").unwrap()[0];
+    assert_eq!(node.hexdump(&mut stream, 64).unwrap(),
+               "00000008  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 00 01 7f  |Hello, world!...|\n\
+                00000018  20 61 6e 64 20 6d 6f 72  65                       | and more|\n");
+    assert_eq!(node.hexdump(&mut stream, 4).unwrap(),
+               "00000008  48 65 6c 6c                                       |Hell|\n\
+                ... 21 more bytes\n");
+}
+
+#[test]
+fn read_pssh() {
+    let widevine = make_fullbox(BoxSize::Auto, b"pssh", 0, |s| {
+        s.append_repeated(0xed, 16)
+         .B32(3)
+         .append_bytes(&[1, 2, 3])
+    });
+    let clearkey = make_fullbox(BoxSize::Auto, b"pssh", 1, |s| {
+        s.append_repeated(0x10, 16)
+         .B32(2)
+         .append_repeated(0xaa, 16)
+         .append_repeated(0xbb, 16)
+         .B32(0)
+    });
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(widevine.get_ref())
+         .append_bytes(clearkey.get_ref())
+    });
+    let mut context = MediaContext::new();
+    super::read_mp4(&mut stream, &mut context).unwrap();
+    assert!(context.features().contains(super::MediaFeatures::ENCRYPTED));
+    assert_eq!(context.psshs.len(), 2);
+    assert_eq!(context.psshs[0].system_id, vec![0xed; 16]);
+    assert!(context.psshs[0].kid.is_empty());
+    assert_eq!(context.psshs[0].data, vec![1, 2, 3]);
+    assert_eq!(context.psshs[0].box_content, *widevine.get_ref());
+    assert_eq!(context.psshs[1].kid, vec![vec![0xaa; 16], vec![0xbb; 16]]);
+    assert!(context.psshs[1].data.is_empty());
+    assert_eq!(context.psshs[1].box_content, *clearkey.get_ref());
+
+    // Data running past the end of the box.
+    let mut stream = make_box(BoxSize::Auto, b"moov", |s| {
+        s.append_bytes(make_fullbox(BoxSize::Auto, b"pssh", 0, |s| {
+            s.append_repeated(0, 16).B32(4).B8(0)
+        }).get_ref())
+    });
+    let mut context = MediaContext::new();
+    assert!(super::read_mp4(&mut stream, &mut context).is_err());
+}
+
+#[test]
+fn track_language_and_name() {
+    let mdhd = make_fullbox(BoxSize::Auto, b"mdhd", 0, |s| {
+        s.B32(0)
+         .B32(0)
+         .B32(1000) // timescale
+         .B32(0) // duration
+         .B16(0x15c7) // "eng"
+         .B16(0)
+    });
+    let hdlr = make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+        s.B32(0)
+         .append_bytes(b"soun")
+         .B32(0)
+         .B32(0)
+         .B32(0)
+         .append_bytes(b"SoundHandler")
+         .B8(0)
+    });
+    let titl = make_fullbox(BoxSize::Auto, b"titl", 0, |s| {
+        s.B16(0x15c7)
+         .append_bytes("Commentary \u{2014} English".as_bytes())
+         .B8(0)
+    });
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| {
+        s.append_bytes(mdhd.get_ref()).append_bytes(hdlr.get_ref())
+    });
+    let udta = make_box(BoxSize::Auto, b"udta", |s| s.append_bytes(titl.get_ref()));
+    let read_track = |trak: Cursor<Vec<u8>>| {
+        let mut stream = trak;
+        let mut iter = super::BoxIter::new(&mut stream);
+        let mut b = iter.next_box().unwrap().unwrap();
+        let mut track = super::Track::new(0);
+        let mut context = MediaContext::new();
+        super::read_trak(&mut b, &mut track, &mut context).unwrap();
+        track
+    };
+
+    let track = read_track(make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(mdia.get_ref())));
+    assert_eq!(track.language, Some(String::from("eng")));
+    assert_eq!(track.name, Some(String::from("SoundHandler")));
+
+    // A titl takes precedence over the hdlr name, wherever it appears.
+    let track = read_track(make_box(BoxSize::Auto, b"trak", |s| {
+        s.append_bytes(udta.get_ref()).append_bytes(mdia.get_ref())
+    }));
+    assert_eq!(track.name, Some(String::from("Commentary \u{2014} English")));
+
+    assert_eq!(super::decode_language(0x55c4), Some(String::from("und")));
+    // QuickTime language codes aren't mapped.
+    assert_eq!(super::decode_language(0), None);
+}
+
+#[test]
+fn read_utf8_coded_numbers() {
+    let read = |bytes: &[u8]| super::read_utf8_coded(&mut Cursor::new(bytes));
+    assert_eq!(read(&[0x00]).unwrap(), 0);
+    assert_eq!(read(&[0x7f]).unwrap(), 0x7f);
+    assert_eq!(read(&[0xc2, 0x80]).unwrap(), 0x80);
+    assert_eq!(read(&[0xe2, 0x82, 0xac]).unwrap(), 0x20ac);
+    // The seven byte form carries 36 bits.
+    assert_eq!(read(&[0xfe, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf]).unwrap(), 0xf_ffff_ffff);
+    assert!(read(&[0x80]).is_err());
+    assert!(read(&[0xff]).is_err());
+    assert!(read(&[0xc2, 0x00]).is_err());
+    match read(&[0xe2, 0x82]) {
+        Err(Error::UnexpectedEOF) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn read_leb128_numbers() {
+    let read = |bytes: &[u8]| super::read_leb128(&mut Cursor::new(bytes));
+    assert_eq!(read(&[0x00]).unwrap(), 0);
+    assert_eq!(read(&[0xe5, 0x8e, 0x26]).unwrap(), 624485);
+    // Padded encodings are allowed.
+    assert_eq!(read(&[0x81, 0x80, 0x00]).unwrap(), 1);
+    assert!(read(&[0x80; 9]).is_err());
+}
+
+#[test]
+fn bit_reader() {
+    let data = [0b1010_0000, 0xff, 0x01];
+    let mut bits = super::BitReader::new(&data);
+    assert_eq!(bits.remaining(), 24);
+    assert_eq!(bits.read_bool().unwrap(), true);
+    assert_eq!(bits.read_bits(2).unwrap(), 0b01);
+    assert_eq!(bits.read_bits(0).unwrap(), 0);
+    assert_eq!(bits.read_bits(13).unwrap(), 0x00ff);
+    assert_eq!(bits.read_bits(8).unwrap(), 1);
+    assert_eq!(bits.remaining(), 0);
+    match bits.read_bits(1) {
+        Err(Error::UnexpectedEOF) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(bits.read_bits(65).is_err());
+}
+
+#[test]
+fn bit_reader_exp_golomb() {
+    // ue(v) 0, 1, 2, 7 then se(v) 1, -1, 2 and a peek at the padding.
+    let data = [0b1010_0110, 0b0010_0001, 0b0011_0010, 0];
+    let mut bits = super::BitReader::new(&data);
+    assert_eq!(bits.read_ue().unwrap(), 0);
+    assert_eq!(bits.read_ue().unwrap(), 1);
+    assert_eq!(bits.read_ue().unwrap(), 2);
+    assert_eq!(bits.read_ue().unwrap(), 7);
+    assert_eq!(bits.read_se().unwrap(), 1);
+    assert_eq!(bits.read_se().unwrap(), -1);
+    assert_eq!(bits.read_se().unwrap(), 2);
+    assert_eq!(bits.peek_bits(3).unwrap(), 0b000);
+    assert_eq!(bits.remaining(), 7);
+    bits.skip_bits(7).unwrap();
+    assert!(bits.skip_bits(1).is_err());
+
+    // A run of zeros longer than any valid code.
+    let mut bits = super::BitReader::new(&[0; 8]);
+    assert!(bits.read_ue().is_err());
+}
+
+#[test]
+fn esds_descriptor() {
+    // AAC at 128 kbit/s peak and 64 kbit/s average, with an HE-AAC v2
+    // AudioSpecificConfig: 24 kHz mono core, 48 kHz output.
+    let esds = [0x03, 0x1a, 0x00, 0x01, 0x00,
+                0x04, 0x12, 0x40, 0x15, 0x00, 0x00, 0x00,
+                0x00, 0x01, 0xf4, 0x00, 0x00, 0x00, 0xfa, 0x00,
+                0x05, 0x03, 0xeb, 0x09, 0x88,
+                0x06, 0x01, 0x02];
+    let descriptor = super::parse_esds(&esds).unwrap();
+    assert_eq!(descriptor, super::ESDescriptor {
+        object_type_indication: 0x40,
+        max_bitrate: 128000,
+        avg_bitrate: 64000,
+        decoder_specific_info: &[0xeb, 0x09, 0x88],
+    });
+    assert_eq!(super::parse_audio_specific_config(descriptor.decoder_specific_info).unwrap(),
+               super::AudioSpecificConfig {
+                   audio_object_type: 2,
+                   extension_object_type: Some(29),
+                   sample_rate: 24000,
+                   extension_sample_rate: Some(48000),
+                   channel_configuration: 1,
+               });
+
+    // An explicit 44.1 kHz rate rather than an index.
+    let config = super::parse_audio_specific_config(&[0x17, 0x80, 0x56, 0x22, 0x10]).unwrap();
+    assert_eq!((config.audio_object_type, config.sample_rate, config.channel_configuration), (2, 44100, 2));
+    assert!(super::parse_audio_specific_config(&[0x16, 0x80]).is_err());
+
+    // The DecoderSpecificInfo is optional.
+    let esds = [0x03, 0x12, 0x00, 0x01, 0x00,
+                0x04, 0x0d, 0x6b, 0x15, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(super::parse_esds(&esds).unwrap().decoder_specific_info, &[]);
+    assert!(super::esds_audio_specific_config(&esds).is_err());
+    assert!(super::parse_esds(&esds[..10]).is_err());
+}
+
+#[test]
+fn avc_sps_cropping() {
+    // Constrained baseline 1080p: 1920x1088 coded, cropped by 8 lines.
+    let sps = [0x67, 0x42, 0xc0, 0x28, 0xf4, 0x03, 0xc0, 0x11, 0x3f, 0x2a];
+    let sps = super::parse_avc_sps(&sps).unwrap();
+    assert_eq!(sps.profile_idc, 66);
+    assert_eq!(sps.constraint_flags, 0xc0);
+    assert_eq!(sps.level_idc, 40);
+    assert_eq!((sps.coded_width, sps.coded_height), (1920, 1088));
+    assert_eq!((sps.width, sps.height), (1920, 1080));
+
+    // The first parameter set of a one SPS, no PPS avcC.
+    let mut avcc = vec![1, 0x42, 0xc0, 0x28, 0xff, 0xe1, 0, 10];
+    avcc.extend_from_slice(&[0x67, 0x42, 0xc0, 0x28, 0xf4, 0x03, 0xc0, 0x11, 0x3f, 0x2a, 0]);
+    assert_eq!(super::avcc_sequence_parameter_set(&avcc).unwrap(), Some(sps));
+    assert_eq!(super::parse_avcc(&avcc).unwrap(), super::AvcDecoderConfig {
+        profile_indication: 66,
+        profile_compatibility: 0xc0,
+        level_indication: 40,
+        nal_length_size: 4,
+    });
+    avcc.truncate(12);
+    assert!(super::avcc_sequence_parameter_set(&avcc).is_err());
+    assert!(super::parse_avcc(&avcc[..4]).is_err());
+    assert!(super::parse_avcc(&[0, 0x42, 0xc0, 0x28, 0xff]).is_err());
+
+    // A PPS isn't an SPS.
+    assert!(super::parse_avc_sps(&[0x68, 0xce, 0x3c, 0x80]).is_err());
+}
+
+#[test]
+fn sps_scaling_list_delta_range() {
+    // se(v) of i32::MAX, which overflows last_scale + delta_scale.
+    let data = [0x00, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xfc];
+    let mut bits = super::BitReader::new(&data);
+    match super::avc::skip_scaling_list(&mut bits, 16) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "SPS delta_scale out of range"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    // Deltas of -8 then 0 end the list early.
+    let mut bits = super::BitReader::new(&[0x08, 0x80]);
+    assert!(super::avc::skip_scaling_list(&mut bits, 16).is_ok());
+}
+
+#[test]
+fn av1_sequence_header() {
+    // Main profile 10 bit 1920x1080 at level 4.0, BT.2020 PQ, after a
+    // temporal delimiter.
+    let mut av1c = vec![0x81, 0x08, 0x4c, 0x00, 0x12, 0x00, 0x0a, 0x0e];
+    av1c.extend_from_slice(&[0x00, 0x00, 0x00, 0x42, 0xab, 0xbf, 0xc3, 0x77,
+                             0x09, 0xe7, 0x42, 0x44, 0x02, 0x41]);
+    let header = super::av1c_sequence_header(&av1c).unwrap().unwrap();
+    assert_eq!(header.seq_profile, 0);
+    assert!(!header.still_picture);
+    assert_eq!(header.seq_level_idx, 8);
+    assert_eq!(header.seq_tier, 0);
+    assert_eq!(header.bit_depth, 10);
+    assert!(header.subsampling_x && header.subsampling_y);
+    assert_eq!((header.color_primaries, header.transfer_characteristics, header.matrix_coefficients),
+               (9, 16, 9));
+    assert!(!header.full_range);
+    assert_eq!((header.max_frame_width, header.max_frame_height), (1920, 1080));
+    assert_eq!(header.codecs_string(), "av01.0.08M.10");
+
+    // A truncated OBU is an error; configOBUs without a sequence
+    // header are not.
+    assert!(super::av1c_sequence_header(&av1c[..av1c.len() - 1]).is_err());
+    assert_eq!(super::av1c_sequence_header(&av1c[..6]).unwrap(), None);
+
+    // High profile 8 bit 4:4:4 still picture, with the reduced header
+    // used by AVIF, in an OBU without a size field.
+    let obu = [0x08, 0x38, 0x15, 0x7f, 0xfc, 0x02, 0x40];
+    let header = super::find_av1_sequence_header(&obu).unwrap().unwrap();
+    assert_eq!(header.seq_profile, 1);
+    assert!(header.still_picture && header.reduced_still_picture_header);
+    assert_eq!(header.bit_depth, 8);
+    assert!(!header.subsampling_x && !header.subsampling_y);
+    assert!(header.full_range);
+    assert_eq!((header.max_frame_width, header.max_frame_height), (64, 64));
+    assert_eq!(header.codecs_string(), "av01.1.00M.08");
+}
+
+#[test]
+fn flac_stream_info() {
+    let mut stream = vec![0x66, 0x4c, 0x61, 0x43, 0x80, 0x00, 0x00, 0x22];
+    stream.extend_from_slice(&[0x10, 0x00, 0x10, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x1f, 0x40,
+                               0x0a, 0xc4, 0x42, 0xf0, 0x00, 0x06, 0xba, 0xa8]);
+    stream.extend((0..16).map(|i| i as u8));
+    let mut src = Cursor::new(&stream);
+    super::read_flac_stream_marker(&mut src).unwrap();
+    let block = super::read_flac_metadata_block(&mut src).unwrap();
+    assert_eq!(block.block_type, super::FLAC_METADATA_STREAMINFO);
+    assert!(block.last);
+    let info = super::parse_flac_stream_info(&block.data).unwrap();
+    assert_eq!((info.min_block_size, info.max_block_size), (4096, 4096));
+    assert_eq!((info.min_frame_size, info.max_frame_size), (14, 8000));
+    assert_eq!(info.sample_rate, 44100);
+    assert_eq!(info.channels, 2);
+    assert_eq!(info.bits_per_sample, 16);
+    assert_eq!(info.total_samples, 441000);
+    assert_eq!(info.md5[15], 15);
+
+    assert!(super::read_flac_stream_marker(&mut Cursor::new(b"OggS")).is_err());
+    assert!(super::parse_flac_stream_info(&block.data[..33]).is_err());
+}
+
+#[test]
+fn read_dfla() {
+    // A PADDING block alone.
+    let mut stream = make_fullbox(BoxSize::Auto, b"dfLa", 0, |s| s.B8(0x81).B8(0).B8(0).B8(2).B16(0));
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut stream = iter.next_box().unwrap().unwrap();
+    assert_eq!(stream.head.name, BoxType::FLACSpecificBox);
+    match super::read_dfla(&mut stream) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "dfLa without STREAMINFO"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn flac_frame_header() {
+    let header = [0xff, 0xf8, 0xc9, 0x18, 0x00, 0xc2];
+    let header = super::read_flac_frame_header(&mut Cursor::new(&header)).unwrap();
+    assert!(!header.variable_block_size);
+    assert_eq!(header.block_size, 4096);
+    assert_eq!(header.sample_rate, Some(44100));
+    assert_eq!(header.channel_assignment, super::FlacChannelAssignment::Independent(2));
+    assert_eq!(header.bits_per_sample, Some(16));
+    assert_eq!(header.number, 0);
+
+    // Explicit block size and sample rate, coded sample number.
+    let data = [0xff, 0xf9, 0x7d, 0xa0, 0xe2, 0x82, 0xac, 0x03, 0xe7, 0x30, 0x39, 0x39];
+    let header = super::read_flac_frame_header(&mut Cursor::new(&data)).unwrap();
+    assert!(header.variable_block_size);
+    assert_eq!(header.block_size, 1000);
+    assert_eq!(header.sample_rate, Some(12345));
+    assert_eq!(header.channel_assignment, super::FlacChannelAssignment::MidSide);
+    assert_eq!(header.channel_assignment.channels(), 2);
+    assert_eq!(header.bits_per_sample, None);
+    assert_eq!(header.number, 0x20ac);
+
+    let mut corrupt = data;
+    corrupt[11] ^= 1;
+    assert!(super::read_flac_frame_header(&mut Cursor::new(&corrupt)).is_err());
+}
+
+/// Build an Ogg page from lacing segments of at most 255 bytes.
+fn make_ogg_page(serial: u32, header_type: u8, segments: &[&[u8]]) -> Vec<u8> {
+    use byteorder::{ByteOrder, LittleEndian};
+    let mut page = b"OggS".to_vec();
+    page.push(0);
+    page.push(header_type);
+    page.extend_from_slice(&[0; 8]);
+    let mut fields = [0; 12];
+    LittleEndian::write_u32(&mut fields[..4], serial);
+    page.extend_from_slice(&fields);
+    page.push(segments.len() as u8);
+    page.extend(segments.iter().map(|s| s.len() as u8));
+    for segment in segments {
+        page.extend_from_slice(segment);
+    }
+    let crc = super::elementary::ogg_crc(&page);
+    LittleEndian::write_u32(&mut page[22..26], crc);
+    page
+}
+
+#[test]
+fn ogg_flac_headers() {
+    let mut first = vec![0x7f, b'F', b'L', b'A', b'C', 1, 0, 0, 1];
+    first.extend_from_slice(b"fLaC");
+    first.extend_from_slice(&[0x00, 0x00, 0x00, 0x22]);
+    first.extend_from_slice(&[0x10, 0x00, 0x10, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x1f, 0x40,
+                              0x0a, 0xc4, 0x42, 0xf0, 0x00, 0x06, 0xba, 0xa8]);
+    first.extend_from_slice(&[0; 16]);
+    // A 296 byte VORBIS_COMMENT block, split across two pages.
+    let mut comment = vec![0x84, 0x00, 0x01, 0x24];
+    comment.extend_from_slice(&[0x55; 0x124]);
+    let frame = [0xff, 0xf8, 0xc9, 0x18, 0x00, 0xc2];
+
+    let mut stream = make_ogg_page(1, 0x02, &[&first]);
+    // A page from another multiplexed stream.
+    stream.extend(make_ogg_page(2, 0x02, &[b"other"]));
+    stream.extend(make_ogg_page(1, 0, &[&comment[..255]]));
+    stream.extend(make_ogg_page(1, 0x05, &[&comment[255..], &frame]));
+
+    let mut packets = super::OggPacketReader::new(Cursor::new(&stream));
+    let blocks = super::read_ogg_flac_headers(&mut packets).unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(super::parse_flac_stream_info(&blocks[0].data).unwrap().sample_rate, 44100);
+    assert_eq!(blocks[1].block_type, 4);
+    assert!(blocks[1].last);
+    assert_eq!(blocks[1].data.len(), 0x124);
+    let audio = packets.next_packet().unwrap().unwrap();
+    assert_eq!(super::read_flac_frame_header(&mut Cursor::new(&audio)).unwrap().block_size, 4096);
+    assert_eq!(packets.next_packet().unwrap(), None);
+
+    // A corrupt page fails its CRC.
+    let mut corrupt = stream.clone();
+    corrupt[40] ^= 1;
+    let mut packets = super::OggPacketReader::new(Cursor::new(&corrupt));
+    assert!(super::read_ogg_flac_headers(&mut packets).is_err());
+
+    // Other codecs are rejected.
+    let opus = make_ogg_page(1, 0x02, &[b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00"]);
+    let mut packets = super::OggPacketReader::new(Cursor::new(&opus));
+    assert!(super::read_ogg_flac_headers(&mut packets).is_err());
+}
+
+/// Build a top-level 'meta' for an image whose items are stored one
+/// after another in the 'idat'. The first item is the primary item.
+/// `extra` boxes are appended to the meta.
+fn make_item_meta(items: &[(u32, &[u8; 4], &[u8])],
+                  references: &[(&[u8; 4], u32, &[u32])],
+                  extra: &[Cursor<Vec<u8>>]) -> Cursor<Vec<u8>> {
+    let hdlr = make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+        s.B32(0).append_bytes(b"pict").append_repeated(0, 12).append_bytes(b"\0")
+    });
+    let pitm = make_fullbox(BoxSize::Auto, b"pitm", 0, |s| s.B16(items[0].0 as u16));
+    let iinf = make_fullbox(BoxSize::Auto, b"iinf", 0, |s| {
+        items.iter().fold(s.B16(items.len() as u16), |s, &(id, item_type, _)| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"infe", 2, |s| {
+                s.B16(id as u16).B16(0).append_bytes(item_type).append_bytes(b"\0")
+            }).into_inner())
+        })
+    });
+    let iloc = make_fullbox(BoxSize::Auto, b"iloc", 1, |s| {
+        // 4 byte offsets and lengths, no base offset or index.
+        let mut offset = 0;
+        items.iter().fold(s.B16(0x4400).B16(items.len() as u16), |s, &(id, _, data)| {
+            offset += data.len() as u32;
+            s.B16(id as u16).B16(1).B16(0).B16(1).B32(offset - data.len() as u32).B32(data.len() as u32)
+        })
+    });
+    let iref = make_fullbox(BoxSize::Auto, b"iref", 0, |s| {
+        references.iter().fold(s, |s, &(reference_type, from, to)| {
+            s.append_bytes(&make_box(BoxSize::Auto, reference_type, |s| {
+                to.iter().fold(s.B16(from as u16).B16(to.len() as u16), |s, &id| s.B16(id as u16))
+            }).into_inner())
+        })
+    });
+    let idat = make_box(BoxSize::Auto, b"idat", |s| {
+        items.iter().fold(s, |s, &(_, _, data)| s.append_bytes(data))
+    });
+    make_fullbox(BoxSize::Auto, b"meta", 0, |s| {
+        let s = s.append_bytes(hdlr.get_ref())
+            .append_bytes(pitm.get_ref())
+            .append_bytes(iinf.get_ref())
+            .append_bytes(iloc.get_ref())
+            .append_bytes(iref.get_ref())
+            .append_bytes(idat.get_ref());
+        extra.iter().fold(s, |s, b| s.append_bytes(b.get_ref()))
+    })
+}
+
+#[test]
+fn item_thumbnails() {
+    let meta = make_item_meta(&[(1, b"av01", b"primary"), (2, b"av01", b"thumb")],
+                              &[(b"thmb", 2, &[1])], &[]);
+    let ftyp = make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(b"avif").B32(0));
+    let mut stream = Cursor::new(Vec::new());
+    stream.get_mut().extend_from_slice(ftyp.get_ref());
+    stream.get_mut().extend_from_slice(meta.get_ref());
+
+    // Image files have no moov.
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let meta = context.item_metadata.as_ref().unwrap();
+    assert_eq!(meta.handler_type, 0x70696374); // "pict"
+    assert_eq!(meta.primary_item().unwrap().item_type, 0x61763031); // "av01"
+    assert_eq!(meta.items.len(), 2);
+    assert_eq!(meta.primary_item_thumbnails(), vec![2]);
+    assert_eq!(meta.thumbnails(2), vec![]);
+    assert_eq!(meta.read_item(&mut stream, 1).unwrap(), b"primary");
+    assert_eq!(meta.read_item(&mut stream, 2).unwrap(), b"thumb");
+    assert!(meta.read_item(&mut stream, 3).is_err());
+
+    // Extents given as file offsets.
+    let mut meta = meta.clone();
+    meta.locations[1].construction_method = 0;
+    meta.locations[1].base_offset = 4;
+    meta.locations[1].extents[0] = super::ItemExtent { offset: 4, length: 4 };
+    assert_eq!(meta.read_item(&mut stream, 2).unwrap(), b"avif");
+    meta.locations[1].extents[0].length = 10000;
+    match meta.read_item(&mut stream, 2) {
+        Err(Error::UnexpectedEOF) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn item_repeated_idat_extents() {
+    let meta = make_item_meta(&[(1, b"av01", b"primary")], &[], &[]);
+    let mut stream = meta.clone();
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+
+    // Every extent covers the whole of a full size idat.
+    let mut meta = context.item_metadata.unwrap();
+    meta.item_data = vec![0; 1024 * 1024];
+    meta.locations[0].extents = vec![super::ItemExtent { offset: 0, length: 0 }; 0xffff];
+    match meta.read_item(&mut stream, 1) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "item exceeds ITEM_SIZE_LIMIT"),
+        r => panic!("unexpected result {:?}", r.map(|data| data.len())),
+    }
+}
+
+#[test]
+fn exif() {
+    let tiff = b"II*\0\x08\0\0\0";
+    let mut item = vec![0, 0, 0, 6];
+    item.extend_from_slice(b"Exif\0\0");
+    item.extend_from_slice(tiff);
+    let meta = make_item_meta(&[(1, b"hvc1", b"image"), (2, b"Exif", &item), (3, b"Exif", b"\0\0\0\x10")],
+                              &[(b"cdsc", 2, &[1]), (b"cdsc", 3, &[1]), (b"thmb", 1, &[2])], &[]);
+    let uuid = make_box(BoxSize::Auto, b"uuid", |s| s.append_bytes(b"JpgTiffExif->JP2").append_bytes(tiff));
+    let other = make_box(BoxSize::Auto, b"uuid", |s| s.append_repeated(0, 16).B32(0));
+    let mut stream = Cursor::new(Vec::new());
+    for part in &[&uuid, &other, &meta] {
+        stream.get_mut().extend_from_slice(part.get_ref());
+    }
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    assert_eq!(context.exif, vec![tiff.to_vec()]);
+    let meta = context.item_metadata.as_ref().unwrap();
+    assert_eq!(meta.exif_items(1), vec![2, 3]);
+    assert_eq!(meta.read_primary_item_exif(&mut stream).unwrap(), Some(tiff.to_vec()));
+    match meta.read_exif(&mut stream, 3) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "Exif TIFF header offset past end of item"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn auxiliary_images() {
+    let auxc = |urn: &str| make_fullbox(BoxSize::Auto, b"auxC", 0, |s| s.append_bytes(urn.as_bytes()).B8(0).B8(0x11));
+    let iprp = make_box(BoxSize::Auto, b"iprp", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"ipco", |s| {
+            s.append_bytes(&make_box(BoxSize::Auto, b"colr", |s| s.B32(0)).into_inner())
+             .append_bytes(&auxc("urn:mpeg:mpegB:cicp:systems:auxiliary:alpha").into_inner())
+             .append_bytes(&auxc("urn:mpeg:hevc:2015:auxid:2").into_inner())
+             .append_bytes(&auxc("urn:example:gain-map").into_inner())
+        }).into_inner())
+        .append_bytes(&make_fullbox(BoxSize::Auto, b"ipma", 0, |s| {
+            s.B32(4)
+             .B16(1).B8(1).B8(0x81)
+             .B16(2).B8(2).B8(0x81).B8(0x82)
+             .B16(3).B8(1).B8(0x83)
+             .B16(4).B8(1).B8(0x04)
+        }).into_inner())
+    });
+    let meta = make_item_meta(&[(1, b"av01", b"color"), (2, b"av01", b"alpha"), (3, b"hvc1", b"depth"), (4, b"av01", b"gain")],
+                              &[(b"auxl", 2, &[1]), (b"auxl", 3, &[1]), (b"auxl", 4, &[1])], &[iprp]);
+    let mut stream = meta;
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let meta = context.item_metadata.as_ref().unwrap();
+    assert_eq!(meta.properties.len(), 4);
+    assert_eq!(meta.properties[0], super::ItemProperty::Unsupported(BoxType::UnknownBox(0x636f6c72)));
+    assert_eq!(meta.item_properties(2).len(), 2);
+    assert_eq!(meta.item_properties(2)[1], &super::ItemProperty::AuxiliaryType(super::AuxiliaryType::Alpha));
+    assert_eq!(meta.alpha_item(1), Some(2));
+    assert_eq!(meta.depth_item(1), Some(3));
+    assert_eq!(meta.auxiliary_items(1)[2], (4, &super::AuxiliaryType::Other(String::from("urn:example:gain-map"))));
+    assert_eq!(meta.alpha_item(2), None);
+
+    // An alpha video track, auxiliary to track 1.
+    let tref = make_box(BoxSize::Auto, b"tref", |s| s.append_bytes(&make_box(BoxSize::Auto, b"auxl", |s| s.B32(1)).into_inner()));
+    let hdlr = make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+        s.B32(0).append_bytes(b"auxv").append_repeated(0, 12).B8(0)
+    });
+    let mdia = make_box(BoxSize::Auto, b"mdia", |s| s.append_bytes(hdlr.get_ref()));
+    let mut stream = make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(tref.get_ref()).append_bytes(mdia.get_ref()));
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut b = iter.next_box().unwrap().unwrap();
+    let mut track = super::Track::new(0);
+    super::read_trak(&mut b, &mut track, &mut context).unwrap();
+    assert_eq!(track.track_type, super::TrackType::Video);
+    assert_eq!(track.auxiliary_for, vec![1]);
+
+    let mut stream = make_box(BoxSize::Auto, b"avc1", |s| {
+        s.append_repeated(0, 6)
+         .B16(1)
+         .append_repeated(0, 16)
+         .B16(320)
+         .B16(240)
+         .append_repeated(0, 14)
+         .append_repeated(0, 32)
+         .append_repeated(0, 4)
+         .append_bytes(&make_box(BoxSize::Auto, b"avcC", |s| s.append_repeated(0, 7)).into_inner())
+         .append_bytes(&make_fullbox(BoxSize::Auto, b"auxi", 0, |s| {
+            s.append_bytes(b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha").B8(0)
+         }).into_inner())
+    });
+    let mut iter = super::BoxIter::new(&mut stream);
+    let mut b = iter.next_box().unwrap().unwrap();
+    super::read_video_desc(&mut b, &mut track, &mut context).unwrap();
+    assert_eq!(track.auxiliary_type, Some(super::AuxiliaryType::Alpha));
+    context.tracks.push(track);
+    assert_eq!(context.auxiliary_tracks(1).len(), 1);
+    assert_eq!(context.auxiliary_tracks(2).len(), 0);
+}
+
+#[test]
+fn pixel_information_and_premultiplied_alpha() {
+    let iprp = make_box(BoxSize::Auto, b"iprp", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"ipco", |s| {
+            s.append_bytes(&make_fullbox(BoxSize::Auto, b"pixi", 0, |s| s.B8(3).B8(10).B8(10).B8(10)).into_inner())
+             .append_bytes(&make_fullbox(BoxSize::Auto, b"pixi", 0, |s| s.B8(1).B8(8)).into_inner())
+             .append_bytes(&make_fullbox(BoxSize::Auto, b"auxC", 0, |s| {
+                s.append_bytes(b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha").B8(0)
+             }).into_inner())
+        }).into_inner())
+        .append_bytes(&make_fullbox(BoxSize::Auto, b"ipma", 0, |s| {
+            s.B32(4)
+             .B16(1).B8(1).B8(0x01)
+             .B16(2).B8(2).B8(0x02).B8(0x83)
+             .B16(3).B8(1).B8(0x01)
+             .B16(4).B8(2).B8(0x02).B8(0x83)
+        }).into_inner())
+    });
+    // Item 1's colour is premultiplied by its alpha, item 3's isn't.
+    let meta = make_item_meta(&[(1, b"av01", b"a"), (2, b"av01", b"b"), (3, b"av01", b"c"), (4, b"av01", b"d")],
+                              &[(b"auxl", 2, &[1]), (b"prem", 1, &[2]), (b"auxl", 4, &[3]), (b"prem", 2, &[1])],
+                              &[iprp]);
+    let mut stream = meta;
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let meta = context.item_metadata.as_ref().unwrap();
+    assert_eq!(meta.pixel_bit_depths(1), Some(&[10, 10, 10][..]));
+    assert_eq!(meta.pixel_bit_depths(2), Some(&[8][..]));
+    assert_eq!(meta.pixel_bit_depths(5), None);
+    assert!(meta.is_alpha_premultiplied(1));
+    assert!(!meta.is_alpha_premultiplied(2));
+    assert!(!meta.is_alpha_premultiplied(3));
+}
+
+#[test]
+fn parse_image_entry_points() {
+    let ftyp = |major: &[u8; 4], compatible: &[u8; 4]| {
+        make_box(BoxSize::Auto, b"ftyp", |s| s.append_bytes(major).B32(0).append_bytes(compatible))
+    };
+    let file = |parts: &[&Cursor<Vec<u8>>]| {
+        let mut stream = Cursor::new(Vec::new());
+        for part in parts {
+            stream.get_mut().extend_from_slice(part.get_ref());
+        }
+        stream
+    };
+    let av01 = make_item_meta(&[(1, b"av01", b"av1 data")], &[], &[]);
+    let hvc1 = make_item_meta(&[(1, b"hvc1", b"hevc data")], &[], &[]);
+
+    let avif = file(&[&ftyp(b"avif", b"mif1"), &av01]);
+    let context = super::parse_avif(&mut avif.clone()).unwrap();
+    assert_eq!(context.format, super::ImageFormat::Avif);
+    assert_eq!(context.major_brand, 0x61766966);
+    assert_eq!(context.primary_item_id, 1);
+    assert_eq!(context.read_primary_item(&mut avif.clone()).unwrap(), b"av1 data");
+    // Any AVIF file is also HEIF.
+    assert_eq!(super::parse_heif(&mut avif.clone()).unwrap().format, super::ImageFormat::Heif);
+
+    let heic = file(&[&ftyp(b"heic", b"mif1"), &hvc1]);
+    assert_eq!(super::parse_heif(&mut heic.clone()).unwrap().primary_item().unwrap().item_type,
+               0x68766331);
+    match super::parse_avif(&mut heic.clone()) {
+        Err(Error::IncompatibleBrand) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    // The avif brand with an HEVC primary item.
+    match super::parse_avif(&mut file(&[&ftyp(b"avif", b"mif1"), &hvc1])) {
+        Err(Error::InvalidData(_)) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    // No meta, so no primary item.
+    match super::parse_heif(&mut file(&[&ftyp(b"mif1", b"heic")])) {
+        Err(Error::NoPrimaryItem) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    // The brands have to come first.
+    match super::parse_heif(&mut file(&[&hvc1, &ftyp(b"mif1", b"heic")])) {
+        Err(Error::InvalidData(_)) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match super::parse_heif(&mut file(&[&ftyp(b"isom", b"mp42")])) {
+        Err(Error::IncompatibleBrand) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn image_grid() {
+    let grid = [0, 0, 1, 2, 0x0f, 0x00, 0x0b, 0x40];
+    let wide_grid = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0x80, 0];
+    let meta = make_item_meta(&[(1, b"grid", &grid),
+                                (2, b"hvc1", b"a"), (3, b"hvc1", b"b"), (4, b"hvc1", b"c"),
+                                (5, b"hvc1", b"d"), (6, b"hvc1", b"e"), (7, b"hvc1", b"f"),
+                                (8, b"grid", &wide_grid), (9, b"grid", &grid)],
+                              &[(b"dimg", 1, &[4, 2, 3, 7, 5, 6]), (b"dimg", 8, &[2]), (b"dimg", 9, &[2])], &[]);
+    let mut stream = meta;
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let meta = context.item_metadata.as_ref().unwrap();
+    assert_eq!(meta.image_grid(&mut stream, 1).unwrap(), Some(super::ImageGrid {
+        rows: 2,
+        columns: 3,
+        output_width: 3840,
+        output_height: 2880,
+        tiles: vec![4, 2, 3, 7, 5, 6],
+    }));
+    let grid = meta.image_grid(&mut stream, 8).unwrap().unwrap();
+    assert_eq!((grid.rows, grid.columns, grid.output_width, grid.output_height), (1, 1, 65536, 32768));
+    assert_eq!(meta.image_grid(&mut stream, 2).unwrap(), None);
+    match meta.image_grid(&mut stream, 9) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "grid tile count doesn't match its size"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn image_transform() {
+    use super::{CleanAperture, CropRect, ImageOrientation, MirrorAxis};
+    let clap = make_box(BoxSize::Auto, b"clap", |s| {
+        s.B32(60).B32(1).B32(40).B32(1).B32(10).B32(1).B32(-10i32 as u32).B32(2)
+    });
+    let irot = |angle| make_box(BoxSize::Auto, b"irot", |s| s.B8(angle));
+    let imir = |axis| make_box(BoxSize::Auto, b"imir", |s| s.B8(axis));
+    let iprp = make_box(BoxSize::Auto, b"iprp", |s| {
+        s.append_bytes(&make_box(BoxSize::Auto, b"ipco", |s| {
+            s.append_bytes(clap.get_ref())
+             .append_bytes(irot(1).get_ref())
+             .append_bytes(imir(0).get_ref())
+             .append_bytes(imir(1).get_ref())
+             .append_bytes(irot(3).get_ref())
+        }).into_inner())
+        .append_bytes(&make_fullbox(BoxSize::Auto, b"ipma", 0, |s| {
+            s.B32(3)
+             .B16(1).B8(3).B8(0x81).B8(0x82).B8(0x83)
+             .B16(2).B8(2).B8(0x84).B8(0x85)
+             .B16(3).B8(2).B8(0x82).B8(0x81)
+        }).into_inner())
+    });
+    let meta = make_item_meta(&[(1, b"hvc1", b"a"), (2, b"hvc1", b"b"), (3, b"hvc1", b"c"), (4, b"hvc1", b"d")],
+                              &[], &[iprp]);
+    let mut stream = meta;
+    let mut context = MediaContext::new();
+    read_mp4(&mut stream, &mut context).unwrap();
+    let meta = context.item_metadata.as_ref().unwrap();
+
+    // Rotated a quarter turn anticlockwise, then swapped left to right.
+    let transform = meta.primary_item_transform().unwrap().unwrap();
+    assert_eq!(transform.orientation, ImageOrientation { rotation: 90, mirror: true });
+    assert_eq!(transform.orientation.exif_orientation(), 7);
+    let clap = transform.clean_aperture.unwrap();
+    assert_eq!(clap.crop_rect(100, 80), Some(CropRect { x: 30, y: 15, width: 60, height: 40 }));
+    assert_eq!(clap.crop_rect(50, 80), None);
+    let half_pixel = CleanAperture { width_n: 61, ..clap };
+    assert_eq!(half_pixel.crop_rect(100, 80), None);
+    let huge = CleanAperture { width_n: 0xffffffff, width_d: 1, horizontal_offset_d: 0xffffffff, ..clap };
+    assert_eq!(huge.crop_rect(0xffffffff, 80), None);
+    assert_eq!(huge.crop_rect(0, 80), None);
+
+    // Swapped top to bottom, then turned clockwise: a transpose.
+    let orientation = meta.image_transform(2).unwrap().orientation;
+    assert_eq!(orientation, ImageOrientation { rotation: 270, mirror: true });
+    assert_eq!(orientation.exif_orientation(), 5);
+    assert_eq!(meta.item_properties(2)[0], &super::ItemProperty::Mirror(MirrorAxis::Horizontal));
+
+    // The crop has to come first.
+    assert!(meta.image_transform(3).is_err());
+    assert_eq!(meta.image_transform(4).unwrap(), Default::default());
+}
+
+#[test]
+fn invalid_pascal_string() {
+    // String claims to be 32 bytes long (we provide 33 bytes to account for
+    // the 1 byte length prefix).
+    let pstr = "\x20xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+    let mut stream = Cursor::new(pstr);
+    // Reader wants to limit the total read length to 32 bytes, so any
+    // returned string must be no longer than 31 bytes.
+    let s = super::read_fixed_length_pascal_string(&mut stream, 32).unwrap();
+    assert_eq!(s.len(), 31);
+}
+
+#[cfg(feature = "decrypt")]
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect()
+}
+
+#[cfg(feature = "decrypt")]
+#[test]
+fn decrypt_sample() {
+    // The AES-128 CTR and CBC examples from NIST SP 800-38A.
+    let mut key = [0; 16];
+    key.copy_from_slice(&from_hex("2b7e151628aed2a6abf7158809cf4f3c"));
+    let plain = from_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+    let ctr = from_hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff");
+    let cbc = from_hex("7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2");
+    let crypto = |iv: &str, subsamples: &[(u16, u32)]| super::SampleCrypto {
+        kid: vec![0; 16],
+        iv: from_hex(iv),
+        subsamples: subsamples.iter()
+            .map(|&(clear, encrypted)| super::Subsample { clear_bytes: clear, encrypted_bytes: encrypted })
+            .collect(),
+    };
+    let decrypt = |data: &[u8], crypto: &super::SampleCrypto, scheme: &[u8; 4], pattern: (u8, u8)| {
+        let mut data = data.to_vec();
+        let scheme = scheme.iter().fold(0, |code, &b| code << 8 | b as u32);
+        super::decrypt_sample(&mut data, crypto, scheme, pattern.0, pattern.1, &key).map(|_| data)
+    };
+    let ctr_iv = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+    let cbc_iv = "000102030405060708090a0b0c0d0e0f";
+
+    // A whole sample, and the same bytes split by clear subsample data,
+    // which the counter runs on across.
+    assert_eq!(decrypt(&ctr, &crypto(ctr_iv, &[]), b"cenc", (0, 0)).unwrap(), plain);
+    let split = [&b"head"[..], &ctr[..20], b"mid", &ctr[20..]].concat();
+    assert_eq!(decrypt(&split, &crypto(ctr_iv, &[(4, 20), (3, 12)]), b"cenc", (0, 0)).unwrap(),
+               [&b"head"[..], &plain[..20], b"mid", &plain[20..]].concat());
+
+    // 'cbc1' chains across subsamples, leaving a trailing partial block
+    // clear.
+    let split = [&b"head"[..], &cbc[..16], b"tail", &cbc[16..], b"end"].concat();
+    assert_eq!(decrypt(&split, &crypto(cbc_iv, &[(4, 20), (0, 19)]), b"cbc1", (0, 0)).unwrap(),
+               [&b"head"[..], &plain[..16], b"tail", &plain[16..], b"end"].concat());
+
+    // 'cbcs' restarts from the IV in each subsample and skips blocks
+    // by the pattern, chaining across the encrypted ones.
+    let skipped = [0x55; 16];
+    let pattern = [&cbc[..16], &skipped[..], &cbc[16..]].concat();
+    assert_eq!(decrypt(&pattern, &crypto(cbc_iv, &[(0, 48)]), b"cbcs", (1, 1)).unwrap(),
+               [&plain[..16], &skipped[..], &plain[16..]].concat());
+    let repeated = [&cbc[..16], &cbc[..16]].concat();
+    assert_eq!(decrypt(&repeated, &crypto(cbc_iv, &[(0, 16), (0, 16)]), b"cbcs", (1, 9)).unwrap(),
+               [&plain[..16], &plain[..16]].concat());
+
+    match decrypt(&ctr, &crypto(ctr_iv, &[(0, 33)]), b"cenc", (0, 0)) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "subsamples exceed sample size"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match decrypt(&ctr, &crypto(ctr_iv, &[]), b"abcd", (0, 0)) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "unknown protection scheme"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[cfg(feature = "decrypt")]
+#[test]
+fn decrypt_to_clear() {
+    let key = super::ContentKey {
+        kid: [7; 16],
+        key: {
+            let mut key = [0; 16];
+            key.copy_from_slice(&from_hex("2b7e151628aed2a6abf7158809cf4f3c"));
+            key
+        },
+    };
+    let plain = from_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+    let ctr = from_hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff");
+
+    // One AVC sample with a clear NAL length ahead of two encrypted
+    // blocks, in an mdat following the moov. `groups` is appended to
+    // the stbl.
+    let make_moov = |offset: u32, groups: &[u8]| {
+        let sinf = make_box(BoxSize::Auto, b"sinf", |s| {
+            s.append_bytes(make_box(BoxSize::Auto, b"frma", |s| s.append_bytes(b"avc1")).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"schm", 0, |s| s.append_bytes(b"cenc").B32(0x10000)).get_ref())
+             .append_bytes(make_box(BoxSize::Auto, b"schi", |s| {
+                 s.append_bytes(make_fullbox(BoxSize::Auto, b"tenc", 0, |s| {
+                     s.B16(0).B8(1).B8(16).append_repeated(7, 16)
+                 }).get_ref())
+             }).get_ref())
+        });
+        let encv = make_box(BoxSize::Auto, b"encv", |s| {
+            s.append_repeated(0, 6)
+             .B16(1)
+             .append_repeated(0, 16)
+             .B16(320)
+             .B16(240)
+             .append_repeated(0, 50)
+             .append_bytes(make_box(BoxSize::Auto, b"avcC", |s| s.append_bytes(b"config")).get_ref())
+             .append_bytes(sinf.get_ref())
+        });
+        let mut senc = make_fullbox(BoxSize::Auto, b"senc", 0, |s| {
+            s.B32(1).append_bytes(&from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")).B16(1).B16(4).B32(32)
+        });
+        // The subsample flag is in the fullbox header.
+        senc.get_mut()[11] = 0x2;
+        let stbl = make_box(BoxSize::Auto, b"stbl", |s| {
+            s.append_bytes(make_fullbox(BoxSize::Auto, b"stsd", 0, |s| s.B32(1).append_bytes(encv.get_ref())).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stts", 0, |s| s.B32(1).B32(1).B32(10)).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stsc", 0, |s| s.B32(1).B32(1).B32(1).B32(1)).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stsz", 0, |s| s.B32(0).B32(1).B32(36)).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stco", 0, |s| s.B32(1).B32(offset)).get_ref())
+             .append_bytes(senc.get_ref())
+             .append_bytes(groups)
+        });
+        let mdia = make_box(BoxSize::Auto, b"mdia", |s| {
+            s.append_bytes(make_fullbox(BoxSize::Auto, b"mdhd", 0, |s| {
+                 s.B32(0).B32(0).B32(1000).B32(10).B16(0x55c4).B16(0)
+             }).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+                 s.B32(0).append_bytes(b"vide").B32(0).B32(0).B32(0).B8(0)
+             }).get_ref())
+             .append_bytes(make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref())).get_ref())
+        });
+        let pssh = make_fullbox(BoxSize::Auto, b"pssh", 0, |s| s.append_repeated(1, 16).B32(0));
+        make_box(BoxSize::Auto, b"moov", |s| {
+            s.append_bytes(make_box(BoxSize::Auto, b"trak", |s| s.append_bytes(mdia.get_ref())).get_ref())
+             .append_bytes(pssh.get_ref())
+        })
+    };
+    let offset = make_moov(0, &[]).get_ref().len() as u32 + 8;
+    let mut input = make_moov(offset, &[]).into_inner();
+    input.extend_from_slice(make_box(BoxSize::Auto, b"mdat", |s| {
+        s.B32(32).append_bytes(&ctr)
+    }).get_ref());
+
+    let mut output = Vec::new();
+    super::decrypt_to_clear(&mut Cursor::new(&input), &mut output, &[key.clone()]).unwrap();
+    assert_eq!(output.len(), input.len());
+    assert_eq!(&output[offset as usize..], &[&[0, 0, 0, 32][..], &plain[..]].concat()[..]);
+
+    let mut context = MediaContext::new();
+    read_mp4(&mut Cursor::new(&output), &mut context).unwrap();
+    assert_eq!(context.tracks[0].mime_type, "video/avc");
+    assert!(context.tracks[0].protection_info.is_none());
+    assert!(context.tracks[0].senc.is_none());
+    assert!(context.psshs.is_empty());
+
+    let other = super::ContentKey { kid: [8; 16], key: key.key };
+    match super::decrypt_to_clear(&mut Cursor::new(&input), &mut Vec::new(), &[other]) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "no key for a sample's key ID"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // A 'seig' group giving the sample a 1:1 pattern, which the 'tenc'
+    // doesn't have, leaves the second block clear.
+    let sgpd = make_fullbox(BoxSize::Auto, b"sgpd", 1, |s| {
+        s.append_bytes(b"seig").B32(20).B32(1).B8(0).B8(0x11).B8(1).B8(16).append_repeated(7, 16)
+    });
+    let sbgp = make_fullbox(BoxSize::Auto, b"sbgp", 0, |s| s.append_bytes(b"seig").B32(1).B32(1).B32(1));
+    let groups = [sgpd.into_inner(), sbgp.into_inner()].concat();
+    let offset = make_moov(0, &groups).get_ref().len() as u32 + 8;
+    let mut input = make_moov(offset, &groups).into_inner();
+    input.extend_from_slice(make_box(BoxSize::Auto, b"mdat", |s| {
+        s.B32(32).append_bytes(&ctr[..16]).append_bytes(&plain[16..])
+    }).get_ref());
+    let mut output = Vec::new();
+    super::decrypt_to_clear(&mut Cursor::new(&input), &mut output, &[key.clone()]).unwrap();
+    assert_eq!(&output[offset as usize..], &[&[0, 0, 0, 32][..], &plain[..]].concat()[..]);
+}
+
+#[cfg(feature = "encrypt")]
+#[test]
+fn encrypt_to_cenc() {
+    let key = super::ContentKey { kid: [7; 16], key: [0x2b; 16] };
+    let iv = [0xf0; 16];
+    // Two AVC samples, the first with a clear parameter set ahead of its
+    // slice, and an AAC sample, in an mdat after the moov.
+    let video_samples = [
+        [&[0, 0, 0, 5, 0x67][..], &[1; 4], &[0, 0, 0, 41, 0x65], &[2; 40]].concat(),
+        [&[0, 0, 0, 21, 0x41][..], &[3; 20]].concat(),
+    ];
+    let audio_sample = vec![4; 20];
+    let make_trak = |id: u32, handler: &[u8; 4], entry: Cursor<Vec<u8>>, sizes: &[u32], offsets: &[u32]| {
+        let count = sizes.len() as u32;
+        let stbl = make_box(BoxSize::Auto, b"stbl", |s| {
+            s.append_bytes(make_fullbox(BoxSize::Auto, b"stsd", 0, |s| s.B32(1).append_bytes(entry.get_ref())).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stts", 0, |s| s.B32(1).B32(count).B32(10)).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stsc", 0, |s| s.B32(1).B32(1).B32(1).B32(1)).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stsz", 0, |s| {
+                 sizes.iter().fold(s.B32(0).B32(count), |s, size| s.B32(*size))
+             }).get_ref())
+             .append_bytes(make_fullbox(BoxSize::Auto, b"stco", 0, |s| {
+                 offsets.iter().fold(s.B32(count), |s, offset| s.B32(*offset))
+             }).get_ref())
+        });
+        make_box(BoxSize::Auto, b"trak", |s| {
+            s.append_bytes(make_fullbox(BoxSize::Auto, b"tkhd", 0, |s| {
+                 s.B32(0).B32(0).B32(id).B32(0).B32(count * 10).append_repeated(0, 60)
+             }).get_ref())
+             .append_bytes(make_box(BoxSize::Auto, b"mdia", |s| {
+                 s.append_bytes(make_fullbox(BoxSize::Auto, b"mdhd", 0, |s| {
+                      s.B32(0).B32(0).B32(1000).B32(count * 10).B16(0x55c4).B16(0)
+                  }).get_ref())
+                  .append_bytes(make_fullbox(BoxSize::Auto, b"hdlr", 0, |s| {
+                      s.B32(0).append_bytes(handler).B32(0).B32(0).B32(0).B8(0)
+                  }).get_ref())
+                  .append_bytes(make_box(BoxSize::Auto, b"minf", |s| s.append_bytes(stbl.get_ref())).get_ref())
+             }).get_ref())
+        })
+    };
+    let make_file = |base: u32| {
+        let avc1 = make_box(BoxSize::Auto, b"avc1", |s| {
+            s.append_repeated(0, 6)
+             .B16(1)
+             .append_repeated(0, 16)
+             .B16(320)
+             .B16(240)
+             .append_repeated(0, 50)
+             .append_bytes(make_box(BoxSize::Auto, b"avcC", |s| s.append_bytes(&[1, 0x42, 0, 0x1e, 0xff, 0xe0, 0])).get_ref())
+        });
+        let mp4a = make_box(BoxSize::Auto, b"mp4a", |s| {
+            s.append_repeated(0, 6)
+             .B16(1)
+             .B32(0)
+             .B32(0)
+             .B16(2)
+             .B16(16)
+             .B16(0)
+             .B16(0)
+             .B32(48000 << 16)
+             .append_bytes(make_fullbox(BoxSize::Auto, b"esds", 0, |s| s.append_repeated(0, 4)).get_ref())
+        });
+        let video_sizes: Vec<u32> = video_samples.iter().map(|s| s.len() as u32).collect();
+        let video = make_trak(1, b"vide", avc1, &video_sizes, &[base, base + video_sizes[0]]);
+        let audio = make_trak(2, b"soun", mp4a, &[20], &[base + video_sizes[0] + video_sizes[1]]);
+        let mut file = make_box(BoxSize::Auto, b"moov", |s| {
+            s.append_bytes(make_mvhd(2).get_ref()).append_bytes(video.get_ref()).append_bytes(audio.get_ref())
+        }).into_inner();
+        file.extend_from_slice(make_box(BoxSize::Auto, b"mdat", |s| {
+            s.append_bytes(&video_samples[0]).append_bytes(&video_samples[1]).append_bytes(&audio_sample)
+        }).get_ref());
+        file
+    };
+    let payload = video_samples.iter().map(|s| s.len()).sum::<usize>() + audio_sample.len();
+    let base = (make_file(0).len() - payload) as u32;
+    let input = make_file(base);
+    let pssh = super::ProtectionSystemSpecificHeaderBox {
+        system_id: vec![1; 16],
+        kid: vec![vec![7; 16]],
+        data: b"data".to_vec(),
+        box_content: Vec::new(),
+    };
+
+    for scheme in &[b"cenc", b"cbcs"] {
+        let scheme_type = scheme.iter().fold(0, |code, &b| code << 8 | b as u32);
+        let mut output = Vec::new();
+        super::encrypt_to_cenc(&mut Cursor::new(&input), &mut output, scheme_type,
+                               &[(1, key.clone()), (2, key.clone())], &iv, &[pssh.clone()]).unwrap();
+
+        let mut context = MediaContext::new();
+        context.mode = super::ParseMode::Full;
+        read_mp4(&mut Cursor::new(&output), &mut context).unwrap();
+        assert_eq!(context.psshs.len(), 1);
+        assert_eq!(context.psshs[0].kid, pssh.kid);
+        assert_eq!(context.psshs[0].data, pssh.data);
+        for track in &context.tracks {
+            let sinf = track.protection_info.as_ref().unwrap();
+            assert_eq!(sinf.scheme_type, Some(scheme_type));
+            assert_eq!(sinf.tenc.as_ref().unwrap().kid, vec![7; 16]);
+        }
+        assert_eq!(context.tracks[0].mime_type, "video/crypto");
+        assert_eq!(context.tracks[0].protection_info.as_ref().unwrap().original_format,
+                   Some(BoxType::AVCSampleEntry));
+        let video = context.tracks[0].samples.as_ref().unwrap();
+        let audio = context.tracks[1].samples.as_ref().unwrap();
+        let subsamples = |sample: &super::FullSample| {
+            sample.crypto.as_ref().unwrap().subsamples.iter()
+                .map(|s| (s.clear_bytes, s.encrypted_bytes))
+                .collect::<Vec<_>>()
+        };
+        // The parameter set, NAL lengths and headers, and the part of
+        // each slice short of a whole block stay clear.
+        assert_eq!(subsamples(&video[0]), vec![(9 + 13, 32)]);
+        assert_eq!(subsamples(&video[1]), vec![(9, 16)]);
+        assert!(audio[0].crypto.as_ref().unwrap().subsamples.is_empty());
+        if *scheme == b"cenc" {
+            assert_eq!(video[0].crypto.as_ref().unwrap().iv, vec![0xf0; 8]);
+            assert_eq!(video[1].crypto.as_ref().unwrap().iv, [&[0xf0; 7][..], &[0xf1]].concat());
+        } else {
+            assert_eq!(video[0].crypto.as_ref().unwrap().iv, vec![0xf0; 16]);
+            let tenc = context.tracks[0].protection_info.as_ref().unwrap().tenc.as_ref().unwrap();
+            assert_eq!((tenc.crypt_byte_block, tenc.skip_byte_block), (1, 9));
+        }
+
+        // The samples moved with the grown moov, and the 'saio' points
+        // at the first sample's entry in the 'senc'.
+        let offset = video[0].info.offset as usize;
+        assert_eq!(&output[offset..offset + 22], &video_samples[0][..22]);
+        assert!(output[offset + 22..offset + 54] != video_samples[0][22..]);
+        let mut stream = Cursor::new(&output);
+        let saio = super::select_boxes(&mut stream, "moov/trak[1]/mdia/minf/stbl/saio").unwrap();
+        let mut payload = Vec::new();
+        std::io::Read::read_to_end(&mut saio[0].payload(&mut stream).unwrap(), &mut payload).unwrap();
+        let senc_entry = payload[8..].iter().fold(0, |offset, &b| offset << 8 | b as usize);
+        let mut first_entry = vec![0, 1, 0, 22, 0, 0, 0, 32];
+        if *scheme == b"cenc" {
+            first_entry = [&[0xf0; 8][..], &first_entry].concat();
+        }
+        assert_eq!(&output[senc_entry..senc_entry + first_entry.len()], &first_entry[..]);
+
+        let mut clear = Vec::new();
+        super::decrypt_to_clear(&mut Cursor::new(&output), &mut clear, &[key.clone()]).unwrap();
+        let samples = video.iter().chain(audio.iter()).map(|s| {
+            clear[s.info.offset as usize..s.info.offset as usize + s.info.size as usize].to_vec()
+        }).collect::<Vec<_>>();
+        assert_eq!(samples, vec![video_samples[0].clone(), video_samples[1].clone(), audio_sample.clone()]);
+    }
+
+    match super::encrypt_to_cenc(&mut Cursor::new(&input), &mut Vec::new(), 0x63656e73,
+                                 &[(1, key.clone())], &iv, &[]) {
+        Err(Error::Unsupported(s)) => assert_eq!(s, "encryption scheme other than cenc or cbcs"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match super::encrypt_to_cenc(&mut Cursor::new(&input), &mut Vec::new(), 0x63656e63,
+                                 &[(3, key.clone())], &iv, &[]) {
+        Err(Error::InvalidData(s)) => assert_eq!(s, "no track with the given ID"),
+        r => panic!("unexpected result {:?}", r),
+    }
 }
@@ -0,0 +1,216 @@
+//! Transformative item properties, which change how an image item is
+//! displayed after decoding.
+//!
+//! An item may be cropped by a 'clap', rotated by an 'irot' and
+//! mirrored by an 'imir'. They apply in the order the 'ipma' lists
+//! them, which must put any crop first. Cameras record their
+//! orientation this way, so ignoring them shows photos sideways.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::Read;
+use byteorder::ReadBytesExt;
+
+use BMFFBox;
+use Error;
+use ItemMetadata;
+use ItemProperty;
+use Result;
+use be_i32;
+use be_u32;
+
+/// Axis an 'imir' mirrors the image about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MirrorAxis {
+    /// Swaps left and right.
+    Vertical,
+    /// Swaps top and bottom.
+    Horizontal,
+}
+
+/// A 'clap' clean aperture, as fractions of pixels.
+///
+/// The aperture is centred on the image, moved by the offsets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CleanAperture {
+    pub width_n: u32,
+    pub width_d: u32,
+    pub height_n: u32,
+    pub height_d: u32,
+    pub horizontal_offset_n: i32,
+    pub horizontal_offset_d: u32,
+    pub vertical_offset_n: i32,
+    pub vertical_offset_d: u32,
+}
+
+/// A rectangle of whole pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The net rotation and mirroring of an image.
+///
+/// The image is rotated first, then mirrored if `mirror` is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImageOrientation {
+    /// Anticlockwise rotation in degrees: 0, 90, 180 or 270.
+    pub rotation: u16,
+    /// Swap left and right after rotating.
+    pub mirror: bool,
+}
+
+/// How to display an image item after decoding it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImageTransform {
+    /// Crop to apply before the orientation, if any.
+    pub clean_aperture: Option<CleanAperture>,
+    pub orientation: ImageOrientation,
+}
+
+/// `num / den` if it's a whole number.
+fn exact(num: i64, den: i64) -> Option<i64> {
+    if den != 0 && num % den == 0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+impl CleanAperture {
+    /// The crop within a `width` by `height` image, or None if the
+    /// aperture doesn't fall on whole pixels inside it.
+    pub fn crop_rect(&self, width: u32, height: u32) -> Option<CropRect> {
+        fn edge(size: u32, n: u32, d: u32, offset_n: i32, offset_d: u32) -> Option<(u32, u32)> {
+            let size = size as i64;
+            exact(n as i64, d as i64).and_then(|length| {
+                // offset + (size - length) / 2, over a common denominator.
+                (size - length).checked_mul(offset_d as i64)
+                    .and_then(|centre| centre.checked_add(2 * offset_n as i64))
+                    .and_then(|start| exact(start, 2 * offset_d as i64))
+                    .map(|start| (start, length))
+            }).and_then(|(start, length)| {
+                if length > 0 && start >= 0 && start + length <= size {
+                    Some((start as u32, length as u32))
+                } else {
+                    None
+                }
+            })
+        }
+        let x = edge(width, self.width_n, self.width_d, self.horizontal_offset_n, self.horizontal_offset_d);
+        let y = edge(height, self.height_n, self.height_d, self.vertical_offset_n, self.vertical_offset_d);
+        match (x, y) {
+            (Some((x, width)), Some((y, height))) => Some(CropRect { x: x, y: y, width: width, height: height }),
+            _ => None,
+        }
+    }
+}
+
+impl ImageOrientation {
+    /// Follow this orientation with an anticlockwise rotation of
+    /// `quarter_turns` times 90 degrees.
+    fn rotate(self, quarter_turns: u16) -> ImageOrientation {
+        let turns = self.rotation / 90;
+        // Rotating after a mirror turns the other way before it.
+        let turns = if self.mirror { turns + 4 - quarter_turns } else { turns + quarter_turns };
+        ImageOrientation { rotation: turns % 4 * 90, mirror: self.mirror }
+    }
+
+    /// Follow this orientation with a mirror about `axis`.
+    fn mirror(self, axis: MirrorAxis) -> ImageOrientation {
+        let turned = match axis {
+            MirrorAxis::Vertical => self,
+            // Swapping top and bottom is a half turn and a left-right swap.
+            MirrorAxis::Horizontal => self.rotate(2),
+        };
+        ImageOrientation { rotation: turned.rotation, mirror: !turned.mirror }
+    }
+
+    /// The equivalent Exif 'Orientation' tag value, from 1 to 8.
+    pub fn exif_orientation(&self) -> u8 {
+        match (self.rotation, self.mirror) {
+            (90, false) => 8,
+            (180, false) => 3,
+            (270, false) => 6,
+            (0, true) => 2,
+            (90, true) => 7,
+            (180, true) => 4,
+            (270, true) => 5,
+            _ => 1,
+        }
+    }
+}
+
+/// Parse an 'irot' item property, returning the anticlockwise
+/// rotation in degrees.
+pub fn read_irot<T: Read>(src: &mut BMFFBox<T>) -> Result<u16> {
+    let angle = try!(src.read_u8()) & 0x03;
+    Ok(angle as u16 * 90)
+}
+
+/// Parse an 'imir' item property.
+pub fn read_imir<T: Read>(src: &mut BMFFBox<T>) -> Result<MirrorAxis> {
+    match try!(src.read_u8()) & 0x01 {
+        0 => Ok(MirrorAxis::Vertical),
+        _ => Ok(MirrorAxis::Horizontal),
+    }
+}
+
+/// Parse a 'clap' item property.
+pub fn read_clap<T: Read>(src: &mut BMFFBox<T>) -> Result<CleanAperture> {
+    Ok(CleanAperture {
+        width_n: try!(be_u32(src)),
+        width_d: try!(be_u32(src)),
+        height_n: try!(be_u32(src)),
+        height_d: try!(be_u32(src)),
+        horizontal_offset_n: try!(be_i32(src)),
+        horizontal_offset_d: try!(be_u32(src)),
+        vertical_offset_n: try!(be_i32(src)),
+        vertical_offset_d: try!(be_u32(src)),
+    })
+}
+
+impl ItemMetadata {
+    /// The crop and orientation to display `item_id` with, combining
+    /// its transformative properties in order.
+    pub fn image_transform(&self, item_id: u32) -> Result<ImageTransform> {
+        let mut transform = ImageTransform::default();
+        let mut oriented = false;
+        for property in self.item_properties(item_id) {
+            match *property {
+                ItemProperty::CleanAperture(clap) => {
+                    if oriented {
+                        return Err(Error::InvalidData("clap must precede irot and imir"));
+                    }
+                    if transform.clean_aperture.is_some() {
+                        return Err(Error::InvalidData("item has more than one clap"));
+                    }
+                    transform.clean_aperture = Some(clap);
+                }
+                ItemProperty::Rotation(degrees) => {
+                    transform.orientation = transform.orientation.rotate(degrees / 90);
+                    oriented = true;
+                }
+                ItemProperty::Mirror(axis) => {
+                    transform.orientation = transform.orientation.mirror(axis);
+                    oriented = true;
+                }
+                _ => (),
+            }
+        }
+        Ok(transform)
+    }
+
+    /// The crop and orientation of the primary item, if there is one.
+    pub fn primary_item_transform(&self) -> Result<Option<ImageTransform>> {
+        match self.primary_item_id {
+            Some(id) => self.image_transform(id).map(Some),
+            None => Ok(None),
+        }
+    }
+}
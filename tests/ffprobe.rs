@@ -0,0 +1,323 @@
+//! Differential tests comparing track information with ffprobe.
+//!
+//! Set MP4PARSE_FFPROBE_CORPUS to a directory of mp4 files to run
+//! these. Each file is parsed and the per-track results compared with
+//! what ffprobe reports; the test is skipped if the variable isn't set
+//! or ffprobe isn't available.
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+extern crate mp4parse;
+
+use std::env;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+
+/// A value from ffprobe's JSON output. Numbers are kept as their text
+/// so they can be compared exactly.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    /// The value as ffprobe would print it in its other formats.
+    fn text(&self) -> Option<String> {
+        match *self {
+            Json::Bool(b) => Some(b.to_string()),
+            Json::Number(ref n) | Json::String(ref n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Just enough of a JSON parser for ffprobe's output.
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.text.len() && (self.text[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+        self.text.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Option<Json> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.expect(b'}').is_some() {
+                    return Some(Json::Object(members));
+                }
+                loop {
+                    let key = match self.peek() {
+                        Some(b'"') => self.string(),
+                        _ => None,
+                    };
+                    let key = match key {
+                        Some(key) => key,
+                        None => return None,
+                    };
+                    if self.expect(b':').is_none() {
+                        return None;
+                    }
+                    let value = match self.value() {
+                        Some(value) => value,
+                        None => return None,
+                    };
+                    members.push((key, value));
+                    if self.expect(b',').is_none() {
+                        return self.expect(b'}').map(|_| Json::Object(members));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = Vec::new();
+                if self.expect(b']').is_some() {
+                    return Some(Json::Array(elements));
+                }
+                loop {
+                    match self.value() {
+                        Some(value) => elements.push(value),
+                        None => return None,
+                    }
+                    if self.expect(b',').is_none() {
+                        return self.expect(b']').map(|_| Json::Array(elements));
+                    }
+                }
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.text.len() && b"+-.eE0123456789".contains(&self.text[self.pos]) {
+                    self.pos += 1;
+                }
+                match self.pos > start {
+                    true => Some(Json::Number(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned())),
+                    false => None,
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(c) => *c,
+                None => return None,
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Some(String::from_utf8_lossy(&bytes).into_owned()),
+                b'\\' => {
+                    let escape = match self.text.get(self.pos) {
+                        Some(escape) => *escape,
+                        None => return None,
+                    };
+                    self.pos += 1;
+                    match escape {
+                        b'b' => bytes.push(8),
+                        b'f' => bytes.push(12),
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'u' => {
+                            let code = self.text.get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+                            self.pos += 4;
+                            // Surrogate pairs aren't needed for the fields compared.
+                            let c = code.and_then(std::char::from_u32).unwrap_or('\u{fffd}');
+                            bytes.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        other => bytes.push(other),
+                    }
+                }
+                other => bytes.push(other),
+            }
+        }
+    }
+}
+
+fn parse_json(text: &[u8]) -> Option<Json> {
+    JsonParser { text: text, pos: 0 }.value()
+}
+
+/// Run ffprobe on `path`, returning its streams, or None if ffprobe
+/// couldn't be run.
+fn probe(path: &Path) -> Option<Vec<Json>> {
+    let output = match Command::new("ffprobe")
+        .args(&["-v", "quiet", "-print_format", "json", "-show_streams"])
+        .arg(path)
+        .output() {
+        Ok(output) => output,
+        Err(_) => return None,
+    };
+    if !output.status.success() {
+        return Some(Vec::new());
+    }
+    match parse_json(&output.stdout) {
+        Some(Json::Object(members)) => {
+            Some(members.into_iter()
+                .filter(|m| m.0 == "streams")
+                .flat_map(|m| match m.1 {
+                    Json::Array(streams) => streams,
+                    _ => Vec::new(),
+                })
+                .collect())
+        }
+        _ => panic!("unparsable ffprobe output for {}", path.display()),
+    }
+}
+
+/// Compare one track with the matching ffprobe stream, appending a
+/// description of each mismatch to `divergences`.
+fn compare_track(track: &mp4parse::Track, stream: &Json, divergences: &mut Vec<String>) {
+    let mut check = |field: &str, ours: Option<String>| {
+        if let (Some(ours), Some(theirs)) = (ours, stream.get(field).and_then(Json::text)) {
+            // ffprobe reports N/A for values it doesn't know.
+            if theirs != "N/A" && ours != theirs {
+                divergences.push(format!("{}: ours {} ffprobe {}", field, ours, theirs));
+            }
+        }
+    };
+    check("codec_type", match track.track_type {
+        mp4parse::TrackType::Video => Some("video".to_string()),
+        mp4parse::TrackType::Audio => Some("audio".to_string()),
+        _ => None,
+    });
+    check("time_base", track.timescale.map(|t| format!("1/{}", t.0)));
+    match track.data {
+        Some(mp4parse::SampleEntry::Video(ref video)) => {
+            check("width", Some(video.width.to_string()));
+            check("height", Some(video.height.to_string()));
+        }
+        Some(mp4parse::SampleEntry::Audio(ref audio)) => {
+            check("sample_rate", Some(audio.samplerate.integer_part().to_string()));
+        }
+        _ => {}
+    }
+    check("nb_frames", track.sample_table().ok().map(|samples| samples.len().to_string()));
+
+    // ffprobe gives the duration in the stream's time base and in
+    // seconds; the latter must agree to within a tick once converted.
+    let (duration, timescale) = match (track.duration, track.timescale) {
+        (Some(duration), Some(timescale)) if timescale.0 > 0 => (duration.0, timescale.0),
+        _ => return,
+    };
+    check("duration_ts", Some(duration.to_string()));
+    let theirs = stream.get("duration").and_then(Json::text).and_then(|d| d.parse::<f64>().ok());
+    if let Some(theirs) = theirs {
+        let ours = duration as f64 / timescale as f64;
+        if (ours - theirs).abs() > 1.0 / timescale as f64 {
+            divergences.push(format!("duration: ours {}s ffprobe {}s", ours, theirs));
+        }
+    }
+}
+
+#[test]
+fn parse_ffprobe_json() {
+    let json = parse_json(br#"{ "streams": [ { "index": 0, "time_base": "1/90000",
+                                 "tags": { "handler_name": "Video \"A\"" }, "side_data": [],
+                                 "avg": -1.5e3, "default": true, "x": null } ] }"#).unwrap();
+    let stream = match json.get("streams") {
+        Some(&Json::Array(ref streams)) => &streams[0],
+        _ => panic!("expected a streams array"),
+    };
+    assert_eq!(stream.get("index").and_then(Json::text), Some("0".to_string()));
+    assert_eq!(stream.get("time_base").and_then(Json::text), Some("1/90000".to_string()));
+    assert_eq!(stream.get("avg").and_then(Json::text), Some("-1.5e3".to_string()));
+    assert_eq!(stream.get("default").and_then(Json::text), Some("true".to_string()));
+    assert_eq!(stream.get("tags").and_then(|t| t.get("handler_name")).and_then(Json::text),
+               Some("Video \"A\"".to_string()));
+    assert!(parse_json(b"{\"streams\": [").is_none());
+}
+
+#[test]
+fn compare_with_ffprobe() {
+    let corpus = match env::var_os("MP4PARSE_FFPROBE_CORPUS") {
+        Some(corpus) => corpus,
+        None => return,
+    };
+    let mut divergences = Vec::new();
+    for entry in fs::read_dir(corpus).expect("unreadable corpus directory") {
+        let path = entry.expect("unreadable corpus entry").path();
+        let streams = match probe(&path) {
+            Some(streams) => streams,
+            None => {
+                println!("ffprobe unavailable, skipping comparison");
+                return;
+            }
+        };
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mut context = mp4parse::MediaContext::new();
+        let parsed = mp4parse::read_mp4(&mut file, &mut context);
+        if parsed.is_err() != streams.is_empty() {
+            divergences.push(format!("{}: ours {:?} ffprobe found {} streams",
+                                     path.display(), parsed, streams.len()));
+            continue;
+        }
+        if streams.len() != context.tracks.len() {
+            divergences.push(format!("{}: ours {} tracks ffprobe {}",
+                                     path.display(), context.tracks.len(), streams.len()));
+            continue;
+        }
+        for (index, (track, stream)) in context.tracks.iter().zip(&streams).enumerate() {
+            let mut found = Vec::new();
+            compare_track(track, stream, &mut found);
+            divergences.extend(found.into_iter()
+                .map(|d| format!("{} track {}: {}", path.display(), index, d)));
+        }
+    }
+    for divergence in &divergences {
+        println!("{}", divergence);
+    }
+    assert!(divergences.is_empty(), "{} divergences from ffprobe", divergences.len());
+}
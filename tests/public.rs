@@ -5,6 +5,8 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 extern crate mp4parse as mp4;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use std::io::{Cursor, Read};
 use std::fs::File;
@@ -20,8 +22,10 @@ fn public_api() {
     let mut context = mp4::MediaContext::new();
     mp4::read_mp4(&mut c, &mut context).expect("read_mp4 failed");
     assert_eq!(context.timescale, Some(mp4::MediaTimeScale(1000)));
+    assert_eq!(context.rate, Some(mp4::SignedFixedPoint16_16(0x00010000)));
+    assert_eq!(context.volume, Some(mp4::FixedPoint8_8(0x0100)));
     for track in context.tracks {
-        match track.data {
+        match track.data.clone() {
             Some(mp4::SampleEntry::Video(v)) => {
                 // track part
                 assert_eq!(track.duration, Some(mp4::TrackScaledTime(512, 0)));
@@ -31,12 +35,23 @@ fn public_api() {
                 assert_eq!(v.width, 320);
                 assert_eq!(v.height, 240);
 
+                let samples = track.sample_table().unwrap();
+                assert_eq!(samples.len(), 1);
+                assert_eq!(samples[0].offset, 1500);
+                assert_eq!(samples[0].size, 751);
+                assert!(samples[0].sync);
+
+                assert!(track.enabled);
+                assert!(track.in_movie);
+
                 // track.tkhd part
                 let tkhd = track.tkhd.unwrap();
                 assert_eq!(tkhd.disabled, false);
                 assert_eq!(tkhd.duration, 40);
-                assert_eq!(tkhd.width, 20971520);
-                assert_eq!(tkhd.height, 15728640);
+                assert_eq!(tkhd.width, mp4::FixedPoint16_16(20971520));
+                assert_eq!(tkhd.height, mp4::FixedPoint16_16(15728640));
+                assert_eq!(tkhd.width.integer_part(), 320);
+                assert_eq!(tkhd.height.integer_part(), 240);
 
                 // track.data part
                 assert_eq!(match v.codec_specific {
@@ -44,6 +59,10 @@ fn public_api() {
                         assert!(v.len() > 0);
                         "AVC"
                     }
+                    mp4::VideoCodecSpecific::HEVCConfig(v) => {
+                        assert!(v.len() > 0);
+                        "HEVC"
+                    }
                     mp4::VideoCodecSpecific::VPxConfig(vpx) => {
                         // We don't enter in here, we just check if fields are public.
                         assert!(vpx.bit_depth > 0);
@@ -52,6 +71,10 @@ fn public_api() {
                         assert!(vpx.codec_init.len() > 0);
                         "VPx"
                     }
+                    mp4::VideoCodecSpecific::AV1Config(v) => {
+                        assert!(v.len() > 0);
+                        "AV1"
+                    }
                 }, "AVC");
             }
             Some(mp4::SampleEntry::Audio(a)) => {
@@ -61,12 +84,17 @@ fn public_api() {
                 assert_eq!(track.media_time, Some(mp4::TrackScaledTime(1024, 1)));
                 assert_eq!(track.timescale, Some(mp4::TrackTimeScale(48000, 1)));
 
+                let range = track.extract_range(1024, 2048).unwrap();
+                assert_eq!(range.first_sample, 1);
+                assert_eq!(range.samples.len(), 1);
+
                 // track.tkhd part
                 let tkhd = track.tkhd.unwrap();
                 assert_eq!(tkhd.disabled, false);
                 assert_eq!(tkhd.duration, 62);
-                assert_eq!(tkhd.width, 0);
-                assert_eq!(tkhd.height, 0);
+                assert_eq!(tkhd.volume, mp4::FixedPoint8_8(0x0100));
+                assert_eq!(tkhd.width, mp4::FixedPoint16_16(0));
+                assert_eq!(tkhd.height, mp4::FixedPoint16_16(0));
 
                 // track.data part
                 assert_eq!(match a.codec_specific {
@@ -79,11 +107,218 @@ fn public_api() {
                         assert!(opus.version > 0);
                         "Opus"
                     }
+                    mp4::AudioCodecSpecific::FLACSpecificBox(flac) => {
+                        // We don't enter in here, we just check if fields are public.
+                        assert!(flac.blocks.len() > 0);
+                        "FLAC"
+                    }
                 }, "ES");
                 assert!(a.samplesize > 0);
-                assert!(a.samplerate > 0);
+                assert_eq!(a.samplerate.integer_part(), 48000);
             }
             Some(mp4::SampleEntry::Unknown) | None => {}
         }
     }
 }
+
+#[test]
+fn demux_elementary_streams() {
+    let mut fd = File::open("examples/minimal.mp4").expect("Unknown file");
+    let mut context = mp4::MediaContext::new();
+    mp4::read_mp4(&mut fd, &mut context).expect("read_mp4 failed");
+    for track in &context.tracks {
+        let samples = track.sample_table().unwrap();
+        let data = mp4::read_sample(&mut fd, &samples[0]).unwrap();
+        assert_eq!(data.len(), samples[0].size as usize);
+        match track.data {
+            Some(mp4::SampleEntry::Video(ref v)) => {
+                let avcc = match v.codec_specific {
+                    mp4::VideoCodecSpecific::AVCConfig(ref avcc) => avcc,
+                    _ => panic!("expected AVC"),
+                };
+                let config = mp4::avcc_to_annexb(avcc).unwrap();
+                assert_eq!(config.nal_length_size, 4);
+                assert_eq!(&config.parameter_sets[..4], &[0, 0, 0, 1]);
+                let annexb = mp4::sample_to_annexb(&data, config.nal_length_size).unwrap();
+                assert_eq!(annexb.len(), data.len());
+                assert_eq!(&annexb[..4], &[0, 0, 0, 1]);
+            }
+            Some(mp4::SampleEntry::Audio(ref a)) => {
+                let esds = match a.codec_specific {
+                    mp4::AudioCodecSpecific::ES_Descriptor(ref esds) => esds,
+                    _ => panic!("expected AAC"),
+                };
+                let config = mp4::esds_audio_specific_config(esds).unwrap();
+                let header = mp4::adts_header(&config, data.len()).unwrap();
+                assert_eq!(&header[..2], &[0xff, 0xf1]);
+                // Frame length includes the header.
+                let length = (header[3] as usize & 0x3) << 11 |
+                             (header[4] as usize) << 3 |
+                             (header[5] as usize) >> 5;
+                assert_eq!(length, data.len() + 7);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn extract_flac() {
+    let mut fd = File::open("examples/flac.mp4").expect("Unknown file");
+    let mut context = mp4::MediaContext::new();
+    mp4::read_mp4(&mut fd, &mut context).expect("read_mp4 failed");
+    let track = &context.tracks[0];
+    assert_eq!(track.mime_type, "audio/flac");
+    let dfla = match track.data {
+        Some(mp4::SampleEntry::Audio(ref a)) => match a.codec_specific {
+            mp4::AudioCodecSpecific::FLACSpecificBox(ref dfla) => dfla,
+            _ => panic!("expected FLAC"),
+        },
+        _ => panic!("expected an audio sample entry"),
+    };
+
+    // As the extract example writes a .flac file.
+    let mut stream = mp4::flac_stream_header(dfla).unwrap();
+    for sample in &track.sample_table().unwrap() {
+        stream.extend_from_slice(&mp4::read_sample(&mut fd, sample).unwrap());
+    }
+
+    let mut output = Cursor::new(stream);
+    mp4::read_flac_stream_marker(&mut output).unwrap();
+    let block = mp4::read_flac_metadata_block(&mut output).unwrap();
+    assert_eq!(block.block_type, mp4::FLAC_METADATA_STREAMINFO);
+    assert!(block.last);
+    let info = mp4::parse_flac_stream_info(&block.data).unwrap();
+    assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (8000, 1, 16));
+    assert_eq!(info.total_samples, 384);
+    for number in 0..2 {
+        let position = output.position();
+        let header = mp4::read_flac_frame_header(&mut output).unwrap();
+        assert_eq!(header.number, number);
+        assert_eq!(header.block_size, 192);
+        // Skip the constant subframe and the frame CRC.
+        output.set_position(position + 11);
+    }
+    assert_eq!(output.position(), output.get_ref().len() as u64);
+}
+
+#[test]
+fn avc_sequence_parameter_set() {
+    let mut fd = File::open("examples/minimal.mp4").expect("Unknown file");
+    let mut context = mp4::MediaContext::new();
+    mp4::read_mp4(&mut fd, &mut context).expect("read_mp4 failed");
+    let sps = context.tracks[0].avc_sequence_parameter_set().unwrap().unwrap();
+    assert_eq!(sps.profile_idc, 100);
+    assert_eq!(sps.level_idc, 13);
+    assert_eq!(sps.chroma_format_idc, 1);
+    assert_eq!(sps.bit_depth_luma, 8);
+    assert_eq!(sps.bit_depth_chroma, 8);
+    assert_eq!((sps.width, sps.height), (320, 240));
+    assert!(context.check_dimensions().is_empty());
+    // The audio track has no SPS.
+    assert_eq!(context.tracks[1].avc_sequence_parameter_set().unwrap(), None);
+}
+
+#[cfg(feature = "parse_cache")]
+#[test]
+fn parse_cache() {
+    let mut file = File::open("examples/minimal.mp4").expect("Unknown file");
+    let mut parsed = mp4::MediaContext::new();
+    parsed.mode = mp4::ParseMode::Full;
+    mp4::read_mp4_seekable(&mut file, &mut parsed).expect("read_mp4_seekable failed");
+
+    let mut cache = Vec::new();
+    mp4::write_parse_cache(&parsed, &mut cache).expect("write_parse_cache failed");
+
+    let mut loaded = mp4::MediaContext::new();
+    loaded.mode = mp4::ParseMode::Full;
+    mp4::read_parse_cache(&mut Cursor::new(&cache), &mut loaded).expect("read_parse_cache failed");
+    assert_eq!(format!("{:?}", loaded), format!("{:?}", parsed));
+    assert!(loaded.tracks[0].samples.as_ref().map_or(false, |s| !s.is_empty()));
+
+    // FLAC sample entries carry their own codec specific box.
+    let mut flac = mp4::MediaContext::new();
+    mp4::read_mp4(&mut File::open("examples/flac.mp4").expect("Unknown file"), &mut flac).expect("read_mp4 failed");
+    let mut flac_cache = Vec::new();
+    mp4::write_parse_cache(&flac, &mut flac_cache).expect("write_parse_cache failed");
+    let mut flac_loaded = mp4::MediaContext::new();
+    mp4::read_parse_cache(&mut Cursor::new(&flac_cache), &mut flac_loaded).expect("read_parse_cache failed");
+    assert_eq!(format!("{:?}", flac_loaded), format!("{:?}", flac));
+
+    // A cache whose dfLa lacks STREAMINFO is rejected, as parsing would.
+    if let Some(mp4::SampleEntry::Audio(ref mut audio)) = flac.tracks[0].data {
+        if let mp4::AudioCodecSpecific::FLACSpecificBox(ref mut dfla) = audio.codec_specific {
+            dfla.blocks.remove(0);
+        }
+    }
+    let mut bad_cache = Vec::new();
+    mp4::write_parse_cache(&flac, &mut bad_cache).expect("write_parse_cache failed");
+    let mut untouched = mp4::MediaContext::new();
+    match mp4::read_parse_cache(&mut Cursor::new(&bad_cache), &mut untouched) {
+        Err(mp4::Error::InvalidData(s)) => assert_eq!(s, "dfLa without STREAMINFO in parse cache"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(untouched.tracks.is_empty());
+
+    let truncated = &cache[..cache.len() - 1];
+    assert!(mp4::read_parse_cache(&mut Cursor::new(truncated), &mut mp4::MediaContext::new()).is_err());
+    cache[0] = b'M';
+    match mp4::read_parse_cache(&mut Cursor::new(&cache), &mut mp4::MediaContext::new()) {
+        Err(mp4::Error::InvalidData(s)) => assert_eq!(s, "not a parse cache"),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tracing::{span, Event, Level, Metadata};
+
+    #[derive(Default)]
+    struct Counts {
+        spans: AtomicUsize,
+        warnings: AtomicUsize,
+    }
+
+    /// Count box spans and warning events.
+    struct CountingSubscriber(Arc<Counts>);
+
+    impl tracing::Subscriber for CountingSubscriber {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, span: &span::Attributes) -> span::Id {
+            if span.metadata().name() == "box" {
+                self.0.spans.fetch_add(1, Ordering::SeqCst);
+            }
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event) {
+            if *event.metadata().level() == Level::WARN {
+                self.0.warnings.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let counts = Arc::new(Counts::default());
+    tracing::subscriber::with_default(CountingSubscriber(counts.clone()), || {
+        let mut file = File::open("examples/minimal.mp4").expect("Unknown file");
+        let mut context = mp4::MediaContext::new();
+        mp4::read_mp4(&mut file, &mut context).expect("read_mp4 failed");
+
+        // A leaf box too short to parse, skipped with a warning.
+        let mut context = mp4::MediaContext::new();
+        context.strictness = mp4::ParseStrictness::Lenient;
+        let moov = b"\0\0\0\x14moov\0\0\0\x0cmvhd\0\0\0\0";
+        let _ = mp4::read_mp4(&mut Cursor::new(&moov[..]), &mut context);
+    });
+    // At least ftyp, moov, mvhd and the tracks' boxes.
+    assert!(counts.spans.load(Ordering::SeqCst) > 10);
+    assert!(counts.warnings.load(Ordering::SeqCst) > 0);
+}